@@ -543,6 +543,11 @@ impl AsRef<Vec<u8>> for IndexId {
         &self.data
     }
 }
+impl AsRef<[u8]> for IndexId {
+    fn as_ref(&self) -> &[u8] {
+        &self.data[..]
+    }
+}
 impl fmt::Display for IndexId {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 
@@ -0,0 +1,83 @@
+#![feature(test)]
+
+extern crate test;
+
+use merkdb::owner::Owner;
+use merkdb::test_utils::*;
+use merkdb::tree::{Commit, PanicSource, SplitCommit, Tree, Walker};
+use merkdb::{Batch, Result};
+use test::Bencher;
+
+/// Encodes every written node, like the committer `Merk` uses, and keeps the
+/// whole tree in memory.
+#[derive(Default)]
+struct EncodeCommit(Vec<(Vec<u8>, Vec<u8>)>);
+
+impl Commit for EncodeCommit {
+    fn write(&mut self, tree: &Tree) -> Result<()> {
+        self.0.push((tree.key().to_vec(), tree.encode()));
+        Ok(())
+    }
+
+    fn prune(&self, _tree: &Tree) -> (bool, bool) {
+        (false, false)
+    }
+}
+
+impl SplitCommit for EncodeCommit {
+    fn split(&self) -> Self {
+        EncodeCommit::default()
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.0.extend(other.0);
+    }
+}
+
+fn apply_uncommitted(tree: Tree, batch: &Batch) -> Tree {
+    let walker = Walker::new(tree, PanicSource {});
+    Walker::apply_to(Some(walker), batch, PanicSource {})
+        .expect("apply failed")
+        .0
+        .expect("expected tree")
+}
+
+fn bench_commit(b: &mut Bencher, depth: u8) {
+    let initial_size = 1_000_000;
+    let batch_size = 10_000;
+
+    let mut tree = Owner::new(make_tree_rand(initial_size, batch_size, 0));
+
+    let mut i = initial_size / batch_size;
+    b.iter(|| {
+        let batch = make_batch_rand(batch_size, i);
+        tree.own(|tree| {
+            let mut tree = apply_uncommitted(tree, &batch);
+            let mut committer = EncodeCommit::default();
+            tree.commit_parallel(&mut committer, depth)
+                .expect("commit failed");
+            tree
+        });
+        i += 1;
+    });
+}
+
+#[bench]
+fn commit_1m_10k_rand_memonly_serial(b: &mut Bencher) {
+    bench_commit(b, 0);
+}
+
+#[bench]
+fn commit_1m_10k_rand_memonly_2_threads(b: &mut Bencher) {
+    bench_commit(b, 1);
+}
+
+#[bench]
+fn commit_1m_10k_rand_memonly_4_threads(b: &mut Bencher) {
+    bench_commit(b, 2);
+}
+
+#[bench]
+fn commit_1m_10k_rand_memonly_8_threads(b: &mut Bencher) {
+    bench_commit(b, 3);
+}
@@ -4,8 +4,20 @@ extern crate test;
 
 use merkdb::owner::Owner;
 use merkdb::test_utils::*;
+use merkdb::tree::{NoopCommit, PanicSource, Tree, Walker};
+use merkdb::Batch;
 use test::Bencher;
 
+fn apply_memonly_parallel(tree: Tree, batch: &Batch, depth: u8) -> Tree {
+    let walker = Walker::new(tree, PanicSource {});
+    let mut tree = Walker::apply_to_parallel(Some(walker), batch, PanicSource {}, depth)
+        .expect("apply failed")
+        .0
+        .expect("expected tree");
+    tree.commit(&mut NoopCommit {}).expect("commit failed");
+    tree
+}
+
 #[bench]
 fn insert_1m_10k_seq_memonly(b: &mut Bencher) {
     let initial_size = 1_000_000;
@@ -36,6 +48,36 @@ fn insert_1m_10k_rand_memonly(b: &mut Bencher) {
     });
 }
 
+#[bench]
+fn insert_1m_10k_rand_memonly_parallel_2_threads(b: &mut Bencher) {
+    let initial_size = 1_000_000;
+    let batch_size = 10_000;
+
+    let mut tree = Owner::new(make_tree_rand(initial_size, batch_size, 0));
+
+    let mut i = initial_size / batch_size;
+    b.iter(|| {
+        let batch = make_batch_rand(batch_size, i);
+        tree.own(|tree| apply_memonly_parallel(tree, &batch, 1));
+        i += 1;
+    });
+}
+
+#[bench]
+fn insert_1m_10k_rand_memonly_parallel_4_threads(b: &mut Bencher) {
+    let initial_size = 1_000_000;
+    let batch_size = 10_000;
+
+    let mut tree = Owner::new(make_tree_rand(initial_size, batch_size, 0));
+
+    let mut i = initial_size / batch_size;
+    b.iter(|| {
+        let batch = make_batch_rand(batch_size, i);
+        tree.own(|tree| apply_memonly_parallel(tree, &batch, 2));
+        i += 1;
+    });
+}
+
 #[bench]
 fn update_1m_10k_seq_memonly(b: &mut Bencher) {
     let initial_size = 1_000_000;
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use merkdb::test_utils::check_decode;
+
+fuzz_target!(|data: &[u8]| {
+    check_decode(data);
+});
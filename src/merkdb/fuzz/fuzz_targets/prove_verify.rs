@@ -0,0 +1,64 @@
+#![no_main]
+
+use std::collections::BTreeMap;
+
+use libfuzzer_sys::fuzz_target;
+use merkdb::proofs::query::QueryItem;
+use merkdb::proofs::ProofEncoding;
+use merkdb::test_utils::check_proof;
+use merkdb::HashAlgorithm;
+
+const HASH_ALGORITHMS: [HashAlgorithm; 3] = [
+    HashAlgorithm::Sha512_256,
+    HashAlgorithm::Blake3,
+    HashAlgorithm::Sha3_256,
+];
+
+// The input is a byte of settings followed by length-prefixed fields: pairs
+// of keys and values for the entries, then the bounds of the query items.
+fuzz_target!(|data: &[u8]| {
+    let (settings, mut rest) = match data.split_first() {
+        Some((settings, rest)) => (*settings, rest),
+        None => return,
+    };
+    let left_to_right = settings & 1 == 0;
+    let hash_algorithm = HASH_ALGORITHMS[(settings as usize >> 1) % 3];
+    let encoding = match settings >> 3 & 3 {
+        0 => ProofEncoding::Legacy,
+        1 => ProofEncoding::Compact { compress: false },
+        _ => ProofEncoding::Compact { compress: true },
+    };
+    let entry_count = (settings >> 5) as usize * 4 + 1;
+
+    let mut fields = std::iter::from_fn(|| {
+        let (len, tail) = rest.split_first()?;
+        let len = (*len as usize).min(tail.len());
+        let (field, tail) = tail.split_at(len);
+        rest = tail;
+        Some(field.to_vec())
+    });
+
+    let mut entries = BTreeMap::new();
+    for _ in 0..entry_count {
+        match (fields.next(), fields.next()) {
+            (Some(key), Some(value)) if !key.is_empty() => {
+                entries.insert(key, value);
+            }
+            (Some(_), Some(_)) => {}
+            _ => break,
+        }
+    }
+
+    let mut items = vec![];
+    while let (Some(kind), Some(start), Some(end)) = (fields.next(), fields.next(), fields.next())
+    {
+        let item = match kind.first().map_or(0, |kind| kind % 3) {
+            0 => QueryItem::Key(start),
+            1 => QueryItem::Range(start..end),
+            _ => QueryItem::RangeInclusive(start..=end),
+        };
+        items.push(item);
+    }
+
+    check_proof(&entries, &items, left_to_right, hash_algorithm, encoding);
+});
@@ -0,0 +1,21 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use merkdb::test_utils::check_restore_chunk;
+
+// The first byte picks the chunk to replace, the second is the number of
+// chunks the restore is told to expect, and each remaining byte is XORed into
+// the chunk at the same offset.
+fuzz_target!(|data: &[u8]| {
+    let (index, stated_length, mask) = match data {
+        [index, stated_length, mask @ ..] => (*index as usize, *stated_length as usize, mask),
+        _ => return,
+    };
+    check_restore_chunk(index, stated_length, |chunk| {
+        chunk
+            .iter()
+            .zip(mask.iter().chain(std::iter::repeat(&0)))
+            .map(|(byte, mask)| byte ^ mask)
+            .collect()
+    });
+});
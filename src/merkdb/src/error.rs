@@ -10,10 +10,16 @@ pub enum Error {
     Bound(String),
     #[error("Chunk Processing Error: {0}")]
     ChunkProcessing(String),
+    #[error("Column Family Error: {0}")]
+    ColumnFamily(String),
+    #[error("Config Error: {0}")]
+    Config(String),
     #[error(transparent)]
     Ed(#[from] ed::Error),
     #[error("Fetch Error: {0}")]
     Fetch(String),
+    #[error("Hash Algorithm Error: {0}")]
+    HashAlgorithm(String),
     #[error("Proof did not match expected hash\n\tExpected: {0:?}\n\tActual: {1:?}")]
     HashMismatch([u8; 32], [u8; 32]),
     #[error("Index OoB Error: {0}")]
@@ -30,10 +36,14 @@ pub enum Error {
     KeyNotFound(String),
     #[error("Proof is missing data for query")]
     MissingData,
+    #[error("Store was not opened as a secondary")]
+    NotSecondary,
     #[error("Path Error: {0}")]
     Path(String),
     #[error("Proof Error: {0}")]
     Proof(String),
+    #[error("Store was opened read-only")]
+    ReadOnly,
     #[cfg(feature = "full")]
     #[error(transparent)]
     RocksDB(#[from] rocksdb::Error),
@@ -45,6 +55,8 @@ pub enum Error {
     UnexpectedNode(String),
     #[error("Unknown Error")]
     Unknown,
+    #[error("Version Error: {0}")]
+    Version(String),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -10,7 +10,6 @@ pub use rocksdb;
 /// Error and Result types.
 mod error;
 /// The top-level store API.
-#[cfg(feature = "full")]
 mod merk;
 /// Provides a container type that allows temporarily taking ownership of a value.
 // TODO: move this into its own crate
@@ -24,13 +23,21 @@ pub mod test_utils;
 /// The core tree data structure.
 pub mod tree;
 
+#[cfg(feature = "prometheus")]
+pub use crate::merk::PrometheusExporter;
+pub use crate::merk::{
+    backend, chunks, Compression, Inconsistency, InconsistencyKind, IntegrityOptions,
+    IntegrityReport, KeyRange, Merk, MerkConfig, MerkSource, MerkStats, MultiStore, RangeIter,
+    RetentionPolicy, Snapshot, Subtree, Transaction, Version, WalSync,
+};
 #[cfg(feature = "full")]
-pub use crate::merk::{chunks, restore, Merk, MerkSource, Snapshot};
+pub use crate::merk::{restore, RepairProgress};
 
 pub use error::{Error, Result};
-pub use tree::{Batch, BatchEntry, Hash, Op, PanicSource, HASH_LENGTH};
+pub use tree::{Batch, BatchEntry, Hash, HashAlgorithm, Op, PanicSource, HASH_LENGTH};
 
 #[allow(deprecated)]
 pub use proofs::query::verify_query;
 
-pub use proofs::query::verify;
+pub use proofs::count::verify_count;
+pub use proofs::query::{verify, verify_entries, verify_membership, verify_subtree};
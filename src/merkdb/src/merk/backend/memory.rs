@@ -0,0 +1,323 @@
+//! An in-memory `Backend` built on `BTreeMap`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::sync::{Arc, RwLock};
+
+use super::{Backend, BackendSnapshot, ColumnFamilySize, RawIterator, WriteBatch};
+use crate::Result;
+
+type Map = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// A `Backend` which keeps all column families in memory. Nothing is
+/// persisted, so the store is lost when the backend is dropped.
+///
+/// Column families are created on first write. Snapshots and iterators share
+/// the column families they read from and only copy one if it is written to
+/// while they are alive.
+#[derive(Default)]
+pub struct MemoryBackend {
+    cfs: RwLock<HashMap<String, Arc<Map>>>,
+}
+
+impl MemoryBackend {
+    /// Creates an empty backend.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn cf(&self, cf: &str) -> Arc<Map> {
+        self.cfs
+            .read()
+            .unwrap()
+            .get(cf)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+impl Backend for MemoryBackend {
+    type RawIter<'a> = MemoryRawIterator;
+    type Snapshot<'a> = MemorySnapshot;
+    type Batch<'a> = MemoryBatch;
+
+    fn get_cf(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let cfs = self.cfs.read().unwrap();
+        Ok(cfs.get(cf).and_then(|map| map.get(key)).cloned())
+    }
+
+    fn batch(&self) -> MemoryBatch {
+        MemoryBatch::default()
+    }
+
+    fn write(&self, batch: MemoryBatch) -> Result<()> {
+        let mut cfs = self.cfs.write().unwrap();
+        for (cf, key, maybe_value) in batch.ops {
+            let map = Arc::make_mut(cfs.entry(cf).or_default());
+            match maybe_value {
+                Some(value) => map.insert(key, value),
+                None => map.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn raw_iter_cf(&self, cf: &str) -> MemoryRawIterator {
+        MemoryRawIterator::new(self.cf(cf))
+    }
+
+    fn snapshot(&self) -> MemorySnapshot {
+        MemorySnapshot {
+            cfs: self.cfs.read().unwrap().clone(),
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn column_family_size(&self, cf: &str) -> Result<Option<ColumnFamilySize>> {
+        let cfs = self.cfs.read().unwrap();
+        Ok(cfs.get(cf).map(|map| ColumnFamilySize {
+            keys: map.len() as u64,
+            bytes: map
+                .iter()
+                .map(|(key, value)| (key.len() + value.len()) as u64)
+                .sum(),
+        }))
+    }
+}
+
+/// A point-in-time view of a `MemoryBackend`.
+pub struct MemorySnapshot {
+    cfs: HashMap<String, Arc<Map>>,
+}
+
+impl BackendSnapshot for MemorySnapshot {
+    type RawIter<'a> = MemoryRawIterator;
+
+    fn get_cf(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.cfs.get(cf).and_then(|map| map.get(key)).cloned())
+    }
+
+    fn raw_iter_cf(&self, cf: &str) -> MemoryRawIterator {
+        MemoryRawIterator::new(self.cfs.get(cf).cloned().unwrap_or_default())
+    }
+}
+
+/// A batch of writes for a `MemoryBackend`.
+#[derive(Default)]
+pub struct MemoryBatch {
+    ops: Vec<(String, Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl WriteBatch for MemoryBatch {
+    fn put_cf(&mut self, cf: &str, key: &[u8], value: &[u8]) {
+        self.ops
+            .push((cf.to_string(), key.to_vec(), Some(value.to_vec())));
+    }
+
+    fn delete_cf(&mut self, cf: &str, key: &[u8]) {
+        self.ops.push((cf.to_string(), key.to_vec(), None));
+    }
+
+    fn len(&self) -> usize {
+        self.ops.len()
+    }
+}
+
+/// A raw iterator over a column family of a `MemoryBackend`. Iterates over
+/// the column family as it was when the iterator was created.
+pub struct MemoryRawIterator {
+    map: Arc<Map>,
+    current: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl MemoryRawIterator {
+    fn new(map: Arc<Map>) -> Self {
+        MemoryRawIterator { map, current: None }
+    }
+}
+
+fn to_owned_entry((key, value): (&Vec<u8>, &Vec<u8>)) -> (Vec<u8>, Vec<u8>) {
+    (key.clone(), value.clone())
+}
+
+impl RawIterator for MemoryRawIterator {
+    fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.current = self.map.iter().next().map(to_owned_entry);
+    }
+
+    fn seek_to_last(&mut self) {
+        self.current = self.map.iter().next_back().map(to_owned_entry);
+    }
+
+    fn seek(&mut self, key: &[u8]) {
+        self.current = self
+            .map
+            .range::<[u8], _>((Included(key), Unbounded))
+            .next()
+            .map(to_owned_entry);
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) {
+        self.current = self
+            .map
+            .range::<[u8], _>((Unbounded, Included(key)))
+            .next_back()
+            .map(to_owned_entry);
+    }
+
+    fn next(&mut self) {
+        let next = self.current.as_ref().and_then(|(key, _)| {
+            self.map
+                .range::<[u8], _>((Excluded(key.as_slice()), Unbounded))
+                .next()
+        });
+        self.current = next.map(to_owned_entry);
+    }
+
+    fn prev(&mut self) {
+        let prev = self.current.as_ref().and_then(|(key, _)| {
+            self.map
+                .range::<[u8], _>((Unbounded, Excluded(key.as_slice())))
+                .next_back()
+        });
+        self.current = prev.map(to_owned_entry);
+    }
+
+    fn key(&self) -> Option<&[u8]> {
+        self.current.as_ref().map(|(key, _)| key.as_slice())
+    }
+
+    fn value(&self) -> Option<&[u8]> {
+        self.current.as_ref().map(|(_, value)| value.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proofs::query::{verify, Query};
+    use crate::test_utils::*;
+    use crate::{Merk, Op};
+
+    fn backend_with(keys: &[u8]) -> MemoryBackend {
+        let backend = MemoryBackend::new();
+        let mut batch = backend.batch();
+        for key in keys {
+            batch.put(&[*key], &[*key * 2]);
+        }
+        backend.write(batch).unwrap();
+        backend
+    }
+
+    #[test]
+    fn raw_iter_seek_and_step() {
+        let backend = backend_with(&[1, 3, 5, 7]);
+        let mut iter = backend.raw_iter();
+        assert!(!iter.valid());
+
+        iter.seek_to_first();
+        assert_eq!(iter.key(), Some(&[1][..]));
+        assert_eq!(iter.value(), Some(&[2][..]));
+        iter.next();
+        assert_eq!(iter.key(), Some(&[3][..]));
+
+        iter.seek(&[4]);
+        assert_eq!(iter.key(), Some(&[5][..]));
+        iter.prev();
+        assert_eq!(iter.key(), Some(&[3][..]));
+
+        iter.seek_for_prev(&[6]);
+        assert_eq!(iter.key(), Some(&[5][..]));
+        iter.seek_for_prev(&[0]);
+        assert!(!iter.valid());
+
+        iter.seek_to_last();
+        assert_eq!(iter.key(), Some(&[7][..]));
+        iter.next();
+        assert!(!iter.valid());
+        iter.next();
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn column_families_are_separate() {
+        let backend = MemoryBackend::new();
+        let mut batch = backend.batch();
+        batch.put(&[1], &[1]);
+        batch.put_cf("aux", &[1], &[2]);
+        batch.put_cf("aux", &[2], &[3]);
+        batch.delete_cf("aux", &[2]);
+        assert_eq!(batch.len(), 4);
+        backend.write(batch).unwrap();
+
+        assert_eq!(backend.get(&[1]).unwrap(), Some(vec![1]));
+        assert_eq!(backend.get_cf("aux", &[1]).unwrap(), Some(vec![2]));
+        assert_eq!(backend.get_cf("aux", &[2]).unwrap(), None);
+        assert_eq!(backend.get_cf("missing", &[1]).unwrap(), None);
+
+        let mut iter = backend.raw_iter_cf("missing");
+        iter.seek_to_first();
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn snapshots_and_iterators_are_isolated() {
+        let backend = backend_with(&[1, 2]);
+        let snapshot = backend.snapshot();
+        let mut iter = backend.raw_iter();
+
+        let mut batch = backend.batch();
+        batch.delete(&[1]);
+        batch.put(&[3], &[6]);
+        backend.write(batch).unwrap();
+
+        assert_eq!(snapshot.get(&[1]).unwrap(), Some(vec![2]));
+        assert_eq!(snapshot.get(&[3]).unwrap(), None);
+        assert_eq!(backend.get(&[1]).unwrap(), None);
+
+        iter.seek_to_first();
+        assert_eq!(iter.key(), Some(&[1][..]));
+        iter.seek_to_last();
+        assert_eq!(iter.key(), Some(&[2][..]));
+
+        let mut snapshot_iter = snapshot.raw_iter();
+        snapshot_iter.seek_to_last();
+        assert_eq!(snapshot_iter.key(), Some(&[2][..]));
+    }
+
+    #[test]
+    fn merk_on_memory_backend() {
+        let mut merk = Merk::open_backend(MemoryBackend::new(), 1).unwrap();
+        merk.apply_versioned(1, &make_batch_seq(0..100), &[(vec![1], Op::Put(vec![2]))])
+            .unwrap();
+        let root_1 = merk.root_hash();
+        merk.apply_versioned(2, &make_del_batch_seq(0..50), &[])
+            .unwrap();
+
+        assert_eq!(merk.get(&seq_key(10)).unwrap(), None);
+        assert_eq!(merk.get(&seq_key(60)).unwrap(), Some(put_entry_value()));
+        assert_eq!(merk.get_aux(&[1]).unwrap(), Some(vec![2]));
+        assert_eq!(merk.version(1).unwrap().root_hash(), root_1);
+        assert_eq!(
+            merk.version(1).unwrap().get(&seq_key(10)).unwrap(),
+            Some(put_entry_value())
+        );
+
+        let mut query = Query::new();
+        query.insert_key(seq_key(60));
+        let proof = merk.prove(query).unwrap();
+        let map = verify(&proof, merk.root_hash()).unwrap();
+        assert_eq!(map.get(&seq_key(60)).unwrap(), Some(&put_entry_value()[..]));
+
+        let snapshot = merk.snapshot().unwrap();
+        assert_eq!(snapshot.root_hash(), merk.root_hash());
+        assert_eq!(snapshot.get(&seq_key(60)).unwrap(), Some(put_entry_value()));
+    }
+}
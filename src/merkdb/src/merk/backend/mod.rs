@@ -0,0 +1,194 @@
+//! Storage backends a `Merk` can be built on.
+//!
+//! A backend is an ordered key/value store with named column families, atomic
+//! batch writes, consistent snapshots and raw ordered iteration. RocksDB is
+//! the default backend when the `full` feature is enabled, and
+//! `MemoryBackend` keeps everything in memory, which is useful for tests and
+//! light clients.
+
+mod memory;
+mod prefixed;
+#[cfg(feature = "full")]
+mod rocks;
+
+pub use self::memory::{MemoryBackend, MemoryBatch, MemoryRawIterator, MemorySnapshot};
+pub use self::prefixed::{PrefixedBackend, PrefixedBatch, PrefixedRawIterator, PrefixedSnapshot};
+#[cfg(feature = "full")]
+pub use self::rocks::{RocksBatch, RocksSnapshot};
+
+use crate::Result;
+
+/// The name of the column family tree nodes are stored in.
+pub const DEFAULT_CF_NAME: &str = "default";
+
+/// The backend used by `Merk` when none is specified.
+#[cfg(feature = "full")]
+pub type DefaultBackend = rocksdb::DB;
+
+/// The backend used by `Merk` when none is specified.
+#[cfg(not(feature = "full"))]
+pub type DefaultBackend = MemoryBackend;
+
+/// An ordered key/value store with named column families.
+///
+/// Reads and writes address column families by name. Reading from or writing
+/// to a column family the backend does not have errors (see
+/// `has_column_family`), and iterating over one may panic.
+pub trait Backend: Sync {
+    /// A raw iterator over one column family.
+    type RawIter<'a>: RawIterator
+    where
+        Self: 'a;
+
+    /// A consistent point-in-time view of the store.
+    type Snapshot<'a>: BackendSnapshot
+    where
+        Self: 'a;
+
+    /// A set of writes which are applied atomically by `write`.
+    type Batch<'a>: WriteBatch
+    where
+        Self: 'a;
+
+    /// Gets the value for `key` in the given column family.
+    fn get_cf(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Gets the value for `key` in the default column family.
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_cf(DEFAULT_CF_NAME, key)
+    }
+
+    /// Returns `true` if the given column family can be read from and written
+    /// to. Backends which create column families on first write, like
+    /// `MemoryBackend`, have all of them.
+    fn has_column_family(&self, _cf: &str) -> bool {
+        true
+    }
+
+    /// Creates an empty batch to be passed to `write`.
+    fn batch(&self) -> Self::Batch<'_>;
+
+    /// Atomically applies all the writes in `batch`.
+    fn write(&self, batch: Self::Batch<'_>) -> Result<()>;
+
+    /// Creates a raw iterator over the given column family. The iterator is
+    /// not positioned until one of its seek methods is called.
+    fn raw_iter_cf(&self, cf: &str) -> Self::RawIter<'_>;
+
+    /// Creates a raw iterator over the default column family.
+    fn raw_iter(&self) -> Self::RawIter<'_> {
+        self.raw_iter_cf(DEFAULT_CF_NAME)
+    }
+
+    /// Takes a snapshot of the current state of the store.
+    fn snapshot(&self) -> Self::Snapshot<'_>;
+
+    /// Flushes any buffered writes to durable storage.
+    fn flush(&self) -> Result<()>;
+
+    /// Syncs the writes logged so far to durable storage, so they are not lost
+    /// if the machine crashes (see `WalSync`). Does nothing unless the backend
+    /// buffers its log.
+    fn sync_wal(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns the number of keys and bytes stored in the given column family,
+    /// which may be estimates. Returns `None` if the backend does not track
+    /// them or does not have the column family.
+    fn column_family_size(&self, _cf: &str) -> Result<Option<ColumnFamilySize>> {
+        Ok(None)
+    }
+}
+
+/// The size of a column family, as reported by
+/// `Backend::column_family_size`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ColumnFamilySize {
+    /// The number of keys.
+    pub keys: u64,
+    /// The number of bytes the keys and values take up.
+    pub bytes: u64,
+}
+
+/// A read-only, point-in-time view of a `Backend`.
+pub trait BackendSnapshot: Sync {
+    /// A raw iterator over one column family of the snapshot.
+    type RawIter<'a>: RawIterator
+    where
+        Self: 'a;
+
+    /// Gets the value for `key` in the given column family.
+    fn get_cf(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Gets the value for `key` in the default column family.
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_cf(DEFAULT_CF_NAME, key)
+    }
+
+    /// Creates a raw iterator over the given column family.
+    fn raw_iter_cf(&self, cf: &str) -> Self::RawIter<'_>;
+
+    /// Creates a raw iterator over the default column family.
+    fn raw_iter(&self) -> Self::RawIter<'_> {
+        self.raw_iter_cf(DEFAULT_CF_NAME)
+    }
+}
+
+/// A cursor over the entries of a column family in key order, modeled after
+/// RocksDB's raw iterator.
+pub trait RawIterator {
+    /// Returns `true` if the iterator is positioned at an entry.
+    fn valid(&self) -> bool;
+
+    /// Moves to the first entry.
+    fn seek_to_first(&mut self);
+
+    /// Moves to the last entry.
+    fn seek_to_last(&mut self);
+
+    /// Moves to the first entry with a key greater than or equal to `key`.
+    fn seek(&mut self, key: &[u8]);
+
+    /// Moves to the last entry with a key less than or equal to `key`.
+    fn seek_for_prev(&mut self, key: &[u8]);
+
+    /// Moves to the next entry.
+    fn next(&mut self);
+
+    /// Moves to the previous entry.
+    fn prev(&mut self);
+
+    /// Returns the key of the current entry, if the iterator is valid.
+    fn key(&self) -> Option<&[u8]>;
+
+    /// Returns the value of the current entry, if the iterator is valid.
+    fn value(&self) -> Option<&[u8]>;
+}
+
+/// A set of puts and deletes to be written atomically.
+pub trait WriteBatch {
+    /// Sets `key` to `value` in the given column family.
+    fn put_cf(&mut self, cf: &str, key: &[u8], value: &[u8]);
+
+    /// Deletes `key` from the given column family.
+    fn delete_cf(&mut self, cf: &str, key: &[u8]);
+
+    /// Sets `key` to `value` in the default column family.
+    fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.put_cf(DEFAULT_CF_NAME, key, value)
+    }
+
+    /// Deletes `key` from the default column family.
+    fn delete(&mut self, key: &[u8]) {
+        self.delete_cf(DEFAULT_CF_NAME, key)
+    }
+
+    /// Returns the number of operations in the batch.
+    fn len(&self) -> usize;
+
+    /// Returns `true` if the batch contains no operations.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
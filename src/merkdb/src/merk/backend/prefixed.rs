@@ -0,0 +1,280 @@
+//! A `Backend` which stores one tree's data under a key prefix of a shared
+//! backend, so that many trees can live in the same store.
+
+use std::sync::Arc;
+
+use super::{Backend, BackendSnapshot, RawIterator, WriteBatch};
+use crate::Result;
+
+/// A view of a shared backend in which every key, in every column family, is
+/// stored under a fixed prefix. Iterators only visit keys under the prefix,
+/// and yield them with the prefix removed.
+///
+/// Prefixes must be chosen so that none is a prefix of another, otherwise the
+/// keyspaces of two views would overlap.
+pub struct PrefixedBackend<B> {
+    db: Arc<B>,
+    prefix: Vec<u8>,
+}
+
+impl<B: Backend + Send> PrefixedBackend<B> {
+    /// Creates a view of `db` which stores keys under `prefix`.
+    pub fn new(db: Arc<B>, prefix: Vec<u8>) -> Self {
+        PrefixedBackend { db, prefix }
+    }
+
+    /// Returns the prefix this view stores keys under.
+    pub fn prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+
+    /// Returns the shared backend.
+    pub fn inner(&self) -> &Arc<B> {
+        &self.db
+    }
+}
+
+fn prefixed(prefix: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut prefixed = Vec::with_capacity(prefix.len() + key.len());
+    prefixed.extend_from_slice(prefix);
+    prefixed.extend_from_slice(key);
+    prefixed
+}
+
+/// Returns the smallest key greater than every key which starts with
+/// `prefix`, or `None` if there is no such key.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(byte) = end.pop() {
+        if byte < u8::MAX {
+            end.push(byte + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+impl<B: Backend + Send> Backend for PrefixedBackend<B> {
+    type RawIter<'a>
+        = PrefixedRawIterator<B::RawIter<'a>>
+    where
+        B: 'a;
+    type Snapshot<'a>
+        = PrefixedSnapshot<B::Snapshot<'a>>
+    where
+        B: 'a;
+    type Batch<'a>
+        = PrefixedBatch<B::Batch<'a>>
+    where
+        B: 'a;
+
+    fn get_cf(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.db.get_cf(cf, &prefixed(&self.prefix, key))
+    }
+
+    fn has_column_family(&self, cf: &str) -> bool {
+        self.db.has_column_family(cf)
+    }
+
+    fn batch(&self) -> Self::Batch<'_> {
+        PrefixedBatch::new(self.db.batch(), self.prefix.clone())
+    }
+
+    fn write(&self, batch: Self::Batch<'_>) -> Result<()> {
+        self.db.write(batch.inner)
+    }
+
+    fn raw_iter_cf(&self, cf: &str) -> Self::RawIter<'_> {
+        PrefixedRawIterator::new(self.db.raw_iter_cf(cf), self.prefix.clone())
+    }
+
+    fn snapshot(&self) -> Self::Snapshot<'_> {
+        PrefixedSnapshot {
+            inner: self.db.snapshot(),
+            prefix: self.prefix.clone(),
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()
+    }
+
+    fn sync_wal(&self) -> Result<()> {
+        self.db.sync_wal()
+    }
+}
+
+/// A snapshot of a `PrefixedBackend`.
+pub struct PrefixedSnapshot<S> {
+    inner: S,
+    prefix: Vec<u8>,
+}
+
+impl<S: BackendSnapshot> BackendSnapshot for PrefixedSnapshot<S> {
+    type RawIter<'a>
+        = PrefixedRawIterator<S::RawIter<'a>>
+    where
+        S: 'a;
+
+    fn get_cf(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_cf(cf, &prefixed(&self.prefix, key))
+    }
+
+    fn raw_iter_cf(&self, cf: &str) -> Self::RawIter<'_> {
+        PrefixedRawIterator::new(self.inner.raw_iter_cf(cf), self.prefix.clone())
+    }
+}
+
+/// A write batch for a `PrefixedBackend`.
+pub struct PrefixedBatch<W> {
+    inner: W,
+    prefix: Vec<u8>,
+}
+
+impl<W> PrefixedBatch<W> {
+    /// Wraps a batch of the shared backend, so that writes to it are made
+    /// under `prefix`.
+    pub(crate) fn new(inner: W, prefix: Vec<u8>) -> Self {
+        PrefixedBatch { inner, prefix }
+    }
+
+    /// Returns the wrapped batch of the shared backend.
+    pub(crate) fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: WriteBatch> WriteBatch for PrefixedBatch<W> {
+    fn put_cf(&mut self, cf: &str, key: &[u8], value: &[u8]) {
+        self.inner.put_cf(cf, &prefixed(&self.prefix, key), value)
+    }
+
+    fn delete_cf(&mut self, cf: &str, key: &[u8]) {
+        self.inner.delete_cf(cf, &prefixed(&self.prefix, key))
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+}
+
+/// A raw iterator over the keys under a prefix, which yields keys with the
+/// prefix removed.
+pub struct PrefixedRawIterator<I> {
+    inner: I,
+    prefix: Vec<u8>,
+}
+
+impl<I: RawIterator> PrefixedRawIterator<I> {
+    fn new(inner: I, prefix: Vec<u8>) -> Self {
+        PrefixedRawIterator { inner, prefix }
+    }
+}
+
+impl<I: RawIterator> RawIterator for PrefixedRawIterator<I> {
+    fn valid(&self) -> bool {
+        self.inner
+            .key()
+            .is_some_and(|key| key.starts_with(&self.prefix))
+    }
+
+    fn seek_to_first(&mut self) {
+        self.inner.seek(&self.prefix);
+    }
+
+    fn seek_to_last(&mut self) {
+        match prefix_end(&self.prefix) {
+            Some(end) => {
+                self.inner.seek_for_prev(&end);
+                if self.inner.key() == Some(end.as_slice()) {
+                    self.inner.prev();
+                }
+            }
+            None => self.inner.seek_to_last(),
+        }
+    }
+
+    fn seek(&mut self, key: &[u8]) {
+        self.inner.seek(&prefixed(&self.prefix, key));
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) {
+        self.inner.seek_for_prev(&prefixed(&self.prefix, key));
+    }
+
+    fn next(&mut self) {
+        self.inner.next();
+    }
+
+    fn prev(&mut self) {
+        self.inner.prev();
+    }
+
+    fn key(&self) -> Option<&[u8]> {
+        self.inner
+            .key()
+            .and_then(|key| key.strip_prefix(self.prefix.as_slice()))
+    }
+
+    fn value(&self) -> Option<&[u8]> {
+        if self.valid() {
+            self.inner.value()
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merk::backend::MemoryBackend;
+
+    #[test]
+    fn prefixed_views_are_separate() {
+        let db = Arc::new(MemoryBackend::new());
+        let a = PrefixedBackend::new(db.clone(), vec![1]);
+        let b = PrefixedBackend::new(db.clone(), vec![2]);
+
+        for (view, value) in [(&a, 10), (&b, 20)] {
+            let mut batch = view.batch();
+            batch.put(&[1], &[value]);
+            batch.put(&[2], &[value + 1]);
+            batch.put_cf("aux", &[1], &[value + 2]);
+            view.write(batch).unwrap();
+        }
+
+        assert_eq!(a.get(&[1]).unwrap(), Some(vec![10]));
+        assert_eq!(b.get(&[1]).unwrap(), Some(vec![20]));
+        assert_eq!(b.get_cf("aux", &[1]).unwrap(), Some(vec![22]));
+        assert_eq!(db.get(&[2, 2]).unwrap(), Some(vec![21]));
+
+        let mut iter = a.raw_iter();
+        iter.seek_to_first();
+        assert_eq!(iter.key(), Some(&[1][..]));
+        iter.next();
+        assert_eq!(iter.key(), Some(&[2][..]));
+        iter.next();
+        assert!(!iter.valid());
+        assert_eq!(iter.key(), None);
+
+        let mut iter = a.raw_iter();
+        iter.seek_to_last();
+        assert_eq!(iter.key(), Some(&[2][..]));
+        assert_eq!(iter.value(), Some(&[11][..]));
+
+        let mut iter = b.raw_iter();
+        iter.seek_to_last();
+        assert_eq!(iter.key(), Some(&[2][..]));
+        iter.seek_for_prev(&[0]);
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn prefix_end_skips_max_bytes() {
+        assert_eq!(prefix_end(&[1, 2]), Some(vec![1, 3]));
+        assert_eq!(prefix_end(&[1, 255]), Some(vec![2]));
+        assert_eq!(prefix_end(&[255, 255]), None);
+        assert_eq!(prefix_end(&[]), None);
+    }
+}
@@ -0,0 +1,203 @@
+//! `Backend` implementation for RocksDB.
+
+use rocksdb::{ColumnFamily, DBRawIterator, DB};
+
+use super::{Backend, BackendSnapshot, ColumnFamilySize, RawIterator, WriteBatch, DEFAULT_CF_NAME};
+use crate::{Error, Result};
+
+fn cf_handle<'a>(db: &'a DB, cf: &str) -> Result<&'a ColumnFamily> {
+    db.cf_handle(cf)
+        .ok_or_else(|| Error::ColumnFamily(format!("Unknown column family \"{cf}\"")))
+}
+
+impl Backend for DB {
+    type RawIter<'a> = DBRawIterator<'a>;
+    type Snapshot<'a> = RocksSnapshot<'a>;
+    type Batch<'a> = RocksBatch<'a>;
+
+    fn get_cf(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let value = if cf == DEFAULT_CF_NAME {
+            self.get_pinned(key)?
+        } else {
+            self.get_pinned_cf(cf_handle(self, cf)?, key)?
+        };
+        Ok(value.map(|value| value.to_vec()))
+    }
+
+    fn has_column_family(&self, cf: &str) -> bool {
+        cf == DEFAULT_CF_NAME || self.cf_handle(cf).is_some()
+    }
+
+    fn batch(&self) -> RocksBatch<'_> {
+        RocksBatch {
+            db: self,
+            inner: rocksdb::WriteBatch::default(),
+            error: None,
+        }
+    }
+
+    fn write(&self, batch: RocksBatch<'_>) -> Result<()> {
+        if let Some(error) = batch.error {
+            return Err(error);
+        }
+        let mut opts = rocksdb::WriteOptions::default();
+        opts.set_sync(false);
+        // TODO: disable WAL once we can ensure consistency with transactions
+        self.write_opt(batch.inner, &opts)?;
+        Ok(())
+    }
+
+    fn raw_iter_cf(&self, cf: &str) -> DBRawIterator<'_> {
+        if cf == DEFAULT_CF_NAME {
+            self.raw_iterator()
+        } else {
+            self.raw_iterator_cf(cf_handle(self, cf).unwrap())
+        }
+    }
+
+    fn snapshot(&self) -> RocksSnapshot<'_> {
+        RocksSnapshot {
+            db: self,
+            inner: DB::snapshot(self),
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(DB::flush(self)?)
+    }
+
+    fn sync_wal(&self) -> Result<()> {
+        Ok(self.flush_wal(true)?)
+    }
+
+    fn column_family_size(&self, cf: &str) -> Result<Option<ColumnFamilySize>> {
+        let property = |name| -> Result<u64> {
+            let value = if cf == DEFAULT_CF_NAME {
+                self.property_int_value(name)?
+            } else {
+                self.property_int_value_cf(cf_handle(self, cf)?, name)?
+            };
+            Ok(value.unwrap_or_default())
+        };
+
+        if !self.has_column_family(cf) {
+            return Ok(None);
+        }
+        // the memtables hold the writes which are not yet in SST files
+        Ok(Some(ColumnFamilySize {
+            keys: property("rocksdb.estimate-num-keys")?,
+            bytes: property("rocksdb.total-sst-files-size")?
+                + property("rocksdb.cur-size-all-mem-tables")?,
+        }))
+    }
+}
+
+/// A RocksDB snapshot, along with the database it was taken from so column
+/// families can be resolved by name.
+pub struct RocksSnapshot<'a> {
+    db: &'a DB,
+    inner: rocksdb::Snapshot<'a>,
+}
+
+impl<'a> BackendSnapshot for RocksSnapshot<'a> {
+    type RawIter<'b>
+        = DBRawIterator<'b>
+    where
+        Self: 'b;
+
+    fn get_cf(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if cf == DEFAULT_CF_NAME {
+            Ok(self.inner.get(key)?)
+        } else {
+            Ok(self.inner.get_cf(cf_handle(self.db, cf)?, key)?)
+        }
+    }
+
+    fn raw_iter_cf(&self, cf: &str) -> DBRawIterator<'_> {
+        if cf == DEFAULT_CF_NAME {
+            self.inner.raw_iterator()
+        } else {
+            self.inner.raw_iterator_cf(cf_handle(self.db, cf).unwrap())
+        }
+    }
+}
+
+/// A RocksDB write batch, along with the database it will be written to so
+/// column families can be resolved by name.
+pub struct RocksBatch<'a> {
+    db: &'a DB,
+    inner: rocksdb::WriteBatch,
+    /// The error from the first write to an unknown column family, which is
+    /// returned by `write` instead of writing the batch.
+    error: Option<Error>,
+}
+
+impl<'a> WriteBatch for RocksBatch<'a> {
+    fn put_cf(&mut self, cf: &str, key: &[u8], value: &[u8]) {
+        if cf == DEFAULT_CF_NAME {
+            self.inner.put(key, value);
+        } else {
+            match cf_handle(self.db, cf) {
+                Ok(handle) => self.inner.put_cf(handle, key, value),
+                Err(error) => {
+                    self.error.get_or_insert(error);
+                }
+            }
+        }
+    }
+
+    fn delete_cf(&mut self, cf: &str, key: &[u8]) {
+        if cf == DEFAULT_CF_NAME {
+            self.inner.delete(key);
+        } else {
+            match cf_handle(self.db, cf) {
+                Ok(handle) => self.inner.delete_cf(handle, key),
+                Err(error) => {
+                    self.error.get_or_insert(error);
+                }
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+}
+
+impl<'a> RawIterator for DBRawIterator<'a> {
+    fn valid(&self) -> bool {
+        DBRawIterator::valid(self)
+    }
+
+    fn seek_to_first(&mut self) {
+        DBRawIterator::seek_to_first(self)
+    }
+
+    fn seek_to_last(&mut self) {
+        DBRawIterator::seek_to_last(self)
+    }
+
+    fn seek(&mut self, key: &[u8]) {
+        DBRawIterator::seek(self, key)
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) {
+        DBRawIterator::seek_for_prev(self, key)
+    }
+
+    fn next(&mut self) {
+        DBRawIterator::next(self)
+    }
+
+    fn prev(&mut self) {
+        DBRawIterator::prev(self)
+    }
+
+    fn key(&self) -> Option<&[u8]> {
+        DBRawIterator::key(self)
+    }
+
+    fn value(&self) -> Option<&[u8]> {
+        DBRawIterator::value(self)
+    }
+}
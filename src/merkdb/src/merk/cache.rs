@@ -0,0 +1,303 @@
+//! Bounds the memory used by the nodes of a `Merk`: a cache of recently
+//! fetched nodes, and the number of levels of the tree kept in memory.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use crate::tree::{Link, Tree};
+
+/// A least-recently-used cache of decoded nodes, holding up to a budget of
+/// bytes (as estimated by `Tree::memory_size`).
+///
+/// Nodes are cached when they are fetched from the backend, so nodes below the
+/// levels of the tree kept in memory which are read often do not have to be
+/// read and decoded again. The cache is shared by the threads fetching nodes
+/// while a batch is applied or committed in parallel.
+pub(crate) struct NodeCache {
+    budget: usize,
+    lru: Mutex<Lru>,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<Vec<u8>, Entry>,
+    /// The keys of the entries by the tick they were last used at, so the
+    /// least recently used entry is first.
+    order: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+    bytes: usize,
+}
+
+struct Entry {
+    node: Tree,
+    size: usize,
+    tick: u64,
+}
+
+impl NodeCache {
+    /// Creates a cache holding up to `budget` bytes of nodes. The cache is
+    /// disabled if the budget is 0.
+    pub(crate) fn new(budget: usize) -> Self {
+        NodeCache {
+            budget,
+            lru: Mutex::new(Lru::default()),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn budget(&self) -> usize {
+        self.budget
+    }
+
+    #[inline]
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.budget > 0
+    }
+
+    /// Returns a copy of the cached node with the given key, marking it as
+    /// the most recently used.
+    pub(crate) fn get(&self, key: &[u8]) -> Option<Tree> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let mut lru = self.lru.lock().unwrap();
+        let tick = lru.next_tick();
+        let entry = lru.entries.get_mut(key)?;
+        let last_tick = std::mem::replace(&mut entry.tick, tick);
+        let node = entry.node.clone_pruned();
+        let key = lru.order.remove(&last_tick).unwrap();
+        lru.order.insert(tick, key);
+        Some(node)
+    }
+
+    /// Caches a copy of a node fetched from the backend, evicting the least
+    /// recently used nodes to stay within the budget. Nodes larger than the
+    /// whole budget are not cached.
+    pub(crate) fn insert(&self, node: &Tree) {
+        let size = node.memory_size() + node.key().len();
+        if size > self.budget {
+            return;
+        }
+
+        let mut lru = self.lru.lock().unwrap();
+        lru.remove(node.key());
+        while lru.bytes + size > self.budget {
+            lru.evict();
+        }
+
+        let tick = lru.next_tick();
+        lru.bytes += size;
+        lru.order.insert(tick, node.key().to_vec());
+        lru.entries.insert(
+            node.key().to_vec(),
+            Entry {
+                node: node.clone_pruned(),
+                size,
+                tick,
+            },
+        );
+    }
+
+    /// Removes the node with the given key, if it is cached.
+    pub(crate) fn remove(&self, key: &[u8]) {
+        if self.is_enabled() {
+            self.lru.lock().unwrap().remove(key);
+        }
+    }
+
+    /// Removes all the cached nodes.
+    pub(crate) fn clear(&self) {
+        *self.lru.lock().unwrap() = Lru::default();
+    }
+
+    /// Returns the number of bytes of nodes cached.
+    pub(crate) fn bytes(&self) -> usize {
+        self.lru.lock().unwrap().bytes
+    }
+}
+
+impl Lru {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.tick);
+            self.bytes -= entry.size;
+        }
+    }
+
+    fn evict(&mut self) {
+        if let Some((_, key)) = self.order.pop_first() {
+            let entry = self.entries.remove(&key).unwrap();
+            self.bytes -= entry.size;
+        }
+    }
+}
+
+/// Prunes the nodes of `tree` which do not fit in `budget` bytes, keeping as
+/// many of its top levels as fit (counted like `Merk`'s `max_levels_in_memory`,
+/// by the height of the nodes). The tree must have been committed.
+///
+/// Only the nodes within the budget are visited, so this takes time
+/// proportional to the budget rather than to the number of nodes in memory.
+pub(crate) fn prune_to_budget(tree: &mut Tree, budget: usize) {
+    if let Some(levels) = levels_within_budget(tree, budget) {
+        prune_levels(tree, tree.height(), levels);
+    }
+}
+
+/// Returns how many levels of `tree` fit in `budget` bytes, or `None` if all
+/// the nodes in memory fit.
+fn levels_within_budget(tree: &Tree, budget: usize) -> Option<u8> {
+    // a node is kept when keeping `levels` levels if the height of its parent
+    // is less than `levels` below the root's, so nodes are grouped by the
+    // number of levels needed to keep them
+    let height = tree.height();
+    let mut groups: Vec<Vec<&Tree>> = vec![vec![]; height as usize + 1];
+    groups[0].push(tree);
+
+    let mut bytes = 0;
+    for levels in 0..groups.len() {
+        let group = std::mem::take(&mut groups[levels]);
+        bytes += group.iter().map(|node| node.memory_size()).sum::<usize>();
+        if bytes > budget {
+            return Some(levels.saturating_sub(1) as u8);
+        }
+
+        for node in group {
+            for child in [node.child(true), node.child(false)].iter().flatten() {
+                groups[(height - node.height()) as usize + 1].push(child);
+            }
+        }
+    }
+    None
+}
+
+fn prune_levels(tree: &mut Tree, height: u8, levels: u8) {
+    let prune = height - tree.height() >= levels;
+    for &left in [true, false].iter() {
+        if prune {
+            let slot = tree.slot_mut(left);
+            *slot = slot.take().map(Link::into_reference);
+        } else if let Some(child) = tree.child_mut(left) {
+            prune_levels(child, height, levels);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merk::backend::MemoryBackend;
+    use crate::proofs::{query::QueryItem, Query};
+    use crate::test_utils::{make_batch_seq, seq_key};
+    use crate::Merk;
+
+    fn node(key: u8, value_len: usize) -> Tree {
+        Tree::new(vec![key], vec![0; value_len]).unwrap()
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let size = node(0, 100).memory_size() + 1;
+        let cache = NodeCache::new(size * 3);
+        for key in 0..3 {
+            cache.insert(&node(key, 100));
+        }
+        assert_eq!(cache.bytes(), size * 3);
+
+        // 0 is used, so 1 is evicted
+        assert_eq!(cache.get(&[0]).unwrap().value(), &[0; 100][..]);
+        cache.insert(&node(3, 100));
+        assert!(cache.get(&[1]).is_none());
+        assert!(cache.get(&[0]).is_some());
+        assert!(cache.get(&[2]).is_some());
+        assert!(cache.get(&[3]).is_some());
+        assert_eq!(cache.bytes(), size * 3);
+
+        // replacing a node does not count it twice
+        cache.insert(&node(3, 100));
+        assert_eq!(cache.bytes(), size * 3);
+
+        // nodes larger than the budget are not cached
+        cache.insert(&node(4, size * 3));
+        assert!(cache.get(&[4]).is_none());
+        assert_eq!(cache.bytes(), size * 3);
+
+        cache.remove(&[0]);
+        assert!(cache.get(&[0]).is_none());
+        assert_eq!(cache.bytes(), size * 2);
+        cache.clear();
+        assert_eq!(cache.bytes(), 0);
+    }
+
+    #[test]
+    fn disabled() {
+        let cache = NodeCache::new(0);
+        cache.insert(&node(0, 1));
+        assert!(cache.get(&[0]).is_none());
+        assert_eq!(cache.bytes(), 0);
+    }
+
+    #[test]
+    fn cached_fetches() {
+        let mut merk = Merk::open_backend(MemoryBackend::new(), 1).unwrap();
+        merk.set_node_cache_budget(1 << 20);
+        merk.apply(&make_batch_seq(0..1_000), &[]).unwrap();
+
+        for _ in 0..3 {
+            merk.get(&seq_key(100)).unwrap().unwrap();
+        }
+        let stats = merk.stats().unwrap();
+        assert_eq!(stats.nodes_fetched, 1);
+        assert_eq!(stats.node_cache_hits, 2);
+        assert!(stats.node_cache_bytes > 0);
+
+        // committed nodes are not read from the cache
+        merk.apply(&[(seq_key(100), crate::Op::Put(vec![1]))], &[])
+            .unwrap();
+        assert_eq!(merk.get(&seq_key(100)).unwrap(), Some(vec![1]));
+        merk.apply(&[(seq_key(100), crate::Op::Delete)], &[])
+            .unwrap();
+        assert_eq!(merk.get(&seq_key(100)).unwrap(), None);
+
+        // the cache does not change the tree
+        let mut uncached = Merk::open_backend(MemoryBackend::new(), 1).unwrap();
+        uncached.apply(&make_batch_seq(0..1_000), &[]).unwrap();
+        uncached
+            .apply(&[(seq_key(100), crate::Op::Delete)], &[])
+            .unwrap();
+        assert_eq!(merk.root_hash(), uncached.root_hash());
+    }
+
+    #[test]
+    fn memory_budget() {
+        let mut merk = Merk::open_backend(MemoryBackend::new(), 10).unwrap();
+        merk.apply(&make_batch_seq(0..1_000), &[]).unwrap();
+        assert_eq!(merk.stats().unwrap().nodes_in_memory, 1_000);
+
+        // a budget for about 20 nodes keeps the top 4 levels, including the
+        // nodes which were not modified
+        let node_size = merk.use_tree(|tree| tree.unwrap().memory_size());
+        merk.set_memory_budget(Some(node_size * 20));
+        merk.apply(&make_batch_seq(0..1), &[]).unwrap();
+        assert_eq!(merk.stats().unwrap().nodes_in_memory, 15);
+
+        // nodes loaded by proofs are pruned by the next commit
+        merk.prove(Query::from(vec![QueryItem::Range(
+            seq_key(0)..seq_key(1_000),
+        )]))
+        .unwrap();
+        assert_eq!(merk.stats().unwrap().nodes_in_memory, 1_000);
+        merk.apply(&make_batch_seq(0..1), &[]).unwrap();
+        assert_eq!(merk.stats().unwrap().nodes_in_memory, 15);
+
+        merk.set_memory_budget(None);
+        merk.apply(&make_batch_seq(0..1_000), &[]).unwrap();
+        assert_eq!(merk.stats().unwrap().nodes_in_memory, 1_000);
+    }
+}
@@ -1,27 +1,29 @@
 //! Provides `ChunkProducer`, which creates chunk proofs for full replication of
 //! a Merk.
 
+use super::backend::{Backend, DefaultBackend, RawIterator};
 use super::Merk;
-use crate::proofs::{chunk::get_next_chunk, Node, Op};
+use crate::proofs::{chunk::get_next_chunk, encode_header, encode_into, Node, Op};
+use crate::tree::HashAlgorithm;
 
 use crate::{Error, Result};
-use ed::Encode;
-use rocksdb::DBRawIterator;
 
 /// A `ChunkProducer` allows the creation of chunk proofs, used for trustlessly
 /// replicating entire Merk trees. Chunks can be generated on the fly in a
 /// random order, or iterated in order for slightly better performance.
-pub struct ChunkProducer<'a> {
+pub struct ChunkProducer<'a, B: Backend + 'a = DefaultBackend> {
     trunk: Vec<Op>,
     chunk_boundaries: Vec<Vec<u8>>,
-    raw_iter: DBRawIterator<'a>,
+    raw_iter: B::RawIter<'a>,
     index: usize,
+    hash_algorithm: HashAlgorithm,
+    aggregate_counts: bool,
 }
 
-impl<'a> ChunkProducer<'a> {
+impl<'a, B: Backend> ChunkProducer<'a, B> {
     /// Creates a new `ChunkProducer` for the given `Merk` instance. In the
     /// constructor, the first chunk (the "trunk") will be created.
-    pub fn new(merk: &'a Merk) -> Result<Self> {
+    pub fn new(merk: &'a Merk<B>) -> Result<Self> {
         let (trunk, has_more) = merk.walk(|maybe_walker| match maybe_walker {
             Some(mut walker) => walker.create_trunk_proof(),
             None => Ok((vec![], false)),
@@ -47,6 +49,8 @@ impl<'a> ChunkProducer<'a> {
             chunk_boundaries,
             raw_iter,
             index: 0,
+            hash_algorithm: merk.hash_algorithm,
+            aggregate_counts: merk.aggregate_counts,
         })
     }
 
@@ -54,6 +58,18 @@ impl<'a> ChunkProducer<'a> {
     /// bounds or the tree is empty - the number of chunks can be checked by calling
     /// `producer.len()`.
     pub fn chunk(&mut self, index: usize) -> Result<Vec<u8>> {
+        self.seek(index)?;
+        self.next_chunk()
+    }
+
+    /// Moves the producer to the chunk with the given index, so that
+    /// iterating over it yields the chunks starting from that index. This
+    /// seeks the underlying iterator directly to the start of the chunk, so
+    /// e.g. an interrupted restore can be continued from the chunk it needs
+    /// next without reading the preceding chunks.
+    ///
+    /// Errors if the index is out of bounds.
+    pub fn seek(&mut self, index: usize) -> Result<()> {
         if index >= self.len() {
             return Err(Error::IndexOutOfBounds("Chunk index out-of-bounds".into()));
         }
@@ -68,7 +84,7 @@ impl<'a> ChunkProducer<'a> {
             self.raw_iter.next();
         }
 
-        self.next_chunk()
+        Ok(())
     }
 
     /// Returns the total number of chunks for the underlying Merk tree.
@@ -93,7 +109,7 @@ impl<'a> ChunkProducer<'a> {
                 ));
             }
             self.index += 1;
-            return Ok(self.trunk.encode()?);
+            return Ok(self.encode_chunk(&self.trunk));
         }
 
         assert!(self.index < self.len(), "Called next_chunk after end");
@@ -104,13 +120,23 @@ impl<'a> ChunkProducer<'a> {
         self.index += 1;
 
         let chunk = get_next_chunk(&mut self.raw_iter, end_key_slice)?;
-        Ok(chunk.encode()?)
+        Ok(self.encode_chunk(&chunk))
+    }
+
+    /// Encodes a chunk's operators, after the header naming the hash
+    /// algorithm of the tree and whether it keeps aggregate counts (see
+    /// `encode_header`).
+    fn encode_chunk(&self, ops: &[Op]) -> Vec<u8> {
+        let mut bytes = vec![];
+        encode_header(self.hash_algorithm, self.aggregate_counts, &mut bytes);
+        encode_into(ops.iter(), &mut bytes);
+        bytes
     }
 }
 
-impl<'a> IntoIterator for ChunkProducer<'a> {
-    type IntoIter = ChunkIter<'a>;
-    type Item = <ChunkIter<'a> as Iterator>::Item;
+impl<'a, B: Backend> IntoIterator for ChunkProducer<'a, B> {
+    type IntoIter = ChunkIter<'a, B>;
+    type Item = <ChunkIter<'a, B> as Iterator>::Item;
 
     fn into_iter(self) -> Self::IntoIter {
         ChunkIter(self)
@@ -120,9 +146,9 @@ impl<'a> IntoIterator for ChunkProducer<'a> {
 /// A `ChunkIter` iterates through all the chunks for the underlying `Merk`
 /// instance in order (the first chunk is the "trunk" chunk). Yields `None`
 /// after all chunks have been yielded.
-pub struct ChunkIter<'a>(ChunkProducer<'a>);
+pub struct ChunkIter<'a, B: Backend + 'a = DefaultBackend>(ChunkProducer<'a, B>);
 
-impl<'a> Iterator for ChunkIter<'a> {
+impl<'a, B: Backend> Iterator for ChunkIter<'a, B> {
     type Item = Result<Vec<u8>>;
 
     fn size_hint(&self) -> (usize, Option<usize>) {
@@ -138,10 +164,10 @@ impl<'a> Iterator for ChunkIter<'a> {
     }
 }
 
-impl Merk {
+impl<B: Backend> Merk<B> {
     /// Creates a `ChunkProducer` which can return chunk proofs for replicating
     /// the entire Merk tree.
-    pub fn chunks(&self) -> Result<ChunkProducer> {
+    pub fn chunks(&self) -> Result<ChunkProducer<B>> {
         ChunkProducer::new(self)
     }
 }
@@ -189,7 +215,7 @@ mod tests {
 
         let chunk = chunks.next().unwrap();
         let ops = Decoder::new(chunk.as_slice());
-        let (trunk, height) = verify_trunk(ops).unwrap();
+        let (trunk, height) = verify_trunk(ops, HashAlgorithm::default(), false).unwrap();
         assert_eq!(height, 14);
         assert_eq!(trunk.hash()?, merk.root_hash());
 
@@ -197,7 +223,7 @@ mod tests {
 
         for (chunk, node) in chunks.zip(trunk.layer(height / 2)) {
             let ops = Decoder::new(chunk.as_slice());
-            verify_leaf(ops, node.hash()?).unwrap();
+            verify_leaf(ops, HashAlgorithm::default(), false, node.hash()?).unwrap();
         }
         Ok(())
     }
@@ -273,6 +299,30 @@ mod tests {
         }
     }
 
+    #[test]
+    fn seek_resumes_iteration() {
+        let mut merk = TempMerk::new().unwrap();
+        let batch = make_batch_seq(1..10_000);
+        merk.apply(batch.as_slice(), &[]).unwrap();
+
+        let chunks = merk
+            .chunks()
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+
+        for index in [0, 1, 2, 77, chunks.len() - 1] {
+            let mut producer = merk.chunks().unwrap();
+            producer.seek(index).unwrap();
+            let resumed = producer.into_iter().map(Result::unwrap).collect::<Vec<_>>();
+            assert_eq!(resumed.as_slice(), &chunks[index..]);
+        }
+
+        let mut producer = merk.chunks().unwrap();
+        assert!(producer.seek(chunks.len()).is_err());
+    }
+
     #[test]
     #[should_panic(expected = "Attempted to fetch chunk on empty tree")]
     fn test_chunk_empty() {
@@ -0,0 +1,384 @@
+//! Configuration for opening a `Merk` backed by RocksDB.
+
+use std::collections::BTreeMap;
+#[cfg(feature = "full")]
+use std::path::Path;
+
+#[cfg(feature = "full")]
+use rocksdb::{BlockBasedOptions, Cache, DBCompressionType, DB};
+
+#[cfg(feature = "full")]
+use super::backend::DEFAULT_CF_NAME;
+#[cfg(feature = "full")]
+use super::{open_db_as_secondary, open_db_read_only, open_db_with, Access, DbOptions, Merk};
+#[cfg(feature = "toml")]
+use crate::error::Error;
+#[cfg(feature = "full")]
+use crate::error::Result;
+
+/// When the writes of a commit are synced to durable storage.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum WalSync {
+    /// Commits are written to the write-ahead log in the buffers of the
+    /// operating system, so they survive the process crashing but the last
+    /// commits may be lost if the machine crashes.
+    #[default]
+    Buffered,
+    /// The write-ahead log is synced to disk before a commit returns, so
+    /// commits survive the machine crashing. This makes commits slower.
+    EveryCommit,
+}
+
+/// The algorithm a column family's data is compressed with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Compression {
+    None,
+    Snappy,
+    Zlib,
+    Bz2,
+    Lz4,
+    Lz4hc,
+    Zstd,
+}
+
+#[cfg(feature = "full")]
+impl From<Compression> for DBCompressionType {
+    fn from(compression: Compression) -> Self {
+        match compression {
+            Compression::None => DBCompressionType::None,
+            Compression::Snappy => DBCompressionType::Snappy,
+            Compression::Zlib => DBCompressionType::Zlib,
+            Compression::Bz2 => DBCompressionType::Bz2,
+            Compression::Lz4 => DBCompressionType::Lz4,
+            Compression::Lz4hc => DBCompressionType::Lz4hc,
+            Compression::Zstd => DBCompressionType::Zstd,
+        }
+    }
+}
+
+/// Options for opening a store, built by chaining setters on
+/// `MerkConfig::default()`, or loaded from a configuration file with the
+/// `serde` feature (or from TOML with the `toml` feature):
+///
+/// ```toml
+/// max_levels_in_memory = 20
+/// block_cache_size = 268435456
+/// bloom_filter_bits = 10.0
+/// compression = "lz4"
+/// mmap_writes = false
+/// wal_sync = "every_commit"
+///
+/// [column_family_compression]
+/// history = "zstd"
+/// ```
+///
+/// Fields which are not set keep the defaults of `Merk::open`.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct MerkConfig {
+    max_levels_in_memory: u8,
+    read_only: bool,
+    column_families: Vec<String>,
+    block_cache_size: Option<usize>,
+    bloom_filter_bits: Option<f64>,
+    compression: Option<Compression>,
+    column_family_compression: BTreeMap<String, Compression>,
+    mmap_reads: bool,
+    mmap_writes: bool,
+    wal_sync: WalSync,
+}
+
+impl Default for MerkConfig {
+    fn default() -> Self {
+        MerkConfig {
+            max_levels_in_memory: 100,
+            read_only: false,
+            column_families: vec![],
+            block_cache_size: None,
+            bloom_filter_bits: None,
+            compression: None,
+            column_family_compression: BTreeMap::new(),
+            mmap_reads: true,
+            mmap_writes: true,
+            wal_sync: WalSync::default(),
+        }
+    }
+}
+
+impl MerkConfig {
+    /// Sets how many levels of the tree are kept in memory between commits.
+    /// Defaults to 100.
+    pub fn max_levels_in_memory(mut self, levels: u8) -> Self {
+        self.max_levels_in_memory = levels;
+        self
+    }
+
+    /// Opens the store read-only, so it can be read while another process
+    /// writes to it (see `Merk::open_read_only`). The store must already
+    /// exist.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Adds a column family for other data of the application, created if it
+    /// does not exist (see `Merk::open_with_column_families`).
+    pub fn column_family(mut self, name: impl Into<String>) -> Self {
+        self.column_families.push(name.into());
+        self
+    }
+
+    /// Sets the size in bytes of the cache of uncompressed blocks shared by
+    /// all the column families. Defaults to RocksDB's 8 MiB cache per column
+    /// family.
+    pub fn block_cache_size(mut self, bytes: usize) -> Self {
+        self.block_cache_size = Some(bytes);
+        self
+    }
+
+    /// Adds bloom filters with the given number of bits per key to the
+    /// column families, so reads of missing keys rarely touch the disk. 10
+    /// bits per key give about 1% false positives. Disabled by default.
+    pub fn bloom_filter_bits(mut self, bits_per_key: f64) -> Self {
+        self.bloom_filter_bits = Some(bits_per_key);
+        self
+    }
+
+    /// Sets the compression of the column families which have none set with
+    /// `column_family_compression`. Defaults to RocksDB's default (Snappy).
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Sets the compression of a single column family, such as `default` (the
+    /// tree's nodes) or `history` (the nodes of retained versions).
+    pub fn column_family_compression(
+        mut self,
+        name: impl Into<String>,
+        compression: Compression,
+    ) -> Self {
+        self.column_family_compression
+            .insert(name.into(), compression);
+        self
+    }
+
+    /// Sets whether files are read with mmap. Enabled by default.
+    pub fn mmap_reads(mut self, mmap_reads: bool) -> Self {
+        self.mmap_reads = mmap_reads;
+        self
+    }
+
+    /// Sets whether files are written with mmap. Enabled by default.
+    pub fn mmap_writes(mut self, mmap_writes: bool) -> Self {
+        self.mmap_writes = mmap_writes;
+        self
+    }
+
+    /// Sets when commits are synced to durable storage. Defaults to
+    /// `WalSync::Buffered`.
+    pub fn wal_sync(mut self, wal_sync: WalSync) -> Self {
+        self.wal_sync = wal_sync;
+        self
+    }
+
+    /// Parses a configuration written in TOML.
+    ///
+    /// Errors with `Error::Config` if it is not valid TOML or has unknown
+    /// fields.
+    #[cfg(feature = "toml")]
+    pub fn from_toml(config: &str) -> crate::Result<MerkConfig> {
+        toml::from_str(config).map_err(|err| Error::Config(err.to_string()))
+    }
+
+    /// Reads and parses a configuration file written in TOML (see
+    /// `from_toml`).
+    #[cfg(feature = "toml")]
+    pub fn load_toml<P: AsRef<std::path::Path>>(path: P) -> crate::Result<MerkConfig> {
+        MerkConfig::from_toml(&std::fs::read_to_string(path)?)
+    }
+}
+
+#[cfg(feature = "full")]
+impl MerkConfig {
+    /// Opens a store at the given path with this configuration. If no store
+    /// exists at that path, one will be created (unless opening read-only).
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<Merk> {
+        if self.read_only {
+            let block_cache = self.block_cache()?;
+            let cf_opts = |name: &str| self.cf_opts(name, block_cache.as_ref());
+            let db = open_db_read_only(path, &cf_opts(DEFAULT_CF_NAME), cf_opts)?;
+            return self.open_merk(db, Access::ReadOnly);
+        }
+
+        let column_families: Vec<&str> = self.column_families.iter().map(String::as_str).collect();
+        let db = self.open_db(path.as_ref(), &column_families)?;
+        let mut merk = self.open_merk(db, Access::ReadWrite)?;
+        merk.db_options = Some(DbOptions::Config(self.clone()));
+        Ok(merk)
+    }
+
+    /// Opens (or creates) the database at `path` for reading and writing with
+    /// this configuration, with the given extra column families.
+    pub(super) fn open_db(&self, path: &Path, extra_column_families: &[&str]) -> Result<DB> {
+        let block_cache = self.block_cache()?;
+        let cf_opts = |name: &str| self.cf_opts(name, block_cache.as_ref());
+        open_db_with(
+            path,
+            &cf_opts(DEFAULT_CF_NAME),
+            cf_opts,
+            extra_column_families,
+        )
+    }
+
+    /// Opens the existing store at `primary_path` as a secondary instance with
+    /// this configuration (see `Merk::open_as_secondary`). The `read_only`
+    /// and `column_family` settings are ignored.
+    pub fn open_as_secondary<P, Q>(&self, primary_path: P, secondary_path: Q) -> Result<Merk>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let block_cache = self.block_cache()?;
+        let cf_opts = |name: &str| self.cf_opts(name, block_cache.as_ref());
+        let db_opts = cf_opts(DEFAULT_CF_NAME);
+
+        let db = open_db_as_secondary(primary_path, secondary_path, &db_opts, cf_opts)?;
+        self.open_merk(db, Access::Secondary)
+    }
+
+    fn open_merk(&self, db: DB, access: Access) -> Result<Merk> {
+        let mut merk = Merk::open_backend(db, self.max_levels_in_memory)?;
+        merk.set_wal_sync(self.wal_sync);
+        merk.access = access;
+        Ok(merk)
+    }
+
+    /// Creates the block cache shared by the column families, if one is
+    /// configured.
+    fn block_cache(&self) -> Result<Option<Cache>> {
+        Ok(self
+            .block_cache_size
+            .map(Cache::new_lru_cache)
+            .transpose()?)
+    }
+
+    /// Returns the RocksDB options for the given column family, whose blocks
+    /// are cached in `block_cache` (shared by all the column families).
+    fn cf_opts(&self, name: &str, block_cache: Option<&Cache>) -> rocksdb::Options {
+        let mut opts = Merk::default_db_opts();
+        opts.set_allow_mmap_reads(self.mmap_reads);
+        opts.set_allow_mmap_writes(self.mmap_writes);
+
+        let compression = self
+            .column_family_compression
+            .get(name)
+            .or(self.compression.as_ref());
+        if let Some(&compression) = compression {
+            opts.set_compression_type(compression.into());
+        }
+
+        if block_cache.is_some() || self.bloom_filter_bits.is_some() {
+            let mut table_opts = BlockBasedOptions::default();
+            if let Some(cache) = block_cache {
+                table_opts.set_block_cache(cache);
+            }
+            if let Some(bits_per_key) = self.bloom_filter_bits {
+                table_opts.set_bloom_filter(bits_per_key, false);
+            }
+            opts.set_block_based_table_factory(&table_opts);
+        }
+
+        opts
+    }
+}
+
+#[cfg(feature = "full")]
+impl Merk {
+    /// Opens a store at the given path with the given configuration (see
+    /// `MerkConfig::open`).
+    pub fn open_with_config<P: AsRef<Path>>(path: P, config: &MerkConfig) -> Result<Merk> {
+        config.open(path)
+    }
+}
+
+#[cfg(all(test, feature = "full"))]
+mod tests {
+    use super::*;
+    use crate::test_utils::{make_batch_seq, seq_key};
+    use std::thread;
+
+    #[test]
+    fn open_with_config() {
+        let path = thread::current().name().unwrap().to_owned();
+        let config = MerkConfig::default()
+            .max_levels_in_memory(1)
+            .column_family("bills")
+            .block_cache_size(1 << 20)
+            .bloom_filter_bits(10.0)
+            .compression(Compression::Lz4)
+            .column_family_compression("history", Compression::Zstd)
+            .mmap_writes(false)
+            .wal_sync(WalSync::EveryCommit);
+        let mut merk = Merk::open_with_config(&path, &config).unwrap();
+        assert_eq!(merk.get_max_levels_in_memory(), 1);
+        assert_eq!(merk.get_wal_sync(), WalSync::EveryCommit);
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        assert!(merk.get_extra("bills", &[1]).unwrap().is_none());
+        let root_hash = merk.root_hash();
+        drop(merk);
+
+        let merk = MerkConfig::default().read_only(true).open(&path).unwrap();
+        assert_eq!(merk.root_hash(), root_hash);
+        assert!(merk.get(&seq_key(5)).unwrap().is_some());
+        drop(merk);
+
+        Merk::open(&path).unwrap().destroy().unwrap();
+        assert!(MerkConfig::default().read_only(true).open(&path).is_err());
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn from_toml() {
+        let config = MerkConfig::from_toml(
+            r#"
+            max_levels_in_memory = 20
+            block_cache_size = 268435456
+            bloom_filter_bits = 10.0
+            compression = "lz4"
+            mmap_writes = false
+            wal_sync = "every_commit"
+
+            [column_family_compression]
+            history = "zstd"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            MerkConfig::default()
+                .max_levels_in_memory(20)
+                .block_cache_size(268435456)
+                .bloom_filter_bits(10.0)
+                .compression(Compression::Lz4)
+                .column_family_compression("history", Compression::Zstd)
+                .mmap_writes(false)
+                .wal_sync(WalSync::EveryCommit)
+        );
+
+        assert_eq!(MerkConfig::from_toml("").unwrap(), MerkConfig::default());
+        assert!(matches!(
+            MerkConfig::from_toml("levels = 1"),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            MerkConfig::from_toml("wal_sync = \"never\""),
+            Err(Error::Config(_))
+        ));
+    }
+}
@@ -0,0 +1,450 @@
+//! Checks that the tree stored in a `Merk` is consistent, to detect corruption
+//! of the data on disk.
+
+use std::fmt;
+
+use super::backend::Backend;
+use super::{Merk, INTERNAL_CF_NAME, ROOT_KEY_KEY};
+use crate::tree::Tree;
+use crate::Result;
+
+/// Options for `Merk::verify_integrity_with`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IntegrityOptions {
+    /// Only checks the nodes whose keys are greater than or equal to this key,
+    /// to resume an earlier check.
+    pub start_key: Option<Vec<u8>>,
+    /// The maximum number of nodes to check before stopping. The report of a
+    /// check which stops early has the key to resume it from.
+    pub max_nodes: Option<u64>,
+    /// Keeps checking after the first inconsistency is found, reporting every
+    /// one.
+    pub report_all: bool,
+}
+
+/// The way a stored node is inconsistent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InconsistencyKind {
+    /// The node is not stored, although its parent (or the pointer to the
+    /// root) refers to it.
+    Missing,
+    /// The node's bytes could not be decoded.
+    Decode,
+    /// The node's key is not between the keys of its ancestors.
+    KeyOrder,
+    /// The node's key/value hash does not match its key and value.
+    KvHash,
+    /// The hash of a child does not match the one stored in the node.
+    ChildHash { left: bool },
+    /// The heights of the children of a child do not match the ones stored in
+    /// the node.
+    ChildHeights { left: bool },
+    /// The heights of the node's children differ by more than one.
+    Balance,
+    /// The node keeps aggregate counts although the store does not, or the
+    /// other way around.
+    AggregateCounts,
+    /// The number of keys under a child does not match the count stored in the
+    /// node.
+    ChildCount { left: bool },
+    /// The hash of the stored root node does not match the root hash of the
+    /// store.
+    RootHash,
+}
+
+/// A node found to be inconsistent by `Merk::verify_integrity`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Inconsistency {
+    /// The key of the node.
+    pub key: Vec<u8>,
+    pub kind: InconsistencyKind,
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} at key {:?}", self.kind, self.key)
+    }
+}
+
+/// The result of `Merk::verify_integrity`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// The number of nodes checked.
+    pub nodes_checked: u64,
+    /// The inconsistencies found, in key order. Only the first is reported
+    /// unless `IntegrityOptions::report_all` is set.
+    pub inconsistencies: Vec<Inconsistency>,
+    /// The key to resume the check from if it stopped after checking
+    /// `IntegrityOptions::max_nodes` nodes, or `None` if it reached the end of
+    /// the tree.
+    pub resume_key: Option<Vec<u8>>,
+}
+
+impl IntegrityReport {
+    /// Returns `true` if no inconsistencies were found.
+    pub fn is_consistent(&self) -> bool {
+        self.inconsistencies.is_empty()
+    }
+}
+
+impl<B: Backend> Merk<B> {
+    /// Walks every node of the stored tree from the root, checking that it is
+    /// consistent. Stops at the first inconsistency.
+    ///
+    /// Errors are only returned if the store can not be read, inconsistencies
+    /// are listed in the report.
+    pub fn verify_integrity(&self) -> Result<IntegrityReport> {
+        self.verify_integrity_with(IntegrityOptions::default())
+    }
+
+    /// Checks the stored tree like `verify_integrity`, with the given options.
+    ///
+    /// Each node is checked against its children, so that its key/value hash,
+    /// the hashes, heights and counts it stores for its children, its balance
+    /// and the order of its key are all verified. Nodes are checked in key
+    /// order, so large stores can be checked in parts by resuming from the
+    /// `resume_key` of the last report.
+    pub fn verify_integrity_with(&self, options: IntegrityOptions) -> Result<IntegrityReport> {
+        let mut checker = Checker {
+            merk: self,
+            options,
+            report: IntegrityReport::default(),
+            done: false,
+        };
+
+        let root_key = match self.db.get_cf(INTERNAL_CF_NAME, ROOT_KEY_KEY)? {
+            Some(root_key) => root_key,
+            None => return Ok(checker.report),
+        };
+        if let Some(root) = checker.fetch(&root_key)? {
+            // the root hash covers the whole tree, so is only checked when
+            // starting from the beginning
+            if checker.options.start_key.is_none() && root.hash() != self.root_hash() {
+                checker.report(&root_key, InconsistencyKind::RootHash);
+            }
+            checker.check_subtree(&root, None, None)?;
+        }
+
+        Ok(checker.report)
+    }
+}
+
+struct Checker<'a, B: Backend> {
+    merk: &'a Merk<B>,
+    options: IntegrityOptions,
+    report: IntegrityReport,
+    done: bool,
+}
+
+impl<'a, B: Backend> Checker<'a, B> {
+    /// Fetches and decodes the node with the given key, reporting it if it is
+    /// missing or can not be decoded.
+    fn fetch(&mut self, key: &[u8]) -> Result<Option<Tree>> {
+        let bytes = match self.merk.db.get(key)? {
+            Some(bytes) => bytes,
+            None => {
+                self.report(key, InconsistencyKind::Missing);
+                return Ok(None);
+            }
+        };
+        match Tree::try_decode_with_algorithm(key.to_vec(), &bytes, self.merk.hash_algorithm) {
+            Ok(tree) => Ok(Some(tree)),
+            Err(_) => {
+                self.report(key, InconsistencyKind::Decode);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Returns `true` if the node with the given key is in the range being
+    /// checked.
+    fn is_checked(&self, key: &[u8]) -> bool {
+        self.options
+            .start_key
+            .as_ref()
+            .is_none_or(|start_key| key >= start_key.as_slice())
+    }
+
+    fn report(&mut self, key: &[u8], kind: InconsistencyKind) {
+        self.report.inconsistencies.push(Inconsistency {
+            key: key.to_vec(),
+            kind,
+        });
+        if !self.options.report_all {
+            self.done = true;
+        }
+    }
+
+    /// Checks the nodes of the subtree rooted at `tree` in key order, given the
+    /// exclusive bounds the keys of the subtree must be between.
+    fn check_subtree(
+        &mut self,
+        tree: &Tree,
+        lower: Option<&[u8]>,
+        upper: Option<&[u8]>,
+    ) -> Result<()> {
+        let key = tree.key();
+
+        // if the node comes before the start key, so does its left subtree, but
+        // its right subtree may not
+        let checked = self.is_checked(key);
+        let left = match tree.link(true) {
+            Some(link) if checked => self.fetch(link.key())?,
+            _ => None,
+        };
+        let right = match tree.link(false) {
+            Some(link) => self.fetch(link.key())?,
+            None => None,
+        };
+        if self.done {
+            return Ok(());
+        }
+
+        if checked {
+            if let Some(left) = &left {
+                self.check_subtree(left, lower, Some(key))?;
+                if self.done {
+                    return Ok(());
+                }
+            }
+
+            if self.options.max_nodes == Some(self.report.nodes_checked) {
+                self.report.resume_key = Some(key.to_vec());
+                self.done = true;
+                return Ok(());
+            }
+            self.check_node(tree, [left.as_ref(), right.as_ref()], lower, upper);
+            self.report.nodes_checked += 1;
+            if self.done {
+                return Ok(());
+            }
+        }
+
+        match &right {
+            Some(right) => self.check_subtree(right, Some(key), upper),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks a node against its children, assuming the children are
+    /// consistent themselves.
+    fn check_node(
+        &mut self,
+        tree: &Tree,
+        children: [Option<&Tree>; 2],
+        lower: Option<&[u8]>,
+        upper: Option<&[u8]>,
+    ) {
+        let key = tree.key();
+        let mut inconsistencies = vec![];
+
+        let in_order =
+            lower.is_none_or(|lower| key > lower) && upper.is_none_or(|upper| key < upper);
+        if !in_order {
+            inconsistencies.push(InconsistencyKind::KeyOrder);
+        }
+
+        let kv_hash = tree.hash_algorithm().kv_hash(key, tree.value());
+        if kv_hash.ok().as_ref() != Some(tree.kv_hash()) {
+            inconsistencies.push(InconsistencyKind::KvHash);
+        }
+
+        if tree.has_aggregate_counts() != self.merk.aggregate_counts {
+            inconsistencies.push(InconsistencyKind::AggregateCounts);
+        }
+
+        for (child, left) in children.iter().zip([true, false].iter().copied()) {
+            let (link, child) = match (tree.link(left), child) {
+                (Some(link), Some(child)) => (link, child),
+                _ => continue,
+            };
+            if child.hash() != *link.hash() {
+                inconsistencies.push(InconsistencyKind::ChildHash { left });
+            }
+            if child.height() != link.height() || child.balance_factor() != link.balance_factor() {
+                inconsistencies.push(InconsistencyKind::ChildHeights { left });
+            }
+            if tree.has_aggregate_counts() && tree.child_count(left) != child.count() {
+                inconsistencies.push(InconsistencyKind::ChildCount { left });
+            }
+        }
+
+        if tree.balance_factor().abs() > 1 {
+            inconsistencies.push(InconsistencyKind::Balance);
+        }
+
+        for kind in inconsistencies {
+            if self.done {
+                break;
+            }
+            self.report(key, kind);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Inconsistency, InconsistencyKind, IntegrityOptions, INTERNAL_CF_NAME, ROOT_KEY_KEY,
+    };
+    use crate::merk::backend::{Backend, MemoryBackend, WriteBatch};
+    use crate::test_utils::{make_batch_seq, seq_key};
+    use crate::Merk;
+
+    fn make_merk(aggregate_counts: bool) -> Merk<MemoryBackend> {
+        let mut merk = Merk::open_backend(MemoryBackend::new(), 1).unwrap();
+        if aggregate_counts {
+            merk.enable_aggregate_counts().unwrap();
+        }
+        merk.apply(&make_batch_seq(0..1_000), &[]).unwrap();
+        merk
+    }
+
+    fn put_node(merk: &Merk<MemoryBackend>, key: &[u8], bytes: &[u8]) {
+        let mut batch = merk.db.batch();
+        batch.put(key, bytes);
+        merk.write(batch).unwrap();
+    }
+
+    /// Changes the value of a node, updating its key/value hash so that only
+    /// its parent is inconsistent.
+    fn rewrite_value(merk: &Merk<MemoryBackend>, key: &[u8]) {
+        let tree = merk.fetch_node(key).unwrap().unwrap();
+        let tree = tree.with_value(vec![1, 2, 3]).unwrap();
+        put_node(merk, key, &tree.encode());
+    }
+
+    fn parent_key(merk: &Merk<MemoryBackend>, key: &[u8]) -> Vec<u8> {
+        merk.range(..)
+            .map(|entry| merk.fetch_node(&entry.unwrap().0).unwrap().unwrap())
+            .find(|tree| {
+                [true, false]
+                    .iter()
+                    .any(|left| tree.link(*left).is_some_and(|link| link.key() == key))
+            })
+            .unwrap()
+            .take_key()
+    }
+
+    #[test]
+    fn consistent() {
+        for &aggregate_counts in &[false, true] {
+            let merk = make_merk(aggregate_counts);
+            let report = merk.verify_integrity().unwrap();
+            assert!(report.is_consistent(), "{:?}", report);
+            assert_eq!(report.nodes_checked, 1_000);
+            assert_eq!(report.resume_key, None);
+        }
+
+        let merk = Merk::open_backend(MemoryBackend::new(), 1).unwrap();
+        let report = merk.verify_integrity().unwrap();
+        assert!(report.is_consistent());
+        assert_eq!(report.nodes_checked, 0);
+    }
+
+    #[test]
+    fn corrupt_nodes() {
+        let inconsistency = |key: &[u8], kind| Inconsistency {
+            key: key.to_vec(),
+            kind,
+        };
+
+        // a flipped bit in a value
+        let merk = make_merk(false);
+        let key = seq_key(500);
+        let mut bytes = merk.db.get(&key).unwrap().unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        put_node(&merk, &key, &bytes);
+        let report = merk.verify_integrity().unwrap();
+        assert_eq!(
+            report.inconsistencies,
+            vec![inconsistency(&key, InconsistencyKind::KvHash)]
+        );
+
+        // a node which is consistent itself, but not with its parent
+        let merk = make_merk(false);
+        let key = seq_key(1);
+        rewrite_value(&merk, &key);
+        let parent = parent_key(&merk, &key);
+        let left = parent.as_slice() > key.as_slice();
+        let report = merk.verify_integrity().unwrap();
+        assert_eq!(
+            report.inconsistencies,
+            vec![inconsistency(
+                &parent,
+                InconsistencyKind::ChildHash { left }
+            )]
+        );
+
+        // the root, whose hash no longer matches the root hash
+        let merk = make_merk(true);
+        let root_key = merk
+            .db
+            .get_cf(INTERNAL_CF_NAME, ROOT_KEY_KEY)
+            .unwrap()
+            .unwrap();
+        rewrite_value(&merk, &root_key);
+        let report = merk.verify_integrity().unwrap();
+        assert_eq!(
+            report.inconsistencies,
+            vec![inconsistency(&root_key, InconsistencyKind::RootHash)]
+        );
+
+        // a missing node, and one which can not be decoded
+        let merk = make_merk(false);
+        let mut batch = merk.db.batch();
+        batch.delete(&seq_key(100));
+        merk.write(batch).unwrap();
+        put_node(&merk, &seq_key(900), &[9]);
+        let report = merk
+            .verify_integrity_with(IntegrityOptions {
+                report_all: true,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(
+            report.inconsistencies,
+            vec![
+                inconsistency(&seq_key(100), InconsistencyKind::Missing),
+                inconsistency(&seq_key(900), InconsistencyKind::Decode),
+            ]
+        );
+        assert!(report.nodes_checked < 1_000);
+    }
+
+    #[test]
+    fn resume() {
+        let merk = make_merk(true);
+        rewrite_value(&merk, &seq_key(700));
+        let parent = parent_key(&merk, &seq_key(700));
+
+        let mut options = IntegrityOptions {
+            max_nodes: Some(64),
+            report_all: true,
+            ..Default::default()
+        };
+        let mut nodes_checked = 0;
+        let mut inconsistencies = vec![];
+        let mut parts = 0;
+        loop {
+            let report = merk.verify_integrity_with(options.clone()).unwrap();
+            assert!(report.nodes_checked <= 64);
+            nodes_checked += report.nodes_checked;
+            inconsistencies.extend(report.inconsistencies);
+            parts += 1;
+
+            match report.resume_key {
+                Some(key) => {
+                    assert!(Some(&key) > options.start_key.as_ref());
+                    options.start_key = Some(key);
+                }
+                None => break,
+            }
+        }
+
+        assert_eq!(parts, 16);
+        assert_eq!(nodes_checked, 1_000);
+        assert_eq!(inconsistencies.len(), 1);
+        assert_eq!(inconsistencies[0].key, parent);
+    }
+}
@@ -1,42 +1,246 @@
+pub mod backend;
+mod cache;
 pub mod chunks;
+pub mod config;
+pub mod integrity;
+pub mod multistore;
+mod prune;
+pub mod range;
+#[cfg(feature = "full")]
+pub mod repair;
+#[cfg(feature = "full")]
 pub mod restore;
 pub mod snapshot;
+pub mod stats;
+pub mod transaction;
+pub mod version;
 
 use std::cell::Cell;
 use std::cmp::Ordering;
+#[cfg(feature = "full")]
+use std::collections::BTreeSet;
 use std::collections::LinkedList;
-use std::path::{Path, PathBuf};
-
-use rocksdb::{checkpoint::Checkpoint, ColumnFamilyDescriptor, WriteBatch, DB};
-
+use std::convert::TryInto;
+use std::ops::RangeBounds;
+#[cfg(feature = "full")]
+use std::path::Path;
+use std::time::Instant;
+
+#[cfg(feature = "full")]
+use rocksdb::{checkpoint::Checkpoint, ColumnFamilyDescriptor, DB};
+
+use self::backend::{Backend, DefaultBackend, RawIterator, WriteBatch, DEFAULT_CF_NAME};
+use self::cache::{prune_to_budget, NodeCache};
+pub use self::config::{Compression, MerkConfig, WalSync};
+pub use self::integrity::{Inconsistency, InconsistencyKind, IntegrityOptions, IntegrityReport};
+pub use self::multistore::{MultiStore, Subtree};
+pub use self::prune::RetentionPolicy;
+pub use self::range::{KeyRange, RangeIter};
+#[cfg(feature = "full")]
+pub use self::repair::RepairProgress;
 pub use self::snapshot::Snapshot;
+use self::stats::Counters;
+pub use self::stats::MerkStats;
+#[cfg(feature = "prometheus")]
+pub use self::stats::PrometheusExporter;
+pub use self::transaction::Transaction;
+pub use self::version::Version;
 use crate::error::{Error, Result};
-use crate::proofs::{encode_into, query::QueryItem, Op as ProofOp, Query};
-use crate::tree::{Batch, Commit, Fetch, GetResult, Hash, Op, RefWalker, Tree, Walker, NULL_HASH};
+use crate::proofs::{encode_proof, query::QueryItem, Op as ProofOp, ProofEncoding, Query};
+use crate::tree::{
+    Batch, Commit, Fetch, GetResult, Hash, HashAlgorithm, Link, Op, RefWalker, SplitCommit, Tree,
+    Walker, HASH_LENGTH, NULL_HASH,
+};
 
 const ROOT_KEY_KEY: &[u8] = b"root";
+const HASH_ALGORITHM_KEY: &[u8] = b"hash_algorithm";
+const AGGREGATE_COUNTS_KEY: &[u8] = b"aggregate_counts";
+const VERSION_KEY_PREFIX: &[u8] = b"version";
 const AUX_CF_NAME: &str = "aux";
 const INTERNAL_CF_NAME: &str = "internal";
+const HISTORY_CF_NAME: &str = "history";
+
+/// The column families the store keeps its own data in, which can not be
+/// written to with `Merk::apply_with_extra`.
+const RESERVED_CF_NAMES: [&str; 4] = [
+    DEFAULT_CF_NAME,
+    AUX_CF_NAME,
+    INTERNAL_CF_NAME,
+    HISTORY_CF_NAME,
+];
+
+/// The number of versions retained by the default `RetentionPolicy`.
+pub const DEFAULT_MAX_VERSIONS: usize = 100;
+
+#[cfg(feature = "full")]
+fn column_families(cf_opts: impl Fn(&str) -> rocksdb::Options) -> Vec<ColumnFamilyDescriptor> {
+    [AUX_CF_NAME, INTERNAL_CF_NAME, HISTORY_CF_NAME]
+        .iter()
+        .map(|name| ColumnFamilyDescriptor::new(*name, cf_opts(name)))
+        .collect()
+}
+
+/// Opens the database at `path` with the store's column families, the given
+/// extra column families, and any extra column families it already has. The
+/// column families are opened with the same options as the database.
+#[cfg(feature = "full")]
+fn open_db<P: AsRef<Path>>(
+    path: P,
+    db_opts: &rocksdb::Options,
+    extra_column_families: &[&str],
+) -> Result<DB> {
+    open_db_with(path, db_opts, |_| db_opts.clone(), extra_column_families)
+}
+
+/// Opens the database at `path` like `open_db`, with the options `cf_opts`
+/// returns for each column family.
+#[cfg(feature = "full")]
+fn open_db_with<P: AsRef<Path>>(
+    path: P,
+    db_opts: &rocksdb::Options,
+    cf_opts: impl Fn(&str) -> rocksdb::Options,
+    extra_column_families: &[&str],
+) -> Result<DB> {
+    for name in extra_column_families {
+        check_extra_column_family(name)?;
+    }
+    repair::recover_repair(path.as_ref())?;
+
+    // the database does not exist yet if it can not be listed
+    let existing = DB::list_cf(db_opts, &path).unwrap_or_default();
+    let extra: BTreeSet<&str> = extra_column_families
+        .iter()
+        .copied()
+        .chain(existing.iter().map(String::as_str))
+        .filter(|name| !RESERVED_CF_NAMES.contains(name))
+        .collect();
+
+    let mut descriptors = column_families(&cf_opts);
+    for name in extra {
+        descriptors.push(ColumnFamilyDescriptor::new(name, cf_opts(name)));
+    }
+    Ok(DB::open_cf_descriptors(db_opts, path, descriptors)?)
+}
+
+/// Opens the existing database at `path` read-only, with all of its column
+/// families.
+#[cfg(feature = "full")]
+fn open_db_read_only<P: AsRef<Path>>(
+    path: P,
+    db_opts: &rocksdb::Options,
+    cf_opts: impl Fn(&str) -> rocksdb::Options,
+) -> Result<DB> {
+    let descriptors: Vec<_> = DB::list_cf(db_opts, &path)?
+        .into_iter()
+        .filter(|name| name != DEFAULT_CF_NAME)
+        .map(|name| {
+            let opts = cf_opts(&name);
+            ColumnFamilyDescriptor::new(name, opts)
+        })
+        .collect();
+    Ok(DB::open_cf_descriptors_read_only(
+        db_opts,
+        path,
+        descriptors,
+        false,
+    )?)
+}
+
+/// Opens the existing database at `primary_path` as a secondary instance with
+/// all of its column families, keeping its own logs at `secondary_path`.
+#[cfg(feature = "full")]
+fn open_db_as_secondary<P: AsRef<Path>, Q: AsRef<Path>>(
+    primary_path: P,
+    secondary_path: Q,
+    db_opts: &rocksdb::Options,
+    cf_opts: impl Fn(&str) -> rocksdb::Options,
+) -> Result<DB> {
+    let descriptors: Vec<_> = DB::list_cf(db_opts, &primary_path)?
+        .into_iter()
+        .filter(|name| name != DEFAULT_CF_NAME)
+        .map(|name| {
+            let opts = cf_opts(&name);
+            ColumnFamilyDescriptor::new(name, opts)
+        })
+        .collect();
+    Ok(DB::open_cf_descriptors_as_secondary(
+        db_opts,
+        primary_path.as_ref(),
+        secondary_path.as_ref(),
+        descriptors,
+    )?)
+}
 
-fn column_families() -> Vec<ColumnFamilyDescriptor> {
-    vec![
-        // TODO: clone opts or take args
-        ColumnFamilyDescriptor::new(AUX_CF_NAME, Merk::default_db_opts()),
-        ColumnFamilyDescriptor::new(INTERNAL_CF_NAME, Merk::default_db_opts()),
-    ]
+fn check_extra_column_family(name: &str) -> Result<()> {
+    if RESERVED_CF_NAMES.contains(&name) {
+        return Err(Error::ColumnFamily(format!(
+            "Column family \"{name}\" is reserved for the store"
+        )));
+    }
+    Ok(())
+}
+
+/// How a store was opened, which decides whether it can be written to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Access {
+    ReadWrite,
+    /// Opened with `Merk::open_read_only`.
+    #[cfg(feature = "full")]
+    ReadOnly,
+    /// Opened with `Merk::open_as_secondary`.
+    #[cfg(feature = "full")]
+    Secondary,
+}
+
+/// The RocksDB options a store was opened with, which `repair` opens the
+/// rebuilt store with.
+#[cfg(feature = "full")]
+#[derive(Clone)]
+enum DbOptions {
+    /// The same options for the database and all of its column families.
+    Uniform(rocksdb::Options),
+    /// The options of a `MerkConfig`.
+    Config(MerkConfig),
 }
 
-/// A handle to a Merkle key/value store backed by RocksDB.
-pub struct Merk {
+#[cfg(feature = "full")]
+impl DbOptions {
+    /// Opens (or creates) the database at `path` for reading and writing, with
+    /// the given extra column families.
+    fn open_db(&self, path: &Path, extra_column_families: &[&str]) -> Result<DB> {
+        match self {
+            DbOptions::Uniform(db_opts) => open_db(path, db_opts, extra_column_families),
+            DbOptions::Config(config) => config.open_db(path, extra_column_families),
+        }
+    }
+}
+
+/// A handle to a Merkle key/value store, backed by RocksDB unless another
+/// `Backend` is given.
+pub struct Merk<B: Backend = DefaultBackend> {
     pub(crate) tree: Cell<Option<Tree>>,
-    pub(crate) db: rocksdb::DB,
-    pub(crate) path: PathBuf,
+    pub(crate) db: B,
     max_levels_in_memory: u8,
+    retention: RetentionPolicy,
+    parallel_apply: bool,
+    hash_algorithm: HashAlgorithm,
+    aggregate_counts: bool,
+    counters: Counters,
+    node_cache: NodeCache,
+    /// The retained versions in ascending order, as last written.
+    versions: Vec<u64>,
+    memory_budget: Option<usize>,
+    wal_sync: WalSync,
+    access: Access,
+    /// The options the store was opened with, if it was opened from a path.
+    #[cfg(feature = "full")]
+    db_options: Option<DbOptions>,
 }
 
 pub type UseTreeMutResult = Result<Vec<(Vec<u8>, Option<Vec<u8>>)>>;
 
-impl Merk {
+#[cfg(feature = "full")]
+impl Merk<DB> {
     /// Opens a store with the specified file path. If no store exists at that
     /// path, one will be created.
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Merk> {
@@ -50,21 +254,89 @@ impl Merk {
     where
         P: AsRef<Path>,
     {
-        let mut path_buf = PathBuf::new();
-        path_buf.push(path);
-        let db = rocksdb::DB::open_cf_descriptors(&db_opts, &path_buf, column_families())?;
+        let db = open_db(path, &db_opts, &[])?;
+        let mut merk = Merk::open_backend(db, levels)?;
+        merk.db_options = Some(DbOptions::Uniform(db_opts));
+        Ok(merk)
+    }
 
-        let mut merk = Merk {
-            tree: Cell::new(None),
-            db,
-            path: path_buf,
-            max_levels_in_memory: levels,
-        };
-        merk.load_root()?;
+    /// Opens a store with the specified file path like `open`, creating the
+    /// given column families for other data of the application if they do not
+    /// exist. They can then be written to atomically with the tree (see
+    /// `apply_with_extra`).
+    ///
+    /// Column families created this way are opened by every later `open`.
+    pub fn open_with_column_families<P: AsRef<Path>>(
+        path: P,
+        column_families: &[&str],
+    ) -> Result<Merk> {
+        let db_opts = Merk::default_db_opts();
+        let db = open_db(path, &db_opts, column_families)?;
+        let mut merk = Merk::open_backend(db, 100)?;
+        merk.db_options = Some(DbOptions::Uniform(db_opts));
+        Ok(merk)
+    }
 
+    /// Opens a store with the specified file path, whose hashes are computed
+    /// with the given algorithm. If no store exists at that path, one will be
+    /// created which uses the algorithm. Errors if the existing store uses
+    /// another algorithm.
+    pub fn open_with_hash_algorithm<P: AsRef<Path>>(
+        path: P,
+        hash_algorithm: HashAlgorithm,
+    ) -> Result<Merk> {
+        let db_opts = Merk::default_db_opts();
+        let db = open_db(path, &db_opts, &[])?;
+        let mut merk = Merk::open_backend_with_hash_algorithm(db, 100, hash_algorithm)?;
+        merk.db_options = Some(DbOptions::Uniform(db_opts));
         Ok(merk)
     }
 
+    /// Opens the existing store at the specified file path read-only, so it can
+    /// be read (for example to serve `get` and `prove`) while another process
+    /// writes to it. The store shows the tree as it was when it was opened.
+    ///
+    /// Methods which would write to the store error with `Error::ReadOnly`.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Merk> {
+        MerkConfig::default().read_only(true).open(path)
+    }
+
+    /// Opens the existing store at `primary_path` as a RocksDB secondary
+    /// instance, which keeps its own logs at `secondary_path`. Unlike a store
+    /// opened with `open_read_only`, it can follow the writes of the process
+    /// which has the store open (the primary) by calling `try_catch_up`.
+    ///
+    /// Methods which would write to the store error with `Error::ReadOnly`.
+    pub fn open_as_secondary<P, Q>(primary_path: P, secondary_path: Q) -> Result<Merk>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        MerkConfig::default().open_as_secondary(primary_path, secondary_path)
+    }
+
+    /// Catches up with the writes committed by the primary since the store
+    /// was opened (or last caught up), reloading the root of the tree from
+    /// the `internal` column family.
+    ///
+    /// Errors with `Error::NotSecondary` if the store was not opened with
+    /// `open_as_secondary`.
+    pub fn try_catch_up(&mut self) -> Result<()> {
+        if self.access != Access::Secondary {
+            return Err(Error::NotSecondary);
+        }
+
+        self.db.try_catch_up_with_primary()?;
+        // the primary may have enabled counts or recorded the algorithm of a
+        // store which was empty
+        self.aggregate_counts =
+            Backend::get_cf(&self.db, INTERNAL_CF_NAME, AGGREGATE_COUNTS_KEY)?.is_some();
+        if let Some(hash_algorithm) = stored_hash_algorithm(&self.db)? {
+            self.hash_algorithm = hash_algorithm;
+        }
+        self.load_root()
+    }
+
     pub fn default_db_opts() -> rocksdb::Options {
         let mut opts = rocksdb::Options::default();
         opts.create_if_missing(true);
@@ -85,15 +357,247 @@ impl Merk {
         opts
     }
 
+    /// Closes the store and deletes all data from disk.
+    pub fn destroy(self) -> Result<()> {
+        self.check_writable()?;
+        let opts = Merk::default_db_opts();
+        let path = self.db.path().to_path_buf();
+        drop(self);
+        DB::destroy(&opts, path)?;
+        Ok(())
+    }
+
+    pub fn iter_opt(
+        &self,
+        mode: rocksdb::IteratorMode,
+        readopts: rocksdb::ReadOptions,
+    ) -> rocksdb::DBIterator {
+        self.db.iterator_opt(mode, readopts)
+    }
+
+    pub fn iter_opt_aux(
+        &self,
+        mode: rocksdb::IteratorMode,
+        readopts: rocksdb::ReadOptions,
+    ) -> rocksdb::DBIterator {
+        let aux_cf = self.db.cf_handle(AUX_CF_NAME).unwrap();
+        self.db.iterator_cf_opt(aux_cf, readopts, mode)
+    }
+
+    pub fn checkpoint<P: AsRef<Path>>(&self, path: P) -> Result<Merk> {
+        Checkpoint::new(&self.db)?.create_checkpoint(&path)?;
+        Merk::open(path)
+    }
+}
+
+impl<B: Backend> Merk<B> {
+    /// Opens a store on top of the given backend, loading the tree which was
+    /// last committed to it (if any). Up to `levels` levels of the tree are
+    /// kept in memory between commits.
+    ///
+    /// The hash algorithm is the one recorded when the store was created (see
+    /// `open_backend_with_hash_algorithm`), or `HashAlgorithm::Sha512_256` if
+    /// none was recorded.
+    pub fn open_backend(db: B, levels: u8) -> Result<Self> {
+        let hash_algorithm = stored_hash_algorithm(&db)?.unwrap_or_default();
+        Merk::open_backend_inner(db, levels, hash_algorithm)
+    }
+
+    /// Opens a store on top of the given backend like `open_backend`, whose
+    /// hashes are computed with the given algorithm. An empty store records
+    /// the algorithm, so it is used whenever the store is opened again.
+    ///
+    /// Errors with `Error::HashAlgorithm` if the store was created with
+    /// another algorithm.
+    pub fn open_backend_with_hash_algorithm(
+        db: B,
+        levels: u8,
+        hash_algorithm: HashAlgorithm,
+    ) -> Result<Self> {
+        let stored = match stored_hash_algorithm(&db)? {
+            Some(stored) => stored,
+            None if db.get_cf(INTERNAL_CF_NAME, ROOT_KEY_KEY)?.is_some() => {
+                HashAlgorithm::default()
+            }
+            None => {
+                let mut batch = db.batch();
+                batch.put_cf(
+                    INTERNAL_CF_NAME,
+                    HASH_ALGORITHM_KEY,
+                    &[hash_algorithm.tag()],
+                );
+                db.write(batch)?;
+                hash_algorithm
+            }
+        };
+
+        if stored != hash_algorithm {
+            return Err(Error::HashAlgorithm(format!(
+                "Store uses {stored:?}, but {hash_algorithm:?} was requested"
+            )));
+        }
+
+        Merk::open_backend_inner(db, levels, hash_algorithm)
+    }
+
+    fn open_backend_inner(db: B, levels: u8, hash_algorithm: HashAlgorithm) -> Result<Self> {
+        let aggregate_counts = db.get_cf(INTERNAL_CF_NAME, AGGREGATE_COUNTS_KEY)?.is_some();
+        let mut merk = Merk {
+            tree: Cell::new(None),
+            db,
+            max_levels_in_memory: levels,
+            retention: RetentionPolicy::default(),
+            parallel_apply: false,
+            hash_algorithm,
+            aggregate_counts,
+            counters: Counters::default(),
+            node_cache: NodeCache::new(0),
+            versions: vec![],
+            memory_budget: None,
+            wal_sync: WalSync::default(),
+            access: Access::ReadWrite,
+            #[cfg(feature = "full")]
+            db_options: None,
+        };
+        merk.load_root()?;
+
+        Ok(merk)
+    }
+
     #[inline]
     pub fn get_max_levels_in_memory(&self) -> u8 {
         self.max_levels_in_memory
     }
 
+    #[inline]
+    pub fn get_retention_policy(&self) -> RetentionPolicy {
+        self.retention
+    }
+
+    /// Sets which versions to retain. Versioned commits forget versions the
+    /// policy no longer covers, and `prune_versions` reclaims their nodes.
+    #[inline]
+    pub fn set_retention_policy(&mut self, retention: RetentionPolicy) {
+        self.retention = retention;
+    }
+
+    /// Returns the algorithm the store's hashes are computed with.
+    #[inline]
+    pub fn get_hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+    }
+
+    /// Returns `true` if the store keeps aggregate counts (see
+    /// `enable_aggregate_counts`).
+    #[inline]
+    pub fn get_aggregate_counts(&self) -> bool {
+        self.aggregate_counts
+    }
+
+    /// Makes the store keep aggregate counts: every node records the number
+    /// of keys in its subtree, and its hash commits to that count. The store
+    /// can then count the keys in a range (see `count_range`) and prove the
+    /// count without listing the keys (see `prove_count`).
+    ///
+    /// Counts can only be enabled while the store is empty, and are recorded
+    /// so they are kept whenever the store is opened again. Errors with
+    /// `Error::Tree` if the store already has keys without counts.
+    pub fn enable_aggregate_counts(&mut self) -> Result<()> {
+        self.check_writable()?;
+        if self.aggregate_counts {
+            return Ok(());
+        }
+        if self.db.get_cf(INTERNAL_CF_NAME, ROOT_KEY_KEY)?.is_some() {
+            return Err(Error::Tree(
+                "Aggregate counts can only be enabled on an empty store".into(),
+            ));
+        }
+
+        let mut batch = self.db.batch();
+        batch.put_cf(INTERNAL_CF_NAME, AGGREGATE_COUNTS_KEY, &[1]);
+        self.db.write(batch)?;
+        self.aggregate_counts = true;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn get_parallel_apply(&self) -> bool {
+        self.parallel_apply
+    }
+
+    /// Sets whether batches are applied to the tree using several threads
+    /// (see `Walker::apply_to_parallel`). This only speeds up large batches,
+    /// and produces the same tree as applying on a single thread. Disabled by
+    /// default.
+    #[inline]
+    pub fn set_parallel_apply(&mut self, parallel_apply: bool) {
+        self.parallel_apply = parallel_apply;
+    }
+
+    #[inline]
+    pub fn get_wal_sync(&self) -> WalSync {
+        self.wal_sync
+    }
+
+    /// Sets when commits are synced to durable storage. Defaults to
+    /// `WalSync::Buffered`.
+    #[inline]
+    pub fn set_wal_sync(&mut self, wal_sync: WalSync) {
+        self.wal_sync = wal_sync;
+    }
+
+    #[inline]
+    pub fn get_node_cache_budget(&self) -> usize {
+        self.node_cache.budget()
+    }
+
+    /// Sets how many bytes of nodes fetched from the backend are kept decoded
+    /// in a least-recently-used cache, so nodes below the levels kept in
+    /// memory which are read often are not read and decoded again. Setting
+    /// the budget empties the cache. Disabled (0) by default.
+    #[inline]
+    pub fn set_node_cache_budget(&mut self, budget: usize) {
+        self.node_cache = NodeCache::new(budget);
+    }
+
+    #[inline]
+    pub fn get_memory_budget(&self) -> Option<usize> {
+        self.memory_budget
+    }
+
+    /// Sets how many bytes of nodes of the tree are kept in memory after a
+    /// commit, as estimated from their keys and values. Each commit prunes
+    /// the tree to as many of its top levels as fit in the budget (and no more
+    /// than `max_levels_in_memory` along the modified nodes), including nodes
+    /// loaded by reads such as proofs, so memory use stays bounded however
+    /// large the nodes are. Unbounded (`None`) by default.
+    #[inline]
+    pub fn set_memory_budget(&mut self, budget: Option<usize>) {
+        self.memory_budget = budget;
+    }
+
     /// Gets an auxiliary value.
     pub fn get_aux(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        let aux_cf = self.db.cf_handle(AUX_CF_NAME);
-        Ok(self.db.get_cf(aux_cf.unwrap(), key)?)
+        self.db.get_cf(AUX_CF_NAME, key)
+    }
+
+    /// Gets a value from a column family of the application (see
+    /// `apply_with_extra`).
+    pub fn get_extra(&self, column_family: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.ensure_extra_column_family(column_family)?;
+        self.db.get_cf(column_family, key)
+    }
+
+    /// Checks that `name` is a column family of the application which the
+    /// backend has, so writing to it can not fail once the tree was changed.
+    fn ensure_extra_column_family(&self, name: &str) -> Result<()> {
+        check_extra_column_family(name)?;
+        if !self.db.has_column_family(name) {
+            return Err(Error::ColumnFamily(format!(
+                "Unknown column family \"{name}\""
+            )));
+        }
+        Ok(())
     }
 
     /// Gets a value for the given key. If the key is not found, `None` is
@@ -103,9 +607,21 @@ impl Merk {
     /// should be a fast operation and has almost no tree overhead.
     pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
         self.use_tree(|maybe_tree| {
-            maybe_tree
-                .and_then(|tree| get(tree, self.source(), key).transpose())
-                .transpose()
+            let tree = match maybe_tree {
+                Some(tree) => tree,
+                None => return Ok(None),
+            };
+            let result = tree.get_value(key)?;
+            self.counters
+                .count_get(!matches!(result, GetResult::Pruned));
+            Ok(match result {
+                GetResult::Found(value) => Some(value),
+                GetResult::NotFound => None,
+                GetResult::Pruned => self
+                    .source()
+                    .fetch_by_key(key)?
+                    .map(|node| node.value().to_vec()),
+            })
         })
     }
 
@@ -137,22 +653,7 @@ impl Merk {
     /// store.apply(batch, &[]).unwrap();
     /// ```
     pub fn apply(&mut self, batch: &Batch, aux: &Batch) -> Result<()> {
-        // ensure keys in batch are sorted and unique
-        let mut maybe_prev_key: Option<Vec<u8>> = None;
-        for (key, _) in batch.iter() {
-            if let Some(prev_key) = maybe_prev_key {
-                match prev_key.cmp(key) {
-                    Ordering::Greater => {
-                        return Err(Error::BatchKey("Keys in batch must be sorted".into()));
-                    }
-                    Ordering::Equal => {
-                        return Err(Error::BatchKey("Keys in batch must be unique".into()));
-                    }
-                    _ => (),
-                }
-            }
-            maybe_prev_key = Some(key.to_vec());
-        }
+        ensure_sorted_unique(batch)?;
 
         unsafe { self.apply_unchecked(batch, aux) }
     }
@@ -179,77 +680,92 @@ impl Merk {
     /// unsafe { store.apply_unchecked(batch, &[]).unwrap() };
     /// ```
     pub unsafe fn apply_unchecked(&mut self, batch: &Batch, aux: &Batch) -> Result<()> {
-        let maybe_walker = self
-            .tree
-            .take()
-            .take()
-            .map(|tree| Walker::new(tree, self.source()));
-
-        let (maybe_tree, deleted_keys) = Walker::apply_to(maybe_walker, batch, self.source())?;
-        self.tree.set(maybe_tree);
+        let deleted_keys = self.apply_to_tree(batch)?;
 
         // commit changes to db
         self.commit(deleted_keys, aux)
     }
 
-    /// Closes the store and deletes all data from disk.
-    pub fn destroy(self) -> Result<()> {
-        let opts = Merk::default_db_opts();
-        let path = self.path.clone();
-        drop(self);
-        rocksdb::DB::destroy(&opts, path)?;
-        Ok(())
-    }
-
-    /// Completely rebuilds the tree, keeping all the same stored keys and
-    /// values.
-    pub fn repair(self) -> Result<Self> {
-        use rocksdb::IteratorMode;
-
-        let path = self.path.clone();
-
-        let create_path = |suffix| {
-            let mut tmp_path = path.clone();
-            let tmp_file_name =
-                format!("{}-{}", path.file_name().unwrap().to_str().unwrap(), suffix);
-            tmp_path.set_file_name(tmp_file_name);
-            tmp_path
-        };
-
-        let tmp_path = create_path("repair1");
-        let tmp = Merk::open(&tmp_path)?;
-        tmp.destroy()?;
-
-        // TODO: split up batch
-        let mut node = Tree::new(vec![], vec![])?;
-        let batch: Vec<_> = self
-            .db
-            .iterator(IteratorMode::Start)
-            .map(|(key, node_bytes)| {
-                node.decode_into(vec![], &node_bytes);
-                (key.to_vec(), Op::Put(node.value().to_vec()))
-            })
-            .collect();
-
-        let aux_cf = self.db.cf_handle(AUX_CF_NAME).unwrap();
-        let aux: Vec<_> = self
-            .db
-            .iterator_cf(aux_cf, IteratorMode::Start)
-            .map(|(key, value)| (key.to_vec(), Op::Put(value.to_vec())))
-            .collect();
+    /// Applies a batch of operations like `apply`, and writes `extra` in the
+    /// same atomic write as the tree. Each entry of `extra` is a batch of
+    /// operations on keys of a column family of the application, which must
+    /// be known to the backend (e.g. created with `open_with_column_families`).
+    ///
+    /// This lets other data of the application, such as records indexed by
+    /// block, be committed along with the state root they belong to.
+    ///
+    /// Errors with `Error::ColumnFamily`, without changing the tree, if a
+    /// column family is one the store keeps its own data in or is not known
+    /// to the backend.
+    ///
+    /// # Example
+    /// ```
+    /// # let mut store = merkdb::Merk::open_backend(merkdb::backend::MemoryBackend::new(), 1).unwrap();
+    /// use merkdb::Op;
+    ///
+    /// let bills = &[(vec![1], Op::Put(vec![100]))];
+    /// store
+    ///     .apply_with_extra(&[(vec![1], Op::Put(vec![2]))], &[], &[("bills", bills)])
+    ///     .unwrap();
+    ///
+    /// assert_eq!(store.get_extra("bills", &[1]).unwrap(), Some(vec![100]));
+    /// ```
+    pub fn apply_with_extra(
+        &mut self,
+        batch: &Batch,
+        aux: &Batch,
+        extra: &[(&str, &Batch)],
+    ) -> Result<()> {
+        ensure_sorted_unique(batch)?;
+        for (column_family, _) in extra {
+            self.ensure_extra_column_family(column_family)?;
+        }
 
-        drop(self);
+        let deleted_keys = self.apply_to_tree(batch)?;
+        self.commit_inner(deleted_keys, aux, extra, None)
+    }
 
-        let mut tmp = Self::open(&tmp_path)?;
-        tmp.apply(&batch, &aux)?;
-        drop(tmp);
+    /// Applies a batch of operations like `apply`, then records the resulting
+    /// root as `version` so it can later be read with `Merk::version`.
+    ///
+    /// Versions are typically block heights, and must be strictly increasing.
+    ///
+    /// # Example
+    /// ```
+    /// # let mut store = merkdb::test_utils::TempMerk::new().unwrap();
+    /// use merkdb::Op;
+    ///
+    /// store.apply_versioned(1, &[(vec![1], Op::Put(vec![1]))], &[]).unwrap();
+    /// store.apply_versioned(2, &[(vec![1], Op::Put(vec![2]))], &[]).unwrap();
+    ///
+    /// assert_eq!(store.version(1).unwrap().get(&[1]).unwrap(), Some(vec![1]));
+    /// assert_eq!(store.get(&[1]).unwrap(), Some(vec![2]));
+    /// ```
+    pub fn apply_versioned(&mut self, version: u64, batch: &Batch, aux: &Batch) -> Result<()> {
+        self.check_version(version)?;
+        ensure_sorted_unique(batch)?;
 
-        let tmp_path2 = create_path("repair2");
-        std::fs::rename(&path, &tmp_path2)?;
-        std::fs::rename(&tmp_path, &path)?;
-        std::fs::remove_dir_all(&tmp_path2)?;
+        let deleted_keys = self.apply_to_tree(batch)?;
+        self.commit_inner(deleted_keys, aux, &[], Some(version))
+    }
 
-        Self::open(path)
+    /// Begins a transaction, which stages puts and deletes in memory until it
+    /// is committed (see `Transaction`). The store can not be written to
+    /// otherwise while the transaction is open.
+    ///
+    /// # Example
+    /// ```
+    /// # let mut store = merkdb::test_utils::TempMerk::new().unwrap();
+    /// let mut tx = store.begin();
+    /// tx.put(vec![1], vec![2]);
+    /// assert_eq!(tx.get(&[1]).unwrap(), Some(vec![2]));
+    /// let root_hash = tx.root_hash_preview().unwrap();
+    /// tx.commit().unwrap();
+    ///
+    /// assert_eq!(store.root_hash(), root_hash);
+    /// ```
+    pub fn begin(&mut self) -> Transaction<'_, B> {
+        Transaction::new(self)
     }
 
     pub fn execute_query(&self, query: Query) -> Result<LinkedList<ProofOp>> {
@@ -278,8 +794,22 @@ impl Merk {
     /// check adds some overhead, so if you are sure your batch is sorted and
     /// unique you can use the unsafe `prove_unchecked` for a small performance
     /// gain.
+    ///
+    /// If the query has a limit, the proof only includes the selected page of
+    /// entries. It can be checked with `merk::verify_entries`.
+    ///
+    /// Errors with `Error::HashAlgorithm` if the query asks for KV digests
+    /// (see `Query::set_kv_digests`) but the store's hash algorithm does not
+    /// support them, as is the case for the default `Sha512_256`.
     pub fn prove(&self, query: Query) -> Result<Vec<u8>> {
-        self.prove_unchecked(query)
+        self.prove_with_encoding(query, ProofEncoding::Legacy)
+    }
+
+    /// Like `prove`, but writes the proof in the given encoding. Compact
+    /// proofs are smaller, but can only be verified by verifiers which support
+    /// the compact encoding.
+    pub fn prove_with_encoding(&self, query: Query, encoding: ProofEncoding) -> Result<Vec<u8>> {
+        self.use_tree_mut(move |maybe_tree| prove(maybe_tree, self.source(), query, encoding))
     }
 
     /// Creates a Merkle proof for the list of queried keys. For each key in the
@@ -304,28 +834,156 @@ impl Merk {
         })
     }
 
+    /// Returns the number of keys in `range`, using the aggregate counts of
+    /// the tree's nodes instead of iterating over the keys.
+    ///
+    /// Errors if the store does not keep aggregate counts (see
+    /// `enable_aggregate_counts`).
+    pub fn count_range<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<u64> {
+        self.check_aggregate_counts()?;
+        self.use_tree_mut(|maybe_tree| match maybe_tree {
+            None => Ok(0),
+            Some(tree) => RefWalker::new(tree, self.source())
+                .create_count_proof(&range)
+                .map(|(_, count)| count),
+        })
+    }
+
+    /// Creates a Merkle proof of the number of keys in `range`. Only the nodes
+    /// on the paths to the range's bounds are included, the rest of the tree
+    /// is abridged to the hashes and counts of its subtrees, so the proof's
+    /// size does not grow with the count.
+    ///
+    /// The proof can be verified with `merk::verify_count`. Errors if the
+    /// store does not keep aggregate counts (see `enable_aggregate_counts`).
+    pub fn prove_count<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<Vec<u8>> {
+        self.check_aggregate_counts()?;
+        self.use_tree_mut(|maybe_tree| {
+            let tree = maybe_tree
+                .ok_or_else(|| Error::Proof("Cannot create proof for empty tree".into()))?;
+            let (proof, _) = RefWalker::new(tree, self.source()).create_count_proof(&range)?;
+
+            let mut bytes = Vec::with_capacity(128);
+            encode_proof(
+                proof.iter(),
+                self.hash_algorithm,
+                true,
+                ProofEncoding::Legacy,
+                &mut bytes,
+            )?;
+            Ok(bytes)
+        })
+    }
+
     pub fn flush(&self) -> Result<()> {
-        Ok(self.db.flush()?)
+        self.db.flush()
     }
 
     pub fn commit(&mut self, deleted_keys: LinkedList<Vec<u8>>, aux: &Batch) -> Result<()> {
-        let internal_cf = self.db.cf_handle(INTERNAL_CF_NAME).unwrap();
-        let aux_cf = self.db.cf_handle(AUX_CF_NAME).unwrap();
+        self.commit_inner(deleted_keys, aux, &[], None)
+    }
 
-        let mut batch = rocksdb::WriteBatch::default();
+    /// Like `commit`, but also records the committed root as `version`.
+    pub fn commit_versioned(
+        &mut self,
+        version: u64,
+        deleted_keys: LinkedList<Vec<u8>>,
+        aux: &Batch,
+    ) -> Result<()> {
+        self.check_version(version)?;
+        self.commit_inner(deleted_keys, aux, &[], Some(version))
+    }
+
+    fn commit_inner(
+        &mut self,
+        deleted_keys: LinkedList<Vec<u8>>,
+        aux: &Batch,
+        extra: &[(&str, &Batch)],
+        version: Option<u64>,
+    ) -> Result<()> {
+        self.check_writable()?;
+        let start = Instant::now();
+
+        let mut batch = self.db.batch();
+        let (nodes_written, versions) =
+            self.stage_commit(&mut batch, deleted_keys, aux, extra, version)?;
+
+        #[cfg(all(test, feature = "full"))]
+        crate::test_utils::crash_point(crate::test_utils::CrashPoint::CommitBeforeWrite);
+
+        // write to db
+        self.db.write(batch)?;
+        if let Some(versions) = versions {
+            self.versions = versions;
+        }
+
+        #[cfg(all(test, feature = "full"))]
+        crate::test_utils::crash_point(crate::test_utils::CrashPoint::CommitAfterWrite);
+
+        if self.wal_sync == WalSync::EveryCommit {
+            self.db.sync_wal()?;
+        }
+        self.counters.count_commit(nodes_written, start.elapsed());
+
+        #[cfg(all(test, feature = "full"))]
+        crate::test_utils::crash_point(crate::test_utils::CrashPoint::CommitAfterFlush);
+
+        Ok(())
+    }
+
+    /// Applies a batch of operations to the in-memory tree like `apply`, then
+    /// adds the writes which commit it to `batch` instead of writing them, so
+    /// they can be written atomically with the writes of other trees sharing
+    /// the backend. Returns the number of nodes written.
+    pub(crate) fn apply_staged(
+        &mut self,
+        batch: &Batch,
+        aux: &Batch,
+        out: &mut B::Batch<'_>,
+    ) -> Result<usize> {
+        ensure_sorted_unique(batch)?;
+
+        let deleted_keys = self.apply_to_tree(batch)?;
+        let (nodes_written, _) = self.stage_commit(out, deleted_keys, aux, &[], None)?;
+        Ok(nodes_written)
+    }
+
+    /// Adds the writes which commit the in-memory tree to `batch`. Returns the
+    /// number of nodes written and, if the commit records a version, the
+    /// versions retained once the batch is written.
+    fn stage_commit(
+        &self,
+        batch: &mut B::Batch<'_>,
+        deleted_keys: LinkedList<Vec<u8>>,
+        aux: &Batch,
+        extra: &[(&str, &Batch)],
+        version: Option<u64>,
+    ) -> Result<(usize, Option<Vec<u64>>)> {
         let mut to_batch = self.use_tree_mut(|maybe_tree| -> UseTreeMutResult {
-            // TODO: concurrent commit
             if let Some(tree) = maybe_tree {
                 let mut committer = MerkCommitter::new(tree.height(), self.max_levels_in_memory);
-                tree.commit(&mut committer)?;
+                tree.commit_parallel(&mut committer, thread_depth())?;
+                if let Some(budget) = self.memory_budget {
+                    prune_to_budget(tree, budget);
+                }
 
                 // update pointer to root node
-                batch.put_cf(internal_cf, ROOT_KEY_KEY, tree.key());
+                batch.put_cf(INTERNAL_CF_NAME, ROOT_KEY_KEY, tree.key());
+                if let Some(version) = version {
+                    batch.put_cf(
+                        INTERNAL_CF_NAME,
+                        &version_key(version),
+                        &version_root(Some(tree)),
+                    );
+                }
 
                 Ok(committer.batch)
             } else {
                 // empty tree, delete pointer to root
-                batch.delete_cf(internal_cf, ROOT_KEY_KEY);
+                batch.delete_cf(INTERNAL_CF_NAME, ROOT_KEY_KEY);
+                if let Some(version) = version {
+                    batch.put_cf(INTERNAL_CF_NAME, &version_key(version), &version_root(None));
+                }
 
                 Ok(vec![])
             }
@@ -336,28 +994,60 @@ impl Merk {
             to_batch.push((key, None));
         }
         to_batch.sort_by(|a, b| a.0.cmp(&b.0));
+
+        // nodes about to be overwritten may still be reachable from a retained
+        // version, so move them into the history column family first
+        if !self.versions.is_empty() {
+            for (key, _) in to_batch.iter() {
+                if let Some(bytes) = self.db.get(key)? {
+                    let node =
+                        Tree::decode_with_algorithm(key.to_vec(), &bytes, self.hash_algorithm);
+                    batch.put_cf(HISTORY_CF_NAME, &node.hash(), &bytes);
+                }
+            }
+        }
+
+        let versions = version.map(|version| {
+            let mut versions = self.versions.clone();
+            versions.push(version);
+            let expired = self.retention.expired(&versions);
+            for old_version in versions.drain(..expired) {
+                batch.delete_cf(INTERNAL_CF_NAME, &version_key(old_version));
+            }
+            versions
+        });
+
+        let nodes_written = to_batch.len();
         for (key, maybe_value) in to_batch {
+            // the cached copies of the nodes are out of date
+            self.node_cache.remove(&key);
             if let Some(value) = maybe_value {
-                batch.put(key, value);
+                batch.put(&key, &value);
             } else {
-                batch.delete(key);
+                batch.delete(&key);
             }
         }
 
         for (key, value) in aux {
             match value {
-                Op::Put(value) => batch.put_cf(aux_cf, key, value),
-                Op::Delete => batch.delete_cf(aux_cf, key),
+                Op::Put(value) => batch.put_cf(AUX_CF_NAME, key, value),
+                Op::Delete => batch.delete_cf(AUX_CF_NAME, key),
             };
         }
 
-        // write to db
-        self.write(batch)?;
+        for (column_family, ops) in extra {
+            for (key, value) in ops.iter() {
+                match value {
+                    Op::Put(value) => batch.put_cf(column_family, key, value),
+                    Op::Delete => batch.delete_cf(column_family, key),
+                };
+            }
+        }
 
-        Ok(())
+        Ok((nodes_written, versions))
     }
 
-    pub fn walk<T>(&self, f: impl FnOnce(Option<RefWalker<MerkSource>>) -> T) -> T {
+    pub fn walk<T>(&self, f: impl FnOnce(Option<RefWalker<MerkSource<B>>>) -> T) -> T {
         let mut tree = self.tree.take();
         let maybe_walker = tree
             .as_mut()
@@ -367,37 +1057,95 @@ impl Merk {
         res
     }
 
-    pub fn raw_iter(&self) -> rocksdb::DBRawIterator {
-        self.db.raw_iterator()
+    pub fn raw_iter(&self) -> B::RawIter<'_> {
+        self.db.raw_iter()
     }
-    pub fn iter_opt(
-        &self,
-        mode: rocksdb::IteratorMode,
-        readopts: rocksdb::ReadOptions,
-    ) -> rocksdb::DBIterator {
-        self.db.iterator_opt(mode, readopts)
+
+    /// Iterates over the keys and values in `range` (a `KeyRange`, or any
+    /// standard range of byte keys) as of the last commit, in ascending key
+    /// order. Call `rev` on the iterator to iterate in descending order.
+    pub fn range(&self, range: impl Into<KeyRange>) -> RangeIter<B::RawIter<'_>> {
+        RangeIter::new(range.into(), self.db.raw_iter(), self.db.raw_iter())
     }
 
-    pub fn iter_opt_aux(
-        &self,
-        mode: rocksdb::IteratorMode,
-        readopts: rocksdb::ReadOptions,
-    ) -> rocksdb::DBIterator {
-        let aux_cf = self.db.cf_handle(AUX_CF_NAME).unwrap();
-        self.db.iterator_cf_opt(aux_cf, readopts, mode)
+    pub fn snapshot(&self) -> Result<Snapshot<B>> {
+        let root = load_root(self.source())?;
+        Ok(Snapshot::new(self.db.snapshot(), root))
     }
 
-    pub fn checkpoint<P: AsRef<Path>>(&self, path: P) -> Result<Merk> {
-        Checkpoint::new(&self.db)?.create_checkpoint(&path)?;
-        Merk::open(path)
+    /// Returns the retained versions, in ascending order.
+    pub fn versions(&self) -> Result<Vec<u64>> {
+        Ok(self.versions.clone())
     }
 
-    pub fn snapshot(&self) -> Result<Snapshot> {
-        Ok(Snapshot::new(self.db.snapshot(), load_root(&self.db)?))
+    /// Returns the most recently recorded version, if any.
+    pub fn latest_version(&self) -> Result<Option<u64>> {
+        Ok(self.versions.last().copied())
     }
 
-    fn source(&self) -> MerkSource {
-        MerkSource { db: &self.db }
+    /// Reads the retained versions from the `internal` column family, in
+    /// ascending order.
+    fn load_versions(&self) -> Result<Vec<u64>> {
+        let mut iter = self.db.raw_iter_cf(INTERNAL_CF_NAME);
+        iter.seek(VERSION_KEY_PREFIX);
+
+        let mut versions = vec![];
+        while let Some(key) = iter.key() {
+            if !key.starts_with(VERSION_KEY_PREFIX) {
+                break;
+            }
+            let bytes: [u8; 8] = key[VERSION_KEY_PREFIX.len()..]
+                .try_into()
+                .map_err(|_| Error::Version(format!("Malformed version key {key:?}")))?;
+            versions.push(u64::from_be_bytes(bytes));
+            iter.next();
+        }
+
+        Ok(versions)
+    }
+
+    /// Opens a read-only view of the tree as it was when `version` was
+    /// committed. Errors if the version was never recorded or is no longer
+    /// retained.
+    pub fn version(&self, version: u64) -> Result<Version<B>> {
+        let source = version::VersionSource::new(&self.db, self.hash_algorithm);
+        let tree = self
+            .version_root(version)?
+            .map(|link| source.fetch(&link))
+            .transpose()?;
+
+        Ok(Version::new(&self.db, tree, self.hash_algorithm))
+    }
+
+    /// Reads the root pointer recorded for `version` as a link to the root
+    /// node, or `None` if the tree was empty at that version.
+    fn version_root(&self, version: u64) -> Result<Option<Link>> {
+        let root = self
+            .db
+            .get_cf(INTERNAL_CF_NAME, &version_key(version))?
+            .ok_or_else(|| Error::Version(format!("Version {version} is not retained")))?;
+
+        if root.is_empty() {
+            return Ok(None);
+        }
+
+        let mut hash = NULL_HASH;
+        hash.copy_from_slice(&root[..HASH_LENGTH]);
+        Ok(Some(Link::Reference {
+            hash,
+            child_heights: (0, 0),
+            key: root[HASH_LENGTH..].to_vec(),
+        }))
+    }
+
+    fn source(&self) -> MerkSource<B> {
+        MerkSource {
+            db: &self.db,
+            hash_algorithm: self.hash_algorithm,
+            aggregate_counts: self.aggregate_counts,
+            counters: &self.counters,
+            node_cache: &self.node_cache,
+        }
     }
 
     fn use_tree<T>(&self, f: impl FnOnce(Option<&Tree>) -> T) -> T {
@@ -414,43 +1162,124 @@ impl Merk {
         res
     }
 
-    pub(crate) fn write(&mut self, batch: WriteBatch) -> Result<()> {
-        let mut opts = rocksdb::WriteOptions::default();
-        opts.set_sync(false);
-        // TODO: disable WAL once we can ensure consistency with transactions
-        self.db.write_opt(batch, &opts)?;
+    pub(crate) fn write(&self, batch: B::Batch<'_>) -> Result<()> {
+        self.db.write(batch)?;
+        if self.wal_sync == WalSync::EveryCommit {
+            self.db.sync_wal()?;
+        }
+        Ok(())
+    }
+
+    /// Applies the batch to the in-memory tree, returning the keys of the
+    /// deleted nodes. Nothing is written until the tree is committed.
+    fn apply_to_tree(&mut self, batch: &Batch) -> Result<LinkedList<Vec<u8>>> {
+        self.check_writable()?;
+        let maybe_walker = self
+            .tree
+            .take()
+            .map(|tree| Walker::new(tree, self.source()));
+
+        let (maybe_tree, deleted_keys) =
+            Walker::apply_to_parallel(maybe_walker, batch, self.source(), self.apply_depth())?;
+        self.tree.set(maybe_tree);
+
+        Ok(deleted_keys)
+    }
+
+    fn apply_depth(&self) -> u8 {
+        if self.parallel_apply {
+            thread_depth()
+        } else {
+            0
+        }
+    }
+
+    /// Errors with `Error::ReadOnly` if the store was opened read-only or as a
+    /// secondary.
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        if self.access != Access::ReadWrite {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    fn check_aggregate_counts(&self) -> Result<()> {
+        if !self.aggregate_counts {
+            return Err(Error::Tree("Store does not keep aggregate counts".into()));
+        }
         Ok(())
     }
 
-    pub(crate) fn set_root_key(&mut self, key: Vec<u8>) -> Result<()> {
-        let internal_cf = self.db.cf_handle(INTERNAL_CF_NAME).unwrap();
-        let mut batch = WriteBatch::default();
-        batch.put_cf(internal_cf, ROOT_KEY_KEY, key);
-        self.write(batch)
+    fn check_version(&self, version: u64) -> Result<()> {
+        match self.latest_version()? {
+            Some(latest) if version <= latest => Err(Error::Version(format!(
+                "Version {version} must be greater than latest version {latest}"
+            ))),
+            _ => Ok(()),
+        }
     }
 
+    #[cfg(feature = "full")]
     pub(crate) fn fetch_node(&self, key: &[u8]) -> Result<Option<Tree>> {
         self.source().fetch_by_key(key)
     }
 
+    /// Reloads the root node and the retained versions from the backend, also
+    /// emptying the node cache since the nodes may have been written without a
+    /// commit (for example by a restore).
     pub(crate) fn load_root(&mut self) -> Result<()> {
-        let root = load_root(&self.db)?;
+        self.node_cache.clear();
+        let root = load_root(self.source())?;
         self.tree = Cell::new(root);
+        self.versions = self.load_versions()?;
         Ok(())
     }
 }
 
-#[derive(Clone)]
-pub struct MerkSource<'a> {
-    db: &'a rocksdb::DB,
+pub struct MerkSource<'a, B = DefaultBackend> {
+    db: &'a B,
+    hash_algorithm: HashAlgorithm,
+    aggregate_counts: bool,
+    counters: &'a Counters,
+    node_cache: &'a NodeCache,
 }
 
-impl<'a> Fetch for MerkSource<'a> {
+impl<'a, B> Clone for MerkSource<'a, B> {
+    fn clone(&self) -> Self {
+        MerkSource {
+            db: self.db,
+            hash_algorithm: self.hash_algorithm,
+            aggregate_counts: self.aggregate_counts,
+            counters: self.counters,
+            node_cache: self.node_cache,
+        }
+    }
+}
+
+impl<'a, B: Backend> Fetch for MerkSource<'a, B> {
     fn fetch_by_key(&self, key: &[u8]) -> Result<Option<Tree>> {
-        Ok(self
+        if let Some(node) = self.node_cache.get(key) {
+            self.counters.count_node_cache_hit();
+            return Ok(Some(node));
+        }
+
+        let maybe_node = self
             .db
-            .get_pinned(key)?
-            .map(|bytes| Tree::decode(key.to_vec(), &bytes)))
+            .get(key)?
+            .map(|bytes| Tree::decode_with_algorithm(key.to_vec(), &bytes, self.hash_algorithm));
+        if let Some(node) = &maybe_node {
+            self.counters.count_fetch();
+            self.node_cache.insert(node);
+        }
+        Ok(maybe_node)
+    }
+
+    fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+    }
+
+    fn aggregate_counts(&self) -> bool {
+        self.aggregate_counts
     }
 }
 
@@ -485,6 +1314,47 @@ impl Commit for MerkCommitter {
     }
 }
 
+impl SplitCommit for MerkCommitter {
+    fn split(&self) -> Self {
+        MerkCommitter {
+            batch: vec![],
+            height: self.height,
+            levels: self.levels,
+        }
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.batch.extend(other.batch);
+    }
+}
+
+/// Returns how many levels of the tree are split across threads when
+/// committing or applying in parallel: enough for one thread per available
+/// core.
+fn thread_depth() -> u8 {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    (usize::BITS - (threads - 1).leading_zeros()) as u8
+}
+
+fn ensure_sorted_unique(batch: &Batch) -> Result<()> {
+    let mut maybe_prev_key: Option<&[u8]> = None;
+    for (key, _) in batch.iter() {
+        if let Some(prev_key) = maybe_prev_key {
+            match prev_key.cmp(key) {
+                Ordering::Greater => {
+                    return Err(Error::BatchKey("Keys in batch must be sorted".into()));
+                }
+                Ordering::Equal => {
+                    return Err(Error::BatchKey("Keys in batch must be unique".into()));
+                }
+                _ => (),
+            }
+        }
+        maybe_prev_key = Some(key);
+    }
+    Ok(())
+}
+
 pub fn get<F: Fetch>(tree: &Tree, source: F, key: &[u8]) -> Result<Option<Vec<u8>>> {
     Ok(match tree.get_value(key)? {
         GetResult::Found(value) => Some(value),
@@ -497,6 +1367,49 @@ fn root_hash(maybe_tree: Option<&Tree>) -> Hash {
     maybe_tree.map_or(NULL_HASH, |tree| tree.hash())
 }
 
+fn prove<F>(
+    maybe_tree: Option<&mut Tree>,
+    source: F,
+    query: Query,
+    encoding: ProofEncoding,
+) -> Result<Vec<u8>>
+where
+    F: Fetch + Send + Clone,
+{
+    let mut limits = query.proof_limits();
+    let left_to_right = query.left_to_right();
+    let kv_digests = query.kv_digests();
+    let query_vec: Vec<QueryItem> = query.into_iter().collect();
+
+    let tree =
+        maybe_tree.ok_or_else(|| Error::Proof("Cannot create proof for empty tree".into()))?;
+
+    let hash_algorithm = source.hash_algorithm();
+    if kv_digests && !hash_algorithm.supports_kv_digest() {
+        return Err(Error::HashAlgorithm(format!(
+            "{hash_algorithm:?} does not support KV digests"
+        )));
+    }
+    let aggregate_counts = tree.has_aggregate_counts();
+    let mut ref_walker = RefWalker::new(tree, source);
+    let (proof, _) = ref_walker.create_limited_proof(
+        query_vec.as_slice(),
+        &mut limits,
+        left_to_right,
+        kv_digests,
+    )?;
+
+    let mut bytes = Vec::with_capacity(128);
+    encode_proof(
+        proof.iter(),
+        hash_algorithm,
+        aggregate_counts,
+        encoding,
+        &mut bytes,
+    )?;
+    Ok(bytes)
+}
+
 fn prove_unchecked<Q, I, F>(maybe_tree: Option<&mut Tree>, source: F, query: I) -> Result<Vec<u8>>
 where
     Q: Into<QueryItem>,
@@ -508,28 +1421,66 @@ where
     let tree =
         maybe_tree.ok_or_else(|| Error::Proof("Cannot create proof for empty tree".into()))?;
 
+    let hash_algorithm = source.hash_algorithm();
+    let aggregate_counts = tree.has_aggregate_counts();
     let mut ref_walker = RefWalker::new(tree, source);
     let (proof, _) = ref_walker.create_proof(query_vec.as_slice())?;
 
     let mut bytes = Vec::with_capacity(128);
-    encode_into(proof.iter(), &mut bytes);
+    encode_proof(
+        proof.iter(),
+        hash_algorithm,
+        aggregate_counts,
+        ProofEncoding::Legacy,
+        &mut bytes,
+    )?;
     Ok(bytes)
 }
 
-fn load_root(db: &DB) -> Result<Option<Tree>> {
-    let internal_cf = db.cf_handle(INTERNAL_CF_NAME).unwrap();
-    db.get_pinned_cf(internal_cf, ROOT_KEY_KEY)?
-        .map(|key| MerkSource { db }.fetch_by_key_expect(key.to_vec().as_slice()))
+fn version_key(version: u64) -> Vec<u8> {
+    let mut key = VERSION_KEY_PREFIX.to_vec();
+    key.extend_from_slice(&version.to_be_bytes());
+    key
+}
+
+/// Encodes the root pointer stored for a version: the root node's hash
+/// followed by its key, or empty for an empty tree.
+fn version_root(maybe_tree: Option<&Tree>) -> Vec<u8> {
+    maybe_tree.map_or_else(Vec::new, |tree| {
+        let mut bytes = tree.hash().to_vec();
+        bytes.extend_from_slice(tree.key());
+        bytes
+    })
+}
+
+fn load_root<B: Backend>(source: MerkSource<B>) -> Result<Option<Tree>> {
+    source
+        .db
+        .get_cf(INTERNAL_CF_NAME, ROOT_KEY_KEY)?
+        .map(|key| source.fetch_by_key_expect(key.as_slice()))
+        .transpose()
+}
+
+/// Reads the hash algorithm recorded in the store, if any.
+fn stored_hash_algorithm<B: Backend>(db: &B) -> Result<Option<HashAlgorithm>> {
+    db.get_cf(INTERNAL_CF_NAME, HASH_ALGORITHM_KEY)?
+        .map(|tag| match tag.as_slice() {
+            [tag] => HashAlgorithm::from_tag(*tag)
+                .ok_or_else(|| Error::HashAlgorithm(format!("Unknown hash algorithm tag {tag}"))),
+            _ => Err(Error::HashAlgorithm("Invalid hash algorithm tag".into())),
+        })
         .transpose()
 }
 
 #[cfg(test)]
 mod test {
-    use super::{Merk, MerkSource, Op, RefWalker};
-    use crate::proofs::query::Query;
+    use super::{Error, Merk, MerkSource, Op, RefWalker};
+    use crate::merk::backend::MemoryBackend;
+    use crate::proofs::{query::Query, ProofEncoding};
     use crate::test_utils::*;
     use crate::tree;
     use std::ops::Range;
+    use std::path::Path;
     use std::thread;
     use tempdir::TempDir;
     // TODO: Close and then reopen test
@@ -574,6 +1525,24 @@ mod test {
         );
     }
 
+    #[test]
+    fn parallel_apply() {
+        let mut serial = TempMerk::new().expect("failed to open merk");
+        let mut parallel = TempMerk::new().expect("failed to open merk");
+        parallel.set_parallel_apply(true);
+        assert!(parallel.get_parallel_apply());
+
+        for (i, batch) in [make_batch_seq(0..10_000), make_batch_rand(10_000, 1)]
+            .iter()
+            .enumerate()
+        {
+            serial.apply_versioned(i as u64, batch, &[]).unwrap();
+            parallel.apply_versioned(i as u64, batch, &[]).unwrap();
+            assert_eq!(parallel.root_hash(), serial.root_hash());
+        }
+        assert_invariants(&parallel);
+    }
+
     #[test]
     fn insert_uncached() {
         let batch_size = 20;
@@ -655,6 +1624,74 @@ mod test {
         assert_eq!(3, ops.len());
     }
 
+    #[test]
+    fn prove_limited_query() {
+        let mut merk = TempMerk::new().expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..1_000), &[])
+            .expect("apply failed");
+
+        let mut query = Query::new();
+        query.insert_range(seq_key(100)..seq_key(900));
+        query.set_limit(Some(3));
+        query.set_offset(1);
+        query.set_left_to_right(false);
+        let proof = merk.prove(query).unwrap();
+
+        let mut query = Query::new();
+        query.insert_range(seq_key(100)..seq_key(900));
+        query.set_limit(Some(3));
+        query.set_offset(1);
+        query.set_left_to_right(false);
+        let entries = crate::verify_entries(&proof, &query, merk.root_hash()).unwrap();
+        let keys: Vec<_> = entries.into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![seq_key(898), seq_key(897), seq_key(896)]);
+    }
+
+    #[test]
+    fn prove_compact() {
+        let mut merk = TempMerk::new().expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..1_000), &[])
+            .expect("apply failed");
+
+        let query = || {
+            let mut query = Query::new();
+            for i in (0..1_000).step_by(37) {
+                query.insert_key(seq_key(i));
+            }
+            query.insert_key(seq_key(5_000));
+            query
+        };
+
+        let legacy = merk.prove(query()).unwrap();
+        let compact = merk
+            .prove_with_encoding(query(), ProofEncoding::Compact { compress: false })
+            .unwrap();
+        assert!(compact.len() < legacy.len());
+        assert_eq!(
+            crate::proofs::reencode(&legacy, ProofEncoding::Compact { compress: false }).unwrap(),
+            compact
+        );
+
+        let expected = crate::verify(&legacy, merk.root_hash()).unwrap();
+        let map = crate::verify(&compact, merk.root_hash()).unwrap();
+        assert_eq!(
+            map.get(&seq_key(37)).unwrap(),
+            expected.get(&seq_key(37)).unwrap()
+        );
+        assert_eq!(map.get(&seq_key(5_000)).unwrap(), None);
+        assert!(crate::verify(&compact[..compact.len() - 1], merk.root_hash()).is_err());
+
+        #[cfg(feature = "zstd")]
+        {
+            let compressed = merk
+                .prove_with_encoding(query(), ProofEncoding::Compact { compress: true })
+                .unwrap();
+            assert!(compressed.len() < compact.len());
+            let map = crate::verify(&compressed, merk.root_hash()).unwrap();
+            assert_eq!(map.get(&seq_key(74)).unwrap(), Some(&put_entry_value()[..]));
+        }
+    }
+
     #[test]
     fn simulated_crash() {
         let path = thread::current().name().unwrap().to_owned();
@@ -781,6 +1818,200 @@ mod test {
         assert_eq!(reopen_nodes, original_nodes);
     }
 
+    #[test]
+    fn prove_kv_digests() {
+        use crate::tree::HashAlgorithm;
+
+        let batch: Vec<_> = (0..100)
+            .map(|i| (seq_key(i), Op::Put(vec![i as u8; 4_096])))
+            .collect();
+        let query = |kv_digests| {
+            let mut query = Query::new();
+            query.insert_key(seq_key(10));
+            query.insert_key(seq_key(1_000));
+            query.set_kv_digests(kv_digests);
+            query
+        };
+
+        let mut merk = Merk::open_backend_with_hash_algorithm(
+            MemoryBackend::new(),
+            100,
+            HashAlgorithm::Blake3,
+        )
+        .unwrap();
+        merk.apply(&batch, &[]).unwrap();
+
+        let full = merk.prove(query(false)).unwrap();
+        let digests = merk.prove(query(true)).unwrap();
+        assert!(digests.len() + 4_000 < full.len());
+
+        let map = crate::verify(&digests, merk.root_hash()).unwrap();
+        assert_eq!(
+            map.value_hash(&seq_key(10)).unwrap(),
+            Some(HashAlgorithm::Blake3.value_hash(&[10; 4_096]))
+        );
+        assert_eq!(map.value_hash(&seq_key(1_000)).unwrap(), None);
+        assert!(matches!(map.get(&seq_key(10)), Err(Error::MissingData)));
+
+        // value hashes can also be read from proofs which include values
+        let map = crate::verify(&full, merk.root_hash()).unwrap();
+        assert_eq!(
+            map.value_hash(&seq_key(10)).unwrap(),
+            Some(HashAlgorithm::Blake3.value_hash(&[10; 4_096]))
+        );
+
+        let compact = merk
+            .prove_with_encoding(query(true), ProofEncoding::Compact { compress: false })
+            .unwrap();
+        let map = crate::verify(&compact, merk.root_hash()).unwrap();
+        assert!(map.value_hash(&seq_key(10)).unwrap().is_some());
+
+        // the legacy algorithm hashes values inline, so it can not prove digests
+        let mut legacy = Merk::open_backend(MemoryBackend::new(), 100).unwrap();
+        legacy.apply(&batch, &[]).unwrap();
+        assert!(matches!(
+            legacy.prove(query(true)),
+            Err(Error::HashAlgorithm(_))
+        ));
+        let proof = legacy.prove(query(false)).unwrap();
+        let map = crate::verify(&proof, legacy.root_hash()).unwrap();
+        assert_eq!(map.get(&seq_key(10)).unwrap(), Some(&[10; 4_096][..]));
+    }
+
+    #[test]
+    fn aggregate_counts() {
+        use std::collections::BTreeSet;
+        use std::ops::Bound;
+
+        let mut merk = Merk::open_backend(MemoryBackend::new(), 1).unwrap();
+        let mut plain = Merk::open_backend(MemoryBackend::new(), 1).unwrap();
+        merk.enable_aggregate_counts().unwrap();
+        assert!(merk.get_aggregate_counts());
+        assert_eq!(merk.count_range(..).unwrap(), 0);
+
+        let mut keys = BTreeSet::new();
+        for (batch, deleted) in [
+            (make_batch_seq(0..1_000), false),
+            (make_del_batch_seq(200..300), true),
+            (make_batch_seq(950..1_200), false),
+        ] {
+            merk.apply(&batch, &[]).unwrap();
+            plain.apply(&batch, &[]).unwrap();
+            for (key, _) in batch {
+                if deleted {
+                    keys.remove(&key);
+                } else {
+                    keys.insert(key);
+                }
+            }
+        }
+
+        let ranges = [
+            (Bound::Unbounded, Bound::Unbounded),
+            (Bound::Included(seq_key(150)), Bound::Excluded(seq_key(350))),
+            (Bound::Excluded(seq_key(0)), Bound::Included(seq_key(1_100))),
+            (Bound::Unbounded, Bound::Excluded(seq_key(500))),
+            (Bound::Included(seq_key(1_199)), Bound::Unbounded),
+            (Bound::Included(seq_key(250)), Bound::Included(seq_key(250))),
+        ];
+        for range in ranges {
+            let expected = keys.range(range.clone()).count() as u64;
+            assert_eq!(merk.count_range(range.clone()).unwrap(), expected);
+            let proof = merk.prove_count(range.clone()).unwrap();
+            assert_eq!(
+                crate::verify_count(&proof, range, merk.root_hash()).unwrap(),
+                expected
+            );
+        }
+
+        // queries are still proven, with the counts of abridged subtrees
+        let mut query = Query::new();
+        query.insert_key(seq_key(10));
+        let map = crate::verify(&merk.prove(query).unwrap(), merk.root_hash()).unwrap();
+        assert_eq!(map.get(&seq_key(10)).unwrap(), Some(&[123; 60][..]));
+
+        // counts are committed to by the root hash, and can not be enabled
+        // for a store which already has keys
+        assert_ne!(merk.root_hash(), plain.root_hash());
+        assert!(plain.enable_aggregate_counts().is_err());
+        assert!(plain.count_range(..).is_err());
+        assert!(plain.prove_count(..).is_err());
+    }
+
+    #[test]
+    fn hash_algorithm() {
+        use crate::tree::HashAlgorithm;
+
+        let dir = TempDir::new("merk_hash_algorithm").unwrap();
+        let path = dir.path().join("blake3");
+        let batch_1 = make_batch_seq(0..1_000);
+        let batch_2 = make_batch_seq(500..1_500);
+
+        let mut expected =
+            Merk::open_opt(dir.path().join("expected"), Merk::default_db_opts(), 100).unwrap();
+        expected.apply_versioned(1, &batch_1, &[]).unwrap();
+        let root_1 = expected.root_hash();
+        expected.apply_versioned(2, &batch_2, &[]).unwrap();
+        assert_eq!(expected.get_hash_algorithm(), HashAlgorithm::Sha512_256);
+
+        {
+            let mut merk = Merk::open_with_hash_algorithm(&path, HashAlgorithm::Blake3).unwrap();
+            merk.apply_versioned(1, &batch_1, &[]).unwrap();
+            assert_ne!(merk.root_hash(), root_1);
+        }
+
+        // the algorithm is recorded, and used for nodes fetched from disk
+        let mut merk = Merk::open_opt(&path, Merk::default_db_opts(), 1).unwrap();
+        assert_eq!(merk.get_hash_algorithm(), HashAlgorithm::Blake3);
+        let blake3_root_1 = merk.root_hash();
+        merk.apply_versioned(2, &batch_2, &[]).unwrap();
+
+        let mut in_memory = Merk::open_backend_with_hash_algorithm(
+            MemoryBackend::new(),
+            100,
+            HashAlgorithm::Blake3,
+        )
+        .unwrap();
+        in_memory.apply(&batch_1, &[]).unwrap();
+        in_memory.apply(&batch_2, &[]).unwrap();
+        assert_eq!(merk.root_hash(), in_memory.root_hash());
+        assert_eq!(merk.version(1).unwrap().root_hash(), blake3_root_1);
+        assert_eq!(merk.snapshot().unwrap().root_hash(), merk.root_hash());
+
+        // proofs name the algorithm, so the same verifier checks both stores
+        let query = || {
+            let mut query = Query::new();
+            query.insert_range(seq_key(10)..seq_key(20));
+            query
+        };
+        let proof = merk.prove(query()).unwrap();
+        assert_eq!(&proof[..2], &[0x20, HashAlgorithm::Blake3.tag()]);
+        let map = crate::verify(&proof, merk.root_hash()).unwrap();
+        assert_eq!(map.get(&seq_key(15)).unwrap(), Some(&put_entry_value()[..]));
+        assert!(crate::verify(&proof[2..], merk.root_hash()).is_err());
+
+        let proof = expected.prove(query()).unwrap();
+        assert_ne!(proof[0], 0x20);
+        crate::verify(&proof, expected.root_hash()).unwrap();
+
+        let version = merk.version(1).unwrap();
+        let proof = version.prove(query()).unwrap();
+        crate::verify(&proof, blake3_root_1).unwrap();
+        drop(version);
+        drop(merk);
+
+        // a store can not be reopened with another algorithm, and one which
+        // was created without a recorded algorithm uses the default
+        assert!(matches!(
+            Merk::open_with_hash_algorithm(&path, HashAlgorithm::Sha3_256),
+            Err(Error::HashAlgorithm(_))
+        ));
+        drop(expected);
+        let expected_path = dir.path().join("expected");
+        assert!(Merk::open_with_hash_algorithm(&expected_path, HashAlgorithm::Sha3_256).is_err());
+        Merk::open_with_hash_algorithm(&expected_path, HashAlgorithm::Sha512_256).unwrap();
+    }
+
     #[test]
     fn checkpoint() {
         let path = thread::current().name().unwrap().to_owned();
@@ -882,6 +2113,154 @@ mod test {
 
         std::fs::remove_dir_all(&path).unwrap();
     }
+    #[test]
+    fn apply_with_extra() {
+        let mut merk = Merk::open_backend(MemoryBackend::new(), 1).unwrap();
+        let bills = [(vec![1], Op::Put(vec![10])), (vec![2], Op::Put(vec![20]))];
+        let events = [(vec![3], Op::Put(vec![30]))];
+        merk.apply_with_extra(
+            &make_batch_seq(0..10),
+            &[(vec![4], Op::Put(vec![40]))],
+            &[("bills", &bills), ("events", &events)],
+        )
+        .unwrap();
+        assert_eq!(merk.get(&seq_key(5)).unwrap(), Some(put_entry_value()));
+        assert_eq!(merk.get_aux(&[4]).unwrap(), Some(vec![40]));
+        assert_eq!(merk.get_extra("bills", &[2]).unwrap(), Some(vec![20]));
+        assert_eq!(merk.get_extra("events", &[3]).unwrap(), Some(vec![30]));
+        assert_eq!(merk.get_extra("events", &[1]).unwrap(), None);
+
+        // the store's own column families can not be written to directly, and
+        // nothing is applied when they are given
+        let root_hash = merk.root_hash();
+        for column_family in ["default", "aux", "internal", "history"] {
+            assert!(matches!(
+                merk.apply_with_extra(&make_del_batch_seq(0..5), &[], &[(column_family, &bills)]),
+                Err(Error::ColumnFamily(_))
+            ));
+            assert!(merk.get_extra(column_family, &[1]).is_err());
+        }
+        assert_eq!(merk.root_hash(), root_hash);
+
+        let mut tx = merk.begin();
+        tx.delete(seq_key(0));
+        tx.commit_with_extra(&[("bills", &[(vec![1], Op::Delete)])])
+            .unwrap();
+        assert_eq!(merk.get(&seq_key(0)).unwrap(), None);
+        assert_eq!(merk.get_extra("bills", &[1]).unwrap(), None);
+        assert_eq!(merk.get_extra("bills", &[2]).unwrap(), Some(vec![20]));
+    }
+
+    #[test]
+    fn open_with_column_families() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = Merk::open_with_column_families(&path, &["bills"]).unwrap();
+        assert!(Merk::open_with_column_families(&path, &["aux"]).is_err());
+
+        merk.apply_with_extra(
+            &make_batch_seq(0..100),
+            &[],
+            &[("bills", &[(vec![1], Op::Put(vec![10]))])],
+        )
+        .unwrap();
+        let root_hash = merk.root_hash();
+
+        // column families the store was not opened with are not written to,
+        // and neither is the tree
+        assert!(matches!(
+            merk.apply_with_extra(
+                &make_batch_seq(100..200),
+                &[],
+                &[("events", &[(vec![1], Op::Put(vec![10]))])],
+            ),
+            Err(Error::ColumnFamily(_))
+        ));
+        assert!(matches!(
+            merk.get_extra("events", &[1]),
+            Err(Error::ColumnFamily(_))
+        ));
+        assert_eq!(merk.root_hash(), root_hash);
+        assert_eq!(merk.get(&seq_key(150)).unwrap(), None);
+        drop(merk);
+
+        // column families created before are opened without naming them
+        let merk = Merk::open(&path).unwrap();
+        assert_eq!(merk.get_extra("bills", &[1]).unwrap(), Some(vec![10]));
+
+        let merk = merk.repair().unwrap();
+        assert_eq!(merk.root_hash(), root_hash);
+        assert_eq!(merk.get_extra("bills", &[1]).unwrap(), Some(vec![10]));
+
+        merk.destroy().unwrap();
+    }
+
+    #[test]
+    fn open_read_only() {
+        let path = thread::current().name().unwrap().to_owned();
+        assert!(Merk::open_read_only(&path).is_err());
+
+        let mut merk = Merk::open(&path).unwrap();
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        let root_hash = merk.root_hash();
+
+        let mut reader = Merk::open_read_only(&path).unwrap();
+        assert_eq!(reader.root_hash(), root_hash);
+        assert!(reader.get(&seq_key(5)).unwrap().is_some());
+        let mut query = Query::new();
+        query.insert_key(seq_key(5));
+        reader.prove(query).unwrap();
+
+        // later writes are not seen
+        merk.apply(&make_batch_seq(100..200), &[]).unwrap();
+        assert!(reader.get(&seq_key(150)).unwrap().is_none());
+        assert!(matches!(reader.try_catch_up(), Err(Error::NotSecondary)));
+
+        assert!(matches!(
+            reader.apply(&make_batch_seq(0..1), &[]),
+            Err(Error::ReadOnly)
+        ));
+        assert!(matches!(reader.begin().commit(), Err(Error::ReadOnly)));
+        assert!(matches!(
+            reader.enable_aggregate_counts(),
+            Err(Error::ReadOnly)
+        ));
+        assert!(matches!(reader.prune_versions(), Err(Error::ReadOnly)));
+        assert_eq!(reader.root_hash(), root_hash);
+        assert!(matches!(reader.destroy(), Err(Error::ReadOnly)));
+
+        merk.destroy().unwrap();
+    }
+
+    #[test]
+    fn open_as_secondary() {
+        let path = thread::current().name().unwrap().to_owned();
+        let secondary_path = format!("{}-secondary", path);
+        let mut merk = Merk::open(&path).unwrap();
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+
+        let mut secondary = Merk::open_as_secondary(&path, Path::new(&secondary_path)).unwrap();
+        assert_eq!(secondary.root_hash(), merk.root_hash());
+
+        merk.apply(&make_batch_seq(100..200), &[]).unwrap();
+        assert!(secondary.get(&seq_key(150)).unwrap().is_none());
+        secondary.try_catch_up().unwrap();
+        assert_eq!(secondary.root_hash(), merk.root_hash());
+        assert!(secondary.get(&seq_key(150)).unwrap().is_some());
+
+        assert!(matches!(
+            secondary.apply(&make_batch_seq(0..1), &[]),
+            Err(Error::ReadOnly)
+        ));
+        assert!(matches!(
+            secondary.commit(Default::default(), &[]),
+            Err(Error::ReadOnly)
+        ));
+
+        drop(secondary);
+        merk.destroy().unwrap();
+        std::fs::remove_dir_all(&secondary_path).ok();
+    }
+
     #[test]
     fn iter_opt_range() {
         let path = thread::current().name().unwrap().to_owned();
@@ -925,6 +2304,19 @@ mod test {
             .collect::<Vec<_>>();
         assert_eq!(expected, actual);
 
+        // the typed iterator decodes the nodes
+        let actual = merk
+            .range(b"k20".to_vec()..b"k50".to_vec())
+            .collect::<crate::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(expected, actual);
+        let actual = merk
+            .range(b"k20".to_vec()..b"k50".to_vec())
+            .rev()
+            .collect::<crate::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(expected.into_iter().rev().collect::<Vec<_>>(), actual);
+
         // iterate aux on range ["k21", "k51")
         let mut readopts_aux = rocksdb::ReadOptions::default();
         readopts_aux.set_iterate_lower_bound(b"k21".to_vec());
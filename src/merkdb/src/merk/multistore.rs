@@ -0,0 +1,415 @@
+//! Many Merk trees sharing one backend, committed under a single root.
+
+use std::collections::BTreeMap;
+#[cfg(feature = "full")]
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+#[cfg(feature = "full")]
+use rocksdb::DB;
+
+use super::backend::{Backend, DefaultBackend, PrefixedBackend, PrefixedBatch, RawIterator};
+use super::Merk;
+use crate::error::{Error, Result};
+use crate::proofs::Query;
+use crate::tree::{Batch, Hash, Op, NULL_HASH};
+
+/// A subtree of a `MultiStore`.
+pub type Subtree<B = DefaultBackend> = Merk<PrefixedBackend<B>>;
+
+/// A set of named Merk trees (subtrees) stored in one backend, each under
+/// its own key prefix, plus a root tree which maps each subtree's name to its
+/// root hash. The root tree's hash commits to the contents of every subtree,
+/// so a proof can chain from it through a subtree's root hash down to the
+/// entries of that subtree.
+///
+/// Subtrees are written through the store so the root tree is kept up to
+/// date.
+pub struct MultiStore<B: Backend + Send = DefaultBackend> {
+    db: Arc<B>,
+    root: Subtree<B>,
+    subtrees: BTreeMap<Vec<u8>, Subtree<B>>,
+    levels: u8,
+}
+
+#[cfg(feature = "full")]
+impl MultiStore<DB> {
+    /// Opens a multi-store with the specified file path. If no store exists at
+    /// that path, one will be created.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let db_opts = Merk::default_db_opts();
+        let db = super::open_db(path, &db_opts, &[])?;
+        MultiStore::open_backend(db, 100)
+    }
+}
+
+impl<B: Backend + Send> MultiStore<B> {
+    /// Opens a multi-store on top of the given backend, loading the root tree
+    /// and every subtree registered in it. Up to `levels` levels of each tree
+    /// are kept in memory between commits.
+    pub fn open_backend(db: B, levels: u8) -> Result<Self> {
+        MultiStore::open_shared(Arc::new(db), levels)
+    }
+
+    /// Like `open_backend`, but for a backend which is also shared with
+    /// other users.
+    pub fn open_shared(db: Arc<B>, levels: u8) -> Result<Self> {
+        let root = Merk::open_backend(PrefixedBackend::new(db.clone(), prefix(&[])), levels)?;
+
+        let mut names = vec![];
+        let mut iter = root.raw_iter();
+        iter.seek_to_first();
+        while let Some(name) = iter.key() {
+            names.push(name.to_vec());
+            iter.next();
+        }
+        drop(iter);
+
+        let mut store = MultiStore {
+            db,
+            root,
+            subtrees: BTreeMap::new(),
+            levels,
+        };
+        for name in names {
+            let subtree = store.open_subtree(&name)?;
+            store.subtrees.insert(name, subtree);
+        }
+
+        Ok(store)
+    }
+
+    /// Returns the root hash of the root tree, which commits to the root hash
+    /// of every subtree.
+    pub fn root_hash(&self) -> Hash {
+        self.root.root_hash()
+    }
+
+    /// Returns the root tree, which maps subtree names to their root hashes.
+    pub fn root(&self) -> &Subtree<B> {
+        &self.root
+    }
+
+    /// Returns the subtree with the given name, or `None` if it has not been
+    /// created.
+    pub fn subtree(&self, name: &[u8]) -> Option<&Subtree<B>> {
+        self.subtrees.get(name)
+    }
+
+    /// Returns an iterator over the names of all subtrees, in order.
+    pub fn subtree_names(&self) -> impl Iterator<Item = &[u8]> {
+        self.subtrees.keys().map(Vec::as_slice)
+    }
+
+    /// Creates an empty subtree and registers it in the root tree.
+    pub fn create_subtree(&mut self, name: &[u8]) -> Result<&Subtree<B>> {
+        if name.is_empty() {
+            return Err(Error::Path("Subtree name must not be empty".into()));
+        }
+        if self.subtrees.contains_key(name) {
+            return Err(Error::Path(format!("Subtree {name:?} already exists")));
+        }
+
+        let mut subtree = self.open_subtree(name)?;
+        commit_subtree(&self.db, &mut self.root, name, &mut subtree, &[], &[])?;
+
+        Ok(self.subtrees.entry(name.to_vec()).or_insert(subtree))
+    }
+
+    /// Applies a batch of operations to the named subtree and records its new
+    /// root hash in the root tree, in one atomic write. See `Merk::apply`.
+    pub fn apply(&mut self, name: &[u8], batch: &Batch, aux: &Batch) -> Result<()> {
+        let subtree = self
+            .subtrees
+            .get_mut(name)
+            .ok_or_else(|| Error::Path(format!("Subtree {name:?} does not exist")))?;
+        commit_subtree(&self.db, &mut self.root, name, subtree, batch, aux)
+    }
+
+    /// Creates a proof for `query` against the named subtree. Returns a proof
+    /// of the subtree's root hash in the root tree, and a proof of the query
+    /// in the subtree (empty if the subtree is empty). Both can be checked
+    /// together with `merk::verify_subtree`.
+    pub fn prove(&self, name: &[u8], query: Query) -> Result<(Vec<u8>, Vec<u8>)> {
+        let subtree = self
+            .subtree(name)
+            .ok_or_else(|| Error::Path(format!("Subtree {name:?} does not exist")))?;
+
+        let mut root_query = Query::new();
+        root_query.insert_key(name.to_vec());
+        let root_proof = self.root.prove(root_query)?;
+
+        let subtree_proof = if subtree.root_hash() == NULL_HASH {
+            vec![]
+        } else {
+            subtree.prove(query)?
+        };
+
+        Ok((root_proof, subtree_proof))
+    }
+
+    /// Flushes the shared backend.
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()
+    }
+
+    fn open_subtree(&self, name: &[u8]) -> Result<Subtree<B>> {
+        Merk::open_backend(
+            PrefixedBackend::new(self.db.clone(), prefix(name)),
+            self.levels,
+        )
+    }
+}
+
+/// Applies `batch` and `aux` to `subtree`, and writes its nodes along with its
+/// new root hash in the root tree as a single batch of the shared backend, so
+/// the root tree never records a root hash other than the subtree's.
+///
+/// If this fails, both trees are reloaded from the backend, so neither is left
+/// ahead of what was written.
+fn commit_subtree<B: Backend + Send>(
+    db: &B,
+    root: &mut Subtree<B>,
+    name: &[u8],
+    subtree: &mut Subtree<B>,
+    batch: &Batch,
+    aux: &Batch,
+) -> Result<()> {
+    let start = Instant::now();
+
+    let (subtree_nodes, root_nodes) = match write_subtree(db, root, name, subtree, batch, aux) {
+        Ok(nodes) => nodes,
+        Err(err) => {
+            subtree.load_root()?;
+            root.load_root()?;
+            return Err(err);
+        }
+    };
+    subtree
+        .counters
+        .count_commit(subtree_nodes, start.elapsed());
+    root.counters.count_commit(root_nodes, start.elapsed());
+    Ok(())
+}
+
+/// The writes of `commit_subtree`, which change both trees in memory before
+/// writing them. Returns the number of nodes written for the subtree and for
+/// the root tree.
+fn write_subtree<B: Backend + Send>(
+    db: &B,
+    root: &mut Subtree<B>,
+    name: &[u8],
+    subtree: &mut Subtree<B>,
+    batch: &Batch,
+    aux: &Batch,
+) -> Result<(usize, usize)> {
+    let mut subtree_batch = PrefixedBatch::new(db.batch(), prefix(name));
+    let subtree_nodes = subtree.apply_staged(batch, aux, &mut subtree_batch)?;
+
+    let entry = [(name.to_vec(), Op::Put(subtree.root_hash().to_vec()))];
+    let mut root_batch = PrefixedBatch::new(subtree_batch.into_inner(), prefix(&[]));
+    let root_nodes = root.apply_staged(&entry, &[], &mut root_batch)?;
+
+    root.write(root_batch)?;
+    Ok((subtree_nodes, root_nodes))
+}
+
+/// Returns the key prefix a tree's data is stored under: the length of its
+/// name followed by the name, so that no prefix is a prefix of another. The
+/// root tree has the empty name.
+fn prefix(name: &[u8]) -> Vec<u8> {
+    let mut prefix = (name.len() as u32).to_be_bytes().to_vec();
+    prefix.extend_from_slice(name);
+    prefix
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::merk::backend::{MemoryBackend, MemoryBatch, MemoryRawIterator, MemorySnapshot};
+    use crate::proofs::query::verify_subtree;
+    use crate::test_utils::*;
+
+    fn store() -> MultiStore<MemoryBackend> {
+        MultiStore::open_backend(MemoryBackend::new(), 1).unwrap()
+    }
+
+    /// A `MemoryBackend` which counts the batches written to it, and fails
+    /// to write them while `fail_writes` is set.
+    #[derive(Default)]
+    struct CountingBackend {
+        inner: MemoryBackend,
+        writes: AtomicUsize,
+        fail_writes: AtomicBool,
+    }
+
+    impl Backend for CountingBackend {
+        type RawIter<'a> = MemoryRawIterator;
+        type Snapshot<'a> = MemorySnapshot;
+        type Batch<'a> = MemoryBatch;
+
+        fn get_cf(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+            self.inner.get_cf(cf, key)
+        }
+
+        fn batch(&self) -> MemoryBatch {
+            self.inner.batch()
+        }
+
+        fn write(&self, batch: MemoryBatch) -> Result<()> {
+            if self.fail_writes.load(Ordering::SeqCst) {
+                return Err(Error::Unknown);
+            }
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.inner.write(batch)
+        }
+
+        fn raw_iter_cf(&self, cf: &str) -> MemoryRawIterator {
+            self.inner.raw_iter_cf(cf)
+        }
+
+        fn snapshot(&self) -> MemorySnapshot {
+            self.inner.snapshot()
+        }
+
+        fn flush(&self) -> Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    #[test]
+    fn subtrees_are_separate() {
+        let mut store = store();
+        store.create_subtree(b"a").unwrap();
+        store.create_subtree(b"b").unwrap();
+        assert!(store.create_subtree(b"a").is_err());
+        assert!(store.create_subtree(b"").is_err());
+
+        store.apply(b"a", &make_batch_seq(0..10), &[]).unwrap();
+        store.apply(b"b", &make_batch_seq(5..20), &[]).unwrap();
+        assert!(store.apply(b"c", &make_batch_seq(0..1), &[]).is_err());
+
+        let a = store.subtree(b"a").unwrap();
+        let b = store.subtree(b"b").unwrap();
+        assert_eq!(a.get(&seq_key(2)).unwrap(), Some(put_entry_value()));
+        assert_eq!(b.get(&seq_key(2)).unwrap(), None);
+        assert_ne!(a.root_hash(), b.root_hash());
+
+        assert_eq!(
+            store.root().get(b"a").unwrap(),
+            Some(a.root_hash().to_vec())
+        );
+        assert_eq!(
+            store.subtree_names().collect::<Vec<_>>(),
+            vec![&b"a"[..], &b"b"[..]]
+        );
+    }
+
+    #[test]
+    fn root_hash_commits_to_subtrees() {
+        let mut store = store();
+        store.create_subtree(b"a").unwrap();
+        let empty = store.root_hash();
+        store.apply(b"a", &make_batch_seq(0..10), &[]).unwrap();
+        let filled = store.root_hash();
+        assert_ne!(empty, filled);
+
+        store.apply(b"a", &make_del_batch_seq(0..10), &[]).unwrap();
+        assert_eq!(store.root_hash(), empty);
+    }
+
+    #[test]
+    fn reopen_loads_subtrees() {
+        let db = Arc::new(MemoryBackend::new());
+        let mut store = MultiStore::open_shared(db.clone(), 1).unwrap();
+        store.create_subtree(b"a").unwrap();
+        store.apply(b"a", &make_batch_seq(0..10), &[]).unwrap();
+        let root_hash = store.root_hash();
+        drop(store);
+
+        let store = MultiStore::open_shared(db, 1).unwrap();
+        assert_eq!(store.root_hash(), root_hash);
+        assert_eq!(
+            store.subtree(b"a").unwrap().get(&seq_key(3)).unwrap(),
+            Some(put_entry_value())
+        );
+    }
+
+    #[test]
+    fn prove_through_root() {
+        let mut store = store();
+        store.create_subtree(b"a").unwrap();
+        store.create_subtree(b"empty").unwrap();
+        store.apply(b"a", &make_batch_seq(0..10), &[]).unwrap();
+
+        let mut query = Query::new();
+        query.insert_key(seq_key(3));
+        query.insert_key(seq_key(30));
+        let (root_proof, subtree_proof) = store.prove(b"a", query).unwrap();
+        let map = verify_subtree(&root_proof, b"a", &subtree_proof, store.root_hash()).unwrap();
+        assert_eq!(map.get(&seq_key(3)).unwrap(), Some(&put_entry_value()[..]));
+        assert_eq!(map.get(&seq_key(30)).unwrap(), None);
+
+        // the subtree proof does not verify under another subtree's name
+        assert!(verify_subtree(&root_proof, b"b", &subtree_proof, store.root_hash()).is_err());
+
+        let mut query = Query::new();
+        query.insert_key(seq_key(3));
+        let (root_proof, subtree_proof) = store.prove(b"empty", query).unwrap();
+        let map = verify_subtree(&root_proof, b"empty", &subtree_proof, store.root_hash()).unwrap();
+        assert_eq!(map.get(&seq_key(3)).unwrap(), None);
+    }
+
+    #[test]
+    fn subtree_and_root_written_together() {
+        let db = Arc::new(CountingBackend::default());
+        let mut store = MultiStore::open_shared(db.clone(), 1).unwrap();
+
+        store.create_subtree(b"a").unwrap();
+        assert_eq!(db.writes.load(Ordering::SeqCst), 1);
+        store.apply(b"a", &make_batch_seq(0..10), &[]).unwrap();
+        assert_eq!(db.writes.load(Ordering::SeqCst), 2);
+
+        let root_hash = store.root_hash();
+        drop(store);
+        let store = MultiStore::open_shared(db, 1).unwrap();
+        assert_eq!(store.root_hash(), root_hash);
+        assert_eq!(
+            store.root().get(b"a").unwrap(),
+            Some(store.subtree(b"a").unwrap().root_hash().to_vec())
+        );
+    }
+
+    #[test]
+    fn failed_write_leaves_trees_unchanged() {
+        let db = Arc::new(CountingBackend::default());
+        let mut store = MultiStore::open_shared(db.clone(), 1).unwrap();
+        store.create_subtree(b"a").unwrap();
+        store.apply(b"a", &make_batch_seq(0..10), &[]).unwrap();
+        let root_hash = store.root_hash();
+        let subtree_hash = store.subtree(b"a").unwrap().root_hash();
+
+        db.fail_writes.store(true, Ordering::SeqCst);
+        assert!(store.apply(b"a", &make_batch_seq(10..20), &[]).is_err());
+        assert!(store.create_subtree(b"b").is_err());
+        assert_eq!(store.root_hash(), root_hash);
+        assert_eq!(store.subtree(b"a").unwrap().root_hash(), subtree_hash);
+        assert!(store.subtree(b"b").is_none());
+
+        db.fail_writes.store(false, Ordering::SeqCst);
+        store.apply(b"a", &make_batch_seq(10..20), &[]).unwrap();
+        assert_eq!(
+            store.subtree(b"a").unwrap().get(&seq_key(15)).unwrap(),
+            Some(put_entry_value())
+        );
+        drop(store);
+        let store = MultiStore::open_shared(db, 1).unwrap();
+        assert_eq!(
+            store.root().get(b"a").unwrap(),
+            Some(store.subtree(b"a").unwrap().root_hash().to_vec())
+        );
+    }
+}
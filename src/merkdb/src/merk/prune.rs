@@ -0,0 +1,213 @@
+//! Reclaims history nodes which are no longer reachable from any retained
+//! version.
+
+use std::collections::HashSet;
+use std::convert::TryFrom;
+
+use super::backend::{Backend, RawIterator, WriteBatch};
+use super::{
+    version::VersionSource, version_key, Merk, DEFAULT_MAX_VERSIONS, HISTORY_CF_NAME,
+    INTERNAL_CF_NAME,
+};
+use crate::tree::{Hash, Link};
+use crate::Result;
+
+/// The maximum number of deletions written in a single batch by
+/// `Merk::prune_versions`.
+const PRUNE_BATCH_SIZE: usize = 10_000;
+
+/// Determines which versions a `Merk` retains.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetentionPolicy {
+    /// Keeps the given number of most recent versions.
+    KeepLast(usize),
+    /// Keeps every version greater than the given height.
+    KeepNewerThan(u64),
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy::KeepLast(DEFAULT_MAX_VERSIONS)
+    }
+}
+
+impl RetentionPolicy {
+    /// Returns how many of the given versions (sorted in ascending order) have
+    /// expired under this policy, counting from the oldest.
+    pub(crate) fn expired(&self, versions: &[u64]) -> usize {
+        match *self {
+            RetentionPolicy::KeepLast(count) => versions.len().saturating_sub(count),
+            RetentionPolicy::KeepNewerThan(height) => {
+                versions.iter().take_while(|v| **v <= height).count()
+            }
+        }
+    }
+}
+
+impl<B: Backend> Merk<B> {
+    /// Forgets any versions the retention policy no longer covers, then deletes
+    /// every history node which no retained version can reach. Returns the
+    /// number of nodes deleted.
+    ///
+    /// Deletions are written in bounded batches, so this can be run on large
+    /// stores. Nodes of the current tree are never touched.
+    pub fn prune_versions(&mut self) -> Result<usize> {
+        self.check_writable()?;
+        let mut versions = self.versions()?;
+        let expired = self.retention.expired(&versions);
+        if expired > 0 {
+            let mut batch = self.db.batch();
+            for version in versions.drain(..expired) {
+                batch.delete_cf(INTERNAL_CF_NAME, &version_key(version));
+            }
+            self.write(batch)?;
+            self.versions.clone_from(&versions);
+        }
+
+        let reachable = self.reachable_history(&versions)?;
+
+        let mut deleted = 0;
+        let mut batch = self.db.batch();
+        let mut iter = self.db.raw_iter_cf(HISTORY_CF_NAME);
+        iter.seek_to_first();
+        while let Some(hash) = iter.key() {
+            let retained = Hash::try_from(hash).is_ok_and(|hash| reachable.contains(&hash));
+            if !retained {
+                batch.delete_cf(HISTORY_CF_NAME, hash);
+                deleted += 1;
+            }
+            if batch.len() >= PRUNE_BATCH_SIZE {
+                self.write(std::mem::replace(&mut batch, self.db.batch()))?;
+            }
+            iter.next();
+        }
+        self.write(batch)?;
+
+        Ok(deleted)
+    }
+
+    /// Collects the hashes of all history nodes reachable from the given
+    /// versions.
+    fn reachable_history(&self, versions: &[u64]) -> Result<HashSet<Hash>> {
+        let source = VersionSource::new(&self.db, self.hash_algorithm);
+        let mut reachable = HashSet::new();
+        let mut stack: Vec<Link> = vec![];
+
+        for version in versions {
+            stack.extend(self.version_root(*version)?);
+
+            while let Some(link) = stack.pop() {
+                if reachable.contains(link.hash()) {
+                    continue;
+                }
+
+                let (mut tree, historical) = source.locate(&link)?;
+                // nodes of the current tree only link to other current nodes,
+                // so there is nothing below them to retain
+                if !historical {
+                    continue;
+                }
+
+                reachable.insert(*link.hash());
+                stack.extend(tree.slot_mut(true).take());
+                stack.extend(tree.slot_mut(false).take());
+            }
+        }
+
+        Ok(reachable)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use crate::Op;
+
+    fn history_len(merk: &Merk) -> usize {
+        let history_cf = merk.db.cf_handle(HISTORY_CF_NAME).unwrap();
+        merk.db
+            .iterator_cf(history_cf, rocksdb::IteratorMode::Start)
+            .count()
+    }
+
+    fn collect_version(merk: &Merk, version: u64) -> Vec<(Vec<u8>, Vec<u8>)> {
+        merk.version(version)
+            .unwrap()
+            .iter()
+            .collect::<Result<_>>()
+            .unwrap()
+    }
+
+    fn apply_versions(merk: &mut Merk, count: u64) {
+        merk.apply_versioned(0, &make_batch_seq(0..100), &[])
+            .unwrap();
+        for version in 1..count {
+            let batch: Vec<_> = (version * 7..version * 7 + 20)
+                .map(|n| (seq_key(n), Op::Put(version.to_be_bytes().to_vec())))
+                .collect();
+            merk.apply_versioned(version, &batch, &[]).unwrap();
+        }
+    }
+
+    #[test]
+    fn expired_versions() {
+        let versions = [3, 5, 8, 13];
+        assert_eq!(RetentionPolicy::KeepLast(2).expired(&versions), 2);
+        assert_eq!(RetentionPolicy::KeepLast(10).expired(&versions), 0);
+        assert_eq!(RetentionPolicy::KeepNewerThan(5).expired(&versions), 2);
+        assert_eq!(RetentionPolicy::KeepNewerThan(2).expired(&versions), 0);
+        assert_eq!(RetentionPolicy::KeepNewerThan(20).expired(&versions), 4);
+    }
+
+    #[test]
+    fn prune_keep_last() {
+        let mut merk = TempMerk::new().unwrap();
+        apply_versions(&mut merk, 10);
+
+        let expected: Vec<_> = (7..10).map(|v| collect_version(&merk, v)).collect();
+        let root_hash = merk.root_hash();
+        let history_before = history_len(&merk);
+
+        merk.set_retention_policy(RetentionPolicy::KeepLast(3));
+        let deleted = merk.prune_versions().unwrap();
+
+        assert!(deleted > 0);
+        assert_eq!(history_len(&merk), history_before - deleted);
+        assert_eq!(merk.versions().unwrap(), vec![7, 8, 9]);
+        assert!(merk.version(6).is_err());
+        for (version, entries) in (7..10).zip(expected) {
+            assert_eq!(collect_version(&merk, version), entries);
+        }
+        assert_eq!(merk.root_hash(), root_hash);
+        assert_eq!(merk.prune_versions().unwrap(), 0);
+    }
+
+    #[test]
+    fn prune_keep_newer_than() {
+        let mut merk = TempMerk::new().unwrap();
+        apply_versions(&mut merk, 8);
+
+        let expected = collect_version(&merk, 6);
+
+        merk.set_retention_policy(RetentionPolicy::KeepNewerThan(5));
+        merk.prune_versions().unwrap();
+
+        assert_eq!(merk.versions().unwrap(), vec![6, 7]);
+        assert_eq!(collect_version(&merk, 6), expected);
+    }
+
+    #[test]
+    fn prune_everything() {
+        let mut merk = TempMerk::new().unwrap();
+        apply_versions(&mut merk, 5);
+        assert!(history_len(&merk) > 0);
+
+        merk.set_retention_policy(RetentionPolicy::KeepLast(0));
+        merk.prune_versions().unwrap();
+
+        assert!(merk.versions().unwrap().is_empty());
+        assert_eq!(history_len(&merk), 0);
+        assert_eq!(merk.get(&seq_key(0)).unwrap(), Some(put_entry_value()));
+    }
+}
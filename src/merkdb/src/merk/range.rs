@@ -0,0 +1,378 @@
+//! Typed iteration over the entries of a store, in forward or reverse key
+//! order.
+
+use std::ops::RangeToInclusive;
+use std::ops::{Bound, Range, RangeBounds, RangeFrom, RangeFull, RangeInclusive, RangeTo};
+
+use ed::Decode;
+
+use super::backend::RawIterator;
+use crate::tree::Tree;
+use crate::Result;
+
+/// A range of keys, given by its bounds or by a prefix which all of its keys
+/// start with.
+///
+/// Any of the standard range types over byte keys (e.g. `Vec<u8>`, `&[u8]`, or
+/// an ID type which implements `AsRef<[u8]>`) can be converted into a
+/// `KeyRange`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyRange {
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+}
+
+impl KeyRange {
+    /// Creates a range with the given bounds.
+    pub fn new<K: AsRef<[u8]>>(start: Bound<K>, end: Bound<K>) -> Self {
+        KeyRange {
+            start: to_owned_bound(start.as_ref()),
+            end: to_owned_bound(end.as_ref()),
+        }
+    }
+
+    /// Creates a range of all keys.
+    pub fn all() -> Self {
+        KeyRange {
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+        }
+    }
+
+    /// Creates a range of the keys which start with `prefix`.
+    pub fn prefix<K: AsRef<[u8]>>(prefix: K) -> Self {
+        let prefix = prefix.as_ref();
+        let end = match prefix_successor(prefix) {
+            Some(successor) => Bound::Excluded(successor),
+            None => Bound::Unbounded,
+        };
+
+        KeyRange {
+            start: Bound::Included(prefix.to_vec()),
+            end,
+        }
+    }
+
+    /// Returns `true` if `key` is in the range.
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        let after_start = match &self.start {
+            Bound::Included(start) => key >= start.as_slice(),
+            Bound::Excluded(start) => key > start.as_slice(),
+            Bound::Unbounded => true,
+        };
+        let before_end = match &self.end {
+            Bound::Included(end) => key <= end.as_slice(),
+            Bound::Excluded(end) => key < end.as_slice(),
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+}
+
+impl RangeBounds<Vec<u8>> for KeyRange {
+    fn start_bound(&self) -> Bound<&Vec<u8>> {
+        self.start.as_ref()
+    }
+
+    fn end_bound(&self) -> Bound<&Vec<u8>> {
+        self.end.as_ref()
+    }
+}
+
+impl From<RangeFull> for KeyRange {
+    fn from(_: RangeFull) -> Self {
+        KeyRange::all()
+    }
+}
+
+impl<K: AsRef<[u8]>> From<(Bound<K>, Bound<K>)> for KeyRange {
+    fn from((start, end): (Bound<K>, Bound<K>)) -> Self {
+        KeyRange::new(start, end)
+    }
+}
+
+macro_rules! impl_from_range {
+    ($($range:ident),*) => {
+        $(
+            impl<K: AsRef<[u8]>> From<$range<K>> for KeyRange {
+                fn from(range: $range<K>) -> Self {
+                    KeyRange {
+                        start: to_owned_bound(range.start_bound()),
+                        end: to_owned_bound(range.end_bound()),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_from_range!(Range, RangeInclusive, RangeFrom, RangeTo, RangeToInclusive);
+
+fn to_owned_bound<K: AsRef<[u8]>>(bound: Bound<&K>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.as_ref().to_vec()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_ref().to_vec()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// Returns the smallest key which is greater than every key starting with
+/// `prefix`, or `None` if there is no such key (the prefix is empty or only
+/// contains `0xff` bytes).
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut successor = prefix.to_vec();
+    while let Some(last) = successor.pop() {
+        if last < u8::MAX {
+            successor.push(last + 1);
+            return Some(successor);
+        }
+    }
+    None
+}
+
+/// An iterator over the keys and values of a store which are in a `KeyRange`.
+///
+/// Entries are yielded in ascending key order, or in descending order when
+/// the iterator is reversed. Both ends can be consumed from, and each entry is
+/// only yielded once.
+pub struct RangeIter<I> {
+    /// The keys which have not been yielded yet from either end.
+    range: KeyRange,
+    front: I,
+    back: I,
+    front_started: bool,
+    back_started: bool,
+    finished: bool,
+}
+
+impl<I: RawIterator> RangeIter<I> {
+    /// Creates an iterator over the entries in `range`, given two raw
+    /// iterators over the same view of the nodes of a store.
+    pub(crate) fn new(range: KeyRange, front: I, back: I) -> Self {
+        RangeIter {
+            range,
+            front,
+            back,
+            front_started: false,
+            back_started: false,
+            finished: false,
+        }
+    }
+
+    /// Decodes the entry the raw iterator is positioned at, if it is in the
+    /// remaining range.
+    fn entry(raw: &I, range: &KeyRange) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        let key = raw.key()?;
+        if !range.contains_key(key) {
+            return None;
+        }
+
+        let tree = match <Tree as Decode>::decode(raw.value()?) {
+            Ok(tree) => tree,
+            Err(err) => return Some(Err(err.into())),
+        };
+        Some(Ok((key.to_vec(), tree.value().to_vec())))
+    }
+
+    fn finish(
+        &mut self,
+        entry: Option<Result<(Vec<u8>, Vec<u8>)>>,
+    ) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        match &entry {
+            None | Some(Err(_)) => self.finished = true,
+            Some(Ok(_)) => {}
+        }
+        entry
+    }
+}
+
+impl<I: RawIterator> Iterator for RangeIter<I> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        if self.front_started {
+            self.front.next();
+        } else {
+            self.front_started = true;
+            match &self.range.start {
+                Bound::Included(start) => self.front.seek(start),
+                Bound::Excluded(start) => {
+                    self.front.seek(start);
+                    if self.front.key() == Some(start.as_slice()) {
+                        self.front.next();
+                    }
+                }
+                Bound::Unbounded => self.front.seek_to_first(),
+            }
+        }
+
+        let entry = Self::entry(&self.front, &self.range);
+        if let Some(Ok((key, _))) = &entry {
+            self.range.start = Bound::Excluded(key.clone());
+        }
+        self.finish(entry)
+    }
+}
+
+impl<I: RawIterator> DoubleEndedIterator for RangeIter<I> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        if self.back_started {
+            self.back.prev();
+        } else {
+            self.back_started = true;
+            match &self.range.end {
+                Bound::Included(end) => self.back.seek_for_prev(end),
+                Bound::Excluded(end) => {
+                    self.back.seek_for_prev(end);
+                    if self.back.key() == Some(end.as_slice()) {
+                        self.back.prev();
+                    }
+                }
+                Bound::Unbounded => self.back.seek_to_last(),
+            }
+        }
+
+        let entry = Self::entry(&self.back, &self.range);
+        if let Some(Ok((key, _))) = &entry {
+            self.range.end = Bound::Excluded(key.clone());
+        }
+        self.finish(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merk::backend::MemoryBackend;
+    use crate::test_utils::{make_batch_seq, make_del_batch_seq, seq_key};
+    use crate::{Merk, Op};
+
+    fn collect<I: Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>>(iter: I) -> Vec<Vec<u8>> {
+        iter.map(|entry| entry.unwrap().0).collect()
+    }
+
+    #[test]
+    fn prefix_bounds() {
+        assert_eq!(
+            KeyRange::prefix([1, 2]),
+            KeyRange::new(Bound::Included(vec![1, 2]), Bound::Excluded(vec![1, 3]))
+        );
+        assert_eq!(
+            KeyRange::prefix([1, 255, 255]),
+            KeyRange::new(Bound::Included(vec![1, 255, 255]), Bound::Excluded(vec![2]))
+        );
+        assert_eq!(
+            KeyRange::prefix([255]),
+            KeyRange::new(Bound::Included(vec![255]), Bound::Unbounded)
+        );
+        assert_eq!(KeyRange::prefix([]), KeyRange::from(vec![]..));
+
+        let range = KeyRange::prefix([1, 2]);
+        assert!(range.contains_key(&[1, 2]));
+        assert!(range.contains_key(&[1, 2, 255, 255]));
+        assert!(!range.contains_key(&[1, 3]));
+        assert!(!range.contains_key(&[1]));
+    }
+
+    #[test]
+    fn range_iter() {
+        let mut merk = Merk::open_backend(MemoryBackend::new(), 1).unwrap();
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        merk.apply(&make_del_batch_seq(40..50), &[]).unwrap();
+
+        let all = (0..40).chain(50..100).map(seq_key).collect::<Vec<_>>();
+        assert_eq!(collect(merk.range(..)), all);
+        assert_eq!(
+            collect(merk.range(..).rev()),
+            all.iter().rev().cloned().collect::<Vec<_>>()
+        );
+
+        let (_, value) = merk.range(seq_key(10)..).next().unwrap().unwrap();
+        assert_eq!(value, vec![123; 60]);
+
+        assert_eq!(
+            collect(merk.range(seq_key(35)..seq_key(52))),
+            [35, 36, 37, 38, 39, 50, 51].map(seq_key)
+        );
+        assert_eq!(
+            collect(merk.range(seq_key(35)..=seq_key(52)).rev()),
+            [52, 51, 50, 39, 38, 37, 36, 35].map(seq_key)
+        );
+        assert_eq!(
+            collect(merk.range((Bound::Excluded(seq_key(97)), Bound::Excluded(seq_key(99))))),
+            [seq_key(98)]
+        );
+        assert_eq!(
+            collect(merk.range(..=seq_key(1)).rev()),
+            [1, 0].map(seq_key)
+        );
+        assert_eq!(
+            collect(merk.range(seq_key(42)..seq_key(48))),
+            Vec::<Vec<u8>>::new()
+        );
+
+        // entries are yielded once when iterating from both ends
+        let mut iter = merk.range(seq_key(0)..seq_key(4));
+        assert_eq!(iter.next().unwrap().unwrap().0, seq_key(0));
+        assert_eq!(iter.next_back().unwrap().unwrap().0, seq_key(3));
+        assert_eq!(iter.next_back().unwrap().unwrap().0, seq_key(2));
+        assert_eq!(iter.next().unwrap().unwrap().0, seq_key(1));
+        assert!(iter.next().is_none());
+        assert!(iter.next_back().is_none());
+    }
+
+    #[test]
+    fn range_iter_prefix() {
+        // keys laid out like the ID key spaces, which start with a type byte
+        // followed by a collection ID
+        let key = |space: u8, collection: u8, n: u8| vec![space, 0, collection, n];
+        let mut batch = vec![];
+        for space in 1..=2 {
+            for collection in [0, 1, 255] {
+                for n in 0..3 {
+                    batch.push((key(space, collection, n), Op::Put(vec![n])));
+                }
+            }
+        }
+        batch.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut merk = Merk::open_backend(MemoryBackend::new(), 1).unwrap();
+        merk.apply(&batch, &[]).unwrap();
+
+        assert_eq!(merk.range(KeyRange::prefix([1])).count(), 9);
+        assert_eq!(
+            collect(merk.range(KeyRange::prefix([2, 0, 1]))),
+            (0..3).map(|n| key(2, 1, n)).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            collect(merk.range(KeyRange::prefix([1, 0, 255])).rev()),
+            (0..3).rev().map(|n| key(1, 255, n)).collect::<Vec<_>>()
+        );
+        assert_eq!(merk.range(KeyRange::prefix([3])).count(), 0);
+    }
+
+    #[test]
+    fn snapshot_range_iter() {
+        let mut merk = Merk::open_backend(MemoryBackend::new(), 1).unwrap();
+        merk.apply(&make_batch_seq(0..10), &[]).unwrap();
+        merk.apply(&make_del_batch_seq(0..5), &[]).unwrap();
+
+        let snapshot = merk.snapshot().unwrap();
+        assert_eq!(
+            collect(snapshot.range(seq_key(3)..)),
+            (5..10).map(seq_key).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            collect(snapshot.range(..).rev()),
+            collect(merk.range(..).rev())
+        );
+    }
+}
@@ -0,0 +1,412 @@
+//! Rebuilds the tree of a store from its stored keys and values.
+
+use std::path::{Path, PathBuf};
+
+use rocksdb::{Direction, IteratorMode, DB};
+
+use super::backend::{Backend, WriteBatch};
+use super::{
+    DbOptions, Merk, RetentionPolicy, WalSync, AUX_CF_NAME, HISTORY_CF_NAME, INTERNAL_CF_NAME,
+    RESERVED_CF_NAMES, VERSION_KEY_PREFIX,
+};
+use crate::error::{Error, Result};
+use crate::tree::{Hash, Op, Tree};
+
+/// The maximum number of entries written in a single batch while rebuilding a
+/// store, which bounds the memory a repair uses.
+const REPAIR_BATCH_SIZE: usize = 10_000;
+
+/// The number of levels of the rebuilt tree kept in memory between batches.
+/// The entries are applied in key order, so only the nodes along the right
+/// edge of the tree are needed by the next batch.
+const REPAIR_LEVELS_IN_MEMORY: u8 = 1;
+
+/// The suffix of the path `repair` builds the repaired store at.
+const REPAIRED_SUFFIX: &str = "repair1";
+
+/// The suffix of the path `repair` moves the store being replaced to.
+const REPLACED_SUFFIX: &str = "repair2";
+
+/// The progress of a repair, passed to the callback of
+/// `Merk::repair_with_progress` after each step.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RepairProgress<'a> {
+    /// The given number of entries of the tree have been copied into the
+    /// rebuilt store.
+    Tree { entries: u64 },
+    /// The given number of entries of the aux or history column family, or of
+    /// a column family of the application, have been copied into the rebuilt
+    /// store.
+    ColumnFamily { name: &'a str, entries: u64 },
+    /// The rebuilt store has been checked and is about to replace the store.
+    Verified,
+}
+
+fn repair_path(path: &Path, suffix: &str) -> PathBuf {
+    let file_name = format!("{}-{}", path.file_name().unwrap().to_str().unwrap(), suffix);
+    path.with_file_name(file_name)
+}
+
+/// Finishes a `repair` of the store at `path` which stopped while swapping the
+/// repaired store into place, so the store is opened with either its old tree
+/// or the repaired one.
+///
+/// The repaired store is complete and checked before the swap starts, so if
+/// the store has already been moved aside, the repaired store is moved into
+/// its place.
+pub(super) fn recover_repair(path: &Path) -> Result<()> {
+    if path.file_name().is_none() {
+        return Ok(());
+    }
+
+    let replaced_path = repair_path(path, REPLACED_SUFFIX);
+    if !replaced_path.exists() {
+        return Ok(());
+    }
+
+    if !path.exists() {
+        let repaired_path = repair_path(path, REPAIRED_SUFFIX);
+        if repaired_path.exists() {
+            std::fs::rename(&repaired_path, path)?;
+        } else {
+            std::fs::rename(&replaced_path, path)?;
+            return Ok(());
+        }
+    }
+    std::fs::remove_dir_all(&replaced_path)?;
+    Ok(())
+}
+
+impl Merk<DB> {
+    /// Completely rebuilds the tree, keeping all the same stored keys and
+    /// values.
+    ///
+    /// If the process stops during a repair, the store is opened with either
+    /// its old tree or the repaired one.
+    pub fn repair(self) -> Result<Self> {
+        self.repair_with_progress(|_| {})
+    }
+
+    /// Rebuilds the tree like `repair`, calling `progress` after each batch of
+    /// entries is copied.
+    ///
+    /// The entries are copied into a new store next to this one in batches of
+    /// bounded size, so stores larger than memory can be repaired. Before the
+    /// new store replaces this one, it is reopened and checked with
+    /// `verify_integrity`, and an error is returned (leaving this store in
+    /// place) if its root hash or any of its nodes is inconsistent.
+    pub fn repair_with_progress<F>(self, mut progress: F) -> Result<Self>
+    where
+        F: FnMut(RepairProgress),
+    {
+        self.check_writable()?;
+        let path = self.db.path().to_path_buf();
+        let hash_algorithm = self.hash_algorithm;
+        let aggregate_counts = self.aggregate_counts;
+        let settings = OpenSettings::of(&self);
+        let has_versions = !self.versions()?.is_empty();
+
+        let tmp_path = repair_path(&path, REPAIRED_SUFFIX);
+        let tmp = Merk::open(&tmp_path)?;
+        tmp.destroy()?;
+
+        let extra_names: Vec<String> = DB::list_cf(&Merk::default_db_opts(), &path)?
+            .into_iter()
+            .filter(|name| !RESERVED_CF_NAMES.contains(&name.as_str()))
+            .collect();
+        let extra_names: Vec<&str> = extra_names.iter().map(String::as_str).collect();
+        let db = settings.db_options.open_db(&tmp_path, &extra_names)?;
+        let mut tmp =
+            Merk::open_backend_with_hash_algorithm(db, REPAIR_LEVELS_IN_MEMORY, hash_algorithm)?;
+        if aggregate_counts {
+            tmp.enable_aggregate_counts()?;
+        }
+
+        let mut entries = 0;
+        let mut node = Tree::new(vec![], vec![])?;
+        let mut batch = Vec::with_capacity(REPAIR_BATCH_SIZE);
+        let mut iter = self.db.iterator(IteratorMode::Start).peekable();
+        while let Some((key, node_bytes)) = iter.next() {
+            node.decode_into(vec![], &node_bytes);
+            batch.push((key.to_vec(), Op::Put(node.value().to_vec())));
+
+            if batch.len() == REPAIR_BATCH_SIZE || iter.peek().is_none() {
+                tmp.apply(&batch, &[])?;
+                entries += batch.len() as u64;
+                batch.clear();
+                progress(RepairProgress::Tree { entries });
+            }
+        }
+
+        for name in std::iter::once(AUX_CF_NAME).chain(extra_names.iter().copied()) {
+            let cf = self.db.cf_handle(name).unwrap();
+            let iter = self
+                .db
+                .iterator_cf(cf, IteratorMode::Start)
+                .map(|(key, value)| (key.to_vec(), value));
+            copy_column_family(&tmp, name, iter, &mut progress)?;
+        }
+
+        if has_versions {
+            // the nodes of the rebuilt tree can differ from the old ones, so
+            // the retained versions read all the old nodes from the history
+            // column family by their hashes
+            let history_cf = self.db.cf_handle(HISTORY_CF_NAME).unwrap();
+            let history = self
+                .db
+                .iterator_cf(history_cf, IteratorMode::Start)
+                .map(|(hash, bytes)| (hash.to_vec(), bytes));
+            let old_nodes = self.db.iterator(IteratorMode::Start).map(|(key, bytes)| {
+                let node = Tree::decode_with_algorithm(key.to_vec(), &bytes, hash_algorithm);
+                (node.hash().to_vec(), bytes)
+            });
+            copy_column_family(
+                &tmp,
+                HISTORY_CF_NAME,
+                history.chain(old_nodes),
+                &mut progress,
+            )?;
+
+            let internal_cf = self.db.cf_handle(INTERNAL_CF_NAME).unwrap();
+            let mode = IteratorMode::From(VERSION_KEY_PREFIX, Direction::Forward);
+            let mut batch = tmp.db.batch();
+            for (key, root) in self.db.iterator_cf(internal_cf, mode) {
+                if !key.starts_with(VERSION_KEY_PREFIX) {
+                    break;
+                }
+                batch.put_cf(INTERNAL_CF_NAME, &key, &root);
+            }
+            tmp.write(batch)?;
+        }
+
+        drop(self);
+
+        let root_hash = tmp.root_hash();
+        drop(tmp);
+        let tmp = settings.open(&tmp_path, &extra_names)?;
+        check_repaired(&tmp, root_hash, entries)?;
+        drop(tmp);
+        progress(RepairProgress::Verified);
+
+        #[cfg(test)]
+        crate::test_utils::crash_point(crate::test_utils::CrashPoint::RepairBeforeRename);
+
+        // the directories can not be swapped atomically, so `recover_repair`
+        // finishes the swap if the process stops partway through it
+        let tmp_path2 = repair_path(&path, REPLACED_SUFFIX);
+        std::fs::rename(&path, &tmp_path2)?;
+
+        #[cfg(test)]
+        crate::test_utils::crash_point(crate::test_utils::CrashPoint::RepairMidRename);
+
+        std::fs::rename(&tmp_path, &path)?;
+
+        #[cfg(test)]
+        crate::test_utils::crash_point(crate::test_utils::CrashPoint::RepairBeforeCleanup);
+
+        std::fs::remove_dir_all(&tmp_path2)?;
+
+        settings.open(&path, &extra_names)
+    }
+}
+
+/// The options and settings of a store which are not stored with it, so the
+/// repaired store is opened the same way as the store it replaces.
+struct OpenSettings {
+    db_options: DbOptions,
+    max_levels_in_memory: u8,
+    retention: RetentionPolicy,
+    parallel_apply: bool,
+    node_cache_budget: usize,
+    memory_budget: Option<usize>,
+    wal_sync: WalSync,
+}
+
+impl OpenSettings {
+    fn of(merk: &Merk) -> Self {
+        OpenSettings {
+            db_options: merk
+                .db_options
+                .clone()
+                .unwrap_or_else(|| DbOptions::Uniform(Merk::default_db_opts())),
+            max_levels_in_memory: merk.max_levels_in_memory,
+            retention: merk.retention,
+            parallel_apply: merk.parallel_apply,
+            node_cache_budget: merk.node_cache.budget(),
+            memory_budget: merk.memory_budget,
+            wal_sync: merk.wal_sync,
+        }
+    }
+
+    fn open(&self, path: &Path, extra_column_families: &[&str]) -> Result<Merk> {
+        let db = self.db_options.open_db(path, extra_column_families)?;
+        let mut merk = Merk::open_backend(db, self.max_levels_in_memory)?;
+        merk.retention = self.retention;
+        merk.parallel_apply = self.parallel_apply;
+        merk.set_node_cache_budget(self.node_cache_budget);
+        merk.memory_budget = self.memory_budget;
+        merk.wal_sync = self.wal_sync;
+        merk.db_options = Some(self.db_options.clone());
+        Ok(merk)
+    }
+}
+
+/// Copies `entries` into the column family `name` of the rebuilt store in
+/// batches, calling `progress` after each batch.
+fn copy_column_family<I, F>(tmp: &Merk, name: &str, entries: I, progress: &mut F) -> Result<()>
+where
+    I: Iterator<Item = (Vec<u8>, Box<[u8]>)>,
+    F: FnMut(RepairProgress),
+{
+    let mut count = 0;
+    let mut batch = tmp.db.batch();
+    let mut entries = entries.peekable();
+    while let Some((key, value)) = entries.next() {
+        batch.put_cf(name, &key, &value);
+
+        if batch.len() == REPAIR_BATCH_SIZE || entries.peek().is_none() {
+            count += batch.len() as u64;
+            tmp.write(std::mem::replace(&mut batch, tmp.db.batch()))?;
+            progress(RepairProgress::ColumnFamily {
+                name,
+                entries: count,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the reopened rebuilt store has the root hash it was built with
+/// and the given number of entries, and that it is consistent.
+fn check_repaired(merk: &Merk, root_hash: Hash, entries: u64) -> Result<()> {
+    if merk.root_hash() != root_hash {
+        return Err(Error::HashMismatch(root_hash, merk.root_hash()));
+    }
+
+    let report = merk.verify_integrity()?;
+    if let Some(inconsistency) = report.inconsistencies.first() {
+        return Err(Error::Tree(format!(
+            "Repaired store is inconsistent: {inconsistency}"
+        )));
+    }
+    if report.nodes_checked != entries {
+        return Err(Error::Tree(format!(
+            "Repaired store has {} entries, expected {}",
+            report.nodes_checked, entries
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{make_batch_seq, make_del_batch_seq, seq_key};
+    use crate::{Merk, MerkConfig, Op, RetentionPolicy, WalSync};
+    use std::thread;
+
+    #[test]
+    fn repair_in_batches() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = Merk::open_with_column_families(&path, &["bills"]).unwrap();
+        merk.enable_aggregate_counts().unwrap();
+        for n in 0..5 {
+            merk.apply_with_extra(
+                &make_batch_seq(n * 5_000..(n + 1) * 5_000),
+                &[(vec![n as u8], Op::Put(vec![n as u8]))],
+                &[("bills", &[(seq_key(n), Op::Put(vec![1]))])],
+            )
+            .unwrap();
+        }
+
+        let mut reports = vec![];
+        let merk = merk
+            .repair_with_progress(|progress| reports.push(format!("{:?}", progress)))
+            .unwrap();
+        assert_eq!(
+            reports,
+            [
+                "Tree { entries: 10000 }",
+                "Tree { entries: 20000 }",
+                "Tree { entries: 25000 }",
+                "ColumnFamily { name: \"aux\", entries: 5 }",
+                "ColumnFamily { name: \"bills\", entries: 5 }",
+                "Verified",
+            ]
+        );
+
+        assert!(merk.get_aggregate_counts());
+        assert_eq!(merk.count_range(..).unwrap(), 25_000);
+        assert_eq!(
+            merk.get(&seq_key(24_999)).unwrap().map(|v| v.len()),
+            Some(60)
+        );
+        assert_eq!(merk.get_aux(&[4]).unwrap(), Some(vec![4]));
+        assert_eq!(merk.get_extra("bills", &seq_key(3)).unwrap(), Some(vec![1]));
+        let report = merk.verify_integrity().unwrap();
+        assert!(report.is_consistent());
+        assert_eq!(report.nodes_checked, 25_000);
+
+        merk.destroy().unwrap();
+    }
+
+    #[test]
+    fn repair_keeps_versions() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = Merk::open(&path).unwrap();
+        merk.apply_versioned(1, &make_batch_seq(0..1_000), &[])
+            .unwrap();
+        merk.apply_versioned(2, &make_del_batch_seq(0..500), &[])
+            .unwrap();
+        let root_hashes: Vec<_> = [1, 2]
+            .iter()
+            .map(|&version| merk.version(version).unwrap().root_hash())
+            .collect();
+
+        let merk = merk.repair().unwrap();
+        assert_eq!(merk.versions().unwrap(), vec![1, 2]);
+        for (&version, root_hash) in [1, 2].iter().zip(root_hashes) {
+            let version = merk.version(version).unwrap();
+            assert_eq!(version.root_hash(), root_hash);
+        }
+        let version = merk.version(1).unwrap();
+        assert_eq!(version.get(&seq_key(0)).unwrap().map(|v| v.len()), Some(60));
+        assert_eq!(
+            version.get(&seq_key(999)).unwrap().map(|v| v.len()),
+            Some(60)
+        );
+        assert_eq!(merk.version(2).unwrap().get(&seq_key(0)).unwrap(), None);
+
+        merk.destroy().unwrap();
+    }
+
+    #[test]
+    fn repair_keeps_options() {
+        let path = thread::current().name().unwrap().to_owned();
+        let config = MerkConfig::default()
+            .max_levels_in_memory(2)
+            .column_family("bills")
+            .wal_sync(WalSync::EveryCommit);
+        let mut merk = Merk::open_with_config(&path, &config).unwrap();
+        merk.set_retention_policy(RetentionPolicy::KeepLast(3));
+        merk.set_parallel_apply(true);
+        merk.set_node_cache_budget(1 << 20);
+        merk.set_memory_budget(Some(1 << 20));
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+
+        let merk = merk.repair().unwrap();
+        assert_eq!(merk.get_max_levels_in_memory(), 2);
+        assert_eq!(merk.get_wal_sync(), WalSync::EveryCommit);
+        assert_eq!(merk.get_retention_policy(), RetentionPolicy::KeepLast(3));
+        assert!(merk.get_parallel_apply());
+        assert_eq!(merk.get_node_cache_budget(), 1 << 20);
+        assert_eq!(merk.get_memory_budget(), Some(1 << 20));
+        assert!(merk.get_extra("bills", &[1]).unwrap().is_none());
+
+        // a store repaired again is still opened the same way
+        let merk = merk.repair().unwrap();
+        assert_eq!(merk.get_max_levels_in_memory(), 2);
+        assert_eq!(merk.get_wal_sync(), WalSync::EveryCommit);
+
+        merk.destroy().unwrap();
+    }
+}
@@ -1,7 +1,10 @@
 //! Provides `Restorer`, which can create a replica of a Merk instance by
 //! receiving chunk proofs.
 
-use super::Merk;
+use super::backend::{Backend, RocksBatch, WriteBatch};
+use super::{
+    Merk, AGGREGATE_COUNTS_KEY, AUX_CF_NAME, HASH_ALGORITHM_KEY, INTERNAL_CF_NAME, ROOT_KEY_KEY,
+};
 use crate::{
     merk::MerkSource,
     proofs::{
@@ -9,19 +12,40 @@ use crate::{
         tree::{Child, Tree as ProofTree},
         Decoder, Node,
     },
-    tree::{Link, RefWalker, Tree},
+    tree::{HashAlgorithm, Link, RefWalker, Tree},
     Error, Hash, Result,
 };
-use rocksdb::WriteBatch;
-use std::iter::Peekable;
+use rocksdb::DB;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::{path::Path, u8};
 
+/// Aux key of the restore parameters: the expected root hash and the stated
+/// number of chunks.
+const PARAMS_KEY: &[u8] = b"restore_params";
+/// Aux key of the data taken from the verified trunk: its height, the hashes
+/// of the leaf chunks and the keys of their parents.
+const TRUNK_KEY: &[u8] = b"restore_trunk";
+/// Prefix of the aux keys recording processed leaf chunks. Each is followed
+/// by the leaf's index, and maps to the key of the leaf's root node, which is
+/// linked to its parent once all leaves have been processed.
+const LEAF_KEY_PREFIX: &[u8] = b"restore_leaf";
+
 /// A `Restorer` handles decoding, verifying, and storing chunk proofs to
-/// replicate an entire Merk tree. It expects the chunks to be processed in
-/// order, retrying the last chunk if verification fails.
+/// replicate an entire Merk tree. The trunk must be processed first, after
+/// which leaf chunks can be processed in any order, retrying any chunk which
+/// fails verification. Leaf chunks can also be processed concurrently from
+/// several threads through `Restorer::leaves`.
+///
+/// Progress is saved to the aux column family of the new store along with
+/// each chunk, so a restore which is interrupted can be continued with
+/// `Restorer::resume`.
 pub struct Restorer {
-    leaf_hashes: Option<Peekable<std::vec::IntoIter<Hash>>>,
-    parent_keys: Option<Peekable<std::vec::IntoIter<Vec<u8>>>>,
+    leaf_hashes: Option<Vec<Hash>>,
+    parent_keys: Vec<Vec<u8>>,
+    leaves_done: Vec<AtomicBool>,
+    leaves_remaining: AtomicUsize,
+    next_leaf: usize,
     trunk_height: Option<usize>,
     merk: Merk,
     expected_root_hash: Hash,
@@ -48,29 +72,133 @@ impl Restorer {
             return Err(Error::Path("The given path already exists".into()));
         }
 
+        let merk = Merk::open(db_path)?;
+        let mut params = expected_root_hash.to_vec();
+        params.extend_from_slice(&(stated_length as u64).to_be_bytes());
+        let mut batch = merk.db.batch();
+        batch.put_cf(AUX_CF_NAME, PARAMS_KEY, &params);
+        merk.write(batch)?;
+
         Ok(Self {
             expected_root_hash,
             stated_length,
             trunk_height: None,
-            merk: Merk::open(db_path)?,
+            merk,
             leaf_hashes: None,
-            parent_keys: None,
+            parent_keys: vec![],
+            leaves_done: vec![],
+            leaves_remaining: AtomicUsize::new(0),
+            next_leaf: 0,
         })
     }
 
+    /// Opens a restore which was started with `Restorer::new` at the given
+    /// path but not finalized, e.g. because the process was interrupted. The
+    /// returned `Restorer` only expects the chunks which were not yet
+    /// successfully processed, see `next_chunk_index`.
+    pub fn resume<P: AsRef<Path>>(db_path: P) -> Result<Self> {
+        if !db_path.as_ref().exists() {
+            return Err(Error::Path("The given path does not exist".into()));
+        }
+
+        let merk = Merk::open(db_path)?;
+        let params = merk.get_aux(PARAMS_KEY)?.ok_or_else(|| {
+            Error::ChunkProcessing("No restore in progress at the given path".into())
+        })?;
+        let mut params = params.as_slice();
+        let expected_root_hash = read_hash(&mut params)?;
+        let stated_length = read_u64(&mut params)? as usize;
+
+        let mut restorer = Self {
+            expected_root_hash,
+            stated_length,
+            trunk_height: None,
+            merk,
+            leaf_hashes: None,
+            parent_keys: vec![],
+            leaves_done: vec![],
+            leaves_remaining: AtomicUsize::new(0),
+            next_leaf: 0,
+        };
+
+        if let Some(trunk) = restorer.merk.get_aux(TRUNK_KEY)? {
+            let mut trunk = trunk.as_slice();
+            restorer.trunk_height = Some(read_u64(&mut trunk)? as usize);
+
+            let leaf_count = read_u64(&mut trunk)? as usize;
+            let leaf_hashes = (0..leaf_count)
+                .map(|_| read_hash(&mut trunk))
+                .collect::<Result<_>>()?;
+            restorer.leaf_hashes = Some(leaf_hashes);
+
+            while !trunk.is_empty() {
+                let len = read_u64(&mut trunk)? as usize;
+                let mut key = vec![0; len];
+                trunk.read_exact(&mut key)?;
+                restorer.parent_keys.push(key);
+            }
+
+            let mut remaining = 0;
+            for index in 0..leaf_count {
+                let done = restorer.merk.get_aux(&leaf_key(index))?.is_some();
+                if !done {
+                    remaining += 1;
+                }
+                restorer.leaves_done.push(AtomicBool::new(done));
+            }
+            restorer.leaves_remaining = AtomicUsize::new(remaining);
+        }
+
+        Ok(restorer)
+    }
+
     /// Verifies a chunk and writes it to the working RocksDB instance. Expects
-    /// to be called for each chunk in order. Returns the number of remaining
+    /// to be called for each chunk in order, skipping chunks which were
+    /// already processed out of order. Returns the number of remaining
     /// chunks.
     ///
     /// Once there are no remaining chunks to be processed, `finalize` should
     /// be called.
     pub fn process_chunk(&mut self, chunk_bytes: &[u8]) -> Result<usize> {
-        let ops = Decoder::new(chunk_bytes);
+        if self.leaf_hashes.is_none() {
+            return self.process_trunk(Decoder::new(chunk_bytes));
+        }
 
-        match self.leaf_hashes {
-            None => self.process_trunk(ops),
-            Some(_) => self.process_leaf(ops),
+        while self
+            .leaves_done
+            .get(self.next_leaf)
+            .is_some_and(|done| done.load(Ordering::SeqCst))
+        {
+            self.next_leaf += 1;
+        }
+        if self.next_leaf >= self.leaves_done.len() {
+            return Err(Error::ChunkProcessing(
+                "Received more chunks than expected".into(),
+            ));
         }
+
+        self.leaves()?
+            .process_chunk(self.next_leaf + 1, chunk_bytes)
+    }
+
+    /// Returns a `LeafProcessor`, which processes leaf chunks by index, in any
+    /// order. It can be shared between threads to verify and write chunks
+    /// concurrently.
+    ///
+    /// Errors if the trunk has not been processed yet.
+    pub fn leaves(&self) -> Result<LeafProcessor> {
+        let leaf_hashes = self.leaf_hashes.as_ref().ok_or_else(|| {
+            Error::ChunkProcessing("The trunk chunk must be processed first".into())
+        })?;
+
+        Ok(LeafProcessor {
+            db: &self.merk.db,
+            hash_algorithm: self.merk.hash_algorithm,
+            aggregate_counts: self.merk.aggregate_counts,
+            leaf_hashes,
+            leaves_done: &self.leaves_done,
+            leaves_remaining: &self.leaves_remaining,
+        })
     }
 
     /// Consumes the `Restorer` and returns the newly-created, fully-populated
@@ -84,9 +212,22 @@ impl Restorer {
             ));
         }
 
+        // both of these passes can safely be repeated if the restore is
+        // interrupted before the progress is deleted
+        self.link_leaves()?;
+        self.merk.flush()?;
+        self.merk.load_root()?;
+
+        let mut batch = self.merk.db.batch();
         if self.trunk_height.unwrap() >= MIN_TRUNK_HEIGHT {
-            self.rewrite_trunk_child_heights()?;
+            self.rewrite_trunk_child_heights(&mut batch)?;
         }
+        batch.delete_cf(AUX_CF_NAME, PARAMS_KEY);
+        batch.delete_cf(AUX_CF_NAME, TRUNK_KEY);
+        for index in 0..self.leaves_done.len() {
+            batch.delete_cf(AUX_CF_NAME, &leaf_key(index));
+        }
+        self.merk.write(batch)?;
 
         self.merk.flush()?;
         self.merk.load_root()?;
@@ -98,32 +239,25 @@ impl Restorer {
     /// the first chunk is processed, this method will return `None` since we do
     /// not yet have enough information to know about the number of chunks.
     pub fn remaining_chunks(&self) -> Option<usize> {
-        self.leaf_hashes.as_ref().map(|lh| lh.len())
+        self.leaf_hashes
+            .as_ref()
+            .map(|_| self.leaves_remaining.load(Ordering::SeqCst))
     }
 
-    /// Writes the data contained in `tree` (extracted from a verified chunk
-    /// proof) to the RocksDB.
-    fn write_chunk(&mut self, tree: ProofTree) -> Result<()> {
-        let mut batch = WriteBatch::default();
-
-        tree.visit_refs(&mut |proof_node| {
-            let (key, mut node) = match &proof_node.node {
-                // TODO: encode tree node without cloning key/value
-                Node::KV(key, value) => match Tree::new(key.clone(), value.clone()) {
-                    Ok(node) => (key, node),
-                    Err(_) => return,
-                },
-                _ => return,
-            };
-
-            *node.slot_mut(true) = proof_node.left.as_ref().map(Child::as_link);
-            *node.slot_mut(false) = proof_node.right.as_ref().map(Child::as_link);
-
-            let bytes = node.encode();
-            batch.put(key, bytes);
-        });
+    /// Returns the index of the first chunk which has not been processed yet,
+    /// which is where a `ChunkProducer` should resume from (see
+    /// `ChunkProducer::seek`). Chunks after it may already have been processed
+    /// out of order.
+    pub fn next_chunk_index(&self) -> usize {
+        if self.leaf_hashes.is_none() {
+            return 0;
+        }
 
-        self.merk.write(batch)
+        let first_pending = self.leaves_done[self.next_leaf..]
+            .iter()
+            .position(|done| !done.load(Ordering::SeqCst))
+            .map_or(self.leaves_done.len(), |i| self.next_leaf + i);
+        first_pending + 1
     }
 
     /// Verifies the trunk then writes its data to the RocksDB.
@@ -131,113 +265,137 @@ impl Restorer {
     /// The trunk contains a height proof which lets us verify the total number
     /// of expected chunks is the same as `stated_length` as passed into
     /// `Restorer::new()`. We also verify the expected root hash at this step.
+    ///
+    /// The new store uses the hash algorithm named by the trunk, which the
+    /// leaf chunks must also use, and keeps aggregate counts if the trunk's
+    /// tree does.
     fn process_trunk(&mut self, ops: Decoder) -> Result<usize> {
-        let (trunk, height) = verify_trunk(ops)?;
+        let hash_algorithm = ops.hash_algorithm()?;
+        let aggregate_counts = ops.aggregate_counts();
+        let (trunk, height) = verify_trunk(ops, hash_algorithm, aggregate_counts)?;
 
         if trunk.hash()? != self.expected_root_hash {
             return Err(Error::HashMismatch(self.expected_root_hash, trunk.hash()?));
         }
+        self.merk.hash_algorithm = hash_algorithm;
+        self.merk.aggregate_counts = aggregate_counts;
 
         let root_key = trunk.key().to_vec();
 
         let trunk_height = height / 2;
-        self.trunk_height = Some(trunk_height);
-
-        let chunks_remaining = if trunk_height >= MIN_TRUNK_HEIGHT {
+        let (leaf_hashes, parent_keys) = if trunk_height >= MIN_TRUNK_HEIGHT {
             let leaf_hashes = trunk
                 .layer(trunk_height)
                 .map(|node| node.hash())
-                .collect::<Result<Vec<_>>>()?
-                .into_iter()
-                .peekable();
-            self.leaf_hashes = Some(leaf_hashes);
-
-            let parent_keys = trunk
+                .collect::<Result<Vec<_>>>()?;
+            let parent_keys: Vec<_> = trunk
                 .layer(trunk_height - 1)
                 .map(|node| node.key().to_vec())
-                .collect::<Vec<Vec<u8>>>()
-                .into_iter()
-                .peekable();
-            self.parent_keys = Some(parent_keys);
-            assert_eq!(
-                self.parent_keys.as_ref().unwrap().len(),
-                self.leaf_hashes.as_ref().unwrap().len() / 2
-            );
-
-            let chunks_remaining = (2_usize).pow(trunk_height as u32);
-            assert_eq!(self.remaining_chunks_unchecked(), chunks_remaining);
-            chunks_remaining
+                .collect();
+            assert_eq!(parent_keys.len(), leaf_hashes.len() / 2);
+            assert_eq!(leaf_hashes.len(), (2_usize).pow(trunk_height as u32));
+            (leaf_hashes, parent_keys)
         } else {
-            self.leaf_hashes = Some(vec![].into_iter().peekable());
-            self.parent_keys = Some(vec![].into_iter().peekable());
-            0
+            (vec![], vec![])
         };
 
-        // FIXME: this one shouldn't be an assert because it comes from a peer
-        assert_eq!(self.stated_length, chunks_remaining + 1);
-
-        // note that these writes don't happen atomically, which is fine here
-        // because if anything fails during the restore process we will just
-        // scrap the whole restore and start over
-        self.write_chunk(trunk)?;
-        self.merk.set_root_key(root_key)?;
+        let chunks_remaining = leaf_hashes.len();
+        if self.stated_length != chunks_remaining + 1 {
+            return Err(Error::ChunkProcessing(format!(
+                "Expected {} chunks, trunk implies {}",
+                self.stated_length,
+                chunks_remaining + 1
+            )));
+        }
+        self.trunk_height = Some(trunk_height);
+        self.leaf_hashes = Some(leaf_hashes);
+        self.parent_keys = parent_keys;
+
+        // the trunk is written along with the progress needed to resume from
+        // the first leaf chunk
+        self.leaves_done = (0..chunks_remaining)
+            .map(|_| AtomicBool::new(false))
+            .collect();
+        self.leaves_remaining = AtomicUsize::new(chunks_remaining);
+
+        let mut batch = self.merk.db.batch();
+        write_chunk(trunk, &mut batch);
+        batch.put_cf(INTERNAL_CF_NAME, ROOT_KEY_KEY, &root_key);
+        batch.put_cf(
+            INTERNAL_CF_NAME,
+            HASH_ALGORITHM_KEY,
+            &[hash_algorithm.tag()],
+        );
+        if aggregate_counts {
+            batch.put_cf(INTERNAL_CF_NAME, AGGREGATE_COUNTS_KEY, &[1]);
+        }
+        batch.put_cf(AUX_CF_NAME, TRUNK_KEY, &self.encode_trunk());
+        self.merk.write(batch)?;
 
         Ok(chunks_remaining)
     }
 
-    /// Verifies a leaf chunk then writes it to the RocksDB. This needs to be
-    /// called in order, retrying the last chunk for any failed verifications.
-    fn process_leaf(&mut self, ops: Decoder) -> Result<usize> {
-        let leaf_hashes = self.leaf_hashes.as_mut().unwrap();
-        let leaf_hash = leaf_hashes
-            .peek()
-            .expect("Received more chunks than expected");
-
-        let leaf = verify_leaf(ops, *leaf_hash)?;
-        self.rewrite_parent_link(&leaf)?;
-        self.write_chunk(leaf)?;
+    /// The parents of the root nodes of the leaves do not know the keys of
+    /// their children when they are first written. Now that all leaves have
+    /// been verified, we can write the keys into the parent nodes' entries.
+    /// Note that this does not need to recalcuate hashes since they already
+    /// had the child hashes.
+    fn link_leaves(&self) -> Result<()> {
+        let mut batch = self.merk.db.batch();
+
+        for (parent_index, parent_key) in self.parent_keys.iter().enumerate() {
+            let mut parent = self
+                .merk
+                .fetch_node(parent_key.as_slice())?
+                .ok_or_else(|| {
+                    Error::ChunkProcessing("Could not find parent of leaf chunk".into())
+                })?;
+
+            for (offset, is_left_child) in [(0, true), (1, false)] {
+                let leaf_index = parent_index * 2 + offset;
+                let leaf_key = self.merk.get_aux(&leaf_key(leaf_index))?.ok_or_else(|| {
+                    Error::ChunkProcessing("Could not find key of leaf chunk".into())
+                })?;
+
+                if let Some(Link::Reference { ref mut key, .. }) = parent.link_mut(is_left_child) {
+                    *key = leaf_key;
+                } else {
+                    return Err(Error::ChunkProcessing(
+                        "Expected parent links to be type Link::Reference".into(),
+                    ));
+                };
+            }
 
-        let leaf_hashes = self.leaf_hashes.as_mut().unwrap();
-        leaf_hashes.next();
+            let parent_bytes = parent.encode();
+            batch.put(parent_key, &parent_bytes);
+        }
 
-        Ok(self.remaining_chunks_unchecked())
+        self.merk.write(batch)
     }
 
-    /// The parent of the root node of the leaf does not know the key of its
-    /// children when it is first written. Now that we have verified this leaf,
-    /// we can write the key into the parent node's entry. Note that this does
-    /// not need to recalcuate hashes since it already had the child hash.
-    fn rewrite_parent_link(&mut self, leaf: &ProofTree) -> Result<()> {
-        let parent_keys = self.parent_keys.as_mut().unwrap();
-        let parent_key = parent_keys.peek().unwrap().clone();
-        let mut parent = self
-            .merk
-            .fetch_node(parent_key.as_slice())?
-            .expect("Could not find parent of leaf chunk");
+    /// Encodes the data taken from the trunk, to be saved so the restore can
+    /// be resumed.
+    fn encode_trunk(&self) -> Vec<u8> {
+        let leaf_hashes = self.leaf_hashes.as_ref().unwrap();
 
-        let is_left_child = self.remaining_chunks_unchecked() % 2 == 0;
-        if let Some(Link::Reference { ref mut key, .. }) = parent.link_mut(is_left_child) {
-            *key = leaf.key().to_vec();
-        } else {
-            panic!("Expected parent links to be type Link::Reference");
-        };
-
-        let parent_bytes = parent.encode();
-        self.merk.db.put(parent_key, parent_bytes)?;
-
-        if !is_left_child {
-            let parent_keys = self.parent_keys.as_mut().unwrap();
-            parent_keys.next();
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&(self.trunk_height.unwrap() as u64).to_be_bytes());
+        bytes.extend_from_slice(&(leaf_hashes.len() as u64).to_be_bytes());
+        for hash in leaf_hashes {
+            bytes.extend_from_slice(hash);
         }
-
-        Ok(())
+        for key in self.parent_keys.iter() {
+            bytes.extend_from_slice(&(key.len() as u64).to_be_bytes());
+            bytes.extend_from_slice(key);
+        }
+        bytes
     }
 
-    fn rewrite_trunk_child_heights(&mut self) -> Result<()> {
+    fn rewrite_trunk_child_heights(&self, batch: &mut RocksBatch) -> Result<()> {
         fn recurse(
             mut node: RefWalker<MerkSource>,
             remaining_depth: usize,
-            batch: &mut WriteBatch,
+            batch: &mut RocksBatch,
         ) -> Result<(u8, u8)> {
             if remaining_depth == 0 {
                 return Ok(node.tree().child_heights());
@@ -257,25 +415,18 @@ impl Restorer {
             *cloned_node.link_mut(false).unwrap().child_heights_mut() = right_child_heights;
 
             let bytes = cloned_node.encode();
-            batch.put(node.tree().key(), bytes);
+            batch.put(node.tree().key(), &bytes);
 
             Ok((left_height, right_height))
         }
 
-        self.merk.flush()?;
-        self.merk.load_root()?;
-
-        let mut batch = WriteBatch::default();
-
         let depth = self.trunk_height.unwrap();
         self.merk.use_tree_mut(|maybe_tree| {
             let tree = maybe_tree.unwrap();
             let walker = RefWalker::new(tree, self.merk.source());
-            recurse(walker, depth, &mut batch)
+            recurse(walker, depth, batch)
         })?;
 
-        self.merk.write(batch)?;
-
         Ok(())
     }
 
@@ -283,10 +434,119 @@ impl Restorer {
     /// panic if called before processing the first chunk (since that chunk
     /// gives us the information to know how many chunks to expect).
     pub fn remaining_chunks_unchecked(&self) -> usize {
-        self.leaf_hashes.as_ref().unwrap().len()
+        self.remaining_chunks().unwrap()
+    }
+}
+
+/// Verifies leaf chunks and writes them to the store of a `Restorer`, in any
+/// order. Each chunk is checked against its hash from the trunk and written
+/// independently, so a `LeafProcessor` can be shared between threads to
+/// process chunks downloaded from several peers concurrently.
+///
+/// Created with `Restorer::leaves`.
+pub struct LeafProcessor<'a> {
+    db: &'a DB,
+    hash_algorithm: HashAlgorithm,
+    aggregate_counts: bool,
+    leaf_hashes: &'a [Hash],
+    leaves_done: &'a [AtomicBool],
+    leaves_remaining: &'a AtomicUsize,
+}
+
+impl<'a> LeafProcessor<'a> {
+    /// Verifies the leaf chunk with the given index (as passed to
+    /// `ChunkProducer::chunk`, so the first leaf chunk has index 1) and writes
+    /// it to the store. Returns the number of remaining chunks.
+    ///
+    /// Processing a chunk which was already processed has no effect.
+    pub fn process_chunk(&self, index: usize, chunk_bytes: &[u8]) -> Result<usize> {
+        if index == 0 || index > self.leaf_hashes.len() {
+            return Err(Error::IndexOutOfBounds(format!(
+                "Leaf chunk index {index} out-of-bounds"
+            )));
+        }
+        let leaf_index = index - 1;
+
+        let ops = Decoder::new(chunk_bytes);
+        if ops.hash_algorithm()? != self.hash_algorithm {
+            return Err(Error::HashAlgorithm(format!(
+                "Leaf chunk {index} does not use the trunk's hash algorithm {:?}",
+                self.hash_algorithm
+            )));
+        }
+        if ops.aggregate_counts() != self.aggregate_counts {
+            return Err(Error::ChunkProcessing(format!(
+                "Leaf chunk {index} does not match whether the trunk keeps aggregate counts"
+            )));
+        }
+        let leaf = verify_leaf(
+            ops,
+            self.hash_algorithm,
+            self.aggregate_counts,
+            self.leaf_hashes[leaf_index],
+        )?;
+
+        // the chunk is written along with the key of its root, which marks it
+        // as processed, so a resumed restore never sees a partial chunk
+        let mut batch = self.db.batch();
+        batch.put_cf(AUX_CF_NAME, &leaf_key(leaf_index), leaf.key());
+        write_chunk(leaf, &mut batch);
+        Backend::write(self.db, batch)?;
+
+        if !self.leaves_done[leaf_index].swap(true, Ordering::SeqCst) {
+            self.leaves_remaining.fetch_sub(1, Ordering::SeqCst);
+        }
+
+        Ok(self.leaves_remaining.load(Ordering::SeqCst))
     }
 }
 
+/// Adds the data contained in `tree` (extracted from a verified chunk proof)
+/// to `batch`.
+fn write_chunk(tree: ProofTree, batch: &mut RocksBatch) {
+    tree.visit_refs(&mut |proof_node| {
+        let (key, mut node) = match &proof_node.node {
+            // TODO: encode tree node without cloning key/value
+            Node::KV(key, value) => match Tree::new_with_algorithm(
+                key.clone(),
+                value.clone(),
+                proof_node.hash_algorithm,
+            ) {
+                Ok(node) => (key, node),
+                Err(_) => return,
+            },
+            _ => return,
+        };
+
+        *node.slot_mut(true) = proof_node.left.as_ref().map(Child::as_link);
+        *node.slot_mut(false) = proof_node.right.as_ref().map(Child::as_link);
+        if proof_node.count.is_some() {
+            node.set_child_counts(proof_node.child_counts());
+        }
+
+        let bytes = node.encode();
+        batch.put(key, &bytes);
+    });
+}
+
+fn leaf_key(index: usize) -> Vec<u8> {
+    let mut key = LEAF_KEY_PREFIX.to_vec();
+    key.extend_from_slice(&(index as u64).to_be_bytes());
+    key
+}
+
+fn read_u64(input: &mut &[u8]) -> Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn read_hash(input: &mut &[u8]) -> Result<Hash> {
+    let mut hash = Hash::default();
+    input.read_exact(&mut hash)?;
+    Ok(hash)
+}
+
 impl Merk {
     /// Creates a new `Restorer`, which can be used to verify chunk proofs to
     /// replicate an entire Merk tree. A new Merk instance will be initialized
@@ -312,6 +572,13 @@ impl ProofTree {
             self.right.as_ref().map_or(0, |c| c.tree.height as u8),
         )
     }
+
+    fn child_counts(&self) -> (u64, u64) {
+        (
+            self.left.as_ref().and_then(|c| c.tree.count).unwrap_or(0),
+            self.right.as_ref().and_then(|c| c.tree.count).unwrap_or(0),
+        )
+    }
 }
 
 impl Child {
@@ -406,6 +673,272 @@ mod tests {
         restore_test(&[&make_batch_seq(0..1)], 1);
     }
 
+    fn resume_test(batch: &Batch, stop_after: usize) {
+        let mut original = TempMerk::new().unwrap();
+        original.apply(batch, &[]).unwrap();
+        original.flush().unwrap();
+
+        let chunks = original
+            .chunks()
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+
+        let path: PathBuf = std::thread::current().name().unwrap().into();
+        if path.exists() {
+            std::fs::remove_dir_all(&path).unwrap();
+        }
+
+        let mut restorer = Merk::restore(&path, original.root_hash(), chunks.len()).unwrap();
+        for chunk in chunks.iter().take(stop_after) {
+            restorer.process_chunk(chunk).unwrap();
+        }
+        drop(restorer);
+
+        let mut restorer = Restorer::resume(&path).unwrap();
+        assert_eq!(restorer.next_chunk_index(), stop_after);
+        if stop_after > 0 {
+            assert_eq!(restorer.remaining_chunks(), Some(chunks.len() - stop_after));
+        }
+
+        let mut producer = original.chunks().unwrap();
+        producer.seek(restorer.next_chunk_index()).unwrap();
+        for chunk in producer {
+            restorer.process_chunk(&chunk.unwrap()).unwrap();
+        }
+
+        let restored = restorer.finalize().unwrap();
+        assert_eq!(restored.root_hash(), original.root_hash());
+        assert_eq!(restored.get_aux(PARAMS_KEY).unwrap(), None);
+        assert_raw_db_entries_eq(&restored, &original, batch.len());
+        drop(restored);
+
+        assert!(Restorer::resume(&path).is_err());
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn resume_before_trunk() {
+        resume_test(&make_batch_seq(0..1000), 0);
+    }
+
+    #[test]
+    fn resume_after_trunk() {
+        resume_test(&make_batch_seq(0..1000), 1);
+    }
+
+    #[test]
+    fn resume_mid_leaves() {
+        resume_test(&make_batch_seq(0..10_000), 50);
+    }
+
+    fn test_path(name: &str) -> PathBuf {
+        let path: PathBuf = name.into();
+        if path.exists() {
+            std::fs::remove_dir_all(&path).unwrap();
+        }
+        path
+    }
+
+    fn chunks_of(merk: &Merk) -> Vec<Vec<u8>> {
+        merk.chunks()
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn restore_leaves_concurrently() {
+        let mut original = TempMerk::new().unwrap();
+        original.apply(&make_batch_seq(0..10_000), &[]).unwrap();
+        original.flush().unwrap();
+        let chunks = chunks_of(&original);
+
+        let path = test_path("restore_leaves_concurrently.db");
+        let mut restorer = Merk::restore(&path, original.root_hash(), chunks.len()).unwrap();
+        assert!(restorer.leaves().is_err());
+        restorer.process_chunk(&chunks[0]).unwrap();
+
+        let leaves = restorer.leaves().unwrap();
+        let leaves = &leaves;
+        let chunks = &chunks;
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                scope.spawn(move || {
+                    // each thread processes its chunks back to front
+                    for index in (1..chunks.len()).rev().filter(|i| i % 4 == thread) {
+                        leaves.process_chunk(index, &chunks[index]).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(restorer.remaining_chunks(), Some(0));
+
+        let restored = restorer.finalize().unwrap();
+        assert_eq!(restored.root_hash(), original.root_hash());
+        assert_raw_db_entries_eq(&restored, &original, 10_000);
+
+        drop(restored);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn restore_leaves_out_of_order() {
+        let mut original = TempMerk::new().unwrap();
+        original.apply(&make_batch_seq(0..10_000), &[]).unwrap();
+        original.flush().unwrap();
+        let chunks = chunks_of(&original);
+
+        let path = test_path("restore_leaves_out_of_order.db");
+        let mut restorer = Merk::restore(&path, original.root_hash(), chunks.len()).unwrap();
+        restorer.process_chunk(&chunks[0]).unwrap();
+
+        let leaves = restorer.leaves().unwrap();
+        assert!(leaves.process_chunk(0, &chunks[0]).is_err());
+        assert!(leaves.process_chunk(chunks.len(), &chunks[1]).is_err());
+        // a chunk does not verify against another chunk's hash
+        assert!(leaves.process_chunk(2, &chunks[1]).is_err());
+
+        leaves.process_chunk(3, &chunks[3]).unwrap();
+        leaves.process_chunk(3, &chunks[3]).unwrap();
+        let remaining = leaves.process_chunk(1, &chunks[1]).unwrap();
+        assert_eq!(remaining, chunks.len() - 3);
+        assert_eq!(restorer.next_chunk_index(), 2);
+        drop(restorer);
+
+        let mut restorer = Restorer::resume(&path).unwrap();
+        assert_eq!(restorer.remaining_chunks(), Some(chunks.len() - 3));
+        assert_eq!(restorer.next_chunk_index(), 2);
+        assert!(restorer
+            .leaves()
+            .unwrap()
+            .process_chunk(2, &chunks[3])
+            .is_err());
+
+        // in-order processing skips the chunk which is already done
+        restorer.process_chunk(&chunks[2]).unwrap();
+        for chunk in &chunks[4..] {
+            restorer.process_chunk(chunk).unwrap();
+        }
+        assert!(matches!(
+            restorer.process_chunk(&chunks[1]),
+            Err(Error::ChunkProcessing(_))
+        ));
+
+        let restored = restorer.finalize().unwrap();
+        assert_eq!(restored.root_hash(), original.root_hash());
+        assert_raw_db_entries_eq(&restored, &original, 10_000);
+
+        drop(restored);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn restore_wrong_stated_length() {
+        let mut original = TempMerk::new().unwrap();
+        original.apply(&make_batch_seq(0..10_000), &[]).unwrap();
+        original.flush().unwrap();
+        let chunks = chunks_of(&original);
+
+        let path = test_path("restore_wrong_stated_length.db");
+        let mut restorer = Merk::restore(&path, original.root_hash(), chunks.len() + 1).unwrap();
+        assert!(matches!(
+            restorer.process_chunk(&chunks[0]),
+            Err(Error::ChunkProcessing(_))
+        ));
+        assert_eq!(restorer.remaining_chunks(), None);
+
+        drop(restorer);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn resume_missing_path() {
+        assert!(Restorer::resume("resume_missing_path.db").is_err());
+    }
+
+    #[test]
+    fn restore_hash_algorithm() {
+        let original_path = test_path("restore_hash_algorithm_original.db");
+        let mut original =
+            Merk::open_with_hash_algorithm(&original_path, HashAlgorithm::Sha3_256).unwrap();
+        original.apply(&make_batch_seq(0..10_000), &[]).unwrap();
+        original.flush().unwrap();
+        let chunks = chunks_of(&original);
+
+        let path = test_path("restore_hash_algorithm.db");
+        let mut restorer = Merk::restore(&path, original.root_hash(), chunks.len()).unwrap();
+        restorer.process_chunk(&chunks[0]).unwrap();
+
+        // leaf chunks must use the trunk's algorithm
+        let mut chunk = chunks[1][2..].to_vec();
+        assert!(matches!(
+            restorer.leaves().unwrap().process_chunk(1, &chunk),
+            Err(Error::HashAlgorithm(_))
+        ));
+        chunk.splice(0..0, [0x20, HashAlgorithm::Blake3.tag()]);
+        assert!(restorer.leaves().unwrap().process_chunk(1, &chunk).is_err());
+        drop(restorer);
+
+        let mut restorer = Restorer::resume(&path).unwrap();
+        for chunk in &chunks[1..] {
+            restorer.process_chunk(chunk).unwrap();
+        }
+
+        let restored = restorer.finalize().unwrap();
+        assert_eq!(restored.get_hash_algorithm(), HashAlgorithm::Sha3_256);
+        assert_eq!(restored.root_hash(), original.root_hash());
+        assert_raw_db_entries_eq(&restored, &original, 10_000);
+
+        drop(restored);
+        drop(original);
+        std::fs::remove_dir_all(&path).unwrap();
+        std::fs::remove_dir_all(&original_path).unwrap();
+    }
+
+    #[test]
+    fn restore_aggregate_counts() {
+        let original_path = test_path("restore_aggregate_counts_original.db");
+        let mut original = Merk::open(&original_path).unwrap();
+        original.enable_aggregate_counts().unwrap();
+        original.apply(&make_batch_seq(0..10_000), &[]).unwrap();
+        original.flush().unwrap();
+        let chunks = chunks_of(&original);
+
+        let path = test_path("restore_aggregate_counts.db");
+        let mut restorer = Merk::restore(&path, original.root_hash(), chunks.len()).unwrap();
+        restorer.process_chunk(&chunks[0]).unwrap();
+
+        // leaf chunks must match the trunk
+        assert!(matches!(
+            restorer.leaves().unwrap().process_chunk(1, &chunks[1][2..]),
+            Err(Error::ChunkProcessing(_))
+        ));
+        drop(restorer);
+
+        let mut restorer = Restorer::resume(&path).unwrap();
+        for chunk in &chunks[1..] {
+            restorer.process_chunk(chunk).unwrap();
+        }
+
+        let restored = restorer.finalize().unwrap();
+        assert!(restored.get_aggregate_counts());
+        assert_eq!(restored.root_hash(), original.root_hash());
+        assert_raw_db_entries_eq(&restored, &original, 10_000);
+        assert_eq!(restored.count_range(..).unwrap(), 10_000);
+        assert_eq!(
+            restored.count_range(seq_key(100)..seq_key(9_000)).unwrap(),
+            8_900
+        );
+
+        drop(restored);
+        drop(original);
+        std::fs::remove_dir_all(&path).unwrap();
+        std::fs::remove_dir_all(&original_path).unwrap();
+    }
+
     fn assert_raw_db_entries_eq(restored: &Merk, original: &Merk, length: usize) {
         let mut original_entries = original.raw_iter();
         let mut restored_entries = restored.raw_iter();
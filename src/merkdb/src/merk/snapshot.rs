@@ -1,21 +1,30 @@
 use std::cell::Cell;
 
+use super::backend::{Backend, BackendSnapshot, DefaultBackend};
+use super::range::{KeyRange, RangeIter};
 use crate::{
-    proofs::{query::QueryItem, Query},
-    tree::{Fetch, RefWalker, Tree, NULL_HASH},
+    proofs::{query::QueryItem, ProofEncoding, Query},
+    tree::{Fetch, HashAlgorithm, RefWalker, Tree, NULL_HASH},
     Hash, Result,
 };
 
-pub struct Snapshot<'a> {
-    db: rocksdb::Snapshot<'a>,
+pub struct Snapshot<'a, B: Backend + 'a = DefaultBackend> {
+    db: B::Snapshot<'a>,
     tree: Cell<Option<Tree>>,
+    hash_algorithm: HashAlgorithm,
 }
 
-impl<'a> Snapshot<'a> {
-    pub fn new(db: rocksdb::Snapshot<'a>, tree: Option<Tree>) -> Self {
+impl<'a, B: Backend + 'a> Snapshot<'a, B> {
+    /// Creates a snapshot whose root node is `tree`. The other nodes are read
+    /// from `db`, and hashed with the same algorithm as the root.
+    pub fn new(db: B::Snapshot<'a>, tree: Option<Tree>) -> Self {
+        let hash_algorithm = tree
+            .as_ref()
+            .map_or_else(HashAlgorithm::default, Tree::hash_algorithm);
         Snapshot {
             db,
             tree: Cell::new(tree),
+            hash_algorithm,
         }
     }
 
@@ -32,7 +41,13 @@ impl<'a> Snapshot<'a> {
     }
 
     pub fn prove(&self, query: Query) -> Result<Vec<u8>> {
-        self.prove_unchecked(query)
+        self.prove_with_encoding(query, ProofEncoding::Legacy)
+    }
+
+    pub fn prove_with_encoding(&self, query: Query, encoding: ProofEncoding) -> Result<Vec<u8>> {
+        self.use_tree_mut(move |maybe_tree| {
+            super::prove(maybe_tree, self.source(), query, encoding)
+        })
     }
 
     pub fn prove_unchecked<Q, I>(&self, query: I) -> Result<Vec<u8>>
@@ -45,7 +60,10 @@ impl<'a> Snapshot<'a> {
         })
     }
 
-    pub fn walk<T>(&self, f: impl FnOnce(Option<RefWalker<SnapshotSource>>) -> T) -> T {
+    pub fn walk<T>(
+        &self,
+        f: impl FnOnce(Option<RefWalker<SnapshotSource<B::Snapshot<'a>>>>) -> T,
+    ) -> T {
         let mut tree = self.tree.take();
         let maybe_walker = tree
             .as_mut()
@@ -55,12 +73,21 @@ impl<'a> Snapshot<'a> {
         res
     }
 
-    pub fn raw_iter(&self) -> rocksdb::DBRawIterator {
-        self.db.raw_iterator()
+    pub fn raw_iter(&self) -> <B::Snapshot<'a> as BackendSnapshot>::RawIter<'_> {
+        self.db.raw_iter()
+    }
+
+    /// Iterates over the keys and values in `range` as of when the snapshot
+    /// was taken (see `Merk::range`).
+    pub fn range(
+        &self,
+        range: impl Into<KeyRange>,
+    ) -> RangeIter<<B::Snapshot<'a> as BackendSnapshot>::RawIter<'_>> {
+        RangeIter::new(range.into(), self.db.raw_iter(), self.db.raw_iter())
     }
 
-    fn source(&self) -> SnapshotSource {
-        SnapshotSource(&self.db)
+    fn source(&self) -> SnapshotSource<B::Snapshot<'a>> {
+        SnapshotSource(&self.db, self.hash_algorithm)
     }
 
     fn use_tree<T>(&self, f: impl FnOnce(Option<&Tree>) -> T) -> T {
@@ -78,14 +105,23 @@ impl<'a> Snapshot<'a> {
     }
 }
 
-#[derive(Clone)]
-pub struct SnapshotSource<'a>(&'a rocksdb::Snapshot<'a>);
+pub struct SnapshotSource<'a, S>(&'a S, HashAlgorithm);
 
-impl<'a> Fetch for SnapshotSource<'a> {
+impl<'a, S> Clone for SnapshotSource<'a, S> {
+    fn clone(&self) -> Self {
+        SnapshotSource(self.0, self.1)
+    }
+}
+
+impl<'a, S: BackendSnapshot> Fetch for SnapshotSource<'a, S> {
     fn fetch_by_key(&self, key: &[u8]) -> Result<Option<Tree>> {
         Ok(self
             .0
             .get(key)?
-            .map(|bytes| Tree::decode(key.to_vec(), &bytes)))
+            .map(|bytes| Tree::decode_with_algorithm(key.to_vec(), &bytes, self.1)))
+    }
+
+    fn hash_algorithm(&self) -> HashAlgorithm {
+        self.1
     }
 }
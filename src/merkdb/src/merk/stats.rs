@@ -0,0 +1,424 @@
+//! Statistics about a `Merk`, for monitoring.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use super::backend::{Backend, ColumnFamilySize, DEFAULT_CF_NAME};
+use super::{Merk, RESERVED_CF_NAMES};
+use crate::tree::Tree;
+use crate::Result;
+
+/// Counters updated as a `Merk` is used, which are reported by `Merk::stats`.
+///
+/// They are atomic since nodes are fetched from several threads when a batch
+/// is applied or committed in parallel.
+#[derive(Default)]
+pub(crate) struct Counters {
+    nodes_fetched: AtomicU64,
+    node_cache_hits: AtomicU64,
+    gets: AtomicU64,
+    get_hits: AtomicU64,
+    commits: AtomicU64,
+    nodes_written: AtomicU64,
+    last_commit_nanos: AtomicU64,
+    total_commit_nanos: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn count_fetch(&self) {
+        self.nodes_fetched.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_node_cache_hit(&self) {
+        self.node_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a call to `Merk::get`, which is a hit if it was answered by the
+    /// nodes kept in memory.
+    pub(crate) fn count_get(&self, hit: bool) {
+        self.gets.fetch_add(1, Ordering::Relaxed);
+        if hit {
+            self.get_hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn count_commit(&self, nodes_written: usize, latency: Duration) {
+        let nanos = latency.as_nanos() as u64;
+        self.commits.fetch_add(1, Ordering::Relaxed);
+        self.nodes_written
+            .fetch_add(nodes_written as u64, Ordering::Relaxed);
+        self.last_commit_nanos.store(nanos, Ordering::Relaxed);
+        self.total_commit_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+}
+
+/// A snapshot of the statistics of a `Merk`, returned by `Merk::stats`.
+///
+/// The counters (`nodes_fetched` to `total_commit_latency`, except
+/// `node_cache_bytes`) start at zero when the store is opened.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MerkStats {
+    /// The number of nodes in the tree. This is exact if the store keeps
+    /// aggregate counts, and otherwise estimated by the backend (`None` if it
+    /// can not tell).
+    pub node_count: Option<u64>,
+    /// The height of the tree, 0 if it is empty.
+    pub tree_height: u8,
+    /// The number of nodes of the tree currently loaded in memory.
+    pub nodes_in_memory: u64,
+    /// The size of each of the store's column families, as estimated by the
+    /// backend. Empty if the backend does not report sizes.
+    pub column_families: BTreeMap<String, ColumnFamilySize>,
+    /// The number of nodes read from the backend.
+    pub nodes_fetched: u64,
+    /// The number of nodes fetched from the node cache rather than read from
+    /// the backend (see `Merk::set_node_cache_budget`).
+    pub node_cache_hits: u64,
+    /// The estimated number of bytes of nodes in the node cache.
+    pub node_cache_bytes: u64,
+    /// The number of calls to `Merk::get`.
+    pub gets: u64,
+    /// The number of calls to `Merk::get` answered by the nodes kept in
+    /// memory, without reading from the backend.
+    pub get_hits: u64,
+    /// The number of commits.
+    pub commits: u64,
+    /// The number of nodes written or deleted by commits.
+    pub nodes_written: u64,
+    /// How long the last commit took.
+    pub last_commit_latency: Duration,
+    /// How long all commits took in total.
+    pub total_commit_latency: Duration,
+}
+
+impl MerkStats {
+    /// Returns the fraction of calls to `Merk::get` answered by the nodes kept
+    /// in memory, or `None` if there were none.
+    pub fn cache_hit_ratio(&self) -> Option<f64> {
+        if self.gets == 0 {
+            return None;
+        }
+        Some(self.get_hits as f64 / self.gets as f64)
+    }
+
+    /// Returns the average time a commit took, or `None` if there were none.
+    pub fn mean_commit_latency(&self) -> Option<Duration> {
+        if self.commits == 0 {
+            return None;
+        }
+        let nanos = self.total_commit_latency.as_nanos() / u128::from(self.commits);
+        Some(Duration::new(
+            (nanos / 1_000_000_000) as u64,
+            (nanos % 1_000_000_000) as u32,
+        ))
+    }
+}
+
+impl<B: Backend> Merk<B> {
+    /// Returns statistics about the store: the size and shape of the tree, the
+    /// size of its column families, and counters of the reads and commits
+    /// made since it was opened.
+    ///
+    /// This walks the nodes kept in memory, so it takes longer the more levels
+    /// of the tree are kept.
+    pub fn stats(&self) -> Result<MerkStats> {
+        let (node_count, tree_height, nodes_in_memory) = self.use_tree(|maybe_tree| {
+            maybe_tree.map_or((Some(0), 0, 0), |tree| {
+                (tree.count(), tree.height(), nodes_in_memory(tree))
+            })
+        });
+
+        let mut column_families = BTreeMap::new();
+        for name in RESERVED_CF_NAMES {
+            if let Some(size) = self.db.column_family_size(name)? {
+                column_families.insert(name.to_string(), size);
+            }
+        }
+        let node_count =
+            node_count.or_else(|| column_families.get(DEFAULT_CF_NAME).map(|size| size.keys));
+
+        let counters = &self.counters;
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        Ok(MerkStats {
+            node_count,
+            tree_height,
+            nodes_in_memory,
+            column_families,
+            nodes_fetched: load(&counters.nodes_fetched),
+            node_cache_hits: load(&counters.node_cache_hits),
+            node_cache_bytes: self.node_cache.bytes() as u64,
+            gets: load(&counters.gets),
+            get_hits: load(&counters.get_hits),
+            commits: load(&counters.commits),
+            nodes_written: load(&counters.nodes_written),
+            last_commit_latency: Duration::from_nanos(load(&counters.last_commit_nanos)),
+            total_commit_latency: Duration::from_nanos(load(&counters.total_commit_nanos)),
+        })
+    }
+}
+
+fn nodes_in_memory(tree: &Tree) -> u64 {
+    let child = |left| tree.child(left).map_or(0, nodes_in_memory);
+    1 + child(true) + child(false)
+}
+
+/// Exports the statistics of a `Merk` as metrics in a Prometheus registry.
+///
+/// The metrics are registered once, then `update` sets them from the result
+/// of `Merk::stats`, for example before the registry is scraped or after each
+/// commit.
+#[cfg(feature = "prometheus")]
+pub struct PrometheusExporter {
+    node_count: prometheus::IntGauge,
+    tree_height: prometheus::IntGauge,
+    nodes_in_memory: prometheus::IntGauge,
+    column_family_keys: prometheus::IntGaugeVec,
+    column_family_bytes: prometheus::IntGaugeVec,
+    nodes_fetched: prometheus::IntCounter,
+    node_cache_hits: prometheus::IntCounter,
+    node_cache_bytes: prometheus::IntGauge,
+    gets: prometheus::IntCounter,
+    get_hits: prometheus::IntCounter,
+    commits: prometheus::IntCounter,
+    nodes_written: prometheus::IntCounter,
+    last_commit_latency: prometheus::Gauge,
+    commit_latency: prometheus::Counter,
+}
+
+#[cfg(feature = "prometheus")]
+impl PrometheusExporter {
+    /// Registers the metrics in `registry`, with names prefixed by
+    /// `namespace` so the metrics of several stores can be told apart.
+    pub fn register(
+        registry: &prometheus::Registry,
+        namespace: &str,
+    ) -> prometheus::Result<PrometheusExporter> {
+        use prometheus::{Counter, Gauge, IntCounter, IntGauge, IntGaugeVec, Opts};
+
+        let opts = |name: &str, help: &str| Opts::new(name, help).namespace(namespace);
+        let exporter = PrometheusExporter {
+            node_count: IntGauge::with_opts(opts("nodes", "Number of nodes in the tree"))?,
+            tree_height: IntGauge::with_opts(opts("tree_height", "Height of the tree"))?,
+            nodes_in_memory: IntGauge::with_opts(opts(
+                "nodes_in_memory",
+                "Number of nodes of the tree loaded in memory",
+            ))?,
+            column_family_keys: IntGaugeVec::new(
+                opts(
+                    "column_family_keys",
+                    "Estimated number of keys in a column family",
+                ),
+                &["column_family"],
+            )?,
+            column_family_bytes: IntGaugeVec::new(
+                opts(
+                    "column_family_bytes",
+                    "Estimated size of a column family in bytes",
+                ),
+                &["column_family"],
+            )?,
+            nodes_fetched: IntCounter::with_opts(opts(
+                "nodes_fetched_total",
+                "Number of nodes read from the backend",
+            ))?,
+            node_cache_hits: IntCounter::with_opts(opts(
+                "node_cache_hits_total",
+                "Number of nodes fetched from the node cache",
+            ))?,
+            node_cache_bytes: IntGauge::with_opts(opts(
+                "node_cache_bytes",
+                "Estimated size of the nodes in the node cache in bytes",
+            ))?,
+            gets: IntCounter::with_opts(opts("gets_total", "Number of gets"))?,
+            get_hits: IntCounter::with_opts(opts(
+                "get_hits_total",
+                "Number of gets answered by the nodes in memory",
+            ))?,
+            commits: IntCounter::with_opts(opts("commits_total", "Number of commits"))?,
+            nodes_written: IntCounter::with_opts(opts(
+                "nodes_written_total",
+                "Number of nodes written or deleted by commits",
+            ))?,
+            last_commit_latency: Gauge::with_opts(opts(
+                "last_commit_seconds",
+                "Duration of the last commit",
+            ))?,
+            commit_latency: Counter::with_opts(opts(
+                "commit_seconds_total",
+                "Total duration of commits",
+            ))?,
+        };
+
+        registry.register(Box::new(exporter.node_count.clone()))?;
+        registry.register(Box::new(exporter.tree_height.clone()))?;
+        registry.register(Box::new(exporter.nodes_in_memory.clone()))?;
+        registry.register(Box::new(exporter.column_family_keys.clone()))?;
+        registry.register(Box::new(exporter.column_family_bytes.clone()))?;
+        registry.register(Box::new(exporter.nodes_fetched.clone()))?;
+        registry.register(Box::new(exporter.node_cache_hits.clone()))?;
+        registry.register(Box::new(exporter.node_cache_bytes.clone()))?;
+        registry.register(Box::new(exporter.gets.clone()))?;
+        registry.register(Box::new(exporter.get_hits.clone()))?;
+        registry.register(Box::new(exporter.commits.clone()))?;
+        registry.register(Box::new(exporter.nodes_written.clone()))?;
+        registry.register(Box::new(exporter.last_commit_latency.clone()))?;
+        registry.register(Box::new(exporter.commit_latency.clone()))?;
+        Ok(exporter)
+    }
+
+    /// Sets the metrics to the given statistics.
+    pub fn update(&self, stats: &MerkStats) {
+        // counters can only go up, so they are advanced to the new totals
+        let advance = |counter: &prometheus::IntCounter, total: u64| {
+            counter.inc_by(total.saturating_sub(counter.get()))
+        };
+
+        self.node_count
+            .set(stats.node_count.unwrap_or_default() as i64);
+        self.tree_height.set(stats.tree_height.into());
+        self.nodes_in_memory.set(stats.nodes_in_memory as i64);
+        for (name, size) in &stats.column_families {
+            self.column_family_keys
+                .with_label_values(&[name])
+                .set(size.keys as i64);
+            self.column_family_bytes
+                .with_label_values(&[name])
+                .set(size.bytes as i64);
+        }
+        advance(&self.nodes_fetched, stats.nodes_fetched);
+        advance(&self.node_cache_hits, stats.node_cache_hits);
+        self.node_cache_bytes.set(stats.node_cache_bytes as i64);
+        advance(&self.gets, stats.gets);
+        advance(&self.get_hits, stats.get_hits);
+        advance(&self.commits, stats.commits);
+        advance(&self.nodes_written, stats.nodes_written);
+        self.last_commit_latency
+            .set(stats.last_commit_latency.as_secs_f64());
+        let total = stats.total_commit_latency.as_secs_f64();
+        self.commit_latency
+            .inc_by((total - self.commit_latency.get()).max(0.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MerkStats;
+    use crate::merk::backend::{ColumnFamilySize, MemoryBackend};
+    use crate::test_utils::{make_batch_seq, seq_key};
+    use crate::Merk;
+    use std::time::Duration;
+
+    #[test]
+    fn stats() {
+        let mut merk = Merk::open_backend(MemoryBackend::new(), 1).unwrap();
+        let stats = merk.stats().unwrap();
+        assert_eq!(stats.node_count, Some(0));
+        assert_eq!(stats.tree_height, 0);
+        assert_eq!(stats.cache_hit_ratio(), None);
+        assert_eq!(stats.mean_commit_latency(), None);
+
+        merk.apply(
+            &make_batch_seq(0..1_000),
+            &[(vec![1], crate::Op::Put(vec![2]))],
+        )
+        .unwrap();
+        let stats = merk.stats().unwrap();
+        assert_eq!(stats.node_count, Some(1_000));
+        assert_eq!(stats.tree_height, 10);
+        // the root and its children are kept
+        assert_eq!(stats.nodes_in_memory, 3);
+        assert_eq!(stats.commits, 1);
+        assert_eq!(stats.nodes_written, 1_000);
+        assert_eq!(stats.mean_commit_latency(), Some(stats.last_commit_latency));
+        assert_eq!(
+            stats.column_families["aux"],
+            ColumnFamilySize { keys: 1, bytes: 2 }
+        );
+        assert_eq!(stats.column_families["default"].keys, 1_000);
+        assert!(!stats.column_families.contains_key("history"));
+
+        // the root is in memory, other keys are fetched
+        let root_key = merk.walk(|walker| walker.unwrap().tree().key().to_vec());
+        merk.get(&root_key).unwrap();
+        merk.get(&seq_key(999)).unwrap();
+        merk.get(&seq_key(5_000)).unwrap();
+        let stats = merk.stats().unwrap();
+        assert_eq!(stats.gets, 3);
+        assert_eq!(stats.get_hits, 1);
+        // pruned keys are fetched directly, and missing keys are not counted
+        assert_eq!(stats.nodes_fetched, 1);
+        assert_eq!(stats.node_cache_hits, 0);
+        assert_eq!(stats.cache_hit_ratio(), Some(1.0 / 3.0));
+
+        // with the node cache, only the first read is fetched
+        merk.set_node_cache_budget(1 << 20);
+        merk.get(&seq_key(999)).unwrap();
+        merk.get(&seq_key(999)).unwrap();
+        let stats = merk.stats().unwrap();
+        assert_eq!(stats.gets, 5);
+        assert_eq!(stats.nodes_fetched, 2);
+        assert_eq!(stats.node_cache_hits, 1);
+        assert_eq!(stats.commits, 1);
+    }
+
+    #[test]
+    fn mean_commit_latency() {
+        // more commits than fit in a u32
+        let stats = MerkStats {
+            commits: u64::from(u32::MAX) + 2,
+            total_commit_latency: Duration::from_secs(u64::from(u32::MAX) + 2),
+            ..Default::default()
+        };
+        assert_eq!(stats.mean_commit_latency(), Some(Duration::from_secs(1)));
+
+        let stats = MerkStats {
+            commits: 3,
+            total_commit_latency: Duration::from_nanos(3_000_000_007),
+            ..Default::default()
+        };
+        assert_eq!(
+            stats.mean_commit_latency(),
+            Some(Duration::from_nanos(1_000_000_002))
+        );
+    }
+
+    #[test]
+    fn stats_with_aggregate_counts() {
+        let mut merk = Merk::open_backend(MemoryBackend::new(), 1).unwrap();
+        merk.enable_aggregate_counts().unwrap();
+        merk.apply(&make_batch_seq(0..1_000), &[]).unwrap();
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        let stats = merk.stats().unwrap();
+        assert_eq!(stats.node_count, Some(1_000));
+        assert_eq!(stats.commits, 2);
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn prometheus_exporter() {
+        use super::PrometheusExporter;
+        use prometheus::Encoder;
+
+        let mut merk = Merk::open_backend(MemoryBackend::new(), 1).unwrap();
+        let registry = prometheus::Registry::new();
+        let exporter = PrometheusExporter::register(&registry, "merk").unwrap();
+        for n in 0..2 {
+            merk.apply(&make_batch_seq(n * 500..(n + 1) * 500), &[])
+                .unwrap();
+            exporter.update(&merk.stats().unwrap());
+        }
+
+        let mut text = vec![];
+        prometheus::TextEncoder::new()
+            .encode(&registry.gather(), &mut text)
+            .unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains("merk_nodes 1000\n"));
+        assert!(text.contains("merk_commits_total 2\n"));
+        assert!(text.contains("merk_column_family_keys{column_family=\"default\"} 1000\n"));
+
+        // the metrics of a store can only be registered once
+        assert!(PrometheusExporter::register(&registry, "merk").is_err());
+    }
+}
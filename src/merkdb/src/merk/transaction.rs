@@ -0,0 +1,220 @@
+//! Staged writes which can be read back and committed or discarded as a
+//! whole.
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+
+use super::backend::{Backend, DefaultBackend};
+use super::{load_root, Merk};
+use crate::tree::{Batch, BatchEntry, Hash, NoopCommit, Op, Walker, NULL_HASH};
+use crate::Result;
+
+/// A set of puts and deletes staged on top of a `Merk`, created with
+/// `Merk::begin`.
+///
+/// Reads see the staged writes, and the root hash the store would have after
+/// committing them can be previewed. Nothing is written to the store until
+/// `commit` is called, and dropping the transaction discards it.
+pub struct Transaction<'a, B: Backend = DefaultBackend> {
+    merk: &'a mut Merk<B>,
+    ops: BTreeMap<Vec<u8>, Op>,
+    root_hash_preview: Cell<Option<Hash>>,
+}
+
+impl<'a, B: Backend> Transaction<'a, B> {
+    pub(super) fn new(merk: &'a mut Merk<B>) -> Self {
+        Transaction {
+            merk,
+            ops: BTreeMap::new(),
+            root_hash_preview: Cell::new(None),
+        }
+    }
+
+    /// Gets the value for the given key, including the writes staged in the
+    /// transaction.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.ops.get(key) {
+            Some(Op::Put(value)) => Ok(Some(value.clone())),
+            Some(Op::Delete) => Ok(None),
+            None => self.merk.get(key),
+        }
+    }
+
+    /// Stages a put of `value` to `key`, replacing any write already staged
+    /// for the key.
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.ops.insert(key, Op::Put(value));
+        self.root_hash_preview.set(None);
+    }
+
+    /// Stages a delete of `key`, replacing any write already staged for the
+    /// key. Deleting a key which does not exist has no effect.
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.ops.insert(key, Op::Delete);
+        self.root_hash_preview.set(None);
+    }
+
+    /// Returns the number of staged writes.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns `true` if no writes are staged.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Returns the root hash the store will have once the transaction is
+    /// committed.
+    ///
+    /// The staged writes are applied to a copy of the tree loaded from the
+    /// store, which is hashed but not written. The result is cached until the
+    /// next write is staged.
+    pub fn root_hash_preview(&self) -> Result<Hash> {
+        if let Some(hash) = self.root_hash_preview.get() {
+            return Ok(hash);
+        }
+
+        let hash = if self.ops.is_empty() {
+            self.merk.root_hash()
+        } else {
+            let source = self.merk.source();
+            let maybe_walker =
+                load_root(source.clone())?.map(|tree| Walker::new(tree, source.clone()));
+            let (maybe_tree, _) = Walker::apply_to(maybe_walker, &self.batch(), source)?;
+            match maybe_tree {
+                Some(mut tree) => {
+                    tree.commit(&mut NoopCommit {})?;
+                    tree.hash()
+                }
+                None => NULL_HASH,
+            }
+        };
+
+        self.root_hash_preview.set(Some(hash));
+        Ok(hash)
+    }
+
+    /// Applies the staged writes to the store in a single batch, as
+    /// `Merk::apply` would.
+    pub fn commit(self) -> Result<()> {
+        self.commit_with_extra(&[])
+    }
+
+    /// Commits the transaction like `commit`, along with writes to column
+    /// families of the application (see `Merk::apply_with_extra`).
+    pub fn commit_with_extra(self, extra: &[(&str, &Batch)]) -> Result<()> {
+        let batch: Vec<BatchEntry> = self.ops.into_iter().collect();
+        self.merk.apply_with_extra(&batch, &[], extra)
+    }
+
+    /// Discards the staged writes, leaving the store unchanged.
+    pub fn rollback(self) {}
+
+    fn batch(&self) -> Vec<BatchEntry> {
+        self.ops
+            .iter()
+            .map(|(key, op)| {
+                let op = match op {
+                    Op::Put(value) => Op::Put(value.clone()),
+                    Op::Delete => Op::Delete,
+                };
+                (key.clone(), op)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::merk::backend::MemoryBackend;
+    use crate::test_utils::{make_batch_seq, seq_key};
+    use crate::tree::{Op, NULL_HASH};
+    use crate::Merk;
+
+    #[test]
+    fn read_own_writes() {
+        let mut merk = Merk::open_backend(MemoryBackend::new(), 1).unwrap();
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        let root_hash = merk.root_hash();
+
+        let mut tx = merk.begin();
+        assert!(tx.is_empty());
+        assert_eq!(tx.root_hash_preview().unwrap(), root_hash);
+
+        tx.put(seq_key(5), vec![1]);
+        tx.put(seq_key(200), vec![2]);
+        tx.delete(seq_key(10));
+        tx.delete(seq_key(300));
+        assert_eq!(tx.len(), 4);
+        assert_eq!(tx.get(&seq_key(5)).unwrap(), Some(vec![1]));
+        assert_eq!(tx.get(&seq_key(200)).unwrap(), Some(vec![2]));
+        assert_eq!(tx.get(&seq_key(10)).unwrap(), None);
+        assert_eq!(tx.get(&seq_key(11)).unwrap(), Some(vec![123; 60]));
+
+        // a later write to the same key replaces the staged one
+        tx.delete(seq_key(200));
+        tx.put(seq_key(10), vec![3]);
+        assert_eq!(tx.get(&seq_key(200)).unwrap(), None);
+        assert_eq!(tx.get(&seq_key(10)).unwrap(), Some(vec![3]));
+
+        let preview = tx.root_hash_preview().unwrap();
+        assert_ne!(preview, root_hash);
+        tx.rollback();
+        assert_eq!(merk.root_hash(), root_hash);
+        assert_eq!(merk.get(&seq_key(10)).unwrap(), Some(vec![123; 60]));
+
+        let mut tx = merk.begin();
+        tx.put(seq_key(5), vec![1]);
+        tx.delete(seq_key(300));
+        tx.put(seq_key(10), vec![3]);
+        assert_eq!(tx.root_hash_preview().unwrap(), preview);
+        tx.commit().unwrap();
+        assert_eq!(merk.root_hash(), preview);
+        assert_eq!(merk.get(&seq_key(10)).unwrap(), Some(vec![3]));
+    }
+
+    #[test]
+    fn root_hash_preview_matches_apply() {
+        let mut merk = Merk::open_backend(MemoryBackend::new(), 1).unwrap();
+        let mut expected = Merk::open_backend(MemoryBackend::new(), 1).unwrap();
+
+        // an empty store, which the transaction builds a tree in
+        let mut tx = merk.begin();
+        for (key, op) in make_batch_seq(0..1_000) {
+            if let Op::Put(value) = op {
+                tx.put(key, value);
+            }
+        }
+        expected.apply(&make_batch_seq(0..1_000), &[]).unwrap();
+        assert_eq!(tx.root_hash_preview().unwrap(), expected.root_hash());
+        tx.commit().unwrap();
+        assert_eq!(merk.root_hash(), expected.root_hash());
+
+        // a pruned tree, with writes on both sides of the root
+        let mut tx = merk.begin();
+        let mut batch = vec![];
+        for n in (0..1_000).step_by(7) {
+            if n % 2 == 0 {
+                tx.delete(seq_key(n));
+                batch.push((seq_key(n), Op::Delete));
+            } else {
+                tx.put(seq_key(n), vec![n as u8]);
+                batch.push((seq_key(n), Op::Put(vec![n as u8])));
+            }
+        }
+        expected.apply(&batch, &[]).unwrap();
+        assert_eq!(tx.root_hash_preview().unwrap(), expected.root_hash());
+        tx.commit().unwrap();
+        assert_eq!(merk.root_hash(), expected.root_hash());
+
+        // deleting every key empties the tree
+        let mut tx = merk.begin();
+        for n in 0..1_000 {
+            tx.delete(seq_key(n));
+        }
+        assert_eq!(tx.root_hash_preview().unwrap(), NULL_HASH);
+        tx.commit().unwrap();
+        assert_eq!(merk.root_hash(), NULL_HASH);
+    }
+}
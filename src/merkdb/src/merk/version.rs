@@ -0,0 +1,402 @@
+//! Provides `Version`, a read-only view of a `Merk` as it was at a past
+//! versioned commit.
+
+use std::cell::Cell;
+
+use super::backend::{Backend, DefaultBackend};
+use super::HISTORY_CF_NAME;
+use crate::{
+    proofs::{query::QueryItem, ProofEncoding, Query},
+    tree::{Fetch, HashAlgorithm, Link, RefWalker, Tree, NULL_HASH},
+    Error, Hash, Result,
+};
+
+/// A read-only view of the tree at a retained version, created with
+/// `Merk::version`.
+///
+/// Nodes which have not changed since the version was committed are read from
+/// the live tree, and nodes which have since been overwritten are read from the
+/// history column family by their hash.
+pub struct Version<'a, B = DefaultBackend> {
+    db: &'a B,
+    tree: Cell<Option<Tree>>,
+    hash_algorithm: HashAlgorithm,
+}
+
+impl<'a, B: Backend> Version<'a, B> {
+    pub(crate) fn new(db: &'a B, tree: Option<Tree>, hash_algorithm: HashAlgorithm) -> Self {
+        Version {
+            db,
+            tree: Cell::new(tree),
+            hash_algorithm,
+        }
+    }
+
+    /// Gets the value for the given key as of this version. If the key is not
+    /// found, `None` is returned.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.walk(|maybe_walker| match maybe_walker {
+            None => Ok(None),
+            Some(mut walker) => get(&mut walker, key),
+        })
+    }
+
+    /// Returns the root hash of the tree as of this version.
+    pub fn root_hash(&self) -> Hash {
+        self.use_tree(|tree| tree.map_or(NULL_HASH, |tree| tree.hash()))
+    }
+
+    /// Creates a Merkle proof for the given query against this version's root
+    /// hash. See `Merk::prove`.
+    pub fn prove(&self, query: Query) -> Result<Vec<u8>> {
+        self.prove_with_encoding(query, ProofEncoding::Legacy)
+    }
+
+    /// Like `prove`, but writes the proof in the given encoding. See
+    /// `Merk::prove_with_encoding`.
+    pub fn prove_with_encoding(&self, query: Query, encoding: ProofEncoding) -> Result<Vec<u8>> {
+        self.use_tree_mut(move |maybe_tree| {
+            super::prove(maybe_tree, self.source(), query, encoding)
+        })
+    }
+
+    /// Creates a Merkle proof for the given query items against this version's
+    /// root hash. See `Merk::prove_unchecked`.
+    pub fn prove_unchecked<Q, I>(&self, query: I) -> Result<Vec<u8>>
+    where
+        Q: Into<QueryItem>,
+        I: IntoIterator<Item = Q>,
+    {
+        self.use_tree_mut(move |maybe_tree| {
+            super::prove_unchecked(maybe_tree, self.source(), query)
+        })
+    }
+
+    pub fn walk<T>(&self, f: impl FnOnce(Option<RefWalker<VersionSource<B>>>) -> T) -> T {
+        let mut tree = self.tree.take();
+        let maybe_walker = tree
+            .as_mut()
+            .map(|tree| RefWalker::new(tree, self.source()));
+        let res = f(maybe_walker);
+        self.tree.set(tree);
+        res
+    }
+
+    /// Creates an iterator which yields all `(key, value)` pairs of this
+    /// version in key order, fetching nodes from the store as it goes.
+    pub fn iter(&self) -> VersionIter<'a, B> {
+        let root = self.use_tree(|maybe_tree| {
+            maybe_tree.map(|tree| Link::Reference {
+                hash: tree.hash(),
+                child_heights: tree.child_heights(),
+                key: tree.key().to_vec(),
+            })
+        });
+
+        let mut iter = VersionIter {
+            source: self.source(),
+            stack: vec![],
+            error: None,
+        };
+        if let Some(link) = root {
+            iter.push_left(&link);
+        }
+        iter
+    }
+
+    fn source(&self) -> VersionSource<'a, B> {
+        VersionSource::new(self.db, self.hash_algorithm)
+    }
+
+    fn use_tree<T>(&self, f: impl FnOnce(Option<&Tree>) -> T) -> T {
+        let tree = self.tree.take();
+        let res = f(tree.as_ref());
+        self.tree.set(tree);
+        res
+    }
+
+    fn use_tree_mut<T>(&self, f: impl FnOnce(Option<&mut Tree>) -> T) -> T {
+        let mut tree = self.tree.take();
+        let res = f(tree.as_mut());
+        self.tree.set(tree);
+        res
+    }
+}
+
+fn get<S>(walker: &mut RefWalker<S>, key: &[u8]) -> Result<Option<Vec<u8>>>
+where
+    S: Fetch + Sized + Clone + Send,
+{
+    if key == walker.tree().key() {
+        return Ok(Some(walker.tree().value().to_vec()));
+    }
+
+    let left = key < walker.tree().key();
+    match walker.walk(left)? {
+        None => Ok(None),
+        Some(mut child) => get(&mut child, key),
+    }
+}
+
+/// A `Fetch` source which resolves links by hash, so that nodes overwritten
+/// after a version was committed can still be found.
+pub struct VersionSource<'a, B = DefaultBackend> {
+    db: &'a B,
+    hash_algorithm: HashAlgorithm,
+}
+
+impl<'a, B> Clone for VersionSource<'a, B> {
+    fn clone(&self) -> Self {
+        VersionSource {
+            db: self.db,
+            hash_algorithm: self.hash_algorithm,
+        }
+    }
+}
+
+impl<'a, B: Backend> VersionSource<'a, B> {
+    pub(crate) fn new(db: &'a B, hash_algorithm: HashAlgorithm) -> Self {
+        VersionSource { db, hash_algorithm }
+    }
+}
+
+impl<'a, B: Backend> Fetch for VersionSource<'a, B> {
+    fn fetch_by_key(&self, key: &[u8]) -> Result<Option<Tree>> {
+        Err(Error::Fetch(format!(
+            "Historical nodes must be fetched by link, not by key: {key:?}"
+        )))
+    }
+
+    fn fetch(&self, link: &Link) -> Result<Tree> {
+        self.locate(link).map(|(tree, _)| tree)
+    }
+
+    fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+    }
+}
+
+impl<'a, B: Backend> VersionSource<'a, B> {
+    /// Fetches the node referenced by `link`, also returning `true` if it was
+    /// read from the history column family rather than the live tree.
+    pub(crate) fn locate(&self, link: &Link) -> Result<(Tree, bool)> {
+        let key = link.key();
+        let hash = link.hash();
+
+        if let Some(bytes) = self.db.get(key)? {
+            let tree = Tree::decode_with_algorithm(key.to_vec(), &bytes, self.hash_algorithm);
+            if &tree.hash() == hash {
+                return Ok((tree, false));
+            }
+        }
+
+        self.db
+            .get_cf(HISTORY_CF_NAME, hash)?
+            .map(|bytes| {
+                let tree = Tree::decode_with_algorithm(key.to_vec(), &bytes, self.hash_algorithm);
+                (tree, true)
+            })
+            .ok_or_else(|| Error::Fetch(format!("Missing historical node for key {key:?}")))
+    }
+}
+
+/// An iterator over the `(key, value)` pairs of a `Version`, created with
+/// `Version::iter`. Yields an error and then stops if a node can not be
+/// fetched.
+pub struct VersionIter<'a, B = DefaultBackend> {
+    source: VersionSource<'a, B>,
+    stack: Vec<Tree>,
+    error: Option<Error>,
+}
+
+impl<'a, B: Backend> VersionIter<'a, B> {
+    /// Fetches the node for `link` and its chain of left descendants, pushing
+    /// them onto the stack.
+    fn push_left(&mut self, link: &Link) {
+        let mut maybe_tree = self.source.fetch(link);
+        loop {
+            let tree = match maybe_tree {
+                Ok(tree) => tree,
+                Err(err) => {
+                    self.error = Some(err);
+                    return;
+                }
+            };
+
+            let next = tree.link(true).map(|left| self.source.fetch(left));
+            self.stack.push(tree);
+            maybe_tree = match next {
+                None => return,
+                Some(next) => next,
+            };
+        }
+    }
+}
+
+impl<'a, B: Backend> Iterator for VersionIter<'a, B> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(err) = self.error.take() {
+            self.stack.clear();
+            return Some(Err(err));
+        }
+
+        let tree = self.stack.pop()?;
+        if let Some(right) = tree.link(false) {
+            self.push_left(right);
+        }
+
+        let entry = (tree.key().to_vec(), tree.value().to_vec());
+        Some(Ok(entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::merk::backend::{Backend, WriteBatch};
+    use crate::merk::INTERNAL_CF_NAME;
+    use crate::proofs::query::{verify, Query};
+    use crate::test_utils::*;
+    use crate::{Error, Merk, Op, RetentionPolicy};
+
+    #[test]
+    fn get_past_versions() {
+        let mut merk = TempMerk::new().unwrap();
+
+        merk.apply_versioned(1, &make_batch_seq(0..100), &[])
+            .unwrap();
+        let root_1 = merk.root_hash();
+        merk.apply_versioned(
+            2,
+            &[
+                (seq_key(5), Op::Put(vec![1, 2, 3])),
+                (seq_key(6), Op::Delete),
+            ],
+            &[],
+        )
+        .unwrap();
+        merk.apply(&[(seq_key(7), Op::Delete)], &[]).unwrap();
+
+        let version_1 = merk.version(1).unwrap();
+        assert_eq!(version_1.root_hash(), root_1);
+        assert_eq!(version_1.get(&seq_key(5)).unwrap(), Some(put_entry_value()));
+        assert_eq!(version_1.get(&seq_key(6)).unwrap(), Some(put_entry_value()));
+        assert_eq!(version_1.get(&seq_key(7)).unwrap(), Some(put_entry_value()));
+        assert_eq!(version_1.get(&seq_key(100)).unwrap(), None);
+
+        let version_2 = merk.version(2).unwrap();
+        assert_eq!(version_2.get(&seq_key(5)).unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(version_2.get(&seq_key(6)).unwrap(), None);
+        assert_eq!(version_2.get(&seq_key(7)).unwrap(), Some(put_entry_value()));
+
+        assert_eq!(merk.get(&seq_key(7)).unwrap(), None);
+        assert_eq!(merk.versions().unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn iter_past_version() {
+        let mut merk = TempMerk::new().unwrap();
+
+        merk.apply_versioned(1, &make_batch_seq(0..50), &[])
+            .unwrap();
+        merk.apply_versioned(2, &make_del_batch_seq(10..40), &[])
+            .unwrap();
+
+        let entries: Vec<_> = merk
+            .version(1)
+            .unwrap()
+            .iter()
+            .collect::<crate::Result<_>>()
+            .unwrap();
+        let expected: Vec<_> = (0..50).map(|n| (seq_key(n), put_entry_value())).collect();
+        assert_eq!(entries, expected);
+
+        assert_eq!(merk.version(2).unwrap().iter().count(), 20);
+    }
+
+    #[test]
+    fn prove_past_version() {
+        let mut merk = TempMerk::new().unwrap();
+
+        merk.apply_versioned(1, &make_batch_seq(0..20), &[])
+            .unwrap();
+        merk.apply_versioned(2, &[(seq_key(3), Op::Put(vec![9]))], &[])
+            .unwrap();
+
+        let version = merk.version(1).unwrap();
+        let mut query = Query::new();
+        query.insert_key(seq_key(3));
+        let proof = version.prove(query).unwrap();
+
+        let map = verify(&proof, version.root_hash()).unwrap();
+        assert_eq!(map.get(&seq_key(3)).unwrap(), Some(&put_entry_value()[..]));
+    }
+
+    #[test]
+    fn empty_version() {
+        let mut merk = TempMerk::new().unwrap();
+
+        merk.apply_versioned(1, &[], &[]).unwrap();
+        merk.apply_versioned(2, &[(vec![1], Op::Put(vec![1]))], &[])
+            .unwrap();
+
+        let version = merk.version(1).unwrap();
+        assert_eq!(version.root_hash(), crate::tree::NULL_HASH);
+        assert_eq!(version.get(&[1]).unwrap(), None);
+        assert_eq!(version.iter().count(), 0);
+    }
+
+    #[test]
+    fn retention_and_ordering() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.set_retention_policy(RetentionPolicy::KeepLast(2));
+
+        for version in 1..=4 {
+            merk.apply_versioned(version, &[(vec![1], Op::Put(vec![version as u8]))], &[])
+                .unwrap();
+        }
+
+        assert_eq!(merk.versions().unwrap(), vec![3, 4]);
+        assert!(merk.version(2).is_err());
+        assert_eq!(merk.version(3).unwrap().get(&[1]).unwrap(), Some(vec![3]));
+
+        assert!(merk
+            .apply_versioned(4, &[(vec![1], Op::Put(vec![5]))], &[])
+            .is_err());
+        assert_eq!(merk.get(&[1]).unwrap(), Some(vec![4]));
+    }
+
+    #[test]
+    fn versions_survive_reopen() {
+        let path = std::thread::current().name().unwrap().to_owned();
+        let root_1 = {
+            let mut merk = Merk::open(&path).unwrap();
+            merk.apply_versioned(1, &make_batch_seq(0..30), &[])
+                .unwrap();
+            let root = merk.root_hash();
+            merk.apply_versioned(2, &make_del_batch_seq(0..15), &[])
+                .unwrap();
+            root
+        };
+
+        let merk = TempMerk::open(&path).unwrap();
+        assert_eq!(merk.latest_version().unwrap(), Some(2));
+        let version = merk.version(1).unwrap();
+        assert_eq!(version.root_hash(), root_1);
+        assert_eq!(version.get(&seq_key(0)).unwrap(), Some(put_entry_value()));
+    }
+
+    #[test]
+    fn malformed_version_key() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply_versioned(1, &make_batch_seq(0..10), &[])
+            .unwrap();
+
+        let mut batch = merk.db.batch();
+        batch.put_cf(INTERNAL_CF_NAME, b"versionx", &[]);
+        merk.db.write(batch).unwrap();
+
+        assert!(matches!(merk.load_root(), Err(Error::Version(_))));
+    }
+}
@@ -1,14 +1,13 @@
 #[cfg(feature = "full")]
 use {
     super::tree::{execute, Tree as ProofTree},
-    crate::tree::Hash,
-    crate::tree::Tree,
-    rocksdb::DBRawIterator,
+    crate::tree::{Hash, HashAlgorithm},
 };
 
 use super::{Node, Op};
 use crate::error::{Error, Result};
-use crate::tree::{Fetch, RefWalker};
+use crate::merk::backend::RawIterator;
+use crate::tree::{Fetch, RefWalker, Tree};
 
 /// The minimum number of layers the trunk will be guaranteed to have before
 /// splitting into multiple chunks. If the tree's height is less than double
@@ -61,8 +60,8 @@ where
                 proof.push(Op::Parent);
             }
 
-            if let Some(right) = self.tree().link(false) {
-                proof.push(Op::Push(Node::Hash(*right.hash())));
+            if self.tree().link(false).is_some() {
+                proof.push(Op::Push(self.tree().to_child_hash_node(false)));
                 proof.push(Op::Child);
             }
         }
@@ -117,12 +116,14 @@ where
     }
 }
 
-/// Builds a chunk proof by iterating over values in a RocksDB, ending the chunk
-/// when a node with key `end_key` is encountered.
+/// Builds a chunk proof by iterating over the nodes stored in a backend,
+/// ending the chunk when a node with key `end_key` is encountered.
 ///
 /// Advances the iterator for all nodes in the chunk and the `end_key` (if any).
-#[cfg(feature = "full")]
-pub(crate) fn get_next_chunk(iter: &mut DBRawIterator, end_key: Option<&[u8]>) -> Result<Vec<Op>> {
+pub(crate) fn get_next_chunk<I: RawIterator>(
+    iter: &mut I,
+    end_key: Option<&[u8]>,
+) -> Result<Vec<Op>> {
     let mut chunk = Vec::with_capacity(512);
     let mut stack = Vec::with_capacity(32);
     let mut node = Tree::new(vec![], vec![])?;
@@ -174,12 +175,20 @@ pub(crate) fn get_next_chunk(iter: &mut DBRawIterator, end_key: Option<&[u8]>) -
 #[cfg(feature = "full")]
 pub(crate) fn verify_leaf<I: Iterator<Item = Result<Op>>>(
     ops: I,
+    hash_algorithm: HashAlgorithm,
+    aggregate_counts: bool,
     expected_hash: Hash,
 ) -> Result<ProofTree> {
-    let tree = execute(ops, false, |node| match node {
-        Node::KV(_, _) => Ok(()),
-        _ => Err(Error::Tree("Leaf chunks must contain full subtree".into())),
-    })?;
+    let tree = execute(
+        ops,
+        false,
+        hash_algorithm,
+        aggregate_counts,
+        |node| match node {
+            Node::KV(_, _) => Ok(()),
+            _ => Err(Error::Tree("Leaf chunks must contain full subtree".into())),
+        },
+    )?;
 
     if tree.hash()? != expected_hash {
         return Err(Error::HashMismatch(expected_hash, tree.hash()?));
@@ -193,11 +202,15 @@ pub(crate) fn verify_leaf<I: Iterator<Item = Result<Op>>>(
 /// height, and all of its inner nodes are not abridged. Returns the tree and
 /// the height given by the height proof.
 #[cfg(feature = "full")]
-pub(crate) fn verify_trunk<I: Iterator<Item = Result<Op>>>(ops: I) -> Result<(ProofTree, usize)> {
+pub(crate) fn verify_trunk<I: Iterator<Item = Result<Op>>>(
+    ops: I,
+    hash_algorithm: HashAlgorithm,
+    aggregate_counts: bool,
+) -> Result<(ProofTree, usize)> {
     fn verify_height_proof(tree: &ProofTree) -> Result<usize> {
         Ok(match tree.child(true) {
             Some(child) => {
-                if let Node::Hash(_) = child.tree.node {
+                if let Node::Hash(_) | Node::HashWithCount(_, _) = child.tree.node {
                     return Err(Error::UnexpectedNode(
                         "Expected height proof to only contain KV and KVHash nodes".into(),
                     ));
@@ -229,7 +242,7 @@ pub(crate) fn verify_trunk<I: Iterator<Item = Result<Op>>>(ops: I) -> Result<(Pr
             recurse(false, false)
         } else if !leftmost {
             match tree.node {
-                Node::Hash(_) => Ok(()),
+                Node::Hash(_) | Node::HashWithCount(_, _) => Ok(()),
                 _ => Err(Error::UnexpectedNode(
                     "Expected trunk leaves to contain Hash nodes".into(),
                 )),
@@ -245,7 +258,7 @@ pub(crate) fn verify_trunk<I: Iterator<Item = Result<Op>>>(ops: I) -> Result<(Pr
     }
 
     let mut kv_only = true;
-    let tree = execute(ops, false, |node| {
+    let tree = execute(ops, false, hash_algorithm, aggregate_counts, |node| {
         kv_only &= matches!(node, Node::KV(_, _));
         Ok(())
     })?;
@@ -288,6 +301,7 @@ mod tests {
                 Node::Hash(_) => counts.hash += 1,
                 Node::KVHash(_) => counts.kvhash += 1,
                 Node::KV(_, _) => counts.kv += 1,
+                Node::KVDigest(_, _) | Node::HashWithCount(_, _) => unreachable!(),
             };
         });
 
@@ -303,7 +317,8 @@ mod tests {
         assert!(!has_more);
 
         println!("{:?}", &proof);
-        let (trunk, _) = verify_trunk(proof.into_iter().map(Ok)).unwrap();
+        let (trunk, _) =
+            verify_trunk(proof.into_iter().map(Ok), HashAlgorithm::default(), false).unwrap();
 
         let counts = count_node_types(trunk);
         assert_eq!(counts.hash, 0);
@@ -318,7 +333,8 @@ mod tests {
 
         let (proof, has_more) = walker.create_trunk_proof().unwrap();
         assert!(has_more);
-        let (trunk, _) = verify_trunk(proof.into_iter().map(Ok)).unwrap();
+        let (trunk, _) =
+            verify_trunk(proof.into_iter().map(Ok), HashAlgorithm::default(), false).unwrap();
 
         let counts = count_node_types(trunk);
         // are these formulas correct for all values of `MIN_TRUNK_HEIGHT`? 🤔
@@ -339,7 +355,8 @@ mod tests {
         let (proof, has_more) = walker.create_trunk_proof().unwrap();
         assert!(!has_more);
 
-        let (trunk, _) = verify_trunk(proof.into_iter().map(Ok)).unwrap();
+        let (trunk, _) =
+            verify_trunk(proof.into_iter().map(Ok), HashAlgorithm::default(), false).unwrap();
         let counts = count_node_types(trunk);
         assert_eq!(counts.hash, 0);
         assert_eq!(counts.kv, 1);
@@ -359,7 +376,8 @@ mod tests {
         let (proof, has_more) = walker.create_trunk_proof().unwrap();
         assert!(!has_more);
 
-        let (trunk, _) = verify_trunk(proof.into_iter().map(Ok)).unwrap();
+        let (trunk, _) =
+            verify_trunk(proof.into_iter().map(Ok), HashAlgorithm::default(), false).unwrap();
         let counts = count_node_types(trunk);
         assert_eq!(counts.hash, 0);
         assert_eq!(counts.kv, 2);
@@ -379,7 +397,8 @@ mod tests {
         let (proof, has_more) = walker.create_trunk_proof().unwrap();
         assert!(!has_more);
 
-        let (trunk, _) = verify_trunk(proof.into_iter().map(Ok)).unwrap();
+        let (trunk, _) =
+            verify_trunk(proof.into_iter().map(Ok), HashAlgorithm::default(), false).unwrap();
         let counts = count_node_types(trunk);
         assert_eq!(counts.hash, 0);
         assert_eq!(counts.kv, 2);
@@ -401,7 +420,8 @@ mod tests {
         let (proof, has_more) = walker.create_trunk_proof().unwrap();
         assert!(!has_more);
 
-        let (trunk, _) = verify_trunk(proof.into_iter().map(Ok)).unwrap();
+        let (trunk, _) =
+            verify_trunk(proof.into_iter().map(Ok), HashAlgorithm::default(), false).unwrap();
         let counts = count_node_types(trunk);
         assert_eq!(counts.hash, 0);
         assert_eq!(counts.kv, 3);
@@ -424,7 +444,7 @@ mod tests {
         iter.seek_to_first();
         let chunk = get_next_chunk(&mut iter, None).unwrap();
         let ops = chunk.into_iter().map(Ok);
-        let chunk = verify_leaf(ops, merk.root_hash()).unwrap();
+        let chunk = verify_leaf(ops, HashAlgorithm::default(), false, merk.root_hash()).unwrap();
         let counts = count_node_types(chunk);
         assert_eq!(counts.kv, 31);
         assert_eq!(counts.hash, 0);
@@ -439,6 +459,8 @@ mod tests {
         let ops = chunk.into_iter().map(Ok);
         let chunk = verify_leaf(
             ops,
+            HashAlgorithm::default(),
+            false,
             [
                 89, 129, 189, 87, 229, 178, 155, 195, 54, 144, 248, 243, 103, 71, 228, 172, 163,
                 193, 94, 87, 248, 34, 10, 83, 141, 28, 237, 227, 247, 25, 158, 145,
@@ -455,6 +477,8 @@ mod tests {
         let ops = chunk.into_iter().map(Ok);
         let chunk = verify_leaf(
             ops,
+            HashAlgorithm::default(),
+            false,
             [
                 106, 189, 157, 182, 120, 31, 131, 28, 104, 107, 209, 63, 201, 238, 48, 3, 138, 53,
                 77, 178, 18, 138, 222, 194, 247, 8, 33, 2, 193, 180, 237, 173,
@@ -0,0 +1,399 @@
+//! A compact, versioned encoding for proofs.
+//!
+//! A compact proof starts with a header of four bytes: `COMPACT_HEADER`, the
+//! format version, a byte of flags, and the tag of the hash algorithm the
+//! proof's hashes are computed with. The header is followed by the encoded
+//! operators, which are compressed into a single zstd frame if `FLAG_ZSTD` is
+//! set.
+//!
+//! Operators use the same variant bytes as the legacy encoding, but lengths
+//! are written as LEB128 varints, so keys and values are not limited to 255
+//! and 65,535 bytes. Since the keys in a proof are in order, the key of each
+//! `Node::KV` or `Node::KVDigest` is written as the length of the prefix it
+//! shares with the previous such key, followed by the rest of the key.
+//!
+//! `Decoder` detects compact proofs by their first byte, which never starts a
+//! legacy proof.
+
+use std::borrow::Cow;
+
+use super::encoding::header_tag;
+use super::{Node, Op};
+use crate::error::{Error, Result};
+use crate::tree::{HashAlgorithm, HASH_LENGTH};
+
+/// The first byte of a compact proof.
+pub(super) const COMPACT_HEADER: u8 = 0x30;
+
+/// The version of the compact format written by `encode_compact_into`.
+const VERSION: u8 = 1;
+
+/// Set in the flags byte if the operators are compressed with zstd.
+const FLAG_ZSTD: u8 = 0x01;
+
+/// The largest body a compressed proof may decompress to, so a small
+/// malicious proof can not exhaust the verifier's memory.
+#[cfg(feature = "zstd")]
+const MAX_DECOMPRESSED_LENGTH: u64 = 64 << 20;
+
+/// Writes `ops` to `output` in the compact encoding, with a header naming
+/// `hash_algorithm` and whether the proven tree keeps `aggregate_counts`. If
+/// `compress` is set the operators are compressed with zstd, which errors if
+/// the `zstd` feature is not enabled.
+pub fn encode_compact_into<'a, T: Iterator<Item = &'a Op>>(
+    ops: T,
+    hash_algorithm: HashAlgorithm,
+    aggregate_counts: bool,
+    compress: bool,
+    output: &mut Vec<u8>,
+) -> Result<()> {
+    let mut body = Vec::with_capacity(128);
+    let mut last_key: &[u8] = &[];
+    for op in ops {
+        encode_op(op, &mut last_key, &mut body);
+    }
+
+    let flags = if compress { FLAG_ZSTD } else { 0 };
+    let tag = header_tag(hash_algorithm, aggregate_counts);
+    output.extend_from_slice(&[COMPACT_HEADER, VERSION, flags, tag]);
+    if compress {
+        output.extend_from_slice(&compress_body(&body)?);
+    } else {
+        output.extend_from_slice(&body);
+    }
+    Ok(())
+}
+
+fn encode_op<'a>(op: &'a Op, last_key: &mut &'a [u8], output: &mut Vec<u8>) {
+    match op {
+        Op::Push(Node::Hash(hash)) => {
+            output.push(0x01);
+            output.extend_from_slice(hash);
+        }
+        Op::Push(Node::KVHash(kv_hash)) => {
+            output.push(0x02);
+            output.extend_from_slice(kv_hash);
+        }
+        Op::Push(Node::KV(key, value)) => {
+            output.push(0x03);
+            encode_key(key, last_key, output);
+            write_varint(value.len(), output);
+            output.extend_from_slice(value);
+        }
+        Op::Push(Node::KVDigest(key, value_hash)) => {
+            output.push(0x04);
+            encode_key(key, last_key, output);
+            output.extend_from_slice(value_hash);
+        }
+        Op::Push(Node::HashWithCount(hash, count)) => {
+            output.push(0x05);
+            output.extend_from_slice(hash);
+            output.extend_from_slice(&count.to_be_bytes());
+        }
+        Op::Parent => output.push(0x10),
+        Op::Child => output.push(0x11),
+    }
+}
+
+/// Writes `key` as the length of the prefix it shares with `last_key`
+/// followed by the rest of the key, then makes it the new `last_key`.
+fn encode_key<'a>(key: &'a [u8], last_key: &mut &'a [u8], output: &mut Vec<u8>) {
+    let shared = key
+        .iter()
+        .zip(last_key.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    write_varint(shared, output);
+    write_varint(key.len() - shared, output);
+    output.extend_from_slice(&key[shared..]);
+
+    *last_key = key;
+}
+
+/// Parses the header of the compact proof `bytes`, returning the tag of the
+/// hash algorithm it names and the (decompressed) encoded operators.
+pub(super) fn decode_header(bytes: &[u8]) -> Result<(u8, Cow<'_, [u8]>)> {
+    let (version, flags, tag, body) = match bytes {
+        [COMPACT_HEADER, version, flags, tag, body @ ..] => (*version, *flags, *tag, body),
+        _ => return Err(Error::Proof("Compact proof header is truncated".into())),
+    };
+
+    if version != VERSION {
+        return Err(Error::Proof(format!(
+            "Unsupported compact proof version {version}"
+        )));
+    }
+    if flags & !FLAG_ZSTD != 0 {
+        return Err(Error::Proof(format!(
+            "Unknown compact proof flags {flags:#04x}"
+        )));
+    }
+
+    let body = if flags & FLAG_ZSTD != 0 {
+        Cow::Owned(decompress_body(body)?)
+    } else {
+        Cow::Borrowed(body)
+    };
+    Ok((tag, body))
+}
+
+/// Decodes one operator from the front of `input`, advancing it past the
+/// operator. `last_key` is the key of the previously decoded `Node::KV` or
+/// `Node::KVDigest`, and is updated if the operator is one.
+pub(super) fn decode_op(input: &mut &[u8], last_key: &mut Vec<u8>) -> Result<Op> {
+    let variant = read_bytes(input, 1)?[0];
+
+    Ok(match variant {
+        0x01 => Op::Push(Node::Hash(read_hash(input)?)),
+        0x02 => Op::Push(Node::KVHash(read_hash(input)?)),
+        0x03 => {
+            let key = decode_key(input, last_key)?;
+            let value_len = read_varint(input)?;
+            let value = read_bytes(input, value_len)?.to_vec();
+            Op::Push(Node::KV(key, value))
+        }
+        0x04 => {
+            let key = decode_key(input, last_key)?;
+            Op::Push(Node::KVDigest(key, read_hash(input)?))
+        }
+        0x05 => {
+            let hash = read_hash(input)?;
+            let mut count = [0; 8];
+            count.copy_from_slice(read_bytes(input, 8)?);
+            Op::Push(Node::HashWithCount(hash, u64::from_be_bytes(count)))
+        }
+        0x10 => Op::Parent,
+        0x11 => Op::Child,
+        byte => {
+            return Err(Error::Proof(format!(
+                "Unexpected byte {byte:#04x} in compact proof"
+            )))
+        }
+    })
+}
+
+/// Reads a key written by `encode_key`, and makes it the new `last_key`.
+fn decode_key(input: &mut &[u8], last_key: &mut Vec<u8>) -> Result<Vec<u8>> {
+    let shared = read_varint(input)?;
+    if shared > last_key.len() {
+        return Err(Error::Proof(format!(
+            "Key shares {} bytes with a previous key of {} bytes",
+            shared,
+            last_key.len()
+        )));
+    }
+    let suffix_len = read_varint(input)?;
+    let mut key = Vec::with_capacity(shared + suffix_len.min(input.len()));
+    key.extend_from_slice(&last_key[..shared]);
+    key.extend_from_slice(read_bytes(input, suffix_len)?);
+
+    last_key.clone_from(&key);
+    Ok(key)
+}
+
+fn write_varint(mut n: usize, output: &mut Vec<u8>) {
+    while n >= 0x80 {
+        output.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    output.push(n as u8);
+}
+
+fn read_varint(input: &mut &[u8]) -> Result<usize> {
+    let mut n: usize = 0;
+    let mut shift = 0;
+    loop {
+        let byte = read_bytes(input, 1)?[0];
+        let bits = ((byte & 0x7f) as usize)
+            .checked_shl(shift)
+            .filter(|bits| bits >> shift == (byte & 0x7f) as usize)
+            .ok_or_else(|| Error::Proof("Varint overflows".into()))?;
+        n |= bits;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+        shift += 7;
+    }
+}
+
+fn read_bytes<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if input.len() < len {
+        return Err(Error::Proof("Unexpected end of compact proof".into()));
+    }
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+    Ok(bytes)
+}
+
+fn read_hash(input: &mut &[u8]) -> Result<[u8; HASH_LENGTH]> {
+    let mut hash = [0; HASH_LENGTH];
+    hash.copy_from_slice(read_bytes(input, HASH_LENGTH)?);
+    Ok(hash)
+}
+
+#[cfg(feature = "zstd")]
+fn compress_body(body: &[u8]) -> Result<Vec<u8>> {
+    Ok(zstd::bulk::compress(body, 0)?)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn compress_body(_body: &[u8]) -> Result<Vec<u8>> {
+    Err(Error::Proof(
+        "Compressing proofs requires the zstd feature".into(),
+    ))
+}
+
+#[cfg(feature = "zstd")]
+fn decompress_body(body: &[u8]) -> Result<Vec<u8>> {
+    use std::io::Read;
+
+    let mut decompressed = vec![];
+    zstd::stream::read::Decoder::new(body)?
+        .take(MAX_DECOMPRESSED_LENGTH + 1)
+        .read_to_end(&mut decompressed)?;
+    if decompressed.len() as u64 > MAX_DECOMPRESSED_LENGTH {
+        return Err(Error::Proof(format!(
+            "Compressed proof is larger than {MAX_DECOMPRESSED_LENGTH} bytes"
+        )));
+    }
+    Ok(decompressed)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress_body(_body: &[u8]) -> Result<Vec<u8>> {
+    Err(Error::Proof(
+        "Decompressing proofs requires the zstd feature".into(),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{encode_header, encode_into, Decoder};
+    use super::*;
+
+    fn ops() -> Vec<Op> {
+        vec![
+            Op::Push(Node::Hash([1; HASH_LENGTH])),
+            Op::Push(Node::KV(b"account/0001".to_vec(), vec![7; 3])),
+            Op::Parent,
+            Op::Push(Node::KVHash([2; HASH_LENGTH])),
+            Op::Child,
+            Op::Push(Node::KVDigest(b"account/0002".to_vec(), [3; HASH_LENGTH])),
+            Op::Parent,
+            Op::Push(Node::KV(b"b".to_vec(), vec![9; 300])),
+            Op::Child,
+        ]
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        for n in [0, 1, 127, 128, 300, 65_536, usize::MAX] {
+            let mut bytes = vec![];
+            write_varint(n, &mut bytes);
+            let mut input = bytes.as_slice();
+            assert_eq!(read_varint(&mut input).unwrap(), n);
+            assert!(input.is_empty());
+        }
+        assert_eq!(
+            {
+                let mut bytes = vec![];
+                write_varint(300, &mut bytes);
+                bytes
+            },
+            vec![0xac, 0x02]
+        );
+
+        assert!(read_varint(&mut &[0x80][..]).is_err());
+        assert!(read_varint(&mut &[0xff; 11][..]).is_err());
+    }
+
+    #[test]
+    fn encode_compact_kv_prefixes() {
+        let ops = [
+            Op::Push(Node::KV(vec![1, 2, 3], vec![4])),
+            Op::Push(Node::KV(vec![1, 2, 5, 6], vec![])),
+        ];
+        let mut bytes = vec![];
+        encode_compact_into(ops.iter(), HashAlgorithm::Blake3, false, false, &mut bytes).unwrap();
+        assert_eq!(
+            bytes,
+            vec![
+                0x30, 1, 0, 1, // header
+                0x03, 0, 3, 1, 2, 3, 1, 4, // first key in full
+                0x03, 2, 2, 5, 6, 0, // second key shares [1, 2]
+            ]
+        );
+    }
+
+    #[test]
+    fn decode_compact() {
+        let ops = ops();
+        let mut bytes = vec![];
+        encode_compact_into(
+            ops.iter(),
+            HashAlgorithm::Sha3_256,
+            false,
+            false,
+            &mut bytes,
+        )
+        .unwrap();
+
+        let decoder = Decoder::new(&bytes);
+        assert_eq!(decoder.hash_algorithm().unwrap(), HashAlgorithm::Sha3_256);
+        assert_eq!(decoder.collect::<Result<Vec<_>>>().unwrap(), ops);
+
+        let mut legacy = vec![];
+        encode_header(HashAlgorithm::Sha3_256, false, &mut legacy);
+        encode_into(ops.iter(), &mut legacy);
+        assert!(bytes.len() < legacy.len());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn decode_compact_zstd() {
+        let ops = ops();
+        let mut bytes = vec![];
+        encode_compact_into(
+            ops.iter(),
+            HashAlgorithm::default(),
+            false,
+            true,
+            &mut bytes,
+        )
+        .unwrap();
+        assert_eq!(&bytes[..4], &[0x30, 1, FLAG_ZSTD, 0]);
+
+        let decoder = Decoder::new(&bytes);
+        assert_eq!(decoder.hash_algorithm().unwrap(), HashAlgorithm::default());
+        assert_eq!(decoder.collect::<Result<Vec<_>>>().unwrap(), ops);
+
+        let mut corrupt = bytes.clone();
+        corrupt.truncate(corrupt.len() - 2);
+        assert!(Decoder::new(&corrupt).next().unwrap().is_err());
+    }
+
+    #[test]
+    fn decode_compact_invalid() {
+        let decode = |bytes: &[u8]| Decoder::new(bytes).collect::<Result<Vec<_>>>();
+
+        // truncated header, unknown version and unknown flags
+        assert!(decode(&[0x30, 1, 0]).is_err());
+        assert!(decode(&[0x30, 2, 0, 0, 0x10]).is_err());
+        assert!(decode(&[0x30, 1, 0x80, 0, 0x10]).is_err());
+
+        // unknown hash algorithm
+        assert!(Decoder::new(&[0x30, 1, 0, 99]).hash_algorithm().is_err());
+
+        // shared prefix longer than the previous key
+        assert!(decode(&[0x30, 1, 0, 0, 0x03, 1, 0, 0]).is_err());
+
+        // value runs past the end
+        assert!(decode(&[0x30, 1, 0, 0, 0x03, 0, 1, 5, 3, 1]).is_err());
+
+        // unknown operator
+        assert!(decode(&[0x30, 1, 0, 0, 0x88]).is_err());
+
+        assert_eq!(decode(&[0x30, 1, 0, 0]).unwrap(), vec![]);
+    }
+}
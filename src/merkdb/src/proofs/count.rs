@@ -0,0 +1,400 @@
+//! Proofs of the number of keys in a range, for stores which keep aggregate
+//! counts (see `Merk::enable_aggregate_counts`).
+//!
+//! A count proof includes the nodes on the paths to the range's bounds. Every
+//! other subtree is abridged to a `Node::HashWithCount`, which lies either
+//! entirely inside or entirely outside the range, as shown by the keys of the
+//! nodes around it. The verifier adds up the counts of the subtrees inside the
+//! range and the included keys which are in it.
+
+use std::collections::LinkedList;
+use std::ops::{Bound, RangeBounds};
+
+use super::tree::execute;
+use super::{Decoder, Node, Op};
+use crate::error::{Error, Result};
+use crate::tree::{Fetch, Hash, RefWalker};
+
+impl<'a, S> RefWalker<'a, S>
+where
+    S: Fetch + Sized + Send + Clone,
+{
+    /// Generates a proof of the number of keys in `range`, and returns it
+    /// along with the count. Errors if the tree does not keep aggregate
+    /// counts.
+    pub(crate) fn create_count_proof<R: RangeBounds<Vec<u8>>>(
+        &mut self,
+        range: &R,
+    ) -> Result<(LinkedList<Op>, u64)> {
+        let count = self
+            .tree()
+            .count()
+            .ok_or_else(|| Error::Tree("Tree does not keep aggregate counts".into()))?;
+
+        if contains_subtree(range, None, None) {
+            let mut proof = LinkedList::new();
+            proof.push_back(Op::Push(self.to_hash_node()));
+            return Ok((proof, count));
+        }
+
+        self.create_node_count_proof(range, None, None)
+    }
+
+    /// Generates a count proof for the subtree of the node, whose keys are all
+    /// between `lower` and `upper` (exclusive).
+    fn create_node_count_proof<R: RangeBounds<Vec<u8>>>(
+        &mut self,
+        range: &R,
+        lower: Option<&[u8]>,
+        upper: Option<&[u8]>,
+    ) -> Result<(LinkedList<Op>, u64)> {
+        let key = self.tree().key().to_vec();
+        let (mut proof, left_count) =
+            self.create_child_count_proof(true, range, lower, Some(&key))?;
+        let (right_proof, right_count) =
+            self.create_child_count_proof(false, range, Some(&key), upper)?;
+
+        let has_left = !proof.is_empty();
+        proof.push_back(Op::Push(self.to_kvdigest_node()));
+        if has_left {
+            proof.push_back(Op::Parent);
+        }
+
+        if !right_proof.is_empty() {
+            proof.extend(right_proof);
+            proof.push_back(Op::Child);
+        }
+
+        let count = left_count + right_count + u64::from(contains_key(range, &key));
+        Ok((proof, count))
+    }
+
+    /// Generates a count proof for the child on the given side. The child is
+    /// abridged if its subtree is entirely inside or outside of `range`.
+    fn create_child_count_proof<R: RangeBounds<Vec<u8>>>(
+        &mut self,
+        left: bool,
+        range: &R,
+        lower: Option<&[u8]>,
+        upper: Option<&[u8]>,
+    ) -> Result<(LinkedList<Op>, u64)> {
+        if self.tree().link(left).is_none() {
+            return Ok((LinkedList::new(), 0));
+        }
+
+        let count = if contains_subtree(range, lower, upper) {
+            self.tree().child_count(left).unwrap_or_default()
+        } else if excludes_subtree(range, lower, upper) {
+            0
+        } else {
+            let mut child = self.walk(left)?.expect("Expected child");
+            return child.create_node_count_proof(range, lower, upper);
+        };
+
+        let mut proof = LinkedList::new();
+        proof.push_back(Op::Push(self.tree().to_child_hash_node(left)));
+        Ok((proof, count))
+    }
+}
+
+/// Verifies the encoded count proof against the expected hash, and returns the
+/// number of keys in `range`.
+///
+/// Errors if the proof is not from a store which keeps aggregate counts, or
+/// if it abridges a subtree which may be partly inside the range.
+pub fn verify_count<R: RangeBounds<Vec<u8>>>(
+    bytes: &[u8],
+    range: R,
+    expected_hash: Hash,
+) -> Result<u64> {
+    /// A node of a count proof, in key order.
+    enum Entry {
+        Key(Vec<u8>),
+        Abridged(u64),
+    }
+
+    let ops = Decoder::new(bytes);
+    let hash_algorithm = ops.hash_algorithm()?;
+    if !ops.aggregate_counts() {
+        return Err(Error::Proof(
+            "Expected proof from a store which keeps aggregate counts".into(),
+        ));
+    }
+
+    let mut entries = vec![];
+    let root = execute(ops, true, hash_algorithm, true, |node| {
+        entries.push(match node {
+            Node::KV(key, _) | Node::KVDigest(key, _) => Entry::Key(key.clone()),
+            Node::HashWithCount(_, count) => Entry::Abridged(*count),
+            _ => {
+                return Err(Error::UnexpectedNode(
+                    "Expected count proof to only contain keys and counts".into(),
+                ))
+            }
+        });
+        Ok(())
+    })?;
+
+    if root.hash()? != expected_hash {
+        return Err(Error::HashMismatch(expected_hash, root.hash()?));
+    }
+
+    let key_at = |index: usize| match entries.get(index) {
+        Some(Entry::Key(key)) => Some(key.as_slice()),
+        _ => None,
+    };
+
+    let mut count: u64 = 0;
+    for (index, entry) in entries.iter().enumerate() {
+        let entry_count = match entry {
+            Entry::Key(key) => u64::from(contains_key(&range, key)),
+            Entry::Abridged(abridged_count) => {
+                let lower = index.checked_sub(1).and_then(key_at);
+                let upper = key_at(index + 1);
+                if contains_subtree(&range, lower, upper) {
+                    *abridged_count
+                } else if excludes_subtree(&range, lower, upper) {
+                    0
+                } else {
+                    return Err(Error::MissingData);
+                }
+            }
+        };
+        count = count
+            .checked_add(entry_count)
+            .ok_or_else(|| Error::Proof("Count overflows".into()))?;
+    }
+
+    Ok(count)
+}
+
+/// Returns `true` if `key` is in `range`.
+fn contains_key<R: RangeBounds<Vec<u8>>>(range: &R, key: &[u8]) -> bool {
+    let after_start = match range.start_bound() {
+        Bound::Included(start) => key >= start.as_slice(),
+        Bound::Excluded(start) => key > start.as_slice(),
+        Bound::Unbounded => true,
+    };
+    let before_end = match range.end_bound() {
+        Bound::Included(end) => key <= end.as_slice(),
+        Bound::Excluded(end) => key < end.as_slice(),
+        Bound::Unbounded => true,
+    };
+    after_start && before_end
+}
+
+/// Returns `true` if every key between `lower` and `upper` (exclusive, or
+/// unbounded if `None`) is in `range`.
+fn contains_subtree<R: RangeBounds<Vec<u8>>>(
+    range: &R,
+    lower: Option<&[u8]>,
+    upper: Option<&[u8]>,
+) -> bool {
+    let after_start = match range.start_bound() {
+        Bound::Included(start) | Bound::Excluded(start) => {
+            lower.is_some_and(|lower| lower >= start.as_slice())
+        }
+        Bound::Unbounded => true,
+    };
+    let before_end = match range.end_bound() {
+        Bound::Included(end) | Bound::Excluded(end) => {
+            upper.is_some_and(|upper| upper <= end.as_slice())
+        }
+        Bound::Unbounded => true,
+    };
+    after_start && before_end
+}
+
+/// Returns `true` if no key between `lower` and `upper` (exclusive, or
+/// unbounded if `None`) is in `range`.
+fn excludes_subtree<R: RangeBounds<Vec<u8>>>(
+    range: &R,
+    lower: Option<&[u8]>,
+    upper: Option<&[u8]>,
+) -> bool {
+    let before_start = match range.start_bound() {
+        Bound::Included(start) | Bound::Excluded(start) => {
+            upper.is_some_and(|upper| upper <= start.as_slice())
+        }
+        Bound::Unbounded => false,
+    };
+    let after_end = match range.end_bound() {
+        Bound::Included(end) | Bound::Excluded(end) => {
+            lower.is_some_and(|lower| lower >= end.as_slice())
+        }
+        Bound::Unbounded => false,
+    };
+    before_start || after_end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proofs::{encode_proof, ProofEncoding};
+    use crate::test_utils::{make_tree_seq, seq_key};
+    use crate::tree::{HashAlgorithm, NoopCommit, PanicSource, Tree};
+
+    fn make_counted_tree(count: u64) -> Tree {
+        // counts are computed and stored when the modified nodes are committed
+        fn with_counts(tree: Tree) -> Tree {
+            let mut tree = tree
+                .walk(true, |child| child.map(with_counts))
+                .walk(false, |child| child.map(with_counts));
+            tree.set_child_counts((0, 0));
+            tree
+        }
+
+        let mut tree = with_counts(make_tree_seq(count));
+        tree.commit(&mut NoopCommit {}).unwrap();
+        tree
+    }
+
+    fn prove_count<R: RangeBounds<Vec<u8>>>(tree: &mut Tree, range: &R) -> (Vec<u8>, u64) {
+        let mut walker = RefWalker::new(tree, PanicSource {});
+        let (proof, count) = walker.create_count_proof(range).unwrap();
+        let mut bytes = vec![];
+        encode_proof(
+            proof.iter(),
+            HashAlgorithm::default(),
+            true,
+            ProofEncoding::Legacy,
+            &mut bytes,
+        )
+        .unwrap();
+        (bytes, count)
+    }
+
+    #[test]
+    fn count_ranges() {
+        // the tree also contains an initial key between `seq_key(0)` and
+        // `seq_key(1)`
+        let mut tree = make_counted_tree(100);
+        assert_eq!(tree.count(), Some(101));
+        let root_hash = tree.hash();
+
+        let ranges = [
+            (Bound::Unbounded, Bound::Unbounded, 101),
+            (
+                Bound::Included(seq_key(10)),
+                Bound::Excluded(seq_key(20)),
+                10,
+            ),
+            (
+                Bound::Excluded(seq_key(10)),
+                Bound::Included(seq_key(20)),
+                10,
+            ),
+            (
+                Bound::Included(seq_key(0)),
+                Bound::Included(seq_key(99)),
+                101,
+            ),
+            (Bound::Unbounded, Bound::Excluded(seq_key(37)), 38),
+            (Bound::Excluded(seq_key(37)), Bound::Unbounded, 62),
+            (
+                Bound::Included(seq_key(50)),
+                Bound::Included(seq_key(50)),
+                1,
+            ),
+            (
+                Bound::Included(seq_key(50)),
+                Bound::Excluded(seq_key(50)),
+                0,
+            ),
+            (Bound::Included(vec![255]), Bound::Unbounded, 0),
+        ];
+
+        for (start, end, expected) in ranges {
+            let range = (start, end);
+            let (bytes, count) = prove_count(&mut tree, &range);
+            assert_eq!(count, expected, "{range:?}");
+            assert_eq!(
+                verify_count(&bytes, range.clone(), root_hash).unwrap(),
+                expected
+            );
+        }
+
+        // a full range is proven by the root's count alone
+        let (bytes, _) = prove_count(&mut tree, &(..));
+        assert_eq!(bytes.len(), 2 + 1 + 32 + 8);
+    }
+
+    #[test]
+    fn verify_count_invalid() {
+        let mut tree = make_counted_tree(100);
+        let root_hash = tree.hash();
+        let range = seq_key(10)..seq_key(20);
+        let (bytes, _) = prove_count(&mut tree, &range);
+
+        // the proof does not show which keys of its abridged subtrees are in
+        // a wider range
+        assert!(matches!(
+            verify_count(&bytes, seq_key(5)..seq_key(30), root_hash),
+            Err(Error::MissingData)
+        ));
+        assert!(verify_count(&bytes, range.clone(), [0; 32]).is_err());
+
+        // abridged counts are committed to by the hashes
+        let mut ops = Decoder::new(&bytes).collect::<Result<Vec<_>>>().unwrap();
+        for op in ops.iter_mut() {
+            if let Op::Push(Node::HashWithCount(_, count)) = op {
+                *count += 1;
+            }
+        }
+        let mut tampered = vec![];
+        encode_proof(
+            ops.iter(),
+            HashAlgorithm::default(),
+            true,
+            ProofEncoding::Legacy,
+            &mut tampered,
+        )
+        .unwrap();
+        assert!(matches!(
+            verify_count(&tampered, range, root_hash),
+            Err(Error::HashMismatch(_, _))
+        ));
+    }
+
+    #[test]
+    fn verify_count_shifted_counts() {
+        let mut tree = make_counted_tree(100);
+        let root_hash = tree.hash();
+        let range = ..tree.key().to_vec();
+        let (bytes, count) = prove_count(&mut tree, &range);
+
+        // the root's children are both abridged, one inside the range and one
+        // outside of it
+        let mut ops = Decoder::new(&bytes).collect::<Result<Vec<_>>>().unwrap();
+        let mut counts: Vec<&mut u64> = ops
+            .iter_mut()
+            .filter_map(|op| match op {
+                Op::Push(Node::HashWithCount(_, count)) => Some(count),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(counts.len(), 2);
+        assert!(*counts[1] >= 7);
+
+        // moving count between them keeps the total, but not the hash
+        *counts[0] += 7;
+        *counts[1] -= 7;
+        let mut tampered = vec![];
+        encode_proof(
+            ops.iter(),
+            HashAlgorithm::default(),
+            true,
+            ProofEncoding::Legacy,
+            &mut tampered,
+        )
+        .unwrap();
+        assert_eq!(
+            verify_count(&bytes, range.clone(), root_hash).unwrap(),
+            count
+        );
+        assert!(matches!(
+            verify_count(&tampered, range, root_hash),
+            Err(Error::HashMismatch(_, _))
+        ));
+    }
+}
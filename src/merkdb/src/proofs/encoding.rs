@@ -1,10 +1,32 @@
+use std::borrow::Cow;
 use std::io::{Read, Write};
 
 use ed::{Decode, Encode, Terminated};
 
+use super::compact::{self, encode_compact_into, COMPACT_HEADER};
 use super::{Node, Op};
-use crate::error::Result;
-use crate::tree::HASH_LENGTH;
+use crate::error::{Error, Result};
+use crate::tree::{HashAlgorithm, HASH_LENGTH};
+
+/// The first byte of the header which names the algorithm a proof's hashes are
+/// computed with, followed by the algorithm's tag. Proofs without a header are
+/// from stores using the default algorithm, so their encoding is the same as
+/// before the algorithm could be chosen.
+const HASH_ALGORITHM_HEADER: u8 = 0x20;
+
+/// Set in the tag of a proof's header if the proven tree keeps aggregate
+/// counts, so its node hashes commit to subtree counts.
+const AGGREGATE_COUNTS_FLAG: u8 = 0x80;
+
+/// Returns the tag written in a proof's header, naming `hash_algorithm` and
+/// whether the proven tree keeps aggregate counts.
+pub(super) fn header_tag(hash_algorithm: HashAlgorithm, aggregate_counts: bool) -> u8 {
+    if aggregate_counts {
+        hash_algorithm.tag() | AGGREGATE_COUNTS_FLAG
+    } else {
+        hash_algorithm.tag()
+    }
+}
 
 impl Encode for Op {
     fn encode_into<W: Write>(&self, dest: &mut W) -> ed::Result<()> {
@@ -26,6 +48,18 @@ impl Encode for Op {
                 (value.len() as u16).encode_into(dest)?;
                 dest.write_all(value)?;
             }
+            Op::Push(Node::KVDigest(key, value_hash)) => {
+                debug_assert!(key.len() < 256);
+
+                dest.write_all(&[0x04, key.len() as u8])?;
+                dest.write_all(key)?;
+                dest.write_all(value_hash)?;
+            }
+            Op::Push(Node::HashWithCount(hash, count)) => {
+                dest.write_all(&[0x05])?;
+                dest.write_all(hash)?;
+                count.encode_into(dest)?;
+            }
             Op::Parent => dest.write_all(&[0x10])?,
             Op::Child => dest.write_all(&[0x11])?,
         };
@@ -37,6 +71,8 @@ impl Encode for Op {
             Op::Push(Node::Hash(_)) => 1 + HASH_LENGTH,
             Op::Push(Node::KVHash(_)) => 1 + HASH_LENGTH,
             Op::Push(Node::KV(key, value)) => 4 + key.len() + value.len(),
+            Op::Push(Node::KVDigest(key, _)) => 2 + key.len() + HASH_LENGTH,
+            Op::Push(Node::HashWithCount(_, _)) => 1 + HASH_LENGTH + 8,
             Op::Parent => 1,
             Op::Child => 1,
         })
@@ -69,6 +105,22 @@ impl Decode for Op {
 
                 Op::Push(Node::KV(key, value))
             }
+            0x04 => {
+                let key_len: u8 = Decode::decode(&mut input)?;
+                let mut key = vec![0; key_len as usize];
+                input.read_exact(key.as_mut_slice())?;
+
+                let mut value_hash = [0; HASH_LENGTH];
+                input.read_exact(&mut value_hash)?;
+
+                Op::Push(Node::KVDigest(key, value_hash))
+            }
+            0x05 => {
+                let mut hash = [0; HASH_LENGTH];
+                input.read_exact(&mut hash)?;
+                let count: u64 = Decode::decode(&mut input)?;
+                Op::Push(Node::HashWithCount(hash, count))
+            }
             0x10 => Op::Parent,
             0x11 => Op::Child,
             byte => {
@@ -81,7 +133,25 @@ impl Decode for Op {
 impl Terminated for Op {}
 
 impl Op {
+    /// Writes the operator in the legacy encoding. Errors if its key or value
+    /// is too long for the legacy encoding to hold.
     fn encode_into<W: Write>(&self, dest: &mut W) -> Result<()> {
+        let (key_len, value_len) = match self {
+            Op::Push(Node::KV(key, value)) => (key.len(), value.len()),
+            Op::Push(Node::KVDigest(key, _)) => (key.len(), 0),
+            _ => (0, 0),
+        };
+        if key_len > u8::MAX as usize {
+            return Err(Error::Proof(format!(
+                "Key of {key_len} bytes is too long for the legacy proof encoding"
+            )));
+        }
+        if value_len > u16::MAX as usize {
+            return Err(Error::Proof(format!(
+                "Value of {value_len} bytes is too long for the legacy proof encoding"
+            )));
+        }
+
         Ok(Encode::encode_into(self, dest)?)
     }
 
@@ -100,41 +170,167 @@ pub fn encode_into<'a, T: Iterator<Item = &'a Op>>(ops: T, output: &mut Vec<u8>)
     }
 }
 
+/// Writes the header naming `hash_algorithm` to `output`, to be followed by
+/// the proof's operators. `aggregate_counts` is set for proofs of trees which
+/// keep aggregate counts. Nothing is written for the default algorithm without
+/// aggregate counts.
+pub fn encode_header(hash_algorithm: HashAlgorithm, aggregate_counts: bool, output: &mut Vec<u8>) {
+    if hash_algorithm != HashAlgorithm::default() || aggregate_counts {
+        output.push(HASH_ALGORITHM_HEADER);
+        output.push(header_tag(hash_algorithm, aggregate_counts));
+    }
+}
+
+/// The encoding a proof is written in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProofEncoding {
+    /// The original encoding, which every verifier can read. Keys are limited
+    /// to 255 bytes and values to 65,535 bytes, and encoding a proof with a
+    /// longer key or value errors.
+    #[default]
+    Legacy,
+    /// The compact encoding (see `encode_compact_into`), optionally compressed
+    /// with zstd.
+    Compact { compress: bool },
+}
+
+/// Writes a proof consisting of `ops`, whose hashes are computed with
+/// `hash_algorithm`, to `output` in the given encoding. `aggregate_counts` is
+/// set for proofs of trees which keep aggregate counts.
+pub fn encode_proof<'a, T: Iterator<Item = &'a Op>>(
+    ops: T,
+    hash_algorithm: HashAlgorithm,
+    aggregate_counts: bool,
+    encoding: ProofEncoding,
+    output: &mut Vec<u8>,
+) -> Result<()> {
+    match encoding {
+        ProofEncoding::Legacy => {
+            let start = output.len();
+            encode_header(hash_algorithm, aggregate_counts, output);
+            for op in ops {
+                if let Err(err) = op.encode_into(output) {
+                    output.truncate(start);
+                    return Err(err);
+                }
+            }
+            Ok(())
+        }
+        ProofEncoding::Compact { compress } => {
+            encode_compact_into(ops, hash_algorithm, aggregate_counts, compress, output)
+        }
+    }
+}
+
+/// Re-encodes the proof `proof_bytes`, in any encoding, in the given encoding.
+pub fn reencode(proof_bytes: &[u8], encoding: ProofEncoding) -> Result<Vec<u8>> {
+    let decoder = Decoder::new(proof_bytes);
+    let hash_algorithm = decoder.hash_algorithm()?;
+    let aggregate_counts = decoder.aggregate_counts();
+    let ops = decoder.collect::<Result<Vec<_>>>()?;
+
+    let mut bytes = Vec::with_capacity(proof_bytes.len());
+    encode_proof(
+        ops.iter(),
+        hash_algorithm,
+        aggregate_counts,
+        encoding,
+        &mut bytes,
+    )?;
+    Ok(bytes)
+}
+
+/// An iterator over the operators of an encoded proof. Reads both the legacy
+/// and the compact encoding.
 pub struct Decoder<'a> {
     offset: usize,
-    bytes: &'a [u8],
+    bytes: Cow<'a, [u8]>,
+    hash_algorithm_tag: Option<u8>,
+    /// The key of the last `Node::KV` or `Node::KVDigest` read from a compact
+    /// proof, or `None` if the proof uses the legacy encoding.
+    compact_key: Option<Vec<u8>>,
+    /// An error from reading a compact proof's header, yielded by the first
+    /// call to `next`.
+    error: Option<Error>,
 }
 
 impl<'a> Decoder<'a> {
     pub fn new(proof_bytes: &'a [u8]) -> Self {
+        if let [COMPACT_HEADER, ..] = proof_bytes {
+            let (bytes, hash_algorithm_tag, error) = match compact::decode_header(proof_bytes) {
+                Ok((tag, body)) => (body, Some(tag), None),
+                Err(err) => (Cow::Borrowed(&[][..]), None, Some(err)),
+            };
+            return Decoder {
+                offset: 0,
+                bytes,
+                hash_algorithm_tag,
+                compact_key: Some(vec![]),
+                error,
+            };
+        }
+
+        let (offset, hash_algorithm_tag) = match proof_bytes {
+            [HASH_ALGORITHM_HEADER, tag, ..] => (2, Some(*tag)),
+            _ => (0, None),
+        };
+
         Decoder {
-            offset: 0,
-            bytes: proof_bytes,
+            offset,
+            bytes: Cow::Borrowed(proof_bytes),
+            hash_algorithm_tag,
+            compact_key: None,
+            error: None,
         }
     }
+
+    /// Returns the algorithm the proof's hashes are computed with, as named by
+    /// its header. Errors if the header names an unknown algorithm.
+    pub fn hash_algorithm(&self) -> Result<HashAlgorithm> {
+        match self.hash_algorithm_tag {
+            None => Ok(HashAlgorithm::default()),
+            Some(tag) => HashAlgorithm::from_tag(tag & !AGGREGATE_COUNTS_FLAG)
+                .ok_or_else(|| Error::Proof(format!("Unknown hash algorithm tag {tag}"))),
+        }
+    }
+
+    /// Returns `true` if the proof's header says the proven tree keeps
+    /// aggregate counts, so its node hashes commit to subtree counts.
+    pub fn aggregate_counts(&self) -> bool {
+        self.hash_algorithm_tag
+            .is_some_and(|tag| tag & AGGREGATE_COUNTS_FLAG != 0)
+    }
 }
 
 impl<'a> Iterator for Decoder<'a> {
     type Item = Result<Op>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if let Some(err) = self.error.take() {
+            return Some(Err(err));
+        }
         if self.offset >= self.bytes.len() {
             return None;
         }
 
-        Some((|| {
-            let bytes = &self.bytes[self.offset..];
-            let op = Op::decode(bytes)?;
-            self.offset += op.encoding_length();
-            Ok(op)
-        })())
+        let bytes = &self.bytes[self.offset..];
+        Some(match &mut self.compact_key {
+            Some(last_key) => {
+                let mut input = bytes;
+                let op = compact::decode_op(&mut input, last_key);
+                self.offset += bytes.len() - input.len();
+                op
+            }
+            None => Op::decode(bytes).inspect(|op| self.offset += op.encoding_length()),
+        })
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::super::{Node, Op};
-    use crate::tree::HASH_LENGTH;
+    use super::{encode_header, encode_into, encode_proof, reencode, Decoder, ProofEncoding};
+    use crate::tree::{HashAlgorithm, HASH_LENGTH};
 
     #[test]
     fn encode_push_hash() {
@@ -180,6 +376,18 @@ mod test {
         assert_eq!(bytes, vec![0x03, 3, 1, 2, 3, 0, 3, 4, 5, 6]);
     }
 
+    #[test]
+    fn encode_push_kvdigest() {
+        let op = Op::Push(Node::KVDigest(vec![1, 2, 3], [123; HASH_LENGTH]));
+        assert_eq!(op.encoding_length(), 5 + HASH_LENGTH);
+
+        let mut bytes = vec![];
+        op.encode_into(&mut bytes).unwrap();
+        assert_eq!(&bytes[..5], &[0x04, 3, 1, 2, 3]);
+        assert_eq!(&bytes[5..], &[123; HASH_LENGTH]);
+        assert_eq!(Op::decode(&bytes).unwrap(), op);
+    }
+
     #[test]
     fn encode_parent() {
         let op = Op::Parent;
@@ -254,4 +462,117 @@ mod test {
         let bytes = [0x88];
         assert!(Op::decode(&bytes[..]).is_err());
     }
+
+    #[test]
+    fn decode_hash_algorithm_header() {
+        let ops = [Op::Push(Node::KV(vec![1], vec![2])), Op::Parent];
+
+        let mut bytes = vec![];
+        encode_header(HashAlgorithm::Sha512_256, false, &mut bytes);
+        encode_into(ops.iter(), &mut bytes);
+        assert_eq!(bytes[0], 0x03);
+        let decoder = Decoder::new(&bytes);
+        assert_eq!(decoder.hash_algorithm().unwrap(), HashAlgorithm::Sha512_256);
+        assert_eq!(decoder.collect::<Result<Vec<_>, _>>().unwrap(), ops);
+
+        let mut bytes = vec![];
+        encode_header(HashAlgorithm::Blake3, false, &mut bytes);
+        encode_into(ops.iter(), &mut bytes);
+        assert_eq!(&bytes[..2], &[0x20, 1]);
+        let decoder = Decoder::new(&bytes);
+        assert_eq!(decoder.hash_algorithm().unwrap(), HashAlgorithm::Blake3);
+        assert_eq!(decoder.collect::<Result<Vec<_>, _>>().unwrap(), ops);
+
+        assert!(Decoder::new(&[0x20, 99, 0x10]).hash_algorithm().is_err());
+        assert!(Decoder::new(&[0x20]).next().unwrap().is_err());
+    }
+
+    #[test]
+    fn reencode_round_trip() {
+        let ops = [
+            Op::Push(Node::KV(vec![1, 2], vec![3])),
+            Op::Push(Node::Hash([4; HASH_LENGTH])),
+            Op::Child,
+        ];
+        let mut legacy = vec![];
+        encode_header(HashAlgorithm::Blake3, false, &mut legacy);
+        encode_into(ops.iter(), &mut legacy);
+
+        let compact = reencode(&legacy, ProofEncoding::Compact { compress: false }).unwrap();
+        assert_eq!(compact[0], 0x30);
+        let decoder = Decoder::new(&compact);
+        assert_eq!(decoder.hash_algorithm().unwrap(), HashAlgorithm::Blake3);
+        assert_eq!(decoder.collect::<Result<Vec<_>, _>>().unwrap(), ops);
+
+        assert_eq!(reencode(&compact, ProofEncoding::Legacy).unwrap(), legacy);
+    }
+
+    #[test]
+    fn reencode_too_long_for_legacy() {
+        for op in [
+            Op::Push(Node::KV(vec![1; 256], vec![2])),
+            Op::Push(Node::KV(vec![1], vec![2; 65536])),
+            Op::Push(Node::KVDigest(vec![1; 300], [3; HASH_LENGTH])),
+        ] {
+            let ops = [op, Op::Push(Node::Hash([4; HASH_LENGTH])), Op::Child];
+            let mut compact = vec![];
+            encode_proof(
+                ops.iter(),
+                HashAlgorithm::Sha512_256,
+                false,
+                ProofEncoding::Compact { compress: false },
+                &mut compact,
+            )
+            .unwrap();
+            assert!(reencode(&compact, ProofEncoding::Legacy).is_err());
+
+            let mut legacy = vec![9];
+            assert!(encode_proof(
+                ops.iter(),
+                HashAlgorithm::Blake3,
+                false,
+                ProofEncoding::Legacy,
+                &mut legacy,
+            )
+            .is_err());
+            assert_eq!(legacy, vec![9]);
+        }
+    }
+
+    #[test]
+    fn encode_decode_hash_with_count() {
+        let op = Op::Push(Node::HashWithCount([123; HASH_LENGTH], 258));
+        let mut bytes = vec![];
+        op.encode_into(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), op.encoding_length());
+        assert_eq!(bytes[0], 0x05);
+        assert_eq!(&bytes[33..], &[0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(Op::decode(&bytes[..]).unwrap(), op);
+    }
+
+    #[test]
+    fn aggregate_counts_header() {
+        let ops = [
+            Op::Push(Node::KV(vec![1], vec![2])),
+            Op::Push(Node::HashWithCount([4; HASH_LENGTH], 7)),
+            Op::Child,
+        ];
+
+        let mut legacy = vec![];
+        encode_header(HashAlgorithm::Sha512_256, true, &mut legacy);
+        encode_into(ops.iter(), &mut legacy);
+        assert_eq!(&legacy[..2], &[0x20, 0x80]);
+        let decoder = Decoder::new(&legacy);
+        assert!(decoder.aggregate_counts());
+        assert_eq!(decoder.hash_algorithm().unwrap(), HashAlgorithm::Sha512_256);
+
+        let compact = reencode(&legacy, ProofEncoding::Compact { compress: false }).unwrap();
+        let decoder = Decoder::new(&compact);
+        assert!(decoder.aggregate_counts());
+        assert_eq!(decoder.collect::<Result<Vec<_>, _>>().unwrap(), ops);
+        assert_eq!(reencode(&compact, ProofEncoding::Legacy).unwrap(), legacy);
+
+        assert!(!Decoder::new(&[0x20, 1, 0x10]).aggregate_counts());
+        assert!(!Decoder::new(&[0x10]).aggregate_counts());
+    }
 }
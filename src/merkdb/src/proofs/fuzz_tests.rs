@@ -0,0 +1,118 @@
+#![cfg(all(test, feature = "full"))]
+
+use super::query::QueryItem;
+use super::{encode_compact_into, Node, Op, ProofEncoding};
+use crate::test_utils::{check_decode, check_proof, check_restore_chunk};
+use crate::tree::{HashAlgorithm, HASH_LENGTH};
+use proptest::collection::{btree_map, vec};
+use proptest::prelude::*;
+
+/// Keys are drawn from a small alphabet so queries often match them.
+fn key() -> impl Strategy<Value = Vec<u8>> {
+    vec(0..4u8, 1..4)
+}
+
+fn query_item() -> impl Strategy<Value = QueryItem> {
+    prop_oneof![
+        key().prop_map(QueryItem::Key),
+        (key(), key()).prop_map(|(start, end)| QueryItem::Range(start..end)),
+        (key(), key()).prop_map(|(start, end)| QueryItem::RangeInclusive(start..=end)),
+    ]
+}
+
+fn hash_algorithm() -> impl Strategy<Value = HashAlgorithm> {
+    prop_oneof![
+        Just(HashAlgorithm::Sha512_256),
+        Just(HashAlgorithm::Blake3),
+        Just(HashAlgorithm::Sha3_256),
+    ]
+}
+
+fn encoding() -> impl Strategy<Value = ProofEncoding> {
+    prop_oneof![
+        Just(ProofEncoding::Legacy),
+        // compressing proofs requires the zstd feature
+        any::<bool>().prop_map(|compress| ProofEncoding::Compact {
+            compress: compress && cfg!(feature = "zstd")
+        }),
+    ]
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn prove_verify(
+        entries in btree_map(key(), vec(any::<u8>(), 0..8), 1..32),
+        items in vec(query_item(), 1..4),
+        left_to_right in any::<bool>(),
+        hash_algorithm in hash_algorithm(),
+        encoding in encoding(),
+    ) {
+        check_proof(&entries, &items, left_to_right, hash_algorithm, encoding);
+    }
+
+    #[test]
+    fn decode_arbitrary(
+        header in prop_oneof![
+            Just(vec![]),
+            Just(vec![0x20, 0x01]),
+            Just(vec![0x20, 0x82]),
+            Just(vec![0x30, 0x00, 0x00]),
+        ],
+        body in vec(any::<u8>(), 0..256),
+    ) {
+        check_decode(&[header, body].concat());
+    }
+
+    #[test]
+    fn decode_long_keys_and_values(
+        keys in vec(prop_oneof![vec(any::<u8>(), 1..8), vec(any::<u8>(), 250..300)], 1..4),
+        value_len in prop_oneof![0..8usize, 65_530..65_540usize],
+        digest in any::<bool>(),
+    ) {
+        let ops: Vec<Op> = keys
+            .into_iter()
+            .map(|key| {
+                if digest {
+                    Op::Push(Node::KVDigest(key, [1; HASH_LENGTH]))
+                } else {
+                    Op::Push(Node::KV(key, vec![2; value_len]))
+                }
+            })
+            .collect();
+        let mut bytes = vec![];
+        encode_compact_into(ops.iter(), HashAlgorithm::Sha512_256, false, false, &mut bytes)
+            .unwrap();
+        check_decode(&bytes);
+    }
+}
+
+proptest! {
+    // each case restores a store
+    #![proptest_config(ProptestConfig::with_cases(8))]
+
+    #[test]
+    fn restore_arbitrary_chunk(
+        index in any::<usize>(),
+        stated_length in 0..64usize,
+        bytes in vec(any::<u8>(), 0..256)
+    ) {
+        check_restore_chunk(index, stated_length, |_| bytes);
+    }
+
+    #[test]
+    fn restore_mutated_chunk(
+        index in any::<usize>(),
+        stated_length in 0..64usize,
+        position in any::<usize>(),
+        flip in 1..=255u8
+    ) {
+        check_restore_chunk(index, stated_length, |chunk| {
+            let mut chunk = chunk.to_vec();
+            let position = position % chunk.len();
+            chunk[position] ^= flip;
+            chunk
+        });
+    }
+}
@@ -1,11 +1,15 @@
 pub mod chunk;
+pub mod compact;
+pub mod count;
 pub mod encoding;
+mod fuzz_tests;
 pub mod query;
 pub mod tree;
 
 use crate::tree::Hash;
 
-pub use encoding::{encode_into, Decoder};
+pub use compact::encode_compact_into;
+pub use encoding::{encode_header, encode_into, encode_proof, reencode, Decoder, ProofEncoding};
 pub use query::Query;
 pub use tree::Tree;
 
@@ -38,4 +42,14 @@ pub enum Node {
 
     /// Represents the key and value of a tree node.
     KV(Vec<u8>, Vec<u8>),
+
+    /// Represents the key of a tree node and the hash of its value (see
+    /// `HashAlgorithm::value_hash`). Only valid in proofs from stores whose
+    /// hash algorithm supports KV digests.
+    KVDigest(Vec<u8>, Hash),
+
+    /// Represents the hash of a tree node and the number of keys in its
+    /// subtree. Used instead of `Node::Hash` in proofs from stores which keep
+    /// aggregate counts.
+    HashWithCount(Hash, u64),
 }
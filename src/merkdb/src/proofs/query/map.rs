@@ -1,4 +1,6 @@
 use super::super::Node;
+use super::QueryItem;
+use crate::tree::{Hash, HashAlgorithm};
 use crate::{Error, Result};
 use std::collections::btree_map;
 use std::collections::BTreeMap;
@@ -11,33 +13,45 @@ pub(crate) struct MapBuilder(Map);
 impl MapBuilder {
     /// Creates a new `MapBuilder` with an empty internal `Map`.
     pub fn new() -> Self {
+        MapBuilder::with_hash_algorithm(HashAlgorithm::default())
+    }
+
+    /// Creates a new `MapBuilder` for a proof whose hashes are computed with
+    /// `hash_algorithm`, which is used to compute value hashes.
+    pub fn with_hash_algorithm(hash_algorithm: HashAlgorithm) -> Self {
         MapBuilder(Map {
             entries: Default::default(),
             right_edge: true,
+            hash_algorithm,
         })
     }
 
-    /// Adds the node's data to the uncerlying `Map` (if node is type `KV`), or
-    /// makes a note of non-contiguous data (if node is type `KVHash` or
-    /// `Hash`).
+    /// Adds the node's data to the uncerlying `Map` (if node is type `KV` or
+    /// `KVDigest`), or makes a note of non-contiguous data (if node is type
+    /// `KVHash` or `Hash`).
     pub fn insert(&mut self, node: &Node) -> Result<()> {
-        match node {
-            Node::KV(key, value) => {
-                if let Some((prev_key, _)) = self.0.entries.last_key_value() {
-                    if key <= prev_key {
-                        return Err(Error::Key(
-                            "Expected nodes to be in increasing key order".into(),
-                        ));
-                    }
-                }
+        let (key, data) = match node {
+            Node::KV(key, value) => (key, EntryData::Value(value.clone())),
+            Node::KVDigest(key, value_hash) => (key, EntryData::ValueHash(*value_hash)),
+            _ => {
+                self.0.right_edge = false;
+                return Ok(());
+            }
+        };
 
-                let value = (self.0.right_edge, value.clone());
-                self.0.entries.insert(key.clone(), value);
-                self.0.right_edge = true;
+        if let Some((prev_key, _)) = self.0.entries.last_key_value() {
+            if key <= prev_key {
+                return Err(Error::Key(
+                    "Expected nodes to be in increasing key order".into(),
+                ));
             }
-            _ => self.0.right_edge = false,
         }
 
+        self.0
+            .entries
+            .insert(key.clone(), (self.0.right_edge, data));
+        self.0.right_edge = true;
+
         Ok(())
     }
 
@@ -51,9 +65,31 @@ impl MapBuilder {
 /// against a known root hash), and allows a consumer to access the data by
 /// looking up individual keys using the `get` method, or iterating over ranges
 /// using the `range` method.
+///
+/// Entries proven by a `Node::KVDigest` only have a value hash, available
+/// with `value_hash`. Looking up their values returns `Error::MissingData`.
 pub struct Map {
-    entries: BTreeMap<Vec<u8>, (bool, Vec<u8>)>,
+    entries: BTreeMap<Vec<u8>, (bool, EntryData)>,
     right_edge: bool,
+    hash_algorithm: HashAlgorithm,
+}
+
+/// The data a proof includes for an entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum EntryData {
+    Value(Vec<u8>),
+    ValueHash(Hash),
+}
+
+impl EntryData {
+    /// Returns the entry's value, or `Error::MissingData` if the proof only
+    /// includes its value hash.
+    fn value(&self) -> Result<&[u8]> {
+        match self {
+            EntryData::Value(value) => Ok(value),
+            EntryData::ValueHash(_) => Err(Error::MissingData),
+        }
+    }
 }
 
 impl Map {
@@ -63,8 +99,8 @@ impl Map {
     /// valid), an error will be returned.
     pub fn get<'a>(&'a self, key: &'a [u8]) -> Result<Option<&'a [u8]>> {
         // if key is in proof just get from entries
-        if let Some((_, value)) = self.entries.get(key) {
-            return Ok(Some(value.as_slice()));
+        if let Some((_, data)) = self.entries.get(key) {
+            return data.value().map(Some);
         }
 
         // otherwise, use range which only includes exact key match to check
@@ -77,6 +113,26 @@ impl Map {
         Ok(entry)
     }
 
+    /// Gets the hash of the value for a single key (see
+    /// `HashAlgorithm::value_hash`), or `None` if the key was proven to not
+    /// exist in the tree. Unlike `get`, this also works for entries which the
+    /// proof only includes the value hash of.
+    pub fn value_hash(&self, key: &[u8]) -> Result<Option<Hash>> {
+        match self.entries.get(key) {
+            Some((_, EntryData::Value(value))) => {
+                return Ok(Some(self.hash_algorithm.value_hash(value)))
+            }
+            Some((_, EntryData::ValueHash(value_hash))) => return Ok(Some(*value_hash)),
+            None => {}
+        }
+
+        // the key is not in the proof, so this only checks its absence
+        self.range((Bound::Included(key), Bound::Included(key)))
+            .next()
+            .transpose()?;
+        Ok(None)
+    }
+
     /// Returns an iterator over all (key, value) entries in the requested range
     /// of keys. If during iteration we encounter a gap in the data (e.g. the
     /// proof did not include all nodes within the range), the iterator will
@@ -92,6 +148,171 @@ impl Map {
             iter: self.entries.range(bounds),
         }
     }
+
+    /// Returns what the proof shows about the keys matched by `item`: the
+    /// entries which exist in the tree, a proof that none exist, or
+    /// `Membership::NotCovered` if the proof leaves out part of the keyspace
+    /// the item covers, or only includes the value hash of an entry it would
+    /// return.
+    pub fn membership(&self, item: &QueryItem) -> Membership {
+        let lower = item.lower_bound();
+        let (upper, upper_inclusive) = item.upper_bound();
+        let mut present = vec![];
+        let mut right = None;
+
+        let bounds = (Bound::Included(lower), Bound::Unbounded);
+        for (key, (contiguous, data)) in self.entries.range::<[u8], _>(bounds) {
+            // a gap right before an exact match on the lower bound does not
+            // hide any matching keys
+            if !contiguous && key.as_slice() != lower {
+                return Membership::NotCovered;
+            }
+            let value = match data.value() {
+                Ok(value) => value.to_vec(),
+                Err(_) => return Membership::NotCovered,
+            };
+
+            if !item.contains(key) {
+                right = Some((key.clone(), value));
+                break;
+            }
+
+            present.push((key.clone(), value));
+            if upper_inclusive && key.as_slice() == upper {
+                return Membership::Present(present);
+            }
+        }
+
+        // reached the end of the proof, ensure nothing was left out at the
+        // global right edge of the tree
+        if right.is_none() && !self.right_edge {
+            return Membership::NotCovered;
+        }
+
+        if !present.is_empty() {
+            return Membership::Present(present);
+        }
+
+        let left = self
+            .entries
+            .range::<[u8], _>((Bound::Unbounded, Bound::Excluded(lower)))
+            .next_back()
+            .map(|(key, (_, data))| data.value().map(|value| (key.clone(), value.to_vec())))
+            .transpose();
+        match left {
+            Ok(left) => Membership::Absent { left, right },
+            Err(_) => Membership::NotCovered,
+        }
+    }
+
+    /// Appends the entries matching `item` to `out` in ascending key order if
+    /// `left_to_right` is set or descending order otherwise, stopping once
+    /// `out` holds `max` entries.
+    ///
+    /// Returns `Error::MissingData` if the proof leaves out part of the item's
+    /// keyspace before all of its entries (or `max` entries) were collected.
+    pub(crate) fn collect_matches(
+        &self,
+        item: &QueryItem,
+        left_to_right: bool,
+        max: Option<usize>,
+        out: &mut Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<()> {
+        let full = |out: &Vec<_>| max.is_some_and(|max| out.len() >= max);
+        let lower = item.lower_bound();
+        let (upper, upper_inclusive) = item.upper_bound();
+
+        if left_to_right {
+            let bounds = (Bound::Included(lower), Bound::Unbounded);
+            for (key, (contiguous, data)) in self.entries.range::<[u8], _>(bounds) {
+                if !contiguous && key.as_slice() != lower {
+                    return Err(Error::MissingData);
+                }
+                if !item.contains(key) {
+                    return Ok(());
+                }
+
+                out.push((key.clone(), data.value()?.to_vec()));
+                if full(out) || (upper_inclusive && key.as_slice() == upper) {
+                    return Ok(());
+                }
+            }
+
+            return if self.right_edge {
+                Ok(())
+            } else {
+                Err(Error::MissingData)
+            };
+        }
+
+        // an entry's contiguity flag says whether the proof left out anything
+        // just below it, so walking down we check the flag of the entry above
+        let (upper_bound, above) = if upper_inclusive {
+            (Bound::Included(upper), Bound::Excluded(upper))
+        } else {
+            (Bound::Excluded(upper), Bound::Included(upper))
+        };
+        let above = (above, Bound::Unbounded);
+        let mut above_contiguous = match self.entries.range::<[u8], _>(above).next() {
+            Some((_, (contiguous, _))) => *contiguous,
+            None => self.right_edge,
+        };
+
+        let bounds = (Bound::Unbounded, upper_bound);
+        for (key, (contiguous, data)) in self.entries.range::<[u8], _>(bounds).rev() {
+            let exact = upper_inclusive && key.as_slice() == upper;
+            if !above_contiguous && !exact {
+                return Err(Error::MissingData);
+            }
+            if !item.contains(key) {
+                return Ok(());
+            }
+
+            out.push((key.clone(), data.value()?.to_vec()));
+            if full(out) || key.as_slice() == lower {
+                return Ok(());
+            }
+            above_contiguous = *contiguous;
+        }
+
+        if above_contiguous {
+            Ok(())
+        } else {
+            Err(Error::MissingData)
+        }
+    }
+}
+
+/// What a verified proof shows about a queried key or range, as returned by
+/// `Map::membership`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Membership {
+    /// The `(key, value)` entries matching the item, proven to be every
+    /// matching entry in the tree.
+    Present(Vec<(Vec<u8>, Vec<u8>)>),
+
+    /// No key matching the item exists in the tree. `left` and `right` are the
+    /// adjacent entries proving the gap, or `None` at the edges of the tree.
+    Absent {
+        left: Option<(Vec<u8>, Vec<u8>)>,
+        right: Option<(Vec<u8>, Vec<u8>)>,
+    },
+
+    /// The proof does not include enough of the tree to show whether matching
+    /// keys exist.
+    NotCovered,
+}
+
+impl Membership {
+    /// Returns `true` if the proof shows at least one matching entry.
+    pub fn is_present(&self) -> bool {
+        matches!(self, Membership::Present(_))
+    }
+
+    /// Returns `true` if the proof shows no matching entries exist.
+    pub fn is_absent(&self) -> bool {
+        matches!(self, Membership::Absent { .. })
+    }
 }
 
 /// Returns `None` for `Bound::Unbounded`, or the inner key value for
@@ -124,7 +345,7 @@ fn bounds_to_vec<'a, R: RangeBounds<&'a [u8]>>(bounds: R) -> impl RangeBounds<Ve
 pub struct Range<'a> {
     map: &'a Map,
     start_key: Option<Vec<u8>>,
-    iter: btree_map::Range<'a, Vec<u8>, (bool, Vec<u8>)>,
+    iter: btree_map::Range<'a, Vec<u8>, (bool, EntryData)>,
     prev_key: Option<Vec<u8>>,
 }
 
@@ -196,7 +417,7 @@ impl<'a> Iterator for Range<'a> {
         }
 
         // passed checks, return entry
-        Some(Ok((key.as_slice(), value.as_slice())))
+        Some(value.value().map(|value| (key.as_slice(), value)))
     }
 }
 
@@ -248,8 +469,14 @@ mod tests {
 
         let map = builder.build();
         let mut entries = map.entries.iter();
-        assert_eq!(entries.next(), Some((&vec![1, 2, 3], &(true, vec![1]))));
-        assert_eq!(entries.next(), Some((&vec![1, 2, 4], &(false, vec![2]))));
+        assert_eq!(
+            entries.next(),
+            Some((&vec![1, 2, 3], &(true, EntryData::Value(vec![1]))))
+        );
+        assert_eq!(
+            entries.next(),
+            Some((&vec![1, 2, 4], &(false, EntryData::Value(vec![2]))))
+        );
         assert_eq!(entries.next(), None);
         assert!(map.right_edge);
     }
@@ -288,6 +515,105 @@ mod tests {
         assert!(map.get(&[1, 2, 3, 4]).unwrap().is_none());
     }
 
+    fn membership_map() -> Map {
+        let mut builder = MapBuilder::new();
+        builder.insert(&Node::KV(vec![2], vec![20])).unwrap();
+        builder.insert(&Node::KV(vec![4], vec![40])).unwrap();
+        builder.insert(&Node::Hash([0; HASH_LENGTH])).unwrap();
+        builder.insert(&Node::KV(vec![8], vec![80])).unwrap();
+        builder.insert(&Node::KV(vec![9], vec![90])).unwrap();
+        builder.build()
+    }
+
+    #[test]
+    fn membership_keys() {
+        let map = membership_map();
+
+        assert_eq!(
+            map.membership(&QueryItem::Key(vec![4])),
+            Membership::Present(vec![(vec![4], vec![40])])
+        );
+        assert_eq!(
+            map.membership(&QueryItem::Key(vec![8])),
+            Membership::Present(vec![(vec![8], vec![80])])
+        );
+        assert_eq!(
+            map.membership(&QueryItem::Key(vec![3])),
+            Membership::Absent {
+                left: Some((vec![2], vec![20])),
+                right: Some((vec![4], vec![40])),
+            }
+        );
+        assert_eq!(
+            map.membership(&QueryItem::Key(vec![1])),
+            Membership::Absent {
+                left: None,
+                right: Some((vec![2], vec![20])),
+            }
+        );
+        assert_eq!(
+            map.membership(&QueryItem::Key(vec![10])),
+            Membership::Absent {
+                left: Some((vec![9], vec![90])),
+                right: None,
+            }
+        );
+        assert_eq!(
+            map.membership(&QueryItem::Key(vec![6])),
+            Membership::NotCovered
+        );
+    }
+
+    #[test]
+    fn membership_ranges() {
+        let map = membership_map();
+
+        assert_eq!(
+            map.membership(&QueryItem::Range(vec![1]..vec![4])),
+            Membership::Present(vec![(vec![2], vec![20])])
+        );
+        assert_eq!(
+            map.membership(&QueryItem::RangeInclusive(vec![2]..=vec![4])),
+            Membership::Present(vec![(vec![2], vec![20]), (vec![4], vec![40])])
+        );
+        assert_eq!(
+            map.membership(&QueryItem::Range(vec![8]..vec![20])),
+            Membership::Present(vec![(vec![8], vec![80]), (vec![9], vec![90])])
+        );
+        assert_eq!(
+            map.membership(&QueryItem::Range(vec![9, 1]..vec![20])),
+            Membership::Absent {
+                left: Some((vec![9], vec![90])),
+                right: None,
+            }
+        );
+        assert_eq!(
+            map.membership(&QueryItem::Range(vec![3]..vec![9])),
+            Membership::NotCovered
+        );
+        assert_eq!(
+            map.membership(&QueryItem::RangeInclusive(vec![4]..=vec![5])),
+            Membership::NotCovered
+        );
+    }
+
+    #[test]
+    fn membership_abridged_right_edge() {
+        let mut builder = MapBuilder::new();
+        builder.insert(&Node::KV(vec![2], vec![20])).unwrap();
+        builder.insert(&Node::KVHash([0; HASH_LENGTH])).unwrap();
+        let map = builder.build();
+
+        assert_eq!(
+            map.membership(&QueryItem::Key(vec![2])),
+            Membership::Present(vec![(vec![2], vec![20])])
+        );
+        assert_eq!(
+            map.membership(&QueryItem::Key(vec![3])),
+            Membership::NotCovered
+        );
+    }
+
     #[test]
     #[should_panic(expected = "MissingData")]
     fn range_abridged() {
@@ -330,4 +656,34 @@ mod tests {
         range.next().unwrap().unwrap();
         assert_eq!(range.next().unwrap().unwrap(), (&[1][..], &[1][..]));
     }
+
+    #[test]
+    fn kv_digest_entries() {
+        let mut builder = MapBuilder::with_hash_algorithm(HashAlgorithm::Blake3);
+        builder.insert(&Node::KV(vec![1], vec![10])).unwrap();
+        builder
+            .insert(&Node::KVDigest(vec![2], [2; HASH_LENGTH]))
+            .unwrap();
+        builder.insert(&Node::KV(vec![4], vec![40])).unwrap();
+        let map = builder.build();
+
+        assert_eq!(map.value_hash(&[2]).unwrap(), Some([2; HASH_LENGTH]));
+        assert_eq!(
+            map.value_hash(&[1]).unwrap(),
+            Some(HashAlgorithm::Blake3.value_hash(&[10]))
+        );
+        // the digest entry's key is known, so it still bounds the gap
+        assert_eq!(map.value_hash(&[3]).unwrap(), None);
+        assert_eq!(map.get(&[3]).unwrap(), None);
+
+        assert!(matches!(map.get(&[2]), Err(Error::MissingData)));
+        let mut range = map.range(&[1][..]..&[5][..]);
+        assert_eq!(range.next().unwrap().unwrap(), (&[1][..], &[10][..]));
+        assert!(range.next().unwrap().is_err());
+        assert_eq!(
+            map.membership(&QueryItem::Key(vec![2])),
+            Membership::NotCovered
+        );
+        assert!(map.membership(&QueryItem::Key(vec![4])).is_present());
+    }
 }
@@ -1,23 +1,45 @@
 mod map;
 
-#[cfg(feature = "full")]
 use {super::Op, std::collections::LinkedList};
 
 use super::tree::execute;
 use super::{Decoder, Node};
 use crate::error::{Error, Result};
-use crate::tree::{Fetch, Hash, Link, RefWalker};
+use crate::tree::{Fetch, Hash, Link, RefWalker, Tree, NULL_HASH};
 use std::cmp::{max, min, Ordering};
 use std::collections::BTreeSet;
+use std::convert::TryInto;
 use std::ops::{Range, RangeInclusive};
 
 pub use map::*;
 
 /// `Query` represents one or more keys or ranges of keys, which can be used to
 /// resolve a proof which will include all of the requested values.
-#[derive(Default)]
+///
+/// By default every matching entry is included. A limit, an offset and a
+/// direction can be set to only prove a page of the matching entries, e.g. the
+/// last 10 entries of a range.
+///
+/// Proofs include the values of matching entries unless `set_kv_digests` is
+/// used to only include their value hashes.
 pub struct Query {
     items: BTreeSet<QueryItem>,
+    limit: Option<usize>,
+    offset: usize,
+    left_to_right: bool,
+    kv_digests: bool,
+}
+
+impl Default for Query {
+    fn default() -> Self {
+        Query {
+            items: BTreeSet::new(),
+            limit: None,
+            offset: 0,
+            left_to_right: true,
+            kv_digests: false,
+        }
+    }
 }
 
 impl Query {
@@ -26,6 +48,68 @@ impl Query {
         Default::default()
     }
 
+    /// Returns the maximum number of entries the query selects, or `None` if
+    /// it selects every matching entry.
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Sets the maximum number of entries the query selects. Proofs for a
+    /// limited query stop including entries once the limit is reached.
+    pub fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+
+    /// Returns the number of matching entries skipped before the selected
+    /// entries.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Sets the number of matching entries to skip. Skipped entries are still
+    /// included in proofs so that verifiers can count them.
+    pub fn set_offset(&mut self, offset: usize) {
+        self.offset = offset;
+    }
+
+    /// Returns `true` if entries are selected in ascending key order (the
+    /// default), or `false` if they are selected in descending order.
+    pub fn left_to_right(&self) -> bool {
+        self.left_to_right
+    }
+
+    /// Sets the order entries are selected in, which determines which entries
+    /// the offset and limit apply to.
+    pub fn set_left_to_right(&mut self, left_to_right: bool) {
+        self.left_to_right = left_to_right;
+    }
+
+    /// Returns `true` if proofs should include the hashes of matching values
+    /// rather than the values themselves.
+    pub fn kv_digests(&self) -> bool {
+        self.kv_digests
+    }
+
+    /// Sets whether proofs include matching entries as `Node::KVDigest`, with
+    /// the hash of the value instead of the value, which keeps proofs of large
+    /// values small. Verifiers can then only read the value hashes, with
+    /// `Map::value_hash`.
+    ///
+    /// Stores whose hash algorithm does not support KV digests (see
+    /// `HashAlgorithm::supports_kv_digest`), including those using the default
+    /// `Sha512_256`, error when proving such a query.
+    pub fn set_kv_digests(&mut self, kv_digests: bool) {
+        self.kv_digests = kv_digests;
+    }
+
+    /// Returns the offset and limit to be applied while creating a proof.
+    pub(crate) fn proof_limits(&self) -> ProofLimits {
+        ProofLimits {
+            limit: self.limit,
+            offset: self.offset,
+        }
+    }
+
     pub(crate) fn len(&self) -> usize {
         self.items.len()
     }
@@ -88,7 +172,10 @@ impl Query {
 impl<Q: Into<QueryItem>> From<Vec<Q>> for Query {
     fn from(other: Vec<Q>) -> Self {
         let items = other.into_iter().map(Into::into).collect();
-        Query { items }
+        Query {
+            items,
+            ..Default::default()
+        }
     }
 }
 
@@ -208,10 +295,41 @@ impl From<Vec<u8>> for QueryItem {
     }
 }
 
+/// The number of matching entries which remain to be skipped or included
+/// while creating a proof.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct ProofLimits {
+    pub(crate) limit: Option<usize>,
+    pub(crate) offset: usize,
+}
+
+impl ProofLimits {
+    /// Returns `true` once the limit has been reached, after which no more
+    /// entries will be included.
+    fn exhausted(&self) -> bool {
+        self.limit == Some(0)
+    }
+
+    /// Counts a matching entry against the offset, or otherwise the limit.
+    /// Returns `false` if the limit has already been reached, meaning the
+    /// entry should not be included.
+    fn take(&mut self) -> bool {
+        if self.exhausted() {
+            return false;
+        }
+
+        if self.offset > 0 {
+            self.offset -= 1;
+        } else if let Some(limit) = self.limit.as_mut() {
+            *limit -= 1;
+        }
+        true
+    }
+}
+
 impl Link {
     /// Creates a `Node::Hash` from this link. Panics if the link is of variant
     /// `Link::Modified` since its hash has not yet been computed.
-    #[cfg(feature = "full")]
     fn to_hash_node(&self) -> Node {
         let hash = match self {
             Link::Reference { hash, .. } => hash,
@@ -225,6 +343,19 @@ impl Link {
     }
 }
 
+impl Tree {
+    /// Creates a `Node::Hash` from the link to the child on the given side, or
+    /// a `Node::HashWithCount` if the tree keeps aggregate counts. Panics if
+    /// there is no child on that side.
+    pub(crate) fn to_child_hash_node(&self, left: bool) -> Node {
+        let link = self.link(left).expect("Expected child link");
+        match (link.to_hash_node(), self.child_count(left)) {
+            (Node::Hash(hash), Some(count)) => Node::HashWithCount(hash, count),
+            (node, _) => node,
+        }
+    }
+}
+
 impl<'a, S> RefWalker<'a, S>
 where
     S: Fetch + Sized + Send + Clone,
@@ -234,18 +365,36 @@ where
         Node::KV(self.tree().key().to_vec(), self.tree().value().to_vec())
     }
 
+    /// Creates a `Node::KVDigest` from the key and value hash of the root node,
+    /// or a `Node::KV` if the tree's hash algorithm does not support KV
+    /// digests.
+    pub(crate) fn to_kvdigest_node(&self) -> Node {
+        let hash_algorithm = self.tree().hash_algorithm();
+        if !hash_algorithm.supports_kv_digest() {
+            return self.to_kv_node();
+        }
+
+        Node::KVDigest(
+            self.tree().key().to_vec(),
+            hash_algorithm.value_hash(self.tree().value()),
+        )
+    }
+
     /// Creates a `Node::KVHash` from the hash of the key/value pair of the root
     /// node.
     pub(crate) fn to_kvhash_node(&self) -> Node {
         Node::KVHash(*self.tree().kv_hash())
     }
 
-    /// Creates a `Node::Hash` from the hash of the node.
+    /// Creates a `Node::Hash` from the hash of the node, or a
+    /// `Node::HashWithCount` if the tree keeps aggregate counts.
     pub(crate) fn to_hash_node(&self) -> Node {
-        Node::Hash(self.tree().hash())
+        match self.tree().count() {
+            Some(count) => Node::HashWithCount(self.tree().hash(), count),
+            None => Node::Hash(self.tree().hash()),
+        }
     }
 
-    #[cfg(feature = "full")]
     pub(crate) fn execute_query(&mut self, query: &[QueryItem]) -> Result<LinkedList<Op>> {
         let node_key = QueryItem::Key(self.tree().key().to_vec());
         let search = query.binary_search_by(|key| key.cmp(&node_key));
@@ -280,7 +429,6 @@ where
         left_ops.append(&mut right_ops);
         Ok(left_ops)
     }
-    #[cfg(feature = "full")]
     fn execute_child_query(&mut self, left: bool, query: &[QueryItem]) -> Result<LinkedList<Op>> {
         Ok(if !query.is_empty() {
             if let Some(mut child) = self.walk(left)? {
@@ -297,10 +445,27 @@ where
     /// containing the generated proof operators, and a tuple representing if
     /// any keys were queried were less than the left edge or greater than the
     /// right edge, respectively.
-    #[cfg(feature = "full")]
     pub(crate) fn create_proof(
         &mut self,
         query: &[QueryItem],
+    ) -> Result<(LinkedList<Op>, (bool, bool))> {
+        self.create_limited_proof(query, &mut ProofLimits::default(), true, false)
+    }
+
+    /// Like `create_proof`, but only includes matching entries until `limits`
+    /// is exhausted, visiting them in ascending key order if `left_to_right`
+    /// is set or descending order otherwise. Subtrees visited after the limit
+    /// is reached are abridged.
+    ///
+    /// If `kv_digests` is set, matching entries are included as
+    /// `Node::KVDigest`. Entries only included to prove the absence of keys
+    /// keep their values.
+    pub(crate) fn create_limited_proof(
+        &mut self,
+        query: &[QueryItem],
+        limits: &mut ProofLimits,
+        left_to_right: bool,
+        kv_digests: bool,
     ) -> Result<(LinkedList<Op>, (bool, bool))> {
         // TODO: don't copy into vec, support comparing QI to byte slice
         let node_key = QueryItem::Key(self.tree().key().to_vec());
@@ -333,20 +498,33 @@ where
             Err(index) => (&query[..index], &query[index..]),
         };
 
-        let (mut proof, left_absence) = self.create_child_proof(true, left_items)?;
-        let (mut right_proof, right_absence) = self.create_child_proof(false, right_items)?;
+        // visit the children and this node in the order entries are
+        // selected, so the limit is applied to the right entries
+        let (left, matched, right) = if left_to_right {
+            let left =
+                self.create_child_proof(true, left_items, limits, left_to_right, kv_digests)?;
+            let matched = search.is_ok() && limits.take();
+            let right =
+                self.create_child_proof(false, right_items, limits, left_to_right, kv_digests)?;
+            (left, matched, right)
+        } else {
+            let right =
+                self.create_child_proof(false, right_items, limits, left_to_right, kv_digests)?;
+            let matched = search.is_ok() && limits.take();
+            let left =
+                self.create_child_proof(true, left_items, limits, left_to_right, kv_digests)?;
+            (left, matched, right)
+        };
+        let ((mut proof, left_absence), (mut right_proof, right_absence)) = (left, right);
 
         let (has_left, has_right) = (!proof.is_empty(), !right_proof.is_empty());
 
-        proof.push_back(match search {
-            Ok(_) => Op::Push(self.to_kv_node()),
-            Err(_) => {
-                if left_absence.1 || right_absence.0 {
-                    Op::Push(self.to_kv_node())
-                } else {
-                    Op::Push(self.to_kvhash_node())
-                }
-            }
+        proof.push_back(if matched && kv_digests {
+            Op::Push(self.to_kvdigest_node())
+        } else if matched || left_absence.1 || right_absence.0 {
+            Op::Push(self.to_kv_node())
+        } else {
+            Op::Push(self.to_kvhash_node())
         });
 
         if has_left {
@@ -363,21 +541,23 @@ where
 
     /// Similar to `create_proof`. Recurses into the child on the given side and
     /// generates a proof for the queried keys.
-    #[cfg(feature = "full")]
     fn create_child_proof(
         &mut self,
         left: bool,
         query: &[QueryItem],
+        limits: &mut ProofLimits,
+        left_to_right: bool,
+        kv_digests: bool,
     ) -> Result<(LinkedList<Op>, (bool, bool))> {
-        Ok(if !query.is_empty() {
+        Ok(if !query.is_empty() && !limits.exhausted() {
             if let Some(mut child) = self.walk(left)? {
-                child.create_proof(query)?
+                child.create_limited_proof(query, limits, left_to_right, kv_digests)?
             } else {
                 (LinkedList::new(), (true, true))
             }
-        } else if let Some(link) = self.tree().link(left) {
+        } else if self.tree().link(left).is_some() {
             let mut proof = LinkedList::new();
-            proof.push_back(Op::Push(link.to_hash_node()));
+            proof.push_back(Op::Push(self.tree().to_child_hash_node(left)));
             (proof, (false, false))
         } else {
             (LinkedList::new(), (false, false))
@@ -387,9 +567,13 @@ where
 
 pub fn verify(bytes: &[u8], expected_hash: Hash) -> Result<Map> {
     let ops = Decoder::new(bytes);
-    let mut map_builder = MapBuilder::new();
+    let hash_algorithm = ops.hash_algorithm()?;
+    let aggregate_counts = ops.aggregate_counts();
+    let mut map_builder = MapBuilder::with_hash_algorithm(hash_algorithm);
 
-    let root = execute(ops, true, |node| map_builder.insert(node))?;
+    let root = execute(ops, true, hash_algorithm, aggregate_counts, |node| {
+        map_builder.insert(node)
+    })?;
 
     if root.hash()? != expected_hash {
         return Err(Error::HashMismatch(expected_hash, root.hash()?));
@@ -398,6 +582,126 @@ pub fn verify(bytes: &[u8], expected_hash: Hash) -> Result<Map> {
     Ok(map_builder.build())
 }
 
+/// Verifies a proof created by `MultiStore::prove`. `root_proof` must prove
+/// the root hash of the subtree called `name` against `expected_hash`, the
+/// root hash of the multi-store, and `subtree_proof` is then verified against
+/// the subtree's root hash.
+///
+/// Returns a `Map` of the subtree's proven entries. An empty subtree has an
+/// empty proof, and every key is proven absent from it.
+pub fn verify_subtree(
+    root_proof: &[u8],
+    name: &[u8],
+    subtree_proof: &[u8],
+    expected_hash: Hash,
+) -> Result<Map> {
+    let root_map = verify(root_proof, expected_hash)?;
+    let subtree_hash: Hash = root_map
+        .get(name)?
+        .ok_or_else(|| Error::Path(format!("Subtree {name:?} does not exist")))?
+        .try_into()
+        .map_err(|_| Error::Proof("Subtree root hash has invalid length".into()))?;
+
+    if subtree_hash == NULL_HASH {
+        if !subtree_proof.is_empty() {
+            return Err(Error::Proof(
+                "Expected empty proof for empty subtree".into(),
+            ));
+        }
+        return Ok(MapBuilder::new().build());
+    }
+
+    verify(subtree_proof, subtree_hash)
+}
+
+/// Verifies the encoded proof against the expected hash, then returns the
+/// entries `query` selects: the matching entries in the query's direction,
+/// skipping its offset and stopping at its limit.
+///
+/// Returns `Error::MissingData` unless the proof shows that these are exactly
+/// the first matching entries, with none left out before or between them.
+pub fn verify_entries(
+    bytes: &[u8],
+    query: &Query,
+    expected_hash: Hash,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let map = verify(bytes, expected_hash)?;
+
+    let max = query.limit.map(|limit| limit.saturating_add(query.offset));
+    let mut entries = vec![];
+    let items: Box<dyn Iterator<Item = &QueryItem>> = if query.left_to_right {
+        Box::new(query.items.iter())
+    } else {
+        Box::new(query.items.iter().rev())
+    };
+    for item in items {
+        if max.is_some_and(|max| entries.len() >= max) {
+            break;
+        }
+        map.collect_matches(item, query.left_to_right, max, &mut entries)?;
+    }
+
+    let offset = query.offset.min(entries.len());
+    entries.drain(..offset);
+    Ok(entries)
+}
+
+/// Verifies the encoded proof against the expected hash, then determines what
+/// it shows about each item in `query`.
+///
+/// Unlike `verify`, a proof which does not cover every item is not an error:
+/// those items are reported as `Membership::NotCovered`, so callers can tell a
+/// key proven absent apart from one the proof says nothing about.
+pub fn verify_membership(bytes: &[u8], query: &Query, expected_hash: Hash) -> Result<QueryResult> {
+    let map = verify(bytes, expected_hash)?;
+    let items = query
+        .iter()
+        .map(|item| (item.clone(), map.membership(item)))
+        .collect();
+
+    Ok(QueryResult { items })
+}
+
+/// The result of `verify_membership`: what a verified proof shows about each
+/// key or range in a query, in key order.
+#[derive(Clone, Debug)]
+pub struct QueryResult {
+    items: Vec<(QueryItem, Membership)>,
+}
+
+impl QueryResult {
+    /// Gets the membership of a key which was added to the query with
+    /// `Query::insert_key`, or `None` if it was not queried on its own.
+    pub fn get(&self, key: &[u8]) -> Option<&Membership> {
+        self.items.iter().find_map(|(item, membership)| match item {
+            QueryItem::Key(queried) if queried.as_slice() == key => Some(membership),
+            _ => None,
+        })
+    }
+
+    /// Returns an iterator over each queried item along with its membership.
+    pub fn iter(&self) -> std::slice::Iter<(QueryItem, Membership)> {
+        self.items.iter()
+    }
+
+    /// Returns `true` if the proof shows, for every queried item, either its
+    /// entries or their absence.
+    pub fn is_complete(&self) -> bool {
+        self.items
+            .iter()
+            .all(|(_, membership)| *membership != Membership::NotCovered)
+    }
+}
+
+impl IntoIterator for QueryResult {
+    type Item = (QueryItem, Membership);
+    type IntoIter = std::vec::IntoIter<(QueryItem, Membership)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.into_iter()
+    }
+}
+
 /// Verifies the encoded proof with the given query and expected hash.
 ///
 /// Every key in `keys` is checked to either have a key/value pair in the proof,
@@ -420,8 +724,10 @@ pub fn verify_query(
     let mut in_range = false;
 
     let ops = Decoder::new(bytes);
+    let hash_algorithm = ops.hash_algorithm()?;
+    let aggregate_counts = ops.aggregate_counts();
 
-    let root = execute(ops, true, |node| {
+    let root = execute(ops, true, hash_algorithm, aggregate_counts, |node| {
         if let Node::KV(key, value) = node {
             while let Some(item) = query.peek() {
                 // get next item in query
@@ -520,7 +826,7 @@ mod test {
     use super::super::encoding::encode_into;
     use super::super::*;
     use super::*;
-    use crate::test_utils::make_tree_seq;
+    use crate::test_utils::{make_tree_seq, seq_key};
     use crate::tree::{NoopCommit, PanicSource, RefWalker, Tree};
 
     fn make_3_node_tree() -> Result<Tree> {
@@ -618,6 +924,77 @@ mod test {
         verify_keys_test(vec![vec![5], vec![6]], vec![Some(vec![5]), None])
     }
 
+    #[test]
+    fn verify_membership_keys() -> Result<()> {
+        let mut tree = make_3_node_tree()?;
+        let expected_hash = tree.hash();
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+
+        let mut query = Query::new();
+        query.insert_key(vec![2]);
+        query.insert_key(vec![5]);
+        query.insert_key(vec![6]);
+        query.insert_key(vec![8]);
+        let items: Vec<_> = query.iter().cloned().collect();
+        let (proof, _) = walker.create_proof(items.as_slice())?;
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+
+        let result = verify_membership(bytes.as_slice(), &query, expected_hash)?;
+        assert!(result.is_complete());
+        assert_eq!(
+            result.get(&[5]),
+            Some(&Membership::Present(vec![(vec![5], vec![5])]))
+        );
+        assert_eq!(
+            result.get(&[2]),
+            Some(&Membership::Absent {
+                left: None,
+                right: Some((vec![3], vec![3])),
+            })
+        );
+        assert_eq!(
+            result.get(&[6]),
+            Some(&Membership::Absent {
+                left: Some((vec![5], vec![5])),
+                right: Some((vec![7], vec![7])),
+            })
+        );
+        assert_eq!(
+            result.get(&[8]),
+            Some(&Membership::Absent {
+                left: Some((vec![7], vec![7])),
+                right: None,
+            })
+        );
+        assert_eq!(result.get(&[3]), None);
+        Ok(())
+    }
+
+    #[test]
+    fn verify_membership_not_covered() -> Result<()> {
+        let mut tree = make_3_node_tree()?;
+        let expected_hash = tree.hash();
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+
+        let (proof, _) = walker.create_proof(vec![QueryItem::Key(vec![3])].as_slice())?;
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+
+        let mut query = Query::new();
+        query.insert_key(vec![3]);
+        query.insert_range(vec![6]..vec![9]);
+
+        let result = verify_membership(bytes.as_slice(), &query, expected_hash)?;
+        assert!(!result.is_complete());
+        assert!(result.get(&[3]).unwrap().is_present());
+        let memberships: Vec<_> = result.into_iter().map(|(_, m)| m).collect();
+        assert_eq!(memberships[1], Membership::NotCovered);
+
+        assert!(verify_membership(bytes.as_slice(), &query, [42; 32]).is_err());
+        Ok(())
+    }
+
     #[test]
     fn empty_proof() -> Result<()> {
         let mut tree = make_3_node_tree()?;
@@ -1525,4 +1902,125 @@ mod test {
 
         let _result = verify_query(bytes.as_slice(), &query, [42; 32]).expect("verify failed");
     }
+
+    fn limited_proof(tree: &mut Tree, query: &Query) -> Vec<u8> {
+        let mut walker = RefWalker::new(tree, PanicSource {});
+        let items: Vec<_> = query.iter().cloned().collect();
+        let (proof, _) = walker
+            .create_limited_proof(
+                &items,
+                &mut query.proof_limits(),
+                query.left_to_right(),
+                query.kv_digests(),
+            )
+            .expect("create_limited_proof errored");
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+        bytes
+    }
+
+    fn seq_keys(range: std::ops::Range<u64>) -> Vec<Vec<u8>> {
+        range.map(seq_key).collect()
+    }
+
+    fn entry_keys(entries: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<Vec<u8>> {
+        entries.into_iter().map(|(key, _)| key).collect()
+    }
+
+    #[test]
+    fn limited_range_proof() {
+        let mut tree = make_tree_seq(100);
+        let mut query = Query::new();
+        query.insert_range(seq_key(10)..seq_key(90));
+        let full_proof = limited_proof(&mut tree, &query);
+
+        query.set_limit(Some(5));
+        let proof = limited_proof(&mut tree, &query);
+        assert!(proof.len() < full_proof.len());
+
+        let entries = verify_entries(&proof, &query, tree.hash()).unwrap();
+        assert_eq!(entry_keys(entries), seq_keys(10..15));
+
+        // a proof for fewer entries can not satisfy a larger limit
+        query.set_limit(Some(30));
+        assert!(matches!(
+            verify_entries(&proof, &query, tree.hash()),
+            Err(Error::MissingData)
+        ));
+    }
+
+    #[test]
+    fn limited_reverse_proof() {
+        let mut tree = make_tree_seq(100);
+        let mut query = Query::new();
+        query.insert_range(seq_key(10)..seq_key(90));
+        query.set_limit(Some(5));
+        query.set_left_to_right(false);
+        let proof = limited_proof(&mut tree, &query);
+
+        let entries = verify_entries(&proof, &query, tree.hash()).unwrap();
+        let mut expected = seq_keys(85..90);
+        expected.reverse();
+        assert_eq!(entry_keys(entries), expected);
+
+        // the proof does not show the lowest entries of the range
+        query.set_left_to_right(true);
+        assert!(verify_entries(&proof, &query, tree.hash()).is_err());
+    }
+
+    #[test]
+    fn limited_proof_with_offset() {
+        let mut tree = make_tree_seq(100);
+        let mut query = Query::new();
+        query.insert_key(seq_key(3));
+        query.insert_range_inclusive(seq_key(20)..=seq_key(40));
+        query.set_offset(2);
+        query.set_limit(Some(4));
+        let proof = limited_proof(&mut tree, &query);
+
+        let entries = verify_entries(&proof, &query, tree.hash()).unwrap();
+        assert_eq!(entry_keys(entries), seq_keys(21..25));
+
+        query.set_left_to_right(false);
+        let proof = limited_proof(&mut tree, &query);
+        let entries = verify_entries(&proof, &query, tree.hash()).unwrap();
+        let mut expected = seq_keys(35..39);
+        expected.reverse();
+        assert_eq!(entry_keys(entries), expected);
+    }
+
+    #[test]
+    fn limit_beyond_matches() {
+        let mut tree = make_tree_seq(20);
+        let mut query = Query::new();
+        query.insert_range(seq_key(15)..seq_key(30));
+        query.insert_key(seq_key(50));
+        query.set_limit(Some(100));
+        let proof = limited_proof(&mut tree, &query);
+
+        let entries = verify_entries(&proof, &query, tree.hash()).unwrap();
+        assert_eq!(entry_keys(entries), seq_keys(15..20));
+
+        query.set_left_to_right(false);
+        let proof = limited_proof(&mut tree, &query);
+        let entries = verify_entries(&proof, &query, tree.hash()).unwrap();
+        let mut expected = seq_keys(15..20);
+        expected.reverse();
+        assert_eq!(entry_keys(entries), expected);
+    }
+
+    #[test]
+    fn unlimited_proof_unchanged() {
+        let mut tree = make_tree_seq(50);
+        let items = vec![
+            QueryItem::Key(seq_key(3)),
+            QueryItem::Range(seq_key(10)..seq_key(20)),
+        ];
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let (proof, _) = walker.create_proof(&items).unwrap();
+        let (reverse_proof, _) = walker
+            .create_limited_proof(&items, &mut ProofLimits::default(), false, false)
+            .unwrap();
+        assert_eq!(proof, reverse_proof);
+    }
 }
@@ -1,6 +1,6 @@
 use super::{Node, Op};
 use crate::error::{Error, Result};
-use crate::tree::{kv_hash, node_hash, Hash, Hasher, NULL_HASH};
+use crate::tree::{Hash, HashAlgorithm, NULL_HASH};
 
 /// Contains a tree's child node and its hash. The hash can always be assumed to
 /// be up-to-date.
@@ -18,17 +18,17 @@ pub struct Tree {
     pub left: Option<Child>,
     pub right: Option<Child>,
     pub height: usize,
+    pub hash_algorithm: HashAlgorithm,
+    /// The number of keys in the subtree, or `None` if the proven tree does
+    /// not keep aggregate counts.
+    pub count: Option<u64>,
 }
 
 impl From<Node> for Tree {
-    /// Creates a childless tree with the target node as the `node` field.
+    /// Creates a childless tree with the target node as the `node` field,
+    /// hashed with the default algorithm.
     fn from(node: Node) -> Self {
-        Tree {
-            node,
-            left: None,
-            right: None,
-            height: 1,
-        }
+        Tree::new(node, HashAlgorithm::default())
     }
 }
 
@@ -42,18 +42,69 @@ impl PartialEq for Tree {
 }
 
 impl Tree {
+    /// Creates a childless tree with the target node as the `node` field,
+    /// whose hashes are computed with the given algorithm.
+    pub fn new(node: Node, hash_algorithm: HashAlgorithm) -> Self {
+        Tree {
+            node,
+            left: None,
+            right: None,
+            height: 1,
+            hash_algorithm,
+            count: None,
+        }
+    }
+
+    /// Creates a childless tree like `new`, for a proven tree which keeps
+    /// aggregate counts. Its count is the one of a `Node::HashWithCount`, or 1
+    /// for a node with a key.
+    pub fn new_with_count(node: Node, hash_algorithm: HashAlgorithm) -> Self {
+        let count = match node {
+            Node::HashWithCount(_, count) => count,
+            _ => 1,
+        };
+        Tree {
+            count: Some(count),
+            ..Tree::new(node, hash_algorithm)
+        }
+    }
+
     /// Gets or computes the hash for this tree node.
     pub fn hash(&self) -> Result<Hash> {
         fn compute_hash(tree: &Tree, kv_hash: Hash) -> Hash {
-            node_hash::<Hasher>(&kv_hash, &tree.child_hash(true), &tree.child_hash(false))
+            let (left, right) = (tree.child_hash(true), tree.child_hash(false));
+            match tree.count {
+                Some(_) => tree.hash_algorithm.node_hash_with_count(
+                    &kv_hash,
+                    &left,
+                    &right,
+                    tree.child_count(true),
+                    tree.child_count(false),
+                ),
+                None => tree.hash_algorithm.node_hash(&kv_hash, &left, &right),
+            }
         }
 
         match &self.node {
-            Node::Hash(hash) => Ok(*hash),
+            Node::Hash(hash) | Node::HashWithCount(hash, _) => Ok(*hash),
             Node::KVHash(kv_hash) => Ok(compute_hash(self, *kv_hash)),
-            Node::KV(key, value) => kv_hash::<Hasher>(key.as_slice(), value.as_slice())
+            Node::KV(key, value) => self
+                .hash_algorithm
+                .kv_hash(key.as_slice(), value.as_slice())
                 .map(|kv_hash| compute_hash(self, kv_hash))
                 .map_err(Into::into),
+            Node::KVDigest(key, value_hash) => {
+                if !self.hash_algorithm.supports_kv_digest() {
+                    return Err(Error::HashAlgorithm(format!(
+                        "{:?} does not support KV digests",
+                        self.hash_algorithm
+                    )));
+                }
+                self.hash_algorithm
+                    .kv_digest_hash(key.as_slice(), value_hash)
+                    .map(|kv_hash| compute_hash(self, kv_hash))
+                    .map_err(Into::into)
+            }
         }
     }
 
@@ -119,6 +170,19 @@ impl Tree {
             ));
         }
 
+        if let Some(count) = self.count {
+            if let Node::HashWithCount(_, _) = self.node {
+                return Err(Error::Attach(
+                    "Tried to attach child to HashWithCount node".into(),
+                ));
+            }
+            let count = child
+                .count
+                .and_then(|child_count| count.checked_add(child_count))
+                .ok_or_else(|| Error::Attach("Invalid aggregate count".into()))?;
+            self.count = Some(count);
+        }
+
         self.height = self.height.max(child.height + 1);
 
         let hash = child.hash()?;
@@ -136,10 +200,26 @@ impl Tree {
         self.child(left).map_or(NULL_HASH, |c| c.hash)
     }
 
+    /// Returns the number of keys in the subtree of this tree node's child on
+    /// the given side, or 0 if there is no child or the proven tree does not
+    /// keep aggregate counts.
+    #[inline]
+    fn child_count(&self, left: bool) -> u64 {
+        self.child(left)
+            .and_then(|c| c.tree.count)
+            .unwrap_or_default()
+    }
+
     /// Consumes the tree node, calculates its hash, and returns a `Node::Hash`
-    /// variant.
+    /// variant (or a `Node::HashWithCount` one if the tree keeps aggregate
+    /// counts).
     fn try_into_hash(self) -> Result<Tree> {
-        self.hash().map(Node::Hash).map(Into::into)
+        let hash_algorithm = self.hash_algorithm;
+        let hash = self.hash()?;
+        Ok(match self.count {
+            Some(count) => Tree::new_with_count(Node::HashWithCount(hash, count), hash_algorithm),
+            None => Tree::new(Node::Hash(hash), hash_algorithm),
+        })
     }
 
     #[cfg(feature = "full")]
@@ -228,10 +308,21 @@ impl<'a> Iterator for LayerIter<'a> {
 /// `Node::Hash`. If `false`, the returned `Tree` will contain the entire
 /// subtree contained in the proof.
 ///
+/// Hashes are computed with `hash_algorithm`, which should be the algorithm
+/// named by the proof's header (see `Decoder::hash_algorithm`). If
+/// `aggregate_counts` is set (see `Decoder::aggregate_counts`), node hashes
+/// commit to subtree counts, and abridged nodes must be `Node::HashWithCount`.
+///
 /// `visit_node` will be called once for every push operation in the proof, in
 /// key-order. If `visit_node` returns an `Err` result, it will halt the
 /// execution and `execute` will return the error.
-pub(crate) fn execute<I, F>(ops: I, collapse: bool, mut visit_node: F) -> Result<Tree>
+pub(crate) fn execute<I, F>(
+    ops: I,
+    collapse: bool,
+    hash_algorithm: HashAlgorithm,
+    aggregate_counts: bool,
+    mut visit_node: F,
+) -> Result<Tree>
 where
     I: IntoIterator<Item = Result<Op>>,
     F: FnMut(&Node) -> Result<()>,
@@ -273,7 +364,7 @@ where
                 stack.push(parent);
             }
             Op::Push(node) => {
-                if let Node::KV(key, _) = &node {
+                if let Node::KV(key, _) | Node::KVDigest(key, _) = &node {
                     // keys should always increase
                     if let Some(last_key) = &maybe_last_key {
                         if key <= last_key {
@@ -284,10 +375,28 @@ where
                     maybe_last_key = Some(key.clone());
                 }
 
+                match (&node, aggregate_counts) {
+                    (Node::Hash(_), true) => {
+                        return Err(Error::UnexpectedNode(
+                            "Expected HashWithCount node in proof with aggregate counts".into(),
+                        ));
+                    }
+                    (Node::HashWithCount(_, _), false) => {
+                        return Err(Error::UnexpectedNode(
+                            "Unexpected HashWithCount node in proof without aggregate counts"
+                                .into(),
+                        ));
+                    }
+                    _ => {}
+                }
+
                 visit_node(&node)?;
 
-                let tree: Tree = node.into();
-                stack.push(tree);
+                stack.push(if aggregate_counts {
+                    Tree::new_with_count(node, hash_algorithm)
+                } else {
+                    Tree::new(node, hash_algorithm)
+                });
             }
         }
     }
@@ -1,9 +1,58 @@
-use crate::{Merk, Result};
+use crate::{Merk, MerkConfig, Result};
+#[cfg(test)]
+use std::cell::Cell;
 use std::fs;
 use std::mem::ManuallyDrop;
 use std::ops::{Deref, DerefMut};
 use std::path::Path;
 
+/// A point in `Merk::commit` or `Merk::repair` where a crash can be simulated
+/// with `crash_at`. Crash points are only compiled into this crate's unit
+/// tests.
+#[cfg(test)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CrashPoint {
+    /// Before the write batch of a commit is written.
+    CommitBeforeWrite,
+    /// After the write batch of a commit is written, before the write-ahead
+    /// log is synced.
+    CommitAfterWrite,
+    /// After the write-ahead log is synced at the end of a commit (see
+    /// `WalSync::EveryCommit`).
+    CommitAfterFlush,
+    /// After the repaired store is written, before any directory is renamed.
+    RepairBeforeRename,
+    /// After the store is moved aside, before the repaired store is moved into
+    /// its place.
+    RepairMidRename,
+    /// After the repaired store is moved into place, before the old store is
+    /// deleted.
+    RepairBeforeCleanup,
+}
+
+#[cfg(test)]
+thread_local! {
+    static CRASH_POINT: Cell<Option<CrashPoint>> = const { Cell::new(None) };
+}
+
+/// Makes the next time the current thread reaches `point` panic, as if the
+/// process was killed there. Nothing after the point runs, other than the
+/// destructors run while unwinding.
+#[cfg(test)]
+pub(crate) fn crash_at(point: CrashPoint) {
+    CRASH_POINT.with(|armed| armed.set(Some(point)));
+}
+
+/// Panics if `crash_at` was called with `point` on the current thread since it
+/// was last reached.
+#[cfg(test)]
+pub(crate) fn crash_point(point: CrashPoint) {
+    if CRASH_POINT.with(|armed| armed.get()) == Some(point) {
+        CRASH_POINT.with(|armed| armed.set(None));
+        panic!("Simulated crash at {:?}", point);
+    }
+}
+
 /// Wraps a Merk instance and drops it without flushing once it goes out of
 /// scope.
 pub struct CrashMerk {
@@ -15,7 +64,16 @@ impl CrashMerk {
     /// Opens a `CrashMerk` at the given file path, creating a new one if it does
     /// not exist.
     pub fn open<P: AsRef<Path>>(path: P) -> Result<CrashMerk> {
-        let merk = Merk::open(&path)?;
+        CrashMerk::wrap(Merk::open(&path)?, path)
+    }
+
+    /// Opens a `CrashMerk` at the given file path like `open`, with the given
+    /// configuration.
+    pub fn open_with_config<P: AsRef<Path>>(path: P, config: &MerkConfig) -> Result<CrashMerk> {
+        CrashMerk::wrap(config.open(&path)?, path)
+    }
+
+    fn wrap<P: AsRef<Path>>(merk: Merk, path: P) -> Result<CrashMerk> {
         let inner = Some(ManuallyDrop::new(merk));
         Ok(CrashMerk {
             inner,
@@ -66,8 +124,181 @@ impl DerefMut for CrashMerk {
 
 #[cfg(test)]
 mod tests {
-    use super::CrashMerk;
-    use crate::Op;
+    use super::{crash_at, CrashMerk, CrashPoint};
+    use crate::merk::backend::{Backend, MemoryBackend};
+    use crate::proofs::query::{Membership, QueryItem};
+    use crate::proofs::Query;
+    use crate::test_utils::{make_batch_seq, make_del_batch_seq};
+    use crate::tree::{BatchEntry, Hash};
+    use crate::{Merk, MerkConfig, Op, WalSync};
+    use std::collections::BTreeMap;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    /// The entries, root hash and aux value of a store, which every commit in
+    /// these tests writes along with the tree.
+    #[derive(Debug, PartialEq)]
+    struct State {
+        entries: BTreeMap<Vec<u8>, Vec<u8>>,
+        root_hash: Hash,
+        aux: Option<Vec<u8>>,
+    }
+
+    impl State {
+        fn of<B: Backend>(merk: &Merk<B>) -> Self {
+            State {
+                entries: merk.range(..).collect::<crate::Result<_>>().unwrap(),
+                root_hash: merk.root_hash(),
+                aux: merk.get_aux(b"commit").unwrap(),
+            }
+        }
+    }
+
+    /// The batches written by the tests, the last of which is interrupted.
+    fn batches() -> Vec<Vec<BatchEntry>> {
+        vec![
+            make_batch_seq(0..1_000),
+            make_del_batch_seq(0..500),
+            make_batch_seq(750..2_000),
+            make_batch_seq(1_500..3_000),
+        ]
+    }
+
+    fn apply<B: Backend>(merk: &mut Merk<B>, batch: &[BatchEntry], n: usize) {
+        merk.apply(batch, &[(b"commit".to_vec(), Op::Put(vec![n as u8]))])
+            .unwrap();
+    }
+
+    /// Returns the states of a store before and after the last batch.
+    fn expected_states() -> (State, State) {
+        let mut merk = Merk::open_backend(MemoryBackend::new(), 1).unwrap();
+        let batches = batches();
+        for (n, batch) in batches[..batches.len() - 1].iter().enumerate() {
+            apply(&mut merk, batch, n);
+        }
+        let old = State::of(&merk);
+        apply(&mut merk, &batches[batches.len() - 1], batches.len() - 1);
+        (old, State::of(&merk))
+    }
+
+    /// Asserts that the store is in one of the given states, and that its tree
+    /// is intact: a proof of every entry verifies against its root hash.
+    /// Returns the index of the state it is in.
+    fn assert_one_of(merk: &Merk, states: &[&State]) -> usize {
+        let state = State::of(merk);
+        let index = states
+            .iter()
+            .position(|expected| **expected == state)
+            .unwrap_or_else(|| panic!("store is in an unexpected state: {:?}", state.root_hash));
+
+        let item = QueryItem::RangeInclusive(vec![]..=vec![0xff; 9]);
+        let mut query = Query::new();
+        query.insert_item(item.clone());
+        let proof = merk.prove(query).unwrap();
+        let map = crate::verify(&proof, state.root_hash).unwrap();
+        let entries = state.entries.into_iter().collect();
+        assert_eq!(map.membership(&item), Membership::Present(entries));
+
+        index
+    }
+
+    #[test]
+    fn commit_crash_matrix() {
+        let (old, new) = expected_states();
+
+        // where the crash happens, whether the store is flushed first, and the
+        // state the store is expected to be in once it is reopened
+        let cases = [
+            (Some(CrashPoint::CommitBeforeWrite), false, 0),
+            (Some(CrashPoint::CommitAfterWrite), false, 1),
+            (Some(CrashPoint::CommitAfterFlush), false, 1),
+            (None, false, 1),
+            (None, true, 1),
+        ];
+
+        // commits sync the write-ahead log, so there is a flush to crash after
+        let config = MerkConfig::default().wal_sync(WalSync::EveryCommit);
+        for (n, &(crash_point, flush, expected)) in cases.iter().enumerate() {
+            let path = format!("{}-{}", std::thread::current().name().unwrap(), n);
+            let mut merk = CrashMerk::open_with_config(path, &config).expect("failed to open merk");
+            let batches = batches();
+            let (last, batches) = batches.split_last().unwrap();
+            for (n, batch) in batches.iter().enumerate() {
+                apply(&mut merk, batch, n);
+            }
+            assert_eq!(State::of(&merk), old);
+
+            if let Some(point) = crash_point {
+                crash_at(point);
+            }
+            let res = catch_unwind(AssertUnwindSafe(|| {
+                apply(&mut merk, last, batches.len());
+                if flush {
+                    merk.flush().unwrap();
+                }
+            }));
+            assert_eq!(res.is_err(), crash_point.is_some(), "{:?}", crash_point);
+
+            unsafe {
+                merk.crash().unwrap();
+            }
+            assert_eq!(assert_one_of(&merk, &[&old, &new]), expected);
+            merk.destroy().unwrap();
+        }
+    }
+
+    #[test]
+    fn repair_crash_matrix() {
+        let (_, old) = expected_states();
+
+        let points = [
+            CrashPoint::RepairBeforeRename,
+            CrashPoint::RepairMidRename,
+            CrashPoint::RepairBeforeCleanup,
+        ];
+
+        for (n, &point) in points.iter().enumerate() {
+            let path = format!("{}-{}", std::thread::current().name().unwrap(), n);
+            let mut merk = Merk::open(&path).expect("failed to open merk");
+            for (n, batch) in batches().iter().enumerate() {
+                apply(&mut merk, batch, n);
+            }
+            assert_eq!(State::of(&merk), old);
+
+            // the repaired tree holds the same entries, but is built in a
+            // single batch so its shape and root hash differ
+            let repaired = {
+                let mut merk = Merk::open_backend(MemoryBackend::new(), 1).unwrap();
+                let batch: Vec<_> = old
+                    .entries
+                    .iter()
+                    .map(|(key, value)| (key.clone(), Op::Put(value.clone())))
+                    .collect();
+                merk.apply(
+                    &batch,
+                    &[(b"commit".to_vec(), Op::Put(old.aux.clone().unwrap()))],
+                )
+                .unwrap();
+                State::of(&merk)
+            };
+            assert_ne!(repaired.root_hash, old.root_hash);
+
+            crash_at(point);
+            let res = catch_unwind(AssertUnwindSafe(|| merk.repair()));
+            assert!(res.is_err(), "{:?} was not reached", point);
+
+            let merk = Merk::open(&path).expect("failed to reopen merk");
+            let index = assert_one_of(&merk, &[&old, &repaired]);
+            assert_eq!(index, usize::from(point != CrashPoint::RepairBeforeRename));
+
+            // a repair after the crash succeeds
+            let merk = merk.repair().unwrap();
+            assert_one_of(&merk, &[&repaired]);
+            merk.destroy().unwrap();
+            for suffix in &["repair1", "repair2"] {
+                assert!(!std::path::Path::new(&format!("{}-{}", path, suffix)).exists());
+            }
+        }
+    }
 
     #[test]
     #[ignore] // currently this still works because we enabled the WAL
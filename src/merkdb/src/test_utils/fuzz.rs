@@ -0,0 +1,216 @@
+//! Checks of proof generation, decoding and restoring from chunks, shared by
+//! the property tests and the `cargo fuzz` targets in `fuzz/`. Each check
+//! panics if the property it tests does not hold for its input.
+
+use std::collections::BTreeMap;
+use std::env::temp_dir;
+use std::fs::remove_dir_all;
+use std::time::SystemTime;
+
+use crate::backend::MemoryBackend;
+use crate::proofs::query::{verify_membership, Membership, QueryItem};
+use crate::proofs::{encode_proof, Decoder, Node, Op as ProofOp, ProofEncoding, Query};
+use crate::restore::Restorer;
+use crate::test_utils::make_batch_seq;
+use crate::tree::{BatchEntry, HashAlgorithm, Op};
+use crate::{verify, Error, Merk};
+
+/// The number of entries in the store `check_restore_chunk` restores, which
+/// gives a trunk and several leaf chunks.
+const RESTORE_ENTRIES: u64 = 2_000;
+
+/// Checks that a proof of the query made of `items` against a store holding
+/// `entries` verifies against the store's root hash and shows exactly the
+/// matching entries, and that changing any single byte of the proof makes it
+/// fail to verify.
+///
+/// Ranges whose start is after their end are flipped.
+pub fn check_proof(
+    entries: &BTreeMap<Vec<u8>, Vec<u8>>,
+    items: &[QueryItem],
+    left_to_right: bool,
+    hash_algorithm: HashAlgorithm,
+    encoding: ProofEncoding,
+) {
+    if entries.is_empty() || items.is_empty() {
+        return;
+    }
+
+    let mut merk =
+        Merk::open_backend_with_hash_algorithm(MemoryBackend::new(), 1, hash_algorithm).unwrap();
+    let batch: Vec<BatchEntry> = entries
+        .iter()
+        .map(|(key, value)| (key.clone(), Op::Put(value.clone())))
+        .collect();
+    merk.apply(&batch, &[]).unwrap();
+    let root_hash = merk.root_hash();
+
+    let query = || {
+        let mut query = Query::new();
+        for item in items {
+            query.insert_item(ordered(item.clone()));
+        }
+        query.set_left_to_right(left_to_right);
+        query
+    };
+    let proof = merk.prove_with_encoding(query(), encoding).unwrap();
+
+    let result = verify_membership(&proof, &query(), root_hash).unwrap();
+    for (item, membership) in result {
+        let expected: Vec<_> = entries
+            .iter()
+            .filter(|(key, _)| item.contains(key))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        if expected.is_empty() {
+            assert!(membership.is_absent(), "{:?}: {:?}", item, membership);
+        } else {
+            assert_eq!(membership, Membership::Present(expected), "{item:?}");
+        }
+    }
+
+    // some bytes of a compressed frame, such as its header flags, can change
+    // without changing what it decompresses to
+    let ops = decode_ops(&proof).unwrap();
+    for index in 0..proof.len() {
+        for flip in [0x01, 0x80, 0xff] {
+            let mut mutated = proof.clone();
+            mutated[index] ^= flip;
+            assert!(
+                verify(&mutated, root_hash).is_err()
+                    || decode_ops(&mutated).is_ok_and(|mutated| mutated == ops),
+                "Proof with byte {index} changed to {:#04x} was accepted",
+                mutated[index]
+            );
+        }
+    }
+}
+
+fn decode_ops(bytes: &[u8]) -> crate::Result<Vec<ProofOp>> {
+    Decoder::new(bytes).collect()
+}
+
+fn ordered(item: QueryItem) -> QueryItem {
+    match item {
+        QueryItem::Range(range) if range.start > range.end => {
+            QueryItem::Range(range.end..range.start)
+        }
+        QueryItem::RangeInclusive(range) if range.start() > range.end() => {
+            let (start, end) = range.into_inner();
+            QueryItem::RangeInclusive(end..=start)
+        }
+        item => item,
+    }
+}
+
+/// Checks that decoding and verifying arbitrary bytes as a proof returns
+/// errors rather than panicking, and that the operators which do decode are
+/// encoded and decoded again unchanged.
+pub fn check_decode(bytes: &[u8]) {
+    let _ = verify(bytes, [0; 32]);
+
+    let decoder = Decoder::new(bytes);
+    let (hash_algorithm, aggregate_counts) = match decoder.hash_algorithm() {
+        Ok(hash_algorithm) => (hash_algorithm, decoder.aggregate_counts()),
+        Err(_) => return,
+    };
+    let ops: Vec<ProofOp> = decoder.map_while(|op| op.ok()).collect();
+
+    for encoding in [
+        ProofEncoding::Legacy,
+        ProofEncoding::Compact { compress: false },
+    ] {
+        let mut encoded = vec![];
+        let result = encode_proof(
+            ops.iter(),
+            hash_algorithm,
+            aggregate_counts,
+            encoding,
+            &mut encoded,
+        );
+        // the legacy encoding limits the lengths of keys and values
+        if encoding == ProofEncoding::Legacy && !ops.iter().all(fits_legacy) {
+            assert!(
+                result.is_err(),
+                "Legacy encoding accepted a long key or value"
+            );
+            continue;
+        }
+        result.unwrap();
+
+        let decoder = Decoder::new(&encoded);
+        assert_eq!(decoder.hash_algorithm().unwrap(), hash_algorithm);
+        assert_eq!(decoder.aggregate_counts(), aggregate_counts);
+        let decoded: Vec<ProofOp> = decoder.map(Result::unwrap).collect();
+        assert_eq!(decoded, ops);
+    }
+}
+
+fn fits_legacy(op: &ProofOp) -> bool {
+    match op {
+        ProofOp::Push(Node::KV(key, value)) => key.len() <= 255 && value.len() <= 65_535,
+        ProofOp::Push(Node::KVDigest(key, _)) => key.len() <= 255,
+        _ => true,
+    }
+}
+
+/// Checks that a restore rejects the chunk returned by `corrupt` in place of
+/// the chunk at `index` of a store, or if it is accepted, that it does not
+/// change the restored tree. Either way, the restore completes with the
+/// remaining valid chunks.
+///
+/// `corrupt` is given the valid chunk, and `index` is taken modulo the number
+/// of chunks. The restore is first started with `stated_length`, and if that
+/// is not the number of chunks, it checks that the first chunk is rejected
+/// before starting over with the right length.
+pub fn check_restore_chunk(
+    index: usize,
+    stated_length: usize,
+    corrupt: impl FnOnce(&[u8]) -> Vec<u8>,
+) {
+    let mut original = Merk::open_backend(MemoryBackend::new(), 1).unwrap();
+    original
+        .apply(&make_batch_seq(0..RESTORE_ENTRIES), &[])
+        .unwrap();
+    let chunks: Vec<Vec<u8>> = original
+        .chunks()
+        .unwrap()
+        .into_iter()
+        .map(Result::unwrap)
+        .collect();
+    let index = index % chunks.len();
+    let chunk = corrupt(&chunks[index]);
+
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let mut path = temp_dir();
+    path.push(format!("merk-fuzz-restore-{time}"));
+
+    if stated_length != chunks.len() {
+        let mut restorer = Restorer::new(&path, original.root_hash(), stated_length).unwrap();
+        let first = if index == 0 { &chunk } else { &chunks[0] };
+        match restorer.process_chunk(first) {
+            Err(Error::ChunkProcessing(_)) => {}
+            Err(_) if index == 0 => {}
+            result => panic!("Expected {stated_length} chunks to be rejected: {result:?}"),
+        }
+        assert_eq!(restorer.remaining_chunks(), None);
+        drop(restorer);
+        remove_dir_all(&path).unwrap();
+    }
+
+    let mut restorer = Restorer::new(&path, original.root_hash(), chunks.len()).unwrap();
+    for valid in &chunks[..index] {
+        restorer.process_chunk(valid).unwrap();
+    }
+    let accepted = restorer.process_chunk(&chunk).is_ok();
+    for valid in &chunks[index + usize::from(accepted)..] {
+        restorer.process_chunk(valid).unwrap();
+    }
+
+    let restored = restorer.finalize().unwrap();
+    assert_eq!(restored.root_hash(), original.root_hash());
+    restored.destroy().unwrap();
+}
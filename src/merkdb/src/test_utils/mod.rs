@@ -1,4 +1,5 @@
 mod crash_merk;
+mod fuzz;
 mod temp_merk;
 
 use crate::tree::{Batch, BatchEntry, NoopCommit, Op, PanicSource, Tree, Walker};
@@ -8,6 +9,9 @@ use std::convert::TryInto;
 use std::ops::Range;
 
 pub use crash_merk::CrashMerk;
+#[cfg(test)]
+pub(crate) use crash_merk::{crash_point, CrashPoint};
+pub use fuzz::{check_decode, check_proof, check_restore_chunk};
 pub use temp_merk::TempMerk;
 
 pub fn assert_tree_invariants(tree: &Tree) {
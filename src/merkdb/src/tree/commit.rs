@@ -17,6 +17,18 @@ pub trait Commit {
     }
 }
 
+/// A `Commit` which can be split into independent committers, so that the
+/// subtrees of a node can be committed on separate threads with
+/// `Tree::commit_parallel`.
+pub trait SplitCommit: Commit + Send + Sized {
+    /// Returns an empty committer with the same configuration as this one.
+    fn split(&self) -> Self;
+
+    /// Appends the writes gathered by a committer returned from `split`, as if
+    /// they had been made through this one.
+    fn merge(&mut self, other: Self);
+}
+
 /// A `Commit` implementation which does not write to a store and does not prune
 /// any nodes from the Tree. Useful when only keeping a tree in memory.
 pub struct NoopCommit {}
@@ -29,3 +41,11 @@ impl Commit for NoopCommit {
         (false, false)
     }
 }
+
+impl SplitCommit for NoopCommit {
+    fn split(&self) -> Self {
+        NoopCommit {}
+    }
+
+    fn merge(&mut self, _other: Self) {}
+}
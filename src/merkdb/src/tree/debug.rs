@@ -27,7 +27,7 @@ impl Debug for Tree {
             if depth > 0 {
                 // draw ancestor's vertical lines
                 for (low, high) in stack.iter().take(depth - 1) {
-                    let draw_line = cursor.key() > low && cursor.key() < high;
+                    let draw_line = cursor.key() > &low[..] && cursor.key() < &high[..];
                     write!(f, "{}", if draw_line { " │  " } else { "    " }.dimmed())?;
                 }
             }
@@ -69,7 +69,7 @@ impl Debug for Tree {
             if depth > 0 {
                 // draw ancestor's vertical lines
                 for (low, high) in stack.iter().take(depth - 1) {
-                    let draw_line = link.key() > low && link.key() < high;
+                    let draw_line = link.key() > &low[..] && link.key() < &high[..];
                     write!(f, "{}", if draw_line { " │  " } else { "    " }.dimmed()).unwrap();
                 }
             }
@@ -1,6 +1,15 @@
-use super::Tree;
+use std::io::{Read, Write};
+
+use super::kv::KV;
+use super::{HashAlgorithm, Tree, TreeInner, NULL_HASH};
 use ed::{Decode, Encode};
 
+/// The first byte of the encoding of a node which keeps aggregate counts,
+/// followed by the counts of its left and right subtrees. Other nodes start
+/// with their left link, whose first byte is 0 or 1, so their encoding is the
+/// same as before nodes could keep counts.
+const AGGREGATE_COUNTS_MARKER: u8 = 0x02;
+
 impl Tree {
     #[inline]
     pub fn encode(&self) -> Vec<u8> {
@@ -34,6 +43,88 @@ impl Tree {
         tree.inner.kv.key = key;
         tree
     }
+
+    /// Decodes a tree like `decode`, for a store whose hashes are computed
+    /// with the given algorithm (which is not part of the encoding).
+    #[inline]
+    pub fn decode_with_algorithm(
+        key: Vec<u8>,
+        input: &[u8],
+        hash_algorithm: HashAlgorithm,
+    ) -> Tree {
+        let mut tree = Tree::decode(key, input);
+        tree.inner.kv.hash_algorithm = hash_algorithm;
+        tree
+    }
+
+    /// Decodes a tree like `decode_with_algorithm`, returning an error rather
+    /// than panicking if the input is not a valid encoding.
+    pub fn try_decode_with_algorithm(
+        key: Vec<u8>,
+        input: &[u8],
+        hash_algorithm: HashAlgorithm,
+    ) -> ed::Result<Tree> {
+        let mut tree: Tree = Decode::decode(input)?;
+        tree.inner.kv.key = key;
+        tree.inner.kv.hash_algorithm = hash_algorithm;
+        Ok(tree)
+    }
+}
+
+impl Encode for TreeInner {
+    fn encode_into<W: Write>(&self, dest: &mut W) -> ed::Result<()> {
+        if let Some((left_count, right_count)) = self.child_counts {
+            dest.write_all(&[AGGREGATE_COUNTS_MARKER])?;
+            left_count.encode_into(dest)?;
+            right_count.encode_into(dest)?;
+        }
+        self.left.encode_into(dest)?;
+        self.right.encode_into(dest)?;
+        self.kv.encode_into(dest)
+    }
+
+    fn encoding_length(&self) -> ed::Result<usize> {
+        let counts_length = match self.child_counts {
+            Some(_) => 17,
+            None => 0,
+        };
+        Ok(counts_length
+            + self.left.encoding_length()?
+            + self.right.encoding_length()?
+            + self.kv.encoding_length()?)
+    }
+}
+
+impl Decode for TreeInner {
+    fn decode<R: Read>(input: R) -> ed::Result<Self> {
+        let mut inner = TreeInner {
+            left: None,
+            right: None,
+            kv: KV::from_fields(vec![], vec![], NULL_HASH),
+            child_counts: None,
+        };
+        inner.decode_into(input)?;
+        Ok(inner)
+    }
+
+    fn decode_into<R: Read>(&mut self, mut input: R) -> ed::Result<()> {
+        let mut variant: u8 = Decode::decode(&mut input)?;
+        self.child_counts = if variant == AGGREGATE_COUNTS_MARKER {
+            let child_counts = (Decode::decode(&mut input)?, Decode::decode(&mut input)?);
+            variant = Decode::decode(&mut input)?;
+            Some(child_counts)
+        } else {
+            None
+        };
+
+        self.left = match variant {
+            0 => None,
+            1 => Some(Decode::decode(&mut input)?),
+            byte => return Err(ed::Error::UnexpectedByte(byte)),
+        };
+        self.right.decode_into(&mut input)?;
+        self.kv.decode_into(input)
+    }
 }
 
 #[cfg(test)]
@@ -182,4 +273,34 @@ mod tests {
             panic!("Expected Link::Reference");
         }
     }
+
+    #[test]
+    fn encode_counted_tree() {
+        let mut tree = Tree::from_fields(
+            vec![0],
+            vec![1],
+            [55; 32],
+            Some(Link::Reference {
+                hash: [66; 32],
+                child_heights: (1, 0),
+                key: vec![2],
+            }),
+            None,
+        );
+        tree.set_child_counts((3, 0));
+
+        let bytes = tree.encode();
+        assert_eq!(bytes.len(), tree.encoding_length());
+        assert_eq!(
+            &bytes[..17],
+            &[2, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+
+        let decoded = Tree::decode(vec![0], bytes.as_slice());
+        assert_eq!(decoded.count(), Some(4));
+        assert_eq!(decoded.child_count(true), Some(3));
+        assert_eq!(decoded.value(), &[1]);
+        assert_eq!(decoded.hash(), tree.hash());
+        assert_ne!(decoded.hash(), Tree::decode(vec![0], &bytes[17..]).hash());
+    }
 }
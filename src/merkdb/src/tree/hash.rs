@@ -1,7 +1,9 @@
 use sha2::{Digest, Sha512_256};
+use sha3::Sha3_256;
 use std::{convert::TryFrom, num::TryFromIntError};
 
-/// The hash algorithm used for both KV hashes and node hashes.
+/// The hash algorithm used for both KV hashes and node hashes by stores which
+/// do not choose another one (see `HashAlgorithm`).
 pub type Hasher = Sha512_256;
 
 /// The length of a `Hash` (in bytes).
@@ -13,41 +15,346 @@ pub const NULL_HASH: Hash = [0; HASH_LENGTH];
 /// A cryptographic hash digest.
 pub type Hash = [u8; HASH_LENGTH];
 
+/// A hash algorithm which a store can use for its KV hashes and node hashes.
+/// Every algorithm produces `HASH_LENGTH`-byte digests.
+///
+/// The algorithm is chosen when a store is created, and is identified by a
+/// one-byte tag in the store and in the proofs it creates.
+///
+/// `Sha512_256` hashes a node's value as part of its KV hash. The other
+/// algorithms hash the value on its own first and commit to that value hash,
+/// which lets proofs include a node's key and value hash instead of its whole
+/// value (see `proofs::Node::KVDigest`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    /// SHA-512/256, used by stores which were created before the algorithm
+    /// could be chosen.
+    #[default]
+    Sha512_256,
+    /// BLAKE3, with its default 32-byte output.
+    Blake3,
+    /// SHA3-256.
+    Sha3_256,
+}
+
+impl HashAlgorithm {
+    /// Returns the tag which identifies the algorithm in stores and proofs.
+    pub fn tag(self) -> u8 {
+        match self {
+            HashAlgorithm::Sha512_256 => 0,
+            HashAlgorithm::Blake3 => 1,
+            HashAlgorithm::Sha3_256 => 2,
+        }
+    }
+
+    /// Returns the algorithm identified by `tag`, or `None` if the tag is
+    /// unknown.
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(HashAlgorithm::Sha512_256),
+            1 => Some(HashAlgorithm::Blake3),
+            2 => Some(HashAlgorithm::Sha3_256),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if KV hashes commit to the value hash rather than to the
+    /// value itself, so a node's KV hash can be computed from its key and
+    /// `value_hash` (see `kv_digest_hash`).
+    pub fn supports_kv_digest(self) -> bool {
+        self != HashAlgorithm::Sha512_256
+    }
+
+    /// Hashes a key/value pair with this algorithm. See `kv_hash`.
+    pub fn kv_hash(self, key: &[u8], value: &[u8]) -> Result<Hash, TryFromIntError> {
+        match self {
+            HashAlgorithm::Sha512_256 => kv_hash::<Sha512_256>(key, value),
+            _ => self.kv_digest_hash(key, &self.value_hash(value)),
+        }
+    }
+
+    /// Hashes a value on its own, which is a plain digest of the value's bytes.
+    pub fn value_hash(self, value: &[u8]) -> Hash {
+        match self {
+            HashAlgorithm::Sha512_256 => to_hash(&Sha512_256::digest(value)),
+            HashAlgorithm::Blake3 => *blake3::hash(value).as_bytes(),
+            HashAlgorithm::Sha3_256 => to_hash(&Sha3_256::digest(value)),
+        }
+    }
+
+    /// Computes the KV hash of a key/value pair from the key and the value's
+    /// `value_hash`. This is only the node's KV hash for algorithms which
+    /// `supports_kv_digest`.
+    pub fn kv_digest_hash(self, key: &[u8], value_hash: &Hash) -> Result<Hash, TryFromIntError> {
+        match self {
+            HashAlgorithm::Sha512_256 => digest_kv_digest::<Sha512_256>(key, value_hash),
+            HashAlgorithm::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                write_kv_digest(
+                    |bytes| {
+                        hasher.update(bytes);
+                    },
+                    key,
+                    value_hash,
+                )?;
+                Ok(*hasher.finalize().as_bytes())
+            }
+            HashAlgorithm::Sha3_256 => digest_kv_digest::<Sha3_256>(key, value_hash),
+        }
+    }
+
+    /// Hashes a node with this algorithm. See `node_hash`.
+    pub fn node_hash(self, kv: &Hash, left: &Hash, right: &Hash) -> Hash {
+        match self {
+            HashAlgorithm::Sha512_256 => node_hash::<Sha512_256>(kv, left, right),
+            HashAlgorithm::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                write_node(
+                    |bytes| {
+                        hasher.update(bytes);
+                    },
+                    kv,
+                    left,
+                    right,
+                );
+                *hasher.finalize().as_bytes()
+            }
+            HashAlgorithm::Sha3_256 => node_hash::<Sha3_256>(kv, left, right),
+        }
+    }
+
+    /// Hashes a node of a tree which keeps aggregate counts, committing to
+    /// `left_count` and `right_count`, the numbers of keys in the subtrees of
+    /// its left and right children (0 if there is no child). Committing to
+    /// each count separately means the counts of a node's two children can not
+    /// be traded against each other.
+    pub fn node_hash_with_count(
+        self,
+        kv: &Hash,
+        left: &Hash,
+        right: &Hash,
+        left_count: u64,
+        right_count: u64,
+    ) -> Hash {
+        match self {
+            HashAlgorithm::Sha512_256 => {
+                node_hash_with_count::<Sha512_256>(kv, left, right, left_count, right_count)
+            }
+            HashAlgorithm::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                write_node_with_count(
+                    |bytes| {
+                        hasher.update(bytes);
+                    },
+                    kv,
+                    left,
+                    right,
+                    left_count,
+                    right_count,
+                );
+                *hasher.finalize().as_bytes()
+            }
+            HashAlgorithm::Sha3_256 => {
+                node_hash_with_count::<Sha3_256>(kv, left, right, left_count, right_count)
+            }
+        }
+    }
+}
+
 /// Hashes a key/value pair.
 ///
 /// **NOTE:** This will error if the key is longer than 4,294,967,296 bytes, or the value
 /// is longer than 4,294,967,296 bytes.
 pub fn kv_hash<D: Digest>(key: &[u8], value: &[u8]) -> Result<Hash, TryFromIntError> {
     let mut hasher = D::new();
-    hasher.update([0]);
-
-    u32::try_from(key.len())
-        .and_then(|key| u32::try_from(value.len()).map(|value| (key, value)))
-        .map(|(key_length, val_length)| {
-            hasher.update(key_length.to_le_bytes());
-            hasher.update(key);
-
-            hasher.update(val_length.to_le_bytes());
-            hasher.update(value);
-
-            let res = hasher.finalize();
-            let mut hash: Hash = Default::default();
-            hash.copy_from_slice(&res[..]);
-            hash
-        })
+    write_kv(|bytes| hasher.update(bytes), key, value)?;
+    Ok(to_hash(&hasher.finalize()))
 }
 
 /// Hashes a node based on the hash of its key/value pair, the hash of its left
 /// child (if any), and the hash of its right child (if any).
 pub fn node_hash<D: Digest>(kv: &Hash, left: &Hash, right: &Hash) -> Hash {
     let mut hasher = D::new();
-    hasher.update([1]);
-    hasher.update(kv);
-    hasher.update(left);
-    hasher.update(right);
+    write_node(|bytes| hasher.update(bytes), kv, left, right);
+    to_hash(&hasher.finalize())
+}
 
-    let res = hasher.finalize();
+fn node_hash_with_count<D: Digest>(
+    kv: &Hash,
+    left: &Hash,
+    right: &Hash,
+    left_count: u64,
+    right_count: u64,
+) -> Hash {
+    let mut hasher = D::new();
+    write_node_with_count(
+        |bytes| hasher.update(bytes),
+        kv,
+        left,
+        right,
+        left_count,
+        right_count,
+    );
+    to_hash(&hasher.finalize())
+}
+
+/// Feeds the preimage of a KV hash to `update`.
+fn write_kv(
+    mut update: impl FnMut(&[u8]),
+    key: &[u8],
+    value: &[u8],
+) -> Result<(), TryFromIntError> {
+    let key_length = u32::try_from(key.len())?;
+    let val_length = u32::try_from(value.len())?;
+
+    update(&[0]);
+    update(&key_length.to_le_bytes());
+    update(key);
+    update(&val_length.to_le_bytes());
+    update(value);
+    Ok(())
+}
+
+fn digest_kv_digest<D: Digest>(key: &[u8], value_hash: &Hash) -> Result<Hash, TryFromIntError> {
+    let mut hasher = D::new();
+    write_kv_digest(|bytes| hasher.update(bytes), key, value_hash)?;
+    Ok(to_hash(&hasher.finalize()))
+}
+
+/// Feeds the preimage of a KV hash which commits to the value hash to
+/// `update`.
+fn write_kv_digest(
+    mut update: impl FnMut(&[u8]),
+    key: &[u8],
+    value_hash: &Hash,
+) -> Result<(), TryFromIntError> {
+    let key_length = u32::try_from(key.len())?;
+
+    update(&[0]);
+    update(&key_length.to_le_bytes());
+    update(key);
+    update(value_hash);
+    Ok(())
+}
+
+/// Feeds the preimage of a node hash to `update`.
+fn write_node(mut update: impl FnMut(&[u8]), kv: &Hash, left: &Hash, right: &Hash) {
+    update(&[1]);
+    update(kv);
+    update(left);
+    update(right);
+}
+
+/// Feeds the preimage of a node hash which commits to the counts of the node's
+/// child subtrees to `update`. The leading byte keeps it distinct from
+/// `write_node`.
+fn write_node_with_count(
+    mut update: impl FnMut(&[u8]),
+    kv: &Hash,
+    left: &Hash,
+    right: &Hash,
+    left_count: u64,
+    right_count: u64,
+) {
+    update(&[2]);
+    update(kv);
+    update(left);
+    update(right);
+    update(&left_count.to_be_bytes());
+    update(&right_count.to_be_bytes());
+}
+
+fn to_hash(digest: &[u8]) -> Hash {
     let mut hash: Hash = Default::default();
-    hash.copy_from_slice(&res[..]);
+    hash.copy_from_slice(digest);
     hash
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALGORITHMS: [HashAlgorithm; 3] = [
+        HashAlgorithm::Sha512_256,
+        HashAlgorithm::Blake3,
+        HashAlgorithm::Sha3_256,
+    ];
+
+    #[test]
+    fn tags_round_trip() {
+        for algorithm in ALGORITHMS {
+            assert_eq!(HashAlgorithm::from_tag(algorithm.tag()), Some(algorithm));
+        }
+        assert_eq!(HashAlgorithm::from_tag(3), None);
+    }
+
+    #[test]
+    fn algorithms_differ() {
+        let kv_hashes: Vec<_> = ALGORITHMS
+            .iter()
+            .map(|algorithm| algorithm.kv_hash(b"key", b"value").unwrap())
+            .collect();
+        assert_eq!(kv_hashes[0], kv_hash::<Hasher>(b"key", b"value").unwrap());
+        assert_ne!(kv_hashes[0], kv_hashes[1]);
+        assert_ne!(kv_hashes[1], kv_hashes[2]);
+        assert_ne!(kv_hashes[0], kv_hashes[2]);
+
+        let node_hashes: Vec<_> = ALGORITHMS
+            .iter()
+            .map(|algorithm| algorithm.node_hash(&kv_hashes[0], &NULL_HASH, &NULL_HASH))
+            .collect();
+        assert_eq!(
+            node_hashes[0],
+            node_hash::<Hasher>(&kv_hashes[0], &NULL_HASH, &NULL_HASH)
+        );
+        assert_ne!(node_hashes[0], node_hashes[1]);
+        assert_ne!(node_hashes[1], node_hashes[2]);
+    }
+
+    #[test]
+    fn blake3_matches_reference() {
+        let mut preimage = vec![0];
+        preimage.extend_from_slice(&3u32.to_le_bytes());
+        preimage.extend_from_slice(b"key");
+        preimage.extend_from_slice(blake3::hash(b"value").as_bytes());
+        assert_eq!(
+            HashAlgorithm::Blake3.kv_hash(b"key", b"value").unwrap(),
+            *blake3::hash(&preimage).as_bytes()
+        );
+    }
+
+    #[test]
+    fn kv_digest() {
+        for algorithm in ALGORITHMS {
+            let value_hash = algorithm.value_hash(b"value");
+            let kv_hash = algorithm.kv_hash(b"key", b"value").unwrap();
+            let digest_hash = algorithm.kv_digest_hash(b"key", &value_hash).unwrap();
+            assert_eq!(kv_hash == digest_hash, algorithm.supports_kv_digest());
+        }
+        assert!(!HashAlgorithm::Sha512_256.supports_kv_digest());
+        assert_eq!(
+            HashAlgorithm::Sha3_256.value_hash(b"value"),
+            to_hash(&Sha3_256::digest(b"value"))
+        );
+    }
+
+    #[test]
+    fn node_hash_with_count() {
+        for algorithm in ALGORITHMS {
+            let kv_hash = algorithm.kv_hash(b"key", b"value").unwrap();
+            let plain = algorithm.node_hash(&kv_hash, &NULL_HASH, &NULL_HASH);
+            let counted = algorithm.node_hash_with_count(&kv_hash, &NULL_HASH, &NULL_HASH, 0, 0);
+            assert_ne!(plain, counted);
+            assert_ne!(
+                counted,
+                algorithm.node_hash_with_count(&kv_hash, &NULL_HASH, &NULL_HASH, 0, 1)
+            );
+
+            // moving count from one child to the other changes the hash
+            assert_ne!(
+                algorithm.node_hash_with_count(&kv_hash, &NULL_HASH, &NULL_HASH, 3, 4),
+                algorithm.node_hash_with_count(&kv_hash, &NULL_HASH, &NULL_HASH, 4, 3)
+            );
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use super::hash::{kv_hash, Hash, Hasher, HASH_LENGTH, NULL_HASH};
+use super::hash::{Hash, HashAlgorithm, HASH_LENGTH, NULL_HASH};
 use ed::{Decode, Encode, Result};
 use std::{
     io::{Read, Write},
@@ -11,24 +11,51 @@ use std::{
 //       field and value field.
 
 /// Contains a key/value pair, and the hash of the key/value pair.
+///
+/// The hash algorithm is not part of the encoding, since it is the same for
+/// every node of a store.
 pub struct KV {
     pub(super) key: Vec<u8>,
     pub(super) value: Vec<u8>,
     pub(super) hash: Hash,
+    pub(super) hash_algorithm: HashAlgorithm,
 }
 
 impl KV {
     /// Creates a new `KV` with the given key and value and computes its hash.
     #[inline]
     pub fn new(key: Vec<u8>, value: Vec<u8>) -> std::result::Result<Self, TryFromIntError> {
-        kv_hash::<Hasher>(key.as_slice(), value.as_slice()).map(|hash| KV { key, value, hash })
+        KV::new_with_algorithm(key, value, HashAlgorithm::default())
+    }
+
+    /// Creates a new `KV` like `new`, and computes its hash with the given
+    /// algorithm.
+    #[inline]
+    pub fn new_with_algorithm(
+        key: Vec<u8>,
+        value: Vec<u8>,
+        hash_algorithm: HashAlgorithm,
+    ) -> std::result::Result<Self, TryFromIntError> {
+        hash_algorithm
+            .kv_hash(key.as_slice(), value.as_slice())
+            .map(|hash| KV {
+                key,
+                value,
+                hash,
+                hash_algorithm,
+            })
     }
 
     /// Creates a new `KV` with the given key, value, and hash. The hash is not
     /// checked to be correct for the given key/value.
     #[inline]
     pub fn from_fields(key: Vec<u8>, value: Vec<u8>, hash: Hash) -> Self {
-        KV { key, value, hash }
+        KV {
+            key,
+            value,
+            hash,
+            hash_algorithm: HashAlgorithm::default(),
+        }
     }
 
     /// Replaces the `KV`'s value with the given value, updates the hash, and
@@ -36,7 +63,7 @@ impl KV {
     #[inline]
     pub fn with_value(mut self, value: Vec<u8>) -> std::result::Result<Self, TryFromIntError> {
         self.value = value;
-        self.hash = kv_hash::<Hasher>(self.key(), self.value())?;
+        self.hash = self.hash_algorithm.kv_hash(self.key(), self.value())?;
         Ok(self)
     }
 
@@ -58,6 +85,12 @@ impl KV {
         &self.hash
     }
 
+    /// Returns the algorithm the hash is computed with.
+    #[inline]
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+    }
+
     /// Consumes the `KV` and returns its key without allocating or cloning.
     #[inline]
     pub fn take_key(self) -> Vec<u8> {
@@ -87,6 +120,7 @@ impl Decode for KV {
             key: Vec::with_capacity(0),
             value: Vec::with_capacity(128),
             hash: NULL_HASH,
+            hash_algorithm: HashAlgorithm::default(),
         };
         KV::decode_into(&mut kv, input)?;
         Ok(kv)
@@ -128,4 +162,17 @@ mod test {
         assert_ne!(kv.hash(), &super::super::hash::NULL_HASH);
         Ok(())
     }
+
+    #[test]
+    fn with_value_keeps_algorithm() -> std::result::Result<(), TryFromIntError> {
+        let kv = KV::new_with_algorithm(vec![1, 2, 3], vec![4, 5, 6], HashAlgorithm::Blake3)?
+            .with_value(vec![7, 8, 9])?;
+
+        assert_eq!(kv.hash_algorithm(), HashAlgorithm::Blake3);
+        assert_eq!(
+            kv.hash(),
+            &HashAlgorithm::Blake3.kv_hash(&[1, 2, 3], &[7, 8, 9])?
+        );
+        Ok(())
+    }
 }
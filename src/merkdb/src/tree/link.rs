@@ -174,6 +174,22 @@ impl Link {
         }
     }
 
+    /// Returns a `Link::Reference` to the same child. Panics if the link is of
+    /// variant `Link::Modified`, since its hash is not known.
+    #[inline]
+    pub(crate) fn to_reference(&self) -> Self {
+        match self {
+            Link::Modified { .. } => panic!("Cannot reference Modified tree"),
+            Link::Reference { child_heights, .. }
+            | Link::Uncommitted { child_heights, .. }
+            | Link::Loaded { child_heights, .. } => Link::Reference {
+                hash: *self.hash(),
+                child_heights: *child_heights,
+                key: self.key().to_vec(),
+            },
+        }
+    }
+
     #[inline]
     #[cfg(feature = "full")]
     pub(crate) fn child_heights_mut(&mut self) -> &mut (u8, u8) {
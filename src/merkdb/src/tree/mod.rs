@@ -15,22 +15,24 @@ use std::cmp::max;
 use ed::{Decode, Encode};
 
 use super::error::Result;
-pub use commit::{Commit, NoopCommit};
-pub use hash::{kv_hash, node_hash, Hash, Hasher, HASH_LENGTH, NULL_HASH};
+pub use commit::{Commit, NoopCommit, SplitCommit};
+pub use hash::{kv_hash, node_hash, Hash, HashAlgorithm, Hasher, HASH_LENGTH, NULL_HASH};
 use kv::KV;
 pub use link::Link;
-pub use ops::{Batch, BatchEntry, Op, PanicSource};
+pub use ops::{Batch, BatchEntry, Op, PanicSource, MIN_PARALLEL_BATCH_SIZE};
 pub use walk::{Fetch, RefWalker, Walker};
 
 // TODO: remove need for `TreeInner`, and just use `Box<Self>` receiver for
 // relevant methods
 
 /// The fields of the `Tree` type, stored on the heap.
-#[derive(Encode, Decode)]
 pub struct TreeInner {
     left: Option<Link>,
     right: Option<Link>,
     kv: KV,
+    /// The number of keys in the left and right subtrees as of the last
+    /// commit, or `None` if the tree does not keep aggregate counts.
+    child_counts: Option<(u64, u64)>,
 }
 
 /// A binary AVL tree data structure, with Merkle hashes.
@@ -48,13 +50,27 @@ impl Tree {
     ///
     /// Hashes the key/value pair and initializes the `kv_hash` field.
     pub fn new(key: Vec<u8>, value: Vec<u8>) -> Result<Self> {
-        KV::new(key, value).map_err(Into::into).map(|kv| Tree {
-            inner: Box::new(TreeInner {
-                kv,
-                left: None,
-                right: None,
-            }),
-        })
+        Tree::new_with_algorithm(key, value, HashAlgorithm::default())
+    }
+
+    /// Creates a new `Tree` like `new`, whose hashes are computed with the
+    /// given algorithm. When applying batches, new nodes are created with the
+    /// algorithm of the `Fetch` source (see `Fetch::hash_algorithm`).
+    pub fn new_with_algorithm(
+        key: Vec<u8>,
+        value: Vec<u8>,
+        hash_algorithm: HashAlgorithm,
+    ) -> Result<Self> {
+        KV::new_with_algorithm(key, value, hash_algorithm)
+            .map_err(Into::into)
+            .map(|kv| Tree {
+                inner: Box::new(TreeInner {
+                    kv,
+                    left: None,
+                    right: None,
+                    child_counts: None,
+                }),
+            })
     }
 
     /// Creates a `Tree` by supplying all the raw struct fields (mainly useful
@@ -71,10 +87,56 @@ impl Tree {
                 kv: KV::from_fields(key, value, kv_hash),
                 left,
                 right,
+                child_counts: None,
+            }),
+        }
+    }
+
+    /// Makes a new tree keep aggregate counts, so its hash commits to the
+    /// number of keys in its subtree (see `count`). When applying batches, new
+    /// nodes keep counts if the `Fetch` source's do (see
+    /// `Fetch::aggregate_counts`).
+    pub fn with_aggregate_counts(mut self) -> Self {
+        if self.inner.child_counts.is_none() {
+            self.inner.child_counts = Some((0, 0));
+        }
+        self
+    }
+
+    /// Returns a copy of the root node, linking to its children with
+    /// `Link::Reference`s so none of its subtrees are copied. Panics if a child
+    /// has been modified since the tree's hashes were last computed.
+    pub(crate) fn clone_pruned(&self) -> Tree {
+        let kv = &self.inner.kv;
+        Tree {
+            inner: Box::new(TreeInner {
+                kv: KV {
+                    key: kv.key.clone(),
+                    value: kv.value.clone(),
+                    hash: kv.hash,
+                    hash_algorithm: kv.hash_algorithm,
+                },
+                left: self.link(true).map(Link::to_reference),
+                right: self.link(false).map(Link::to_reference),
+                child_counts: self.inner.child_counts,
             }),
         }
     }
 
+    /// Returns an estimate of the number of bytes of memory the root node
+    /// uses, not including the nodes of its subtrees.
+    pub(crate) fn memory_size(&self) -> usize {
+        let link_key_len = |left| match self.link(left) {
+            Some(Link::Reference { key, .. }) => key.len(),
+            _ => 0,
+        };
+        std::mem::size_of::<TreeInner>()
+            + self.key().len()
+            + self.value().len()
+            + link_key_len(true)
+            + link_key_len(false)
+    }
+
     /// Returns the root node's key as a slice.
     #[inline]
     pub fn key(&self) -> &[u8] {
@@ -100,6 +162,12 @@ impl Tree {
         self.inner.kv.hash()
     }
 
+    /// Returns the algorithm the root node's hashes are computed with.
+    #[inline]
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.inner.kv.hash_algorithm()
+    }
+
     /// Returns a reference to the root node's `Link` on the given side, if any.
     /// If there is no child, returns `None`.
     #[inline]
@@ -152,14 +220,77 @@ impl Tree {
         self.link(left).map_or(&NULL_HASH, |link| link.hash())
     }
 
-    /// Computes and returns the hash of the root node.
+    /// Computes and returns the hash of the root node. If the tree keeps
+    /// aggregate counts, the hash commits to the count of each child.
     #[inline]
     pub fn hash(&self) -> Hash {
-        node_hash::<Hasher>(
+        let (kv, left, right) = (
             self.inner.kv.hash(),
             self.child_hash(true),
             self.child_hash(false),
-        )
+        );
+        match (self.child_count(true), self.child_count(false)) {
+            (Some(left_count), Some(right_count)) => {
+                self.hash_algorithm()
+                    .node_hash_with_count(kv, left, right, left_count, right_count)
+            }
+            _ => self.hash_algorithm().node_hash(kv, left, right),
+        }
+    }
+
+    /// Returns `true` if the tree keeps aggregate counts.
+    #[inline]
+    pub fn has_aggregate_counts(&self) -> bool {
+        self.inner.child_counts.is_some()
+    }
+
+    /// Returns the number of keys in the tree (including the root node), or
+    /// `None` if the tree does not keep aggregate counts.
+    #[inline]
+    pub fn count(&self) -> Option<u64> {
+        Some(1 + self.child_count(true)? + self.child_count(false)?)
+    }
+
+    /// Returns the number of keys in the subtree of the child on the given
+    /// side (0 if there is no child), or `None` if the tree does not keep
+    /// aggregate counts.
+    ///
+    /// Only modified children are counted by walking them, the counts of other
+    /// children are the ones stored in the node.
+    #[inline]
+    pub fn child_count(&self, left: bool) -> Option<u64> {
+        let (left_count, right_count) = self.inner.child_counts?;
+        match self.link(left) {
+            None => Some(0),
+            Some(Link::Modified { tree, .. }) => tree.count(),
+            Some(_) if left => Some(left_count),
+            Some(_) => Some(right_count),
+        }
+    }
+
+    /// Sets the stored counts of the left and right subtrees, making the tree
+    /// keep aggregate counts. The counts are not ensured to be correct.
+    #[cfg(feature = "full")]
+    #[inline]
+    pub(crate) fn set_child_counts(&mut self, child_counts: (u64, u64)) {
+        self.inner.child_counts = Some(child_counts);
+    }
+
+    /// Updates the stored counts of the children after they were committed,
+    /// so they are correct when the node is written.
+    fn update_child_counts(&mut self) {
+        let (left_count, right_count) = match self.inner.child_counts {
+            Some(child_counts) => child_counts,
+            None => return,
+        };
+        let count = |link: Option<&Link>, stored| match link {
+            None => 0,
+            Some(link) => link.tree().and_then(Tree::count).unwrap_or(stored),
+        };
+        self.inner.child_counts = Some((
+            count(self.link(true), left_count),
+            count(self.link(false), right_count),
+        ));
     }
 
     /// Returns the number of pending writes for the child on the given side, if
@@ -362,6 +493,49 @@ impl Tree {
             }
         }
 
+        self.write_and_prune(c)
+    }
+
+    /// Like `commit`, but when both children of a node have at least
+    /// `MIN_PARALLEL_BATCH_SIZE` modified nodes to write, commits the left
+    /// subtree on a new thread while the right one is committed on the current
+    /// thread. Nodes are split this way down to `depth` levels below the root,
+    /// so up to `2^depth` threads are used.
+    ///
+    /// Each thread writes through its own committer split from `c`, and the
+    /// committers are merged in the order `commit` would have made the writes,
+    /// so the result is the same as committing serially.
+    pub fn commit_parallel<C: SplitCommit>(&mut self, c: &mut C, depth: u8) -> Result<()> {
+        let split = depth > 0
+            && self.child_pending_writes(true) >= MIN_PARALLEL_BATCH_SIZE
+            && self.child_pending_writes(false) >= MIN_PARALLEL_BATCH_SIZE;
+        if !split {
+            commit_link(&mut self.inner.left, c, depth)?;
+            commit_link(&mut self.inner.right, c, depth)?;
+        } else {
+            let mut left_c = c.split();
+            let mut right_c = c.split();
+            let (left, right) = (&mut self.inner.left, &mut self.inner.right);
+
+            let (left_result, right_result) = std::thread::scope(|scope| {
+                let left_handle = scope.spawn(|| commit_link(left, &mut left_c, depth - 1));
+                let right_result = commit_link(right, &mut right_c, depth - 1);
+                (left_handle.join(), right_result)
+            });
+            left_result.expect("Commit thread panicked")?;
+            right_result?;
+
+            c.merge(left_c);
+            c.merge(right_c);
+        }
+
+        self.write_and_prune(c)
+    }
+
+    /// Writes the node after its children were committed, then prunes the
+    /// children the committer does not keep in memory.
+    fn write_and_prune<C: Commit>(&mut self, c: &mut C) -> Result<()> {
+        self.update_child_counts();
         c.write(self)?;
 
         let (prune_left, prune_right) = c.prune(self);
@@ -439,12 +613,39 @@ pub fn side_to_str(left: bool) -> &'static str {
     }
 }
 
+/// Commits the subtree of a modified link with `Tree::commit_parallel`, and
+/// replaces the link with a `Link::Loaded` one. Other links are left as is.
+fn commit_link<C: SplitCommit>(slot: &mut Option<Link>, c: &mut C, depth: u8) -> Result<()> {
+    if !matches!(slot, Some(Link::Modified { .. })) {
+        return Ok(());
+    }
+
+    if let Some(Link::Modified {
+        mut tree,
+        child_heights,
+        ..
+    }) = slot.take()
+    {
+        tree.commit_parallel(c, depth)?;
+        *slot = Some(Link::Loaded {
+            hash: tree.hash(),
+            tree,
+            child_heights,
+        });
+    } else {
+        unreachable!()
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
-    use super::commit::NoopCommit;
+    use super::commit::{Commit, NoopCommit, SplitCommit};
     use super::hash::NULL_HASH;
-    use super::Tree;
+    use super::{PanicSource, Tree, Walker, MIN_PARALLEL_BATCH_SIZE};
     use crate::error::Result;
+    use crate::test_utils::{make_batch_seq, make_tree_seq};
 
     #[test]
     fn build_tree() -> Result<()> {
@@ -532,6 +733,56 @@ mod test {
         Ok(())
     }
 
+    #[derive(Default)]
+    struct RecordCommit(Vec<(Vec<u8>, Vec<u8>)>);
+
+    impl Commit for RecordCommit {
+        fn write(&mut self, tree: &Tree) -> Result<()> {
+            self.0.push((tree.key().to_vec(), tree.encode()));
+            Ok(())
+        }
+    }
+
+    impl SplitCommit for RecordCommit {
+        fn split(&self) -> Self {
+            RecordCommit::default()
+        }
+
+        fn merge(&mut self, other: Self) {
+            self.0.extend(other.0);
+        }
+    }
+
+    #[test]
+    fn commit_parallel_matches_serial() {
+        let modified_tree = || {
+            let tree = make_tree_seq(10_000);
+            let walker = Walker::new(tree, PanicSource {});
+            let batch = make_batch_seq(0..10_000);
+            Walker::apply_to(Some(walker), &batch, PanicSource {})
+                .unwrap()
+                .0
+                .unwrap()
+        };
+
+        let mut serial_tree = modified_tree();
+        // enough nodes were modified on both sides for the commit to split
+        assert!(serial_tree.child_pending_writes(true) >= MIN_PARALLEL_BATCH_SIZE);
+        assert!(serial_tree.child_pending_writes(false) >= MIN_PARALLEL_BATCH_SIZE);
+        let mut serial = RecordCommit::default();
+        serial_tree.commit(&mut serial).unwrap();
+
+        for depth in [0, 1, 3, 8] {
+            let mut parallel_tree = modified_tree();
+            let mut parallel = RecordCommit::default();
+            parallel_tree.commit_parallel(&mut parallel, depth).unwrap();
+
+            assert_eq!(parallel.0, serial.0);
+            assert_eq!(parallel_tree.hash(), serial_tree.hash());
+            assert!(!parallel_tree.link(true).unwrap().is_modified());
+        }
+    }
+
     #[test]
     fn child_hash() -> Result<()> {
         let mut tree =
@@ -40,6 +40,13 @@ impl Fetch for PanicSource {
     }
 }
 
+/// The smallest number of operations for which the parallel apply path hands
+/// one half of a batch to another thread, and of modified nodes for which
+/// `Tree::commit_parallel` hands one subtree to another thread. Smaller halves
+/// are handled on the current thread, since spawning a thread would cost more
+/// than it saves.
+pub const MIN_PARALLEL_BATCH_SIZE: usize = 1_000;
+
 impl<S> Walker<S>
 where
     S: Fetch + Sized + Send + Clone,
@@ -53,13 +60,33 @@ where
         maybe_tree: Option<Self>,
         batch: &Batch,
         source: S,
+    ) -> Result<(Option<Tree>, LinkedList<Vec<u8>>)> {
+        Self::apply_to_parallel(maybe_tree, batch, source, 0)
+    }
+
+    /// Like `apply_to`, but when the operations for both subtrees of a node
+    /// are numerous enough (see `MIN_PARALLEL_BATCH_SIZE`), applies the left
+    /// half of the batch on a new thread while the right half is applied on
+    /// the current thread. Nodes are split this way down to `depth` levels
+    /// below the root, so up to `2^depth` threads are used.
+    ///
+    /// The two halves touch disjoint subtrees and the node is rebalanced only
+    /// once both are done, so the resulting tree, including its shape and root
+    /// hash, is the same as with `apply_to`.
+    ///
+    /// Keys in batch must be sorted and unique.
+    pub fn apply_to_parallel(
+        maybe_tree: Option<Self>,
+        batch: &Batch,
+        source: S,
+        depth: u8,
     ) -> Result<(Option<Tree>, LinkedList<Vec<u8>>)> {
         let (maybe_walker, deleted_keys) = if batch.is_empty() {
             (maybe_tree, LinkedList::default())
         } else {
             match maybe_tree {
-                None => return Ok((Self::build(batch, source)?, LinkedList::default())),
-                Some(tree) => tree.apply(batch)?,
+                None => return Ok((Self::build(batch, source, depth)?, LinkedList::default())),
+                Some(tree) => tree.apply(batch, depth)?,
             }
         };
 
@@ -70,7 +97,7 @@ where
     /// Builds a `Tree` from a batch of operations.
     ///
     /// Keys in batch must be sorted and unique.
-    fn build(batch: &Batch, source: S) -> Result<Option<Tree>> {
+    fn build(batch: &Batch, source: S, depth: u8) -> Result<Option<Tree>> {
         if batch.is_empty() {
             return Ok(None);
         }
@@ -82,11 +109,11 @@ where
                 let left_batch = &batch[..mid_index];
                 let right_batch = &batch[mid_index + 1..];
 
-                let maybe_tree = Self::build(left_batch, source.clone())?
+                let maybe_tree = Self::build(left_batch, source.clone(), depth)?
                     .map(|tree| Self::new(tree, source.clone()));
                 let maybe_tree = match maybe_tree {
-                    Some(tree) => tree.apply(right_batch)?.0,
-                    None => Self::build(right_batch, source.clone())?
+                    Some(tree) => tree.apply(right_batch, depth)?.0,
+                    None => Self::build(right_batch, source.clone(), depth)?
                         .map(|tree| Self::new(tree, source.clone())),
                 };
                 return Ok(maybe_tree.map(|tree| tree.into()));
@@ -95,10 +122,17 @@ where
         };
 
         // TODO: take from batch so we don't have to clone
-        let mid_tree = Tree::new(mid_key.to_vec(), mid_value.to_vec())?;
-        let mid_walker = Walker::new(mid_tree, PanicSource {});
+        let mut mid_tree = Tree::new_with_algorithm(
+            mid_key.to_vec(),
+            mid_value.to_vec(),
+            source.hash_algorithm(),
+        )?;
+        if source.aggregate_counts() {
+            mid_tree = mid_tree.with_aggregate_counts();
+        }
+        let mid_walker = Walker::new(mid_tree, source);
         Ok(mid_walker
-            .recurse(batch, mid_index, true)?
+            .recurse(batch, mid_index, true, depth)?
             .0 // use walker, ignore deleted_keys since it should be empty
             .map(|w| w.into_inner()))
     }
@@ -107,7 +141,7 @@ where
     /// `Walker<S>::apply`_to, but requires a populated tree.
     ///
     /// Keys in batch must be sorted and unique.
-    fn apply(self, batch: &Batch) -> Result<(Option<Self>, LinkedList<Vec<u8>>)> {
+    fn apply(self, batch: &Batch, depth: u8) -> Result<(Option<Self>, LinkedList<Vec<u8>>)> {
         // binary search to see if this node's key is in the batch, and to split
         // into left and right batches
         let search = batch.binary_search_by(|(key, _op)| key.as_slice().cmp(self.tree().key()));
@@ -123,13 +157,17 @@ where
                     let (walker, maybe_left) = self.detach(true)?;
                     let (walker, maybe_right) = walker.detach(false)?;
 
-                    let (maybe_left, mut deleted_keys) =
-                        Self::apply_to(maybe_left, &batch[..index], source.clone())?;
+                    let (maybe_left, mut deleted_keys) = Self::apply_to_parallel(
+                        maybe_left,
+                        &batch[..index],
+                        source.clone(),
+                        depth,
+                    )?;
 
                     deleted_keys.push_back(key);
 
                     let (maybe_right, mut deleted_keys_right) =
-                        Self::apply_to(maybe_right, &batch[index + 1..], source)?;
+                        Self::apply_to_parallel(maybe_right, &batch[index + 1..], source, depth)?;
                     deleted_keys.append(&mut deleted_keys_right);
 
                     let maybe_walker = walker
@@ -151,19 +189,21 @@ where
             Err(index) => (index, false),
         };
 
-        tree?.recurse(batch, mid, exclusive)
+        tree?.recurse(batch, mid, exclusive, depth)
     }
 
     /// Recursively applies operations to the tree's children (if there are any
     /// operations for them).
     ///
-    /// This recursion executes serially in the same thread, but in the future
-    /// will be dispatched to workers in other threads.
+    /// This recursion executes serially in the same thread, unless `depth` is
+    /// non-zero and both halves of the batch are large enough to apply them
+    /// on separate threads.
     fn recurse(
         self,
         batch: &Batch,
         mid: usize,
         exclusive: bool,
+        depth: u8,
     ) -> Result<(Option<Self>, LinkedList<Vec<u8>>)> {
         let left_batch = &batch[..mid];
         let right_batch = if exclusive {
@@ -172,13 +212,20 @@ where
             &batch[mid..]
         };
 
+        if depth > 0
+            && left_batch.len() >= MIN_PARALLEL_BATCH_SIZE
+            && right_batch.len() >= MIN_PARALLEL_BATCH_SIZE
+        {
+            return self.recurse_parallel(left_batch, right_batch, depth);
+        }
+
         let mut deleted_keys = LinkedList::default();
 
         let tree = if !left_batch.is_empty() {
             let source = self.clone_source();
             self.walk(true, |maybe_left| {
                 let (maybe_left, mut deleted_keys_left) =
-                    Self::apply_to(maybe_left, left_batch, source)?;
+                    Self::apply_to_parallel(maybe_left, left_batch, source, depth)?;
                 deleted_keys.append(&mut deleted_keys_left);
                 Ok(maybe_left)
             })?
@@ -190,7 +237,7 @@ where
             let source = tree.clone_source();
             tree.walk(false, |maybe_right| {
                 let (maybe_right, mut deleted_keys_right) =
-                    Self::apply_to(maybe_right, right_batch, source)?;
+                    Self::apply_to_parallel(maybe_right, right_batch, source, depth)?;
                 deleted_keys.append(&mut deleted_keys_right);
                 Ok(maybe_right)
             })?
@@ -203,6 +250,40 @@ where
         Ok((Some(tree), deleted_keys))
     }
 
+    /// Applies `left_batch` to the left child on a new thread, and
+    /// `right_batch` to the right child on the current thread, then reattaches
+    /// both children and rebalances, as `recurse` would.
+    fn recurse_parallel(
+        self,
+        left_batch: &Batch,
+        right_batch: &Batch,
+        depth: u8,
+    ) -> Result<(Option<Self>, LinkedList<Vec<u8>>)> {
+        let left_source = self.clone_source();
+        let right_source = self.clone_source();
+        let (tree, maybe_left) = self.detach(true)?;
+        let (tree, maybe_right) = tree.detach(false)?;
+
+        let (left_result, right_result) = std::thread::scope(|scope| {
+            let left_handle = scope.spawn(move || {
+                Self::apply_to_parallel(maybe_left, left_batch, left_source, depth - 1)
+            });
+            let right_result =
+                Self::apply_to_parallel(maybe_right, right_batch, right_source, depth - 1);
+            (left_handle.join(), right_result)
+        });
+        let (maybe_left, mut deleted_keys) = left_result.expect("Apply thread panicked")?;
+        let (maybe_right, mut deleted_keys_right) = right_result?;
+        deleted_keys.append(&mut deleted_keys_right);
+
+        let tree = tree
+            .attach(true, maybe_left)
+            .attach(false, maybe_right)
+            .maybe_balance()?;
+
+        Ok((Some(tree), deleted_keys))
+    }
+
     /// Gets the wrapped tree's balance factor.
     #[inline]
     fn balance_factor(&self) -> i8 {
@@ -308,7 +389,7 @@ mod test {
         let batch = [(b"foo2".to_vec(), Op::Put(b"bar2".to_vec()))];
         let tree = Tree::new(b"foo".to_vec(), b"bar".to_vec())?;
         let (maybe_walker, deleted_keys) = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+            .apply(&batch, 0)
             .expect("apply errored");
         let walker = maybe_walker.expect("should be Some");
         assert_eq!(walker.tree().key(), b"foo");
@@ -322,7 +403,7 @@ mod test {
         let batch = [(b"foo".to_vec(), Op::Put(b"bar2".to_vec()))];
         let tree = Tree::new(b"foo".to_vec(), b"bar".to_vec())?;
         let (maybe_walker, deleted_keys) = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+            .apply(&batch, 0)
             .expect("apply errored");
         let walker = maybe_walker.expect("should be Some");
         assert_eq!(walker.tree().key(), b"foo");
@@ -348,7 +429,7 @@ mod test {
             }),
         );
         let (maybe_walker, deleted_keys) = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+            .apply(&batch, 0)
             .expect("apply errored");
         let walker = maybe_walker.expect("should be Some");
         assert_eq!(walker.tree().key(), b"foo");
@@ -364,7 +445,7 @@ mod test {
     fn delete_non_existent() -> Result<()> {
         let batch = [(b"foo2".to_vec(), Op::Delete)];
         let tree = Tree::new(b"foo".to_vec(), b"bar".to_vec())?;
-        Walker::new(tree, PanicSource {}).apply(&batch).unwrap();
+        Walker::new(tree, PanicSource {}).apply(&batch, 0).unwrap();
         Ok(())
     }
 
@@ -373,7 +454,7 @@ mod test {
         let batch = [(b"foo".to_vec(), Op::Delete)];
         let tree = Tree::new(b"foo".to_vec(), b"bar".to_vec())?;
         let (maybe_walker, deleted_keys) = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+            .apply(&batch, 0)
             .expect("apply errored");
         assert!(maybe_walker.is_none());
         assert_eq!(deleted_keys.len(), 1);
@@ -386,7 +467,7 @@ mod test {
         let tree = make_tree_seq(50);
         let batch = [del_entry(5)];
         let (maybe_walker, deleted_keys) = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+            .apply(&batch, 0)
             .expect("apply errored");
         maybe_walker.expect("should be Some");
         assert_eq!(deleted_keys.len(), 1);
@@ -398,7 +479,7 @@ mod test {
         let tree = make_tree_seq(50);
         let batch = [del_entry(29), del_entry(34)];
         let (maybe_walker, mut deleted_keys) = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+            .apply(&batch, 0)
             .expect("apply errored");
         maybe_walker.expect("should be Some");
         assert_eq!(deleted_keys.len(), 2);
@@ -411,7 +492,7 @@ mod test {
         let tree = make_tree_seq(10);
         let batch = [del_entry(7), del_entry(9)];
         let (maybe_walker, deleted_keys) = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+            .apply(&batch, 0)
             .expect("apply errored");
         maybe_walker.expect("should be Some");
         let mut deleted_keys: Vec<&Vec<u8>> = deleted_keys.iter().collect();
@@ -424,7 +505,7 @@ mod test {
         let tree = make_tree_seq(7);
 
         let walker = Walker::new(tree, PanicSource {})
-            .apply(&[(vec![0; 20], Delete)])
+            .apply(&[(vec![0; 20], Delete)], 0)
             .expect("apply errored")
             .0
             .unwrap();
@@ -438,7 +519,7 @@ mod test {
             del_entry(5),
             del_entry(6),
         ];
-        let (maybe_walker, deleted_keys) = walker.apply(&batch).expect("apply errored");
+        let (maybe_walker, deleted_keys) = walker.apply(&batch, 0).expect("apply errored");
         let walker = maybe_walker.expect("should be Some");
 
         let mut deleted_keys: Vec<&Vec<u8>> = deleted_keys.iter().collect();
@@ -536,9 +617,55 @@ mod test {
         }
 
         let (maybe_walker, deleted_keys) = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+            .apply(&batch, 0)
             .expect("apply errored");
         maybe_walker.expect("should be Some");
         assert_eq!(deleted_keys.len(), 1_500);
     }
+
+    #[test]
+    fn apply_parallel_matches_serial() {
+        let apply = |batch: &Batch, depth| {
+            let tree = make_tree_seq(20_000);
+            let walker = Walker::new(tree, PanicSource {});
+            let (maybe_tree, deleted_keys) =
+                Walker::apply_to_parallel(Some(walker), batch, PanicSource {}, depth)
+                    .expect("apply errored");
+            let mut tree = maybe_tree.expect("expected tree");
+            tree.commit(&mut NoopCommit {}).expect("commit failed");
+            assert_tree_invariants(&tree);
+            (tree, deleted_keys)
+        };
+
+        // deletes every third key and inserts keys between the existing ones
+        let mut batch = vec![];
+        for i in 0..20_000 {
+            if i % 3 == 0 {
+                batch.push(del_entry(i));
+            }
+            let mut key = seq_key(i);
+            key.push(0);
+            batch.push((key, Op::Put(vec![123; 60])));
+        }
+
+        let (serial, serial_deleted) = apply(&batch, 0);
+        for depth in [1, 2, 4] {
+            let (parallel, parallel_deleted) = apply(&batch, depth);
+            assert_eq!(parallel.hash(), serial.hash());
+            assert_eq!(parallel.key(), serial.key());
+            assert_eq!(parallel.height(), serial.height());
+            assert_eq!(parallel_deleted, serial_deleted);
+        }
+
+        let build = |depth| {
+            let mut tree =
+                Walker::<PanicSource>::apply_to_parallel(None, &batch[1..], PanicSource {}, depth)
+                    .expect("apply errored")
+                    .0
+                    .expect("expected tree");
+            tree.commit(&mut NoopCommit {}).expect("commit failed");
+            tree
+        };
+        assert_eq!(build(3).hash(), build(0).hash());
+    }
 }
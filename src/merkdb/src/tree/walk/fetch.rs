@@ -1,4 +1,4 @@
-use super::super::{Link, Tree};
+use super::super::{HashAlgorithm, Link, Tree};
 use crate::error::{Error, Result};
 
 /// A source of data to be used by the tree when encountering a pruned node.
@@ -13,6 +13,18 @@ pub trait Fetch {
         self.fetch_by_key_expect(link.key())
     }
 
+    /// Returns the algorithm the hashes of the fetched trees are computed
+    /// with, which is also used for nodes created when applying batches.
+    fn hash_algorithm(&self) -> HashAlgorithm {
+        HashAlgorithm::default()
+    }
+
+    /// Returns `true` if the fetched trees keep aggregate counts, in which case
+    /// nodes created when applying batches keep them too.
+    fn aggregate_counts(&self) -> bool {
+        false
+    }
+
     fn fetch_by_key_expect(&self, key: &[u8]) -> Result<Tree> {
         self.fetch_by_key(key)?
             .ok_or_else(|| Error::Key(format!("Key does not exist: {key:?}")))